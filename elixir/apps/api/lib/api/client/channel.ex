@@ -121,6 +121,22 @@ defmodule API.Client.Channel do
     end
   end
 
+  # This message is sent by the gateway once it let the client
+  # reach a resource over a connection that is being reused
+  def handle_info(
+        {:access_allowed, socket_ref, resource_id, {opentelemetry_ctx, opentelemetry_span_ctx}},
+        socket
+      ) do
+    OpenTelemetry.Ctx.attach(opentelemetry_ctx)
+    OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
+
+    OpenTelemetry.Tracer.with_span "client.access_allowed", %{resource_id: resource_id} do
+      reply(socket_ref, {:ok, %{resource_id: resource_id}})
+
+      {:noreply, socket}
+    end
+  end
+
   def handle_info({:resource_added, resource_id}, socket) do
     OpenTelemetry.Ctx.attach(socket.assigns.opentelemetry_ctx)
     OpenTelemetry.Tracer.set_current_span(socket.assigns.opentelemetry_span_ctx)
@@ -235,12 +251,13 @@ defmodule API.Client.Channel do
         :ok =
           API.Gateway.Channel.broadcast(
             gateway,
-            {:allow_access,
+            {:allow_access, {self(), socket_ref(socket)},
              %{
                client_id: socket.assigns.client.id,
                resource_id: resource.id,
                flow_id: flow.id,
-               authorization_expires_at: socket.assigns.subject.expires_at
+               authorization_expires_at: socket.assigns.subject.expires_at,
+               wildcard_match: Map.get(attrs, "wildcard_match")
              }, {opentelemetry_ctx, opentelemetry_span_ctx}}
           )
 
@@ -265,7 +282,7 @@ defmodule API.Client.Channel do
           "resource_id" => resource_id,
           "client_rtc_session_description" => client_rtc_session_description,
           "client_preshared_key" => preshared_key
-        },
+        } = attrs,
         socket
       ) do
     ctx_attrs = %{gateway_id: gateway_id, resource_id: resource_id}
@@ -295,7 +312,8 @@ defmodule API.Client.Channel do
                flow_id: flow.id,
                authorization_expires_at: socket.assigns.subject.expires_at,
                client_rtc_session_description: client_rtc_session_description,
-               client_preshared_key: preshared_key
+               client_preshared_key: preshared_key,
+               wildcard_match: Map.get(attrs, "wildcard_match")
              }, {opentelemetry_ctx, opentelemetry_span_ctx}}
           )
 
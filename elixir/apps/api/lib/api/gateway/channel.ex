@@ -5,6 +5,10 @@ defmodule API.Gateway.Channel do
   require Logger
   require OpenTelemetry.Tracer
 
+  # The gateway doesn't answer the messages it failed to act on,
+  # so the references it never replies to are dropped after a while
+  @ref_ttl :timer.minutes(1)
+
   def broadcast(%Gateways.Gateway{} = gateway, payload) do
     broadcast(gateway.id, payload)
   end
@@ -75,11 +79,18 @@ defmodule API.Gateway.Channel do
     end
   end
 
-  def handle_info({:allow_access, attrs, {opentelemetry_ctx, opentelemetry_span_ctx}}, socket) do
+  def handle_info(
+        {:allow_access, {channel_pid, socket_ref}, attrs,
+         {opentelemetry_ctx, opentelemetry_span_ctx}},
+        socket
+      ) do
     OpenTelemetry.Ctx.attach(opentelemetry_ctx)
     OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
 
     OpenTelemetry.Tracer.with_span "gateway.allow_access" do
+      opentelemetry_ctx = OpenTelemetry.Ctx.get_current()
+      opentelemetry_span_ctx = OpenTelemetry.Tracer.current_span_ctx()
+
       %{
         client_id: client_id,
         resource_id: resource_id,
@@ -89,13 +100,24 @@ defmodule API.Gateway.Channel do
 
       resource = Resources.fetch_resource_by_id!(resource_id)
 
+      ref = Ecto.UUID.generate()
+
       push(socket, "allow_access", %{
+        ref: ref,
         client_id: client_id,
         flow_id: flow_id,
         resource: Views.Resource.render(resource),
-        expires_at: DateTime.to_unix(authorization_expires_at, :second)
+        expires_at: DateTime.to_unix(authorization_expires_at, :second),
+        wildcard_match: Map.get(attrs, :wildcard_match)
       })
 
+      socket =
+        put_ref(
+          socket,
+          ref,
+          {channel_pid, socket_ref, resource_id, {opentelemetry_ctx, opentelemetry_span_ctx}}
+        )
+
       {:noreply, socket}
     end
   end
@@ -156,7 +178,8 @@ defmodule API.Gateway.Channel do
         relays: Views.Relay.render_many(relays, authorization_expires_at),
         resource: Views.Resource.render(resource),
         client: Views.Client.render(client, rtc_session_description, preshared_key),
-        expires_at: DateTime.to_unix(authorization_expires_at, :second)
+        expires_at: DateTime.to_unix(authorization_expires_at, :second),
+        wildcard_match: Map.get(attrs, :wildcard_match)
       })
 
       Logger.debug("Awaiting gateway connection_ready message",
@@ -166,19 +189,32 @@ defmodule API.Gateway.Channel do
         ref: ref
       )
 
-      refs =
-        Map.put(
-          socket.assigns.refs,
+      socket =
+        put_ref(
+          socket,
           ref,
           {channel_pid, socket_ref, resource_id, {opentelemetry_ctx, opentelemetry_span_ctx}}
         )
 
-      socket = assign(socket, :refs, refs)
-
       {:noreply, socket}
     end
   end
 
+  def handle_info({:expire_ref, ref}, socket) do
+    case Map.pop(socket.assigns.refs, ref) do
+      {nil, _refs} ->
+        {:noreply, socket}
+
+      {{_channel_pid, _socket_ref, resource_id, _opentelemetry_ctx}, refs} ->
+        Logger.debug("Gateway did not reply to the message in time",
+          resource_id: resource_id,
+          ref: ref
+        )
+
+        {:noreply, assign(socket, :refs, refs)}
+    end
+  end
+
   @impl true
   def handle_in(
         "connection_ready",
@@ -188,31 +224,64 @@ defmodule API.Gateway.Channel do
         },
         socket
       ) do
-    {{channel_pid, socket_ref, resource_id, {opentelemetry_ctx, opentelemetry_span_ctx}}, refs} =
-      Map.pop(socket.assigns.refs, ref)
+    case Map.pop(socket.assigns.refs, ref) do
+      {nil, _refs} ->
+        Logger.warning("Gateway replied with an unknown reference", ref: ref)
+        {:reply, {:error, :invalid_reference}, socket}
+
+      {{channel_pid, socket_ref, resource_id, {opentelemetry_ctx, opentelemetry_span_ctx}},
+       refs} ->
+        OpenTelemetry.Ctx.attach(opentelemetry_ctx)
+        OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
+
+        OpenTelemetry.Tracer.with_span "gateway.connection_ready" do
+          opentelemetry_ctx = OpenTelemetry.Ctx.get_current()
+          opentelemetry_span_ctx = OpenTelemetry.Tracer.current_span_ctx()
+
+          socket = assign(socket, :refs, refs)
+
+          send(
+            channel_pid,
+            {:connect, socket_ref, resource_id, socket.assigns.gateway.public_key,
+             rtc_session_description, {opentelemetry_ctx, opentelemetry_span_ctx}}
+          )
 
-    OpenTelemetry.Ctx.attach(opentelemetry_ctx)
-    OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
+          Logger.debug("Gateway replied to the Client with :connect message",
+            resource_id: resource_id,
+            channel_pid: inspect(channel_pid),
+            ref: ref
+          )
 
-    OpenTelemetry.Tracer.with_span "gateway.connection_ready" do
-      opentelemetry_ctx = OpenTelemetry.Ctx.get_current()
-      opentelemetry_span_ctx = OpenTelemetry.Tracer.current_span_ctx()
+          {:reply, :ok, socket}
+        end
+    end
+  end
 
-      socket = assign(socket, :refs, refs)
+  def handle_in("access_allowed", %{"ref" => ref}, socket) do
+    case Map.pop(socket.assigns.refs, ref) do
+      {nil, _refs} ->
+        Logger.warning("Gateway replied with an unknown reference", ref: ref)
+        {:reply, {:error, :invalid_reference}, socket}
 
-      send(
-        channel_pid,
-        {:connect, socket_ref, resource_id, socket.assigns.gateway.public_key,
-         rtc_session_description, {opentelemetry_ctx, opentelemetry_span_ctx}}
-      )
+      {{channel_pid, socket_ref, resource_id, {opentelemetry_ctx, opentelemetry_span_ctx}},
+       refs} ->
+        OpenTelemetry.Ctx.attach(opentelemetry_ctx)
+        OpenTelemetry.Tracer.set_current_span(opentelemetry_span_ctx)
 
-      Logger.debug("Gateway replied to the Client with :connect message",
-        resource_id: resource_id,
-        channel_pid: inspect(channel_pid),
-        ref: ref
-      )
+        OpenTelemetry.Tracer.with_span "gateway.access_allowed" do
+          opentelemetry_ctx = OpenTelemetry.Ctx.get_current()
+          opentelemetry_span_ctx = OpenTelemetry.Tracer.current_span_ctx()
 
-      {:reply, :ok, socket}
+          socket = assign(socket, :refs, refs)
+
+          send(
+            channel_pid,
+            {:access_allowed, socket_ref, resource_id,
+             {opentelemetry_ctx, opentelemetry_span_ctx}}
+          )
+
+          {:reply, :ok, socket}
+        end
     end
   end
 
@@ -266,6 +335,8 @@ defmodule API.Gateway.Channel do
             "tx_bytes" => tx_bytes
           } = metric
 
+          {:ok, destination} = Domain.Types.IPPort.cast(destination)
+
           %{
             window_started_at: window_started_at,
             window_ended_at: window_ended_at,
@@ -282,4 +353,9 @@ defmodule API.Gateway.Channel do
       {:reply, :ok, socket}
     end
   end
+
+  defp put_ref(socket, ref, value) do
+    Process.send_after(self(), {:expire_ref, ref}, @ref_ttl)
+    assign(socket, :refs, Map.put(socket.assigns.refs, ref, value))
+  end
 end
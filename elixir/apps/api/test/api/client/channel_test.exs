@@ -392,7 +392,7 @@ defmodule API.Client.ChannelTest do
       assert_reply ref, :error, :offline
     end
 
-    test "broadcasts allow_access to the gateways and then returns connect message", %{
+    test "broadcasts allow_access to the gateways and then returns access_allowed reply", %{
       dns_resource: resource,
       gateway: gateway,
       client: client,
@@ -409,9 +409,9 @@ defmodule API.Client.ChannelTest do
         "gateway_id" => gateway.id
       }
 
-      push(socket, "reuse_connection", attrs)
+      ref = push(socket, "reuse_connection", attrs)
 
-      assert_receive {:allow_access, payload, _opentelemetry_ctx}
+      assert_receive {:allow_access, {channel_pid, socket_ref}, payload, _opentelemetry_ctx}
 
       assert %{
                resource_id: ^resource_id,
@@ -420,6 +420,37 @@ defmodule API.Client.ChannelTest do
              } = payload
 
       assert authorization_expires_at == socket.assigns.subject.expires_at
+
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+      send(channel_pid, {:access_allowed, socket_ref, resource_id, otel_ctx})
+
+      assert_reply ref, :ok, %{resource_id: ^resource_id}
+    end
+
+    test "forwards the name the client matched against a wildcard resource", %{
+      dns_resource: resource,
+      gateway: gateway,
+      socket: socket
+    } do
+      :ok = Domain.Gateways.connect_gateway(gateway)
+      Phoenix.PubSub.subscribe(Domain.PubSub, API.Gateway.Socket.id(gateway))
+
+      wildcard_match = %{
+        "name" => "gitlab.internal.example.com",
+        "ipv4" => "198.18.0.1",
+        "ipv6" => "fd00:2021:1111:8000::1"
+      }
+
+      attrs = %{
+        "resource_id" => resource.id,
+        "gateway_id" => gateway.id,
+        "wildcard_match" => wildcard_match
+      }
+
+      push(socket, "reuse_connection", attrs)
+
+      assert_receive {:allow_access, _channel_pid_and_ref, payload, _opentelemetry_ctx}
+      assert payload.wildcard_match == wildcard_match
     end
   end
 
@@ -551,6 +582,34 @@ defmodule API.Client.ChannelTest do
         gateway_rtc_session_description: "FULL_RTC_SD"
       }
     end
+
+    test "forwards the name the client matched against a wildcard resource", %{
+      dns_resource: resource,
+      gateway: gateway,
+      socket: socket
+    } do
+      :ok = Domain.Gateways.connect_gateway(gateway)
+      Phoenix.PubSub.subscribe(Domain.PubSub, API.Gateway.Socket.id(gateway))
+
+      wildcard_match = %{
+        "name" => "gitlab.internal.example.com",
+        "ipv4" => "198.18.0.1",
+        "ipv6" => "fd00:2021:1111:8000::1"
+      }
+
+      attrs = %{
+        "resource_id" => resource.id,
+        "gateway_id" => gateway.id,
+        "client_rtc_session_description" => "RTC_SD",
+        "client_preshared_key" => "PSK",
+        "wildcard_match" => wildcard_match
+      }
+
+      push(socket, "request_connection", attrs)
+
+      assert_receive {:request_connection, _channel_pid_and_ref, payload, _opentelemetry_ctx}
+      assert payload.wildcard_match == wildcard_match
+    end
   end
 
   describe "handle_in/3 broadcast_ice_candidates" do
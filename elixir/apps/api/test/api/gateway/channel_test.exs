@@ -70,6 +70,8 @@ defmodule API.Gateway.ChannelTest do
       relay: relay,
       socket: socket
     } do
+      channel_pid = self()
+      socket_ref = make_ref()
       expires_at = DateTime.utc_now() |> DateTime.add(30, :second)
       otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
       flow_id = Ecto.UUID.generate()
@@ -79,7 +81,7 @@ defmodule API.Gateway.ChannelTest do
 
       send(
         socket.channel_pid,
-        {:allow_access,
+        {:allow_access, {channel_pid, socket_ref},
          %{
            client_id: client.id,
            resource_id: resource.id,
@@ -107,6 +109,37 @@ defmodule API.Gateway.ChannelTest do
       assert payload.flow_id == flow_id
       assert payload.client_id == client.id
       assert DateTime.from_unix!(payload.expires_at) == DateTime.truncate(expires_at, :second)
+      assert is_binary(payload.ref)
+      assert is_nil(payload.wildcard_match)
+    end
+
+    test "pushes the name the client matched against a wildcard resource", %{
+      client: client,
+      resource: resource,
+      socket: socket
+    } do
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      wildcard_match = %{
+        "name" => "gitlab.internal.example.com",
+        "ipv4" => "198.18.0.1",
+        "ipv6" => "fd00:2021:1111:8000::1"
+      }
+
+      send(
+        socket.channel_pid,
+        {:allow_access, {self(), make_ref()},
+         %{
+           client_id: client.id,
+           resource_id: resource.id,
+           flow_id: Ecto.UUID.generate(),
+           authorization_expires_at: DateTime.utc_now() |> DateTime.add(30, :second),
+           wildcard_match: wildcard_match
+         }, otel_ctx}
+      )
+
+      assert_push "allow_access", payload
+      assert payload.wildcard_match == wildcard_match
     end
   end
 
@@ -235,6 +268,42 @@ defmodule API.Gateway.ChannelTest do
              }
 
       assert DateTime.from_unix!(payload.expires_at) == DateTime.truncate(expires_at, :second)
+      assert is_nil(payload.wildcard_match)
+    end
+
+    test "pushes the name the client matched against a wildcard resource", %{
+      client: client,
+      resource: resource,
+      relay: relay,
+      socket: socket
+    } do
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      stamp_secret = Ecto.UUID.generate()
+      :ok = Domain.Relays.connect_relay(relay, stamp_secret)
+
+      wildcard_match = %{
+        "name" => "gitlab.internal.example.com",
+        "ipv4" => "198.18.0.1",
+        "ipv6" => "fd00:2021:1111:8000::1"
+      }
+
+      send(
+        socket.channel_pid,
+        {:request_connection, {self(), make_ref()},
+         %{
+           client_id: client.id,
+           resource_id: resource.id,
+           flow_id: Ecto.UUID.generate(),
+           authorization_expires_at: DateTime.utc_now() |> DateTime.add(30, :second),
+           client_rtc_session_description: "RTC_SD",
+           client_preshared_key: "PSK",
+           wildcard_match: wildcard_match
+         }, otel_ctx}
+      )
+
+      assert_push "request_connection", payload
+      assert payload.wildcard_match == wildcard_match
     end
   end
 
@@ -287,6 +356,130 @@ defmodule API.Gateway.ChannelTest do
 
       assert resource_id == resource.id
     end
+
+    test "replies with an error when the reference is unknown", %{
+      socket: socket
+    } do
+      push_ref =
+        push(socket, "connection_ready", %{
+          "ref" => Ecto.UUID.generate(),
+          "gateway_rtc_session_description" => "RTC_SD"
+        })
+
+      assert_reply push_ref, :error, :invalid_reference
+      refute_receive {:connect, _socket_ref, _resource_id, _public_key, _rtc_sd, _otel_ctx}
+    end
+  end
+
+  describe "handle_in/3 access_allowed" do
+    test "forwards the access to the client channel", %{
+      client: client,
+      resource: resource,
+      socket: socket
+    } do
+      channel_pid = self()
+      socket_ref = make_ref()
+      expires_at = DateTime.utc_now() |> DateTime.add(30, :second)
+      flow_id = Ecto.UUID.generate()
+
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      send(
+        socket.channel_pid,
+        {:allow_access, {channel_pid, socket_ref},
+         %{
+           client_id: client.id,
+           resource_id: resource.id,
+           flow_id: flow_id,
+           authorization_expires_at: expires_at
+         }, otel_ctx}
+      )
+
+      assert_push "allow_access", %{ref: ref, flow_id: ^flow_id}
+
+      push_ref = push(socket, "access_allowed", %{"ref" => ref})
+
+      assert_reply push_ref, :ok
+
+      assert_receive {:access_allowed, ^socket_ref, resource_id, _opentelemetry_ctx}
+
+      assert resource_id == resource.id
+    end
+
+    test "replies with an error when the reference is unknown", %{
+      socket: socket
+    } do
+      push_ref = push(socket, "access_allowed", %{"ref" => Ecto.UUID.generate()})
+
+      assert_reply push_ref, :error, :invalid_reference
+      refute_receive {:access_allowed, _socket_ref, _resource_id, _opentelemetry_ctx}
+    end
+
+    test "replies with an error when the access is acknowledged twice", %{
+      client: client,
+      resource: resource,
+      socket: socket
+    } do
+      channel_pid = self()
+      socket_ref = make_ref()
+      expires_at = DateTime.utc_now() |> DateTime.add(30, :second)
+      flow_id = Ecto.UUID.generate()
+
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      send(
+        socket.channel_pid,
+        {:allow_access, {channel_pid, socket_ref},
+         %{
+           client_id: client.id,
+           resource_id: resource.id,
+           flow_id: flow_id,
+           authorization_expires_at: expires_at
+         }, otel_ctx}
+      )
+
+      assert_push "allow_access", %{ref: ref, flow_id: ^flow_id}
+
+      push_ref = push(socket, "access_allowed", %{"ref" => ref})
+      assert_reply push_ref, :ok
+      assert_receive {:access_allowed, ^socket_ref, _resource_id, _opentelemetry_ctx}
+
+      push_ref = push(socket, "access_allowed", %{"ref" => ref})
+      assert_reply push_ref, :error, :invalid_reference
+      refute_receive {:access_allowed, ^socket_ref, _resource_id, _opentelemetry_ctx}
+    end
+
+    test "forgets the references the gateway never replied to", %{
+      client: client,
+      resource: resource,
+      socket: socket
+    } do
+      channel_pid = self()
+      socket_ref = make_ref()
+      expires_at = DateTime.utc_now() |> DateTime.add(30, :second)
+      flow_id = Ecto.UUID.generate()
+
+      otel_ctx = {OpenTelemetry.Ctx.new(), OpenTelemetry.Tracer.start_span("connect")}
+
+      send(
+        socket.channel_pid,
+        {:allow_access, {channel_pid, socket_ref},
+         %{
+           client_id: client.id,
+           resource_id: resource.id,
+           flow_id: flow_id,
+           authorization_expires_at: expires_at
+         }, otel_ctx}
+      )
+
+      assert_push "allow_access", %{ref: ref, flow_id: ^flow_id}
+
+      send(socket.channel_pid, {:expire_ref, ref})
+
+      push_ref = push(socket, "access_allowed", %{"ref" => ref})
+      assert_reply push_ref, :error, :invalid_reference
+      refute_receive {:access_allowed, ^socket_ref, _resource_id, _opentelemetry_ctx}
+    end
   end
 
   describe "handle_in/3 broadcast_ice_candidates" do
@@ -347,8 +540,9 @@ defmodule API.Gateway.ChannelTest do
       now = DateTime.utc_now() |> DateTime.truncate(:second)
       one_minute_ago = DateTime.add(now, -1, :minute)
 
-      {:ok, destination} = Domain.Types.IPPort.cast("127.0.0.1:80")
+      {:ok, destination} = Domain.Types.IPPort.cast("127.0.0.1")
 
+      # As the gateway sends it
       attrs =
         %{
           "started_at" => DateTime.to_unix(one_minute_ago),
@@ -356,7 +550,7 @@ defmodule API.Gateway.ChannelTest do
           "metrics" => [
             %{
               "flow_id" => flow.id,
-              "destination" => destination,
+              "destination" => "127.0.0.1",
               "rx_bytes" => 100,
               "tx_bytes" => 200
             }
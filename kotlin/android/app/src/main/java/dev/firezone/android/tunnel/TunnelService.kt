@@ -54,19 +54,33 @@ class TunnelService : VpnService() {
             }
         }
 
+        override fun onResourceUnreachable(
+            resourceId: String,
+            reason: String,
+        ) {
+            Log.w(TAG, "onResourceUnreachable: [resourceId:$resourceId] [reason:$reason]")
+        }
+
+        override fun onStats(statsJSON: String) {
+            Log.d(TAG, "onStats: $statsJSON")
+            tunnelRepository.setStats(statsJSON)
+        }
+
         override fun onSetInterfaceConfig(
             tunnelAddressIPv4: String,
             tunnelAddressIPv6: String,
-            dnsAddress: String,
+            dnsAddressIPv4: String,
+            dnsAddressIPv6: String,
             dnsFallbackStrategy: String,
         ): Int {
-            Log.d(TAG, "onSetInterfaceConfig: [IPv4:$tunnelAddressIPv4] [IPv6:$tunnelAddressIPv6] [dns:$dnsAddress] [dnsFallbackStrategy:$dnsFallbackStrategy]")
+            Log.d(TAG, "onSetInterfaceConfig: [IPv4:$tunnelAddressIPv4] [IPv6:$tunnelAddressIPv6] [dnsIPv4:$dnsAddressIPv4] [dnsIPv6:$dnsAddressIPv6] [dnsFallbackStrategy:$dnsFallbackStrategy]")
 
             tunnelRepository.setConfig(
                 TunnelConfig(
                     tunnelAddressIPv4,
                     tunnelAddressIPv6,
-                    dnsAddress,
+                    dnsAddressIPv4,
+                    dnsAddressIPv6,
                     dnsFallbackStrategy,
                 ),
             )
@@ -215,7 +229,8 @@ class TunnelService : VpnService() {
                 addAddress(tunnel.config.tunnelAddressIPv4, 32)
                 addAddress(tunnel.config.tunnelAddressIPv6, 128)
 
-                addDnsServer(tunnel.config.dnsAddress)
+                addDnsServer(tunnel.config.dnsAddressIPv4)
+                addDnsServer(tunnel.config.dnsAddressIPv6)
 
                 /*tunnel.routes.forEach {
                     addRoute(it, 32)
@@ -223,6 +238,7 @@ class TunnelService : VpnService() {
 
                 // TODO: These are the staging Resources. Remove these in favor of the onUpdateResources callback.
                 addRoute("100.100.111.1", 32)
+                addRoute("fd00:2021:1111:8000:100:100:111:1", 128)
                 addRoute("172.31.82.179", 32)
                 addRoute("172.31.83.10", 32)
                 addRoute("172.31.92.238", 32)
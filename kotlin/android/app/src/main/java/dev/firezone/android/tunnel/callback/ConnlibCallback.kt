@@ -2,7 +2,7 @@
 package dev.firezone.android.tunnel.callback
 
 interface ConnlibCallback {
-    fun onSetInterfaceConfig(tunnelAddressIPv4: String, tunnelAddressIPv6: String, dnsAddress: String, dnsFallbackStrategy: String): Int
+    fun onSetInterfaceConfig(tunnelAddressIPv4: String, tunnelAddressIPv6: String, dnsAddressIPv4: String, dnsAddressIPv6: String, dnsFallbackStrategy: String): Int
 
     fun onTunnelReady(): Boolean
 
@@ -12,6 +12,13 @@ interface ConnlibCallback {
 
     fun onUpdateResources(resourceListJSON: String)
 
+    fun onResourceUnreachable(
+        resourceId: String,
+        reason: String,
+    )
+
+    fun onStats(statsJSON: String)
+
     fun onDisconnect(error: String?): Boolean
 
     fun onError(error: String): Boolean
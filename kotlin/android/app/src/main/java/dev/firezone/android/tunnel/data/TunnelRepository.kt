@@ -22,6 +22,10 @@ interface TunnelRepository {
 
     fun getResources(): List<Resource>
 
+    fun setStats(statsJSON: String)
+
+    fun getStats(): String?
+
     fun addRoute(route: String)
 
     fun removeRoute(route: String)
@@ -40,5 +44,6 @@ interface TunnelRepository {
         const val STATE_KEY = "tunnelStateKey"
         const val RESOURCES_KEY = "tunnelResourcesKey"
         const val ROUTES_KEY = "tunnelRoutesKey"
+        const val STATS_KEY = "tunnelStatsKey"
     }
 }
@@ -8,6 +8,7 @@ import dev.firezone.android.tunnel.data.TunnelRepository.Companion.CONFIG_KEY
 import dev.firezone.android.tunnel.data.TunnelRepository.Companion.RESOURCES_KEY
 import dev.firezone.android.tunnel.data.TunnelRepository.Companion.ROUTES_KEY
 import dev.firezone.android.tunnel.data.TunnelRepository.Companion.STATE_KEY
+import dev.firezone.android.tunnel.data.TunnelRepository.Companion.STATS_KEY
 import dev.firezone.android.tunnel.model.Resource
 import dev.firezone.android.tunnel.model.Tunnel
 import dev.firezone.android.tunnel.model.TunnelConfig
@@ -80,6 +81,16 @@ class TunnelRepositoryImpl @Inject constructor(
         }
     }
 
+    override fun setStats(statsJSON: String) {
+        synchronized(lock) {
+            sharedPreferences.edit().putString(STATS_KEY, statsJSON).apply()
+        }
+    }
+
+    override fun getStats(): String? = synchronized(lock) {
+        return sharedPreferences.getString(STATS_KEY, null)
+    }
+
     override fun addRoute(route: String) {
         synchronized(lock) {
             getRoutes().toMutableList().run {
@@ -8,6 +8,7 @@ import kotlinx.parcelize.Parcelize
 data class TunnelConfig(
     val tunnelAddressIPv4: String = "",
     val tunnelAddressIPv6: String = "",
-    val dnsAddress: String = "",
+    val dnsAddressIPv4: String = "",
+    val dnsAddressIPv6: String = "",
     val dnsFallbackStrategy: String = "",
 ) : Parcelable
@@ -3,7 +3,10 @@
 // However, this consideration has made it idiomatic for Java FFI in the Rust
 // ecosystem, so it's used here for consistency.
 
-use connlib_client_shared::{file_logger, Callbacks, Error, ResourceDescription, Session};
+use connlib_client_shared::{
+    file_logger, stats::TunnelStats, Callbacks, Error, ResourceDescription, ResourceId, Session,
+    SessionConfig,
+};
 use ip_network::IpNetwork;
 use jni::{
     objects::{GlobalRef, JClass, JObject, JString, JValue},
@@ -13,6 +16,7 @@ use jni::{
 use secrecy::SecretString;
 use std::path::Path;
 use std::sync::OnceLock;
+use std::time::Duration;
 use std::{
     net::{Ipv4Addr, Ipv6Addr},
     os::fd::RawFd,
@@ -22,6 +26,9 @@ use thiserror::Error;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
+/// How often the app gets the stats of the tunnel.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
 pub struct CallbackHandler {
     vm: JavaVM,
     callback_handler: GlobalRef,
@@ -136,7 +143,8 @@ impl Callbacks for CallbackHandler {
         &self,
         tunnel_address_v4: Ipv4Addr,
         tunnel_address_v6: Ipv6Addr,
-        dns_address: Ipv4Addr,
+        dns_address_v4: Ipv4Addr,
+        dns_address_v6: Ipv6Addr,
         dns_fallback_strategy: String,
     ) -> Result<RawFd, Self::Error> {
         self.env(|mut env| {
@@ -152,9 +160,15 @@ impl Callbacks for CallbackHandler {
                         name: "tunnel_address_v6",
                         source,
                     })?;
-            let dns_address = env.new_string(dns_address.to_string()).map_err(|source| {
+            let dns_address_v4 = env.new_string(dns_address_v4.to_string()).map_err(|source| {
+                CallbackError::NewStringFailed {
+                    name: "dns_address_v4",
+                    source,
+                }
+            })?;
+            let dns_address_v6 = env.new_string(dns_address_v6.to_string()).map_err(|source| {
                 CallbackError::NewStringFailed {
-                    name: "dns_address",
+                    name: "dns_address_v6",
                     source,
                 }
             })?;
@@ -170,11 +184,12 @@ impl Callbacks for CallbackHandler {
             env.call_method(
                 &self.callback_handler,
                 name,
-                "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)I",
+                "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)I",
                 &[
                     JValue::from(&tunnel_address_v4),
                     JValue::from(&tunnel_address_v6),
-                    JValue::from(&dns_address),
+                    JValue::from(&dns_address_v4),
+                    JValue::from(&dns_address_v6),
                     JValue::from(&dns_fallback_strategy),
                 ],
             )
@@ -252,6 +267,52 @@ impl Callbacks for CallbackHandler {
         })
     }
 
+    fn on_resource_unreachable(
+        &self,
+        resource_id: ResourceId,
+        reason: &Error,
+    ) -> Result<(), Self::Error> {
+        self.env(|mut env| {
+            let resource_id = env.new_string(resource_id.to_string()).map_err(|source| {
+                CallbackError::NewStringFailed {
+                    name: "resource_id",
+                    source,
+                }
+            })?;
+            let reason = env.new_string(reason.to_string()).map_err(|source| {
+                CallbackError::NewStringFailed {
+                    name: "reason",
+                    source,
+                }
+            })?;
+            call_method(
+                &mut env,
+                &self.callback_handler,
+                "onResourceUnreachable",
+                "(Ljava/lang/String;Ljava/lang/String;)V",
+                &[JValue::from(&resource_id), JValue::from(&reason)],
+            )
+        })
+    }
+
+    fn on_stats(&self, stats: &TunnelStats) -> Result<(), Self::Error> {
+        self.env(|mut env| {
+            let stats = env
+                .new_string(serde_json::to_string(stats)?)
+                .map_err(|source| CallbackError::NewStringFailed {
+                    name: "stats",
+                    source,
+                })?;
+            call_method(
+                &mut env,
+                &self.callback_handler,
+                "onStats",
+                "(Ljava/lang/String;)V",
+                &[JValue::from(&stats)],
+            )
+        })
+    }
+
     fn on_disconnect(&self, error: Option<&Error>) -> Result<(), Self::Error> {
         self.env(|mut env| {
             let error = env
@@ -370,7 +431,17 @@ fn connect(
         handle,
     };
 
-    let session = Session::connect(portal_url.as_str(), secret, device_id, callback_handler)?;
+    let config = SessionConfig {
+        stats_interval: Some(STATS_INTERVAL),
+        ..Default::default()
+    };
+    let session = Session::connect(
+        portal_url.as_str(),
+        secret,
+        device_id,
+        config,
+        callback_handler,
+    )?;
 
     Ok(session)
 }
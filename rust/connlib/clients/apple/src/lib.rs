@@ -1,7 +1,10 @@
 // Swift bridge generated code triggers this below
 #![allow(clippy::unnecessary_cast, improper_ctypes, non_camel_case_types)]
 
-use connlib_client_shared::{file_logger, Callbacks, Error, ResourceDescription, Session};
+use connlib_client_shared::{
+    file_logger, stats::TunnelStats, Callbacks, Error, ResourceDescription, ResourceId, Session,
+    SessionConfig,
+};
 use ip_network::IpNetwork;
 use secrecy::SecretString;
 use std::{
@@ -9,10 +12,14 @@ use std::{
     os::fd::RawFd,
     path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
+/// How often the app gets the stats of the tunnel.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
 #[swift_bridge::bridge]
 mod ffi {
     extern "Rust" {
@@ -39,7 +46,8 @@ mod ffi {
             &self,
             tunnelAddressIPv4: String,
             tunnelAddressIPv6: String,
-            dnsAddress: String,
+            dnsAddressIPv4: String,
+            dnsAddressIPv6: String,
             dnsFallbackStrategy: String,
         );
 
@@ -55,11 +63,17 @@ mod ffi {
         #[swift_bridge(swift_name = "onUpdateResources")]
         fn on_update_resources(&self, resourceList: String);
 
+        #[swift_bridge(swift_name = "onResourceUnreachable")]
+        fn on_resource_unreachable(&self, resourceId: String, reason: String);
+
         #[swift_bridge(swift_name = "onDisconnect")]
         fn on_disconnect(&self, error: String);
 
         #[swift_bridge(swift_name = "onError")]
         fn on_error(&self, error: String);
+
+        #[swift_bridge(swift_name = "onStats")]
+        fn on_stats(&self, stats: String);
     }
 }
 
@@ -81,19 +95,22 @@ pub struct CallbackHandler {
 }
 
 impl Callbacks for CallbackHandler {
-    type Error = std::convert::Infallible;
+    // Panicking would unwind into Swift
+    type Error = serde_json::Error;
 
     fn on_set_interface_config(
         &self,
         tunnel_address_v4: Ipv4Addr,
         tunnel_address_v6: Ipv6Addr,
-        dns_address: Ipv4Addr,
+        dns_address_v4: Ipv4Addr,
+        dns_address_v6: Ipv6Addr,
         dns_fallback_strategy: String,
     ) -> Result<RawFd, Self::Error> {
         self.inner.on_set_interface_config(
             tunnel_address_v4.to_string(),
             tunnel_address_v6.to_string(),
-            dns_address.to_string(),
+            dns_address_v4.to_string(),
+            dns_address_v6.to_string(),
             dns_fallback_strategy.to_string(),
         );
         Ok(-1)
@@ -118,10 +135,18 @@ impl Callbacks for CallbackHandler {
         &self,
         resource_list: Vec<ResourceDescription>,
     ) -> Result<(), Self::Error> {
-        self.inner.on_update_resources(
-            serde_json::to_string(&resource_list)
-                .expect("developer error: failed to serialize resource list"),
-        );
+        self.inner
+            .on_update_resources(serde_json::to_string(&resource_list)?);
+        Ok(())
+    }
+
+    fn on_resource_unreachable(
+        &self,
+        resource_id: ResourceId,
+        reason: &Error,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .on_resource_unreachable(resource_id.to_string(), reason.to_string());
         Ok(())
     }
 
@@ -136,6 +161,11 @@ impl Callbacks for CallbackHandler {
         Ok(())
     }
 
+    fn on_stats(&self, stats: &TunnelStats) -> Result<(), Self::Error> {
+        self.inner.on_stats(serde_json::to_string(stats)?);
+        Ok(())
+    }
+
     fn roll_log_file(&self) -> Option<PathBuf> {
         self.handle.roll_to_new_file().unwrap_or_else(|e| {
             tracing::debug!("Failed to roll over to new file: {e}");
@@ -175,6 +205,10 @@ impl WrappedSession {
             portal_url.as_str(),
             secret,
             device_id,
+            SessionConfig {
+                stats_interval: Some(STATS_INTERVAL),
+                ..Default::default()
+            },
             CallbackHandler {
                 inner: Arc::new(callback_handler),
                 handle: init_logging(log_dir.into(), log_filter),
@@ -2,7 +2,7 @@ use std::path::PathBuf;
 use std::{io, sync::Arc};
 
 use crate::messages::{
-    BroadcastGatewayIceCandidates, Connect, ConnectionDetails, EgressMessages,
+    AccessAllowed, BroadcastGatewayIceCandidates, Connect, ConnectionDetails, EgressMessages,
     GatewayIceCandidates, InitClient, Messages,
 };
 use connlib_shared::{
@@ -36,7 +36,7 @@ impl ControlSignal for ControlSignaler {
                     resource_id: resource.id(),
                     connected_gateway_ids: connected_gateway_ids.to_vec(),
                 },
-                reference,
+                prepare_connection_reference(resource.id(), reference),
             )
             .await?;
         Ok(())
@@ -141,13 +141,19 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
-    fn remove_resource(&self, id: ResourceId) {
-        todo!()
+    async fn remove_resource(&self, id: ResourceId) {
+        if let Err(e) = self.tunnel.remove_resource(id).await {
+            tracing::error!(message = "Can't remove resource", error = ?e);
+            let _ = self.tunnel.callbacks().on_error(&e);
+        }
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
-    fn update_resource(&self, resource_description: ResourceDescription) {
-        todo!()
+    async fn update_resource(&self, resource_description: ResourceDescription) {
+        if let Err(e) = self.tunnel.update_resource(resource_description).await {
+            tracing::error!(message = "Can't update resource", error = ?e);
+            let _ = self.tunnel.callbacks().on_error(&e);
+        }
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
@@ -164,6 +170,7 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
         let tunnel = Arc::clone(&self.tunnel);
         let mut control_signaler = self.control_signaler.clone();
         tokio::spawn(async move {
+            let reference = reference.map(|r| connection_attempt(&r).to_owned());
             let err = match tunnel
                 .request_connection(resource_id, gateway_id, relays, reference)
                 .await
@@ -201,9 +208,13 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
                 Err(err) => err,
             };
 
-            tunnel.cleanup_connection(resource_id.into());
             tracing::error!("Error request connection details: {err}");
             let _ = tunnel.callbacks().on_error(&err);
+            match err {
+                // A late or duplicated response, the resource might very well be reachable
+                Error::UnexpectedConnectionDetails => tunnel.cleanup_connection(resource_id.into()),
+                err => tunnel.resource_unreachable(resource_id, &err),
+            }
         });
     }
 
@@ -238,9 +249,12 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
                 self.connection_details(connection_details, reference)
             }
             Messages::Connect(connect) => self.connect(connect).await,
+            Messages::AccessAllowed(AccessAllowed { resource_id }) => {
+                self.tunnel.access_allowed(resource_id)
+            }
             Messages::ResourceAdded(resource) => self.add_resource(resource).await,
-            Messages::ResourceRemoved(resource) => self.remove_resource(resource.id),
-            Messages::ResourceUpdated(resource) => self.update_resource(resource),
+            Messages::ResourceRemoved(resource) => self.remove_resource(resource.id).await,
+            Messages::ResourceUpdated(resource) => self.update_resource(resource).await,
             Messages::IceCandidates(ice_candidate) => self.add_ice_candidate(ice_candidate).await,
             Messages::SignedLogUrl(url) => {
                 let Some(path) = self.tunnel.callbacks().roll_log_file() else {
@@ -262,7 +276,7 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
         if matches!(reply_error.error, ErrorInfo::Offline) {
             match reference {
                 Some(reference) => {
-                    let Ok(resource_id) = reference.parse::<ResourceId>() else {
+                    let Some(resource_id) = referenced_resource(&reference) else {
                         tracing::error!(
                             "An offline error came back with a reference to a non-valid resource id"
                         );
@@ -273,7 +287,8 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
                         return;
                     };
                     // TODO: Rate limit the number of attempts of getting the relays before just trying a local network connection
-                    self.tunnel.cleanup_connection(resource_id.into());
+                    self.tunnel
+                        .resource_unreachable(resource_id, &Error::GatewayOffline);
                 }
                 None => {
                     tracing::error!(
@@ -289,7 +304,19 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
     }
 
     pub async fn stats_event(&mut self) {
-        tracing::debug!(target: "tunnel_state", stats = ?self.tunnel.stats());
+        // Asks every peer connection for its ICE stats, only worth it if they are logged
+        if !tracing::enabled!(target: "tunnel_state", tracing::Level::DEBUG) {
+            return;
+        }
+
+        let stats = self.tunnel.stats().await;
+        tracing::debug!(target: "tunnel_state", ?stats);
+    }
+
+    /// Hands a snapshot of the tunnel to the callbacks, see [Callbacks::on_stats].
+    pub async fn report_stats_event(&mut self) {
+        let stats = self.tunnel.stats().await;
+        let _ = self.tunnel.callbacks().on_stats(&stats);
     }
 
     pub async fn request_log_upload_url(&mut self) {
@@ -303,6 +330,28 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
     }
 }
 
+// `prepare_connection` references carry the resource along with the attempt,
+// so that an error reply to it can be traced back to the resource.
+fn prepare_connection_reference(resource_id: ResourceId, attempt: usize) -> Reference {
+    format!("{resource_id}:{attempt}")
+}
+
+// The attempt is what the tunnel matches `connection_details` against.
+fn connection_attempt(reference: &str) -> &str {
+    reference
+        .split_once(':')
+        .map_or(reference, |(_, attempt)| attempt)
+}
+
+// Other requests use the bare resource id as reference.
+fn referenced_resource(reference: &str) -> Option<ResourceId> {
+    reference
+        .split_once(':')
+        .map_or(reference, |(resource_id, _)| resource_id)
+        .parse()
+        .ok()
+}
+
 async fn upload(path: PathBuf, url: Url) -> io::Result<()> {
     tracing::info!(path = %path.display(), %url, "Uploading log file");
 
@@ -335,3 +384,23 @@ async fn upload(path: PathBuf, url: Url) -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use connlib_shared::messages::ResourceId;
+
+    use super::{connection_attempt, prepare_connection_reference, referenced_resource};
+
+    #[test]
+    fn prepare_connection_reference_round_trip() {
+        let resource_id: ResourceId = "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b".parse().unwrap();
+        let reference = prepare_connection_reference(resource_id, 3);
+
+        assert_eq!(connection_attempt(&reference), "3");
+        assert_eq!(referenced_resource(&reference), Some(resource_id));
+        assert_eq!(
+            referenced_resource(&resource_id.to_string()),
+            Some(resource_id)
+        );
+    }
+}
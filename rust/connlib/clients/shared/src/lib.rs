@@ -1,6 +1,11 @@
 //! Main connlib library for clients.
-pub use connlib_shared::{get_device_id, messages::ResourceDescription};
-pub use connlib_shared::{Callbacks, Error};
+pub use connlib_shared::{
+    device_identity::DeviceIdentity,
+    get_device_id,
+    messages::{ConnId, GatewayId, ResourceDescription, ResourceId},
+    stats,
+};
+pub use connlib_shared::{Callbacks, ConnectionRetryPolicy, Error, SessionConfig};
 pub use tracing_appender::non_blocking::WorkerGuard;
 
 use crate::control::ControlSignaler;
@@ -23,6 +28,10 @@ mod control;
 pub mod file_logger;
 mod messages;
 
+// Long enough for the tasks to be dropped, e.g. to remove the interface and its routes, but we don't
+// want to hang forever on a task that doesn't yield.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 struct StopRuntime;
 
 /// A session is the entry-point for connlib, maintains the runtime and the tunnel.
@@ -30,6 +39,7 @@ struct StopRuntime;
 /// A session is created using [Session::connect], then to stop a session we use [Session::disconnect].
 pub struct Session<CB: Callbacks> {
     runtime_stopper: tokio::sync::mpsc::Sender<StopRuntime>,
+    runtime_thread: Option<std::thread::JoinHandle<()>>,
     pub callbacks: CallbackErrorFacade<CB>,
 }
 
@@ -64,6 +74,7 @@ where
         portal_url: impl TryInto<Url>,
         token: SecretString,
         device_id: String,
+        config: SessionConfig,
         callbacks: CB,
     ) -> Result<Self> {
         // TODO: We could use tokio::runtime::current() to get the current runtime
@@ -74,8 +85,9 @@ where
 
         let callbacks = CallbackErrorFacade(callbacks);
         let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-        let this = Self {
+        let mut this = Self {
             runtime_stopper: tx.clone(),
+            runtime_thread: None,
             callbacks,
         };
         let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -105,12 +117,13 @@ where
             portal_url.try_into().map_err(|_| Error::UriError)?,
             token,
             device_id,
+            config,
             this.callbacks.clone(),
         );
-        std::thread::spawn(move || {
+        this.runtime_thread = Some(std::thread::spawn(move || {
             rx.blocking_recv();
-            runtime.shutdown_background();
-        });
+            runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        }));
 
         Ok(this)
     }
@@ -121,11 +134,12 @@ where
         portal_url: Url,
         token: SecretString,
         device_id: String,
+        config: SessionConfig,
         callbacks: CallbackErrorFacade<CB>,
     ) {
         runtime.spawn(async move {
             let (connect_url, private_key) = fatal_error!(
-                login_url(Mode::Client, portal_url, token, device_id),
+                login_url(Mode::Client, portal_url, token, device_id, config.state_dir.as_deref()),
                 runtime_stopper,
                 &callbacks
             );
@@ -147,7 +161,7 @@ where
 
             let control_signaler = ControlSignaler { control_signal: connection.sender_with_topic("client".to_owned()) };
             let tunnel = fatal_error!(
-                Tunnel::new(private_key, control_signaler.clone(), callbacks.clone()).await,
+                Tunnel::new(private_key, control_signaler.clone(), callbacks.clone(), &config).await,
                 runtime_stopper,
                 &callbacks
             );
@@ -158,8 +172,11 @@ where
                 tunnel_init: Mutex::new(false),
             };
 
+            // A zero interval would mean reporting in a busy loop
+            let report_stats_period = config.stats_interval.filter(|p| !p.is_zero());
             tokio::spawn(async move {
                 let mut log_stats_interval = tokio::time::interval(Duration::from_secs(10));
+                let mut report_stats_interval = tokio::time::interval(report_stats_period.unwrap_or(Duration::from_secs(10)));
                 let mut upload_logs_interval = upload_interval();
                 loop {
                     tokio::select! {
@@ -170,6 +187,7 @@ where
                             }
                         },
                         _ = log_stats_interval.tick() => control_plane.stats_event().await,
+                        _ = report_stats_interval.tick(), if report_stats_period.is_some() => control_plane.report_stats_event().await,
                         _ = upload_logs_interval.tick() => control_plane.request_log_upload_url().await,
                         else => break
                     }
@@ -237,9 +255,16 @@ where
     /// Cleanup a [Session].
     ///
     /// For now this just drops the runtime, which should drop all pending tasks.
-    /// Further cleanup should be done here. (Otherwise we can just drop [Session]).
+    /// It blocks until that's done so the interface and its routes are gone when this returns,
+    /// or until [SHUTDOWN_TIMEOUT] passed.
     pub fn disconnect(&mut self, error: Option<Error>) {
-        Self::disconnect_inner(self.runtime_stopper.clone(), &self.callbacks, error)
+        Self::disconnect_inner(self.runtime_stopper.clone(), &self.callbacks, error);
+
+        if let Some(runtime_thread) = self.runtime_thread.take() {
+            if runtime_thread.join().is_err() {
+                tracing::error!("Runtime thread panicked while shutting down");
+            }
+        }
     }
 }
 
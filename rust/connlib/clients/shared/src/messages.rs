@@ -47,6 +47,12 @@ impl PartialEq for Connect {
 
 impl Eq for Connect {}
 
+/// The gateway of a connection we reused lets us reach the resource now.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AccessAllowed {
+    pub resource_id: ResourceId,
+}
+
 // These messages are the messages that can be received
 // by a client.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -89,6 +95,8 @@ pub struct GatewayIceCandidates {
 pub enum ReplyMessages {
     ConnectionDetails(ConnectionDetails),
     Connect(Connect),
+    /// Response for [`EgressMessages::ReuseConnection`].
+    AccessAllowed(AccessAllowed),
     /// Response for [`EgressMessages::CreateLogSink`].
     SignedLogUrl(Url),
 }
@@ -100,6 +108,7 @@ pub enum Messages {
     Init(InitClient),
     ConnectionDetails(ConnectionDetails),
     Connect(Connect),
+    AccessAllowed(AccessAllowed),
     SignedLogUrl(Url),
 
     // Resources: arrive in an orderly fashion
@@ -127,6 +136,7 @@ impl From<ReplyMessages> for Messages {
         match value {
             ReplyMessages::ConnectionDetails(m) => Self::ConnectionDetails(m),
             ReplyMessages::Connect(m) => Self::Connect(m),
+            ReplyMessages::AccessAllowed(m) => Self::AccessAllowed(m),
             ReplyMessages::SignedLogUrl(url) => Self::SignedLogUrl(url),
         }
     }
@@ -154,15 +164,17 @@ mod test {
     use connlib_shared::{
         control::PhoenixMessage,
         messages::{
-            Interface, Relay, ResourceDescription, ResourceDescriptionCidr, ResourceDescriptionDns,
-            Stun, Turn,
+            DnsRecord, Interface, Relay, ResourceDescription, ResourceDescriptionCidr,
+            ResourceDescriptionDns, Stun, Turn,
         },
     };
 
     use chrono::NaiveDateTime;
     use connlib_shared::control::ErrorInfo;
 
-    use crate::messages::{ConnectionDetails, EgressMessages, ReplyMessages};
+    use crate::messages::{
+        AccessAllowed, ConnectionDetails, EgressMessages, RemoveResource, ReplyMessages,
+    };
 
     use super::{IngressMessages, InitClient};
 
@@ -205,6 +217,7 @@ mod test {
                         id: "73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap(),
                         address: "172.172.0.0/16".parse().unwrap(),
                         name: "172.172.0.0/16".to_string(),
+                        filters: vec![],
                     }),
                     ResourceDescription::Dns(ResourceDescriptionDns {
                         id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
@@ -212,6 +225,8 @@ mod test {
                         ipv4: "100.126.44.50".parse().unwrap(),
                         ipv6: "fd00:2021:1111::e:7758".parse().unwrap(),
                         name: "gitlab.mycorp.com".to_string(),
+                        records: vec![],
+                        filters: vec![],
                     }),
                 ],
             }),
@@ -250,6 +265,117 @@ mod test {
         assert_eq!(m, ingress_message);
     }
 
+    #[test]
+    fn resource_removed_message() {
+        let m = PhoenixMessage::<IngressMessages, ReplyMessages>::new(
+            "client",
+            IngressMessages::ResourceRemoved(RemoveResource {
+                id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
+            }),
+            None,
+        );
+        let message = r#"{
+            "event": "resource_removed",
+            "payload": {
+                "id": "03000143-e25e-45c7-aafb-144990e57dcd"
+            },
+            "ref": null,
+            "topic": "client"
+        }"#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
+    #[test]
+    fn resource_updated_message() {
+        let m = PhoenixMessage::<IngressMessages, ReplyMessages>::new(
+            "client",
+            IngressMessages::ResourceUpdated(ResourceDescription::Dns(ResourceDescriptionDns {
+                id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
+                address: "gitlab.mycorp.com".to_string(),
+                ipv4: "100.126.44.50".parse().unwrap(),
+                ipv6: "fd00:2021:1111::e:7758".parse().unwrap(),
+                name: "gitlab.mycorp.com".to_string(),
+                records: vec![],
+                filters: vec![],
+            })),
+            None,
+        );
+        let message = r#"{
+            "event": "resource_updated",
+            "payload": {
+                "address": "gitlab.mycorp.com",
+                "id": "03000143-e25e-45c7-aafb-144990e57dcd",
+                "ipv4": "100.126.44.50",
+                "ipv6": "fd00:2021:1111::e:7758",
+                "name": "gitlab.mycorp.com",
+                "type": "dns"
+            },
+            "ref": null,
+            "topic": "client"
+        }"#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
+    #[test]
+    fn resource_added_with_records_message() {
+        let m = PhoenixMessage::<IngressMessages, ReplyMessages>::new(
+            "client",
+            IngressMessages::ResourceAdded(ResourceDescription::Dns(ResourceDescriptionDns {
+                id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
+                address: "_ldap._tcp.mycorp.com".to_string(),
+                ipv4: "100.126.44.50".parse().unwrap(),
+                ipv6: "fd00:2021:1111::e:7758".parse().unwrap(),
+                name: "LDAP".to_string(),
+                records: vec![
+                    DnsRecord::Srv {
+                        priority: 10,
+                        weight: 5,
+                        port: 389,
+                        target: "ldap.mycorp.com".to_string(),
+                    },
+                    DnsRecord::Txt {
+                        value: "v=spf1 -all".to_string(),
+                    },
+                ],
+                filters: vec![],
+            })),
+            None,
+        );
+        let message = r#"{
+            "event": "resource_added",
+            "payload": {
+                "address": "_ldap._tcp.mycorp.com",
+                "id": "03000143-e25e-45c7-aafb-144990e57dcd",
+                "ipv4": "100.126.44.50",
+                "ipv6": "fd00:2021:1111::e:7758",
+                "name": "LDAP",
+                "type": "dns",
+                "records": [
+                    {
+                        "type": "srv",
+                        "priority": 10,
+                        "weight": 5,
+                        "port": 389,
+                        "target": "ldap.mycorp.com"
+                    },
+                    {
+                        "type": "txt",
+                        "value": "v=spf1 -all"
+                    }
+                ]
+            },
+            "ref": null,
+            "topic": "client"
+        }"#;
+        let ingress_message: PhoenixMessage<IngressMessages, ReplyMessages> =
+            serde_json::from_str(message).unwrap();
+        assert_eq!(m, ingress_message);
+    }
+
     #[test]
     fn list_relays_message() {
         let m = PhoenixMessage::<EgressMessages, ()>::new(
@@ -351,6 +477,23 @@ mod test {
         assert_eq!(m, reply_message);
     }
 
+    #[test]
+    fn access_allowed_reply() {
+        let json = r#"{"event":"phx_reply","ref":"f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3","topic":"client","payload":{"status":"ok","response":{"resource_id":"f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3"}}}"#;
+
+        let actual =
+            serde_json::from_str::<PhoenixMessage<EgressMessages, ReplyMessages>>(json).unwrap();
+        let expected = PhoenixMessage::new_ok_reply(
+            "client",
+            ReplyMessages::AccessAllowed(AccessAllowed {
+                resource_id: "f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3".parse().unwrap(),
+            }),
+            "f16ecfa0-a94f-4bfd-a2ef-1cc1f2ef3da3".to_owned(),
+        );
+
+        assert_eq!(actual, expected)
+    }
+
     #[test]
     fn create_log_sink_error_response() {
         let json = r#"{"event":"phx_reply","ref":"unique_log_sink_ref","topic":"client","payload":{"status":"error","response":"disabled"}}"#;
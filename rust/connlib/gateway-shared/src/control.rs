@@ -1,8 +1,10 @@
 use super::messages::{
-    ConnectionReady, EgressMessages, IngressMessages, InitGateway, RequestConnection,
+    AccessAllowed, ConnectionReady, EgressMessages, IngressMessages, InitGateway, RequestConnection,
 };
 use crate::messages::{AllowAccess, BroadcastClientIceCandidates, ClientIceCandidates};
+use crate::metrics::TrafficReport;
 use async_trait::async_trait;
+use chrono::Utc;
 use connlib_shared::Error::ControlProtocolError;
 use connlib_shared::{
     control::PhoenixSenderWithTopic,
@@ -16,6 +18,7 @@ use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
 pub struct ControlPlane<CB: Callbacks> {
     pub tunnel: Arc<Tunnel<ControlSignaler, CB>>,
     pub control_signaler: ControlSignaler,
+    pub(crate) traffic_report: TrafficReport,
 }
 
 #[derive(Clone)]
@@ -66,16 +69,29 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
     pub async fn init(&mut self, init: InitGateway) -> Result<()> {
         if let Err(e) = self.tunnel.set_interface(&init.interface).await {
             tracing::error!("Couldn't initialize interface: {e}");
-            Err(e)
-        } else {
-            // TODO: Enable masquerading here.
-            tracing::info!("Firezoned Started!");
-            Ok(())
+            return Err(e);
+        }
+
+        if let Err(e) = self
+            .tunnel
+            .set_masquerade(init.ipv4_masquerade_enabled, init.ipv6_masquerade_enabled)
+            .await
+        {
+            tracing::error!("Couldn't set up masquerading: {e}");
+            return Err(e);
         }
+
+        tracing::info!("Firezoned Started!");
+        Ok(())
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
-    pub fn connection_request(&self, connection_request: RequestConnection) {
+    pub fn connection_request(&mut self, connection_request: RequestConnection) {
+        self.traffic_report.set_flow(
+            connection_request.client.id,
+            connection_request.resource.id(),
+            connection_request.flow_id,
+        );
         let tunnel = Arc::clone(&self.tunnel);
         let mut control_signaler = self.control_signaler.clone();
         tokio::spawn(async move {
@@ -87,6 +103,7 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
                     connection_request.client.id,
                     connection_request.expires_at,
                     connection_request.resource,
+                    connection_request.wildcard_match,
                 )
                 .await
             {
@@ -112,15 +129,41 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
-    pub fn allow_access(
-        &self,
+    pub async fn allow_access(
+        &mut self,
         AllowAccess {
             client_id,
             resource,
+            flow_id,
             expires_at,
+            wildcard_match,
+            reference,
         }: AllowAccess,
     ) {
-        self.tunnel.allow_access(resource, client_id, expires_at)
+        self.traffic_report
+            .set_flow(client_id, resource.id(), flow_id);
+
+        if let Err(e) = self
+            .tunnel
+            .allow_access(resource, wildcard_match, client_id, expires_at)
+        {
+            tracing::warn!(err = ?e, "allow_access");
+            let _ = self.tunnel.callbacks().on_error(&e);
+            return;
+        }
+
+        let Some(reference) = reference else {
+            return;
+        };
+        if let Err(e) = self
+            .control_signaler
+            .control_signal
+            .send(EgressMessages::AccessAllowed(AccessAllowed { reference }))
+            .await
+        {
+            tracing::warn!(err = ?e, "access_allowed");
+            let _ = self.tunnel.callbacks().on_error(&e);
+        }
     }
 
     async fn add_ice_candidate(
@@ -150,7 +193,7 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
                 self.connection_request(connection_request)
             }
             IngressMessages::AllowAccess(allow_access) => {
-                self.allow_access(allow_access);
+                self.allow_access(allow_access).await;
             }
 
             IngressMessages::IceCandidates(ice_candidate) => {
@@ -161,6 +204,51 @@ impl<CB: Callbacks + 'static> ControlPlane<CB> {
     }
 
     pub async fn stats_event(&mut self) {
-        tracing::debug!(target: "tunnel_state", stats = ?self.tunnel.stats());
+        // Asks every peer connection for its ICE stats, only worth it if they are logged
+        if !tracing::enabled!(target: "tunnel_state", tracing::Level::DEBUG) {
+            return;
+        }
+
+        let stats = self.tunnel.stats().await;
+        tracing::debug!(target: "tunnel_state", ?stats);
+    }
+
+    /// Hands a snapshot of the tunnel to the callbacks, see [Callbacks::on_stats].
+    pub async fn report_stats_event(&mut self) {
+        let stats = self.tunnel.stats().await;
+        let _ = self.tunnel.callbacks().on_stats(&stats);
+    }
+
+    /// Reports the traffic of each flow to each address since the last report.
+    pub async fn metrics_event(&mut self) {
+        // Peers removed in between show up in both, `TrafficReport` expects the live ones first
+        let stats = self.tunnel.stats().await;
+        let removed = self.tunnel.take_removed_traffic();
+        self.traffic_report
+            .update(&stats.resource_traffic, &removed);
+
+        let metrics = self.traffic_report.take_metrics(Utc::now());
+        let connected = stats
+            .peers
+            .iter()
+            .filter_map(|peer| match peer.conn_id {
+                ConnId::Client(client_id) => Some(client_id),
+                _ => None,
+            })
+            .collect();
+        self.traffic_report.prune_flows(&connected);
+        if metrics.metrics.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self
+            .control_signaler
+            .control_signal
+            .send(EgressMessages::Metrics(metrics))
+            .await
+        {
+            tracing::warn!(error = ?e, "send_metrics");
+            let _ = self.tunnel.callbacks().on_error(&e);
+        }
     }
 }
@@ -1,5 +1,8 @@
 //! Main connlib library for gateway.
-pub use connlib_shared::{get_device_id, messages::ResourceDescription, Callbacks, Error};
+pub use connlib_shared::{
+    device_identity::DeviceIdentity, get_device_id, messages::ResourceDescription, stats,
+    Callbacks, Error, SessionConfig,
+};
 
 use crate::control::ControlSignaler;
 use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
@@ -9,21 +12,34 @@ use control::ControlPlane;
 use firezone_tunnel::Tunnel;
 use messages::IngressMessages;
 use secrecy::{Secret, SecretString};
-use std::sync::Arc;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::runtime::Runtime;
 use url::Url;
 
 mod control;
 mod messages;
+mod metrics;
+
+// Long enough for the teardown, e.g. removing the masquerading rules, and for the tasks to be dropped,
+// but we don't want to hang forever on a task that doesn't yield.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
 
 struct StopRuntime;
 
+/// Cleanup that has to be awaited before the runtime shuts down, since tasks spawned while it does never run.
+type Teardown = Pin<Box<dyn Future<Output = ()> + Send>>;
+
 /// A session is the entry-point for connlib, maintains the runtime and the tunnel.
 ///
 /// A session is created using [Session::connect], then to stop a session we use [Session::disconnect].
 pub struct Session<CB: Callbacks> {
     runtime_stopper: tokio::sync::mpsc::Sender<StopRuntime>,
+    runtime_thread: Option<std::thread::JoinHandle<()>>,
     pub callbacks: CallbackErrorFacade<CB>,
 }
 
@@ -58,6 +74,7 @@ where
         portal_url: impl TryInto<Url>,
         token: SecretString,
         device_id: String,
+        config: SessionConfig,
         callbacks: CB,
     ) -> Result<Self> {
         // TODO: We could use tokio::runtime::current() to get the current runtime
@@ -68,8 +85,9 @@ where
 
         let callbacks = CallbackErrorFacade(callbacks);
         let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-        let this = Self {
+        let mut this = Self {
             runtime_stopper: tx.clone(),
+            runtime_thread: None,
             callbacks,
         };
         let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -93,18 +111,21 @@ where
             }));
         }
 
+        let teardown = Arc::new(Mutex::new(None));
         Self::connect_inner(
             &runtime,
             tx,
             portal_url.try_into().map_err(|_| Error::UriError)?,
             token,
             device_id,
+            config,
             this.callbacks.clone(),
+            Arc::clone(&teardown),
         );
-        std::thread::spawn(move || {
+        this.runtime_thread = Some(std::thread::spawn(move || {
             rx.blocking_recv();
-            runtime.shutdown_background();
-        });
+            shutdown_runtime(runtime, teardown);
+        }));
 
         Ok(this)
     }
@@ -115,11 +136,13 @@ where
         portal_url: Url,
         token: SecretString,
         device_id: String,
+        config: SessionConfig,
         callbacks: CallbackErrorFacade<CB>,
+        teardown: Arc<Mutex<Option<Teardown>>>,
     ) {
         runtime.spawn(async move {
             let (connect_url, private_key) = fatal_error!(
-                login_url(Mode::Gateway, portal_url, token, device_id),
+                login_url(Mode::Gateway, portal_url, token, device_id, config.state_dir.as_deref()),
                 runtime_stopper,
                 &callbacks
             );
@@ -142,18 +165,31 @@ where
             // Used to send internal messages
             let control_signaler = ControlSignaler { control_signal: connection.sender_with_topic("gateway".to_owned()) };
             let tunnel = fatal_error!(
-                Tunnel::new(private_key, control_signaler.clone(), callbacks.clone()).await,
+                Tunnel::new(private_key, control_signaler.clone(), callbacks.clone(), &config)
+                    .await
+                    .map(|tunnel| tunnel.with_dns_interception(false)),
                 runtime_stopper,
                 &callbacks
             );
 
+            let tunnel = Arc::new(tunnel);
+            *teardown.lock().unwrap() = Some(Box::pin({
+                let tunnel = Arc::clone(&tunnel);
+                async move { tunnel.remove_masquerade().await }
+            }));
+
             let mut control_plane = ControlPlane {
-                tunnel: Arc::new(tunnel),
+                tunnel,
                 control_signaler,
+                traffic_report: Default::default(),
             };
 
+            // A zero interval would mean reporting in a busy loop
+            let report_stats_period = config.stats_interval.filter(|p| !p.is_zero());
             tokio::spawn(async move {
                 let mut interval = tokio::time::interval(Duration::from_secs(10));
+                let mut report_stats_interval = tokio::time::interval(report_stats_period.unwrap_or(Duration::from_secs(10)));
+                let mut metrics_interval = tokio::time::interval(METRICS_INTERVAL);
                 loop {
                     tokio::select! {
                         Some((msg, _)) = control_plane_receiver.recv() => {
@@ -163,6 +199,8 @@ where
                             }
                         },
                         _ = interval.tick() => control_plane.stats_event().await,
+                        _ = report_stats_interval.tick(), if report_stats_period.is_some() => control_plane.report_stats_event().await,
+                        _ = metrics_interval.tick() => control_plane.metrics_event().await,
                         else => break
                     }
                 }
@@ -230,9 +268,74 @@ where
 
     /// Cleanup a [Session].
     ///
-    /// For now this just drops the runtime, which should drop all pending tasks.
-    /// Further cleanup should be done here. (Otherwise we can just drop [Session]).
+    /// For now this removes the masquerading rules and drops the runtime, which should drop all pending tasks.
+    /// It blocks until that's done so the system is left as we found it when this returns,
+    /// further cleanup should be done here. (Otherwise we can just drop [Session]).
     pub fn disconnect(&mut self, error: Option<Error>) {
-        Self::disconnect_inner(self.runtime_stopper.clone(), &self.callbacks, error)
+        Self::disconnect_inner(self.runtime_stopper.clone(), &self.callbacks, error);
+
+        if let Some(runtime_thread) = self.runtime_thread.take() {
+            if runtime_thread.join().is_err() {
+                tracing::error!("Runtime thread panicked while shutting down");
+            }
+        }
+    }
+}
+
+/// Awaits the teardown, if the session got far enough to have one, then drops the tasks of the runtime.
+fn shutdown_runtime(runtime: Runtime, teardown: Arc<Mutex<Option<Teardown>>>) {
+    let teardown = teardown.lock().unwrap().take();
+    if let Some(teardown) = teardown {
+        if runtime
+            .block_on(tokio::time::timeout(SHUTDOWN_TIMEOUT, teardown))
+            .is_err()
+        {
+            tracing::warn!("Teardown timed out");
+        }
+    }
+
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+
+    use super::{shutdown_runtime, StopRuntime, Teardown};
+
+    #[test]
+    fn teardown_finishes_before_the_runtime_is_dropped() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let torn_down = Arc::new(AtomicBool::new(false));
+        let teardown: Teardown = Box::pin({
+            let torn_down = Arc::clone(&torn_down);
+            async move {
+                // Like the netlink requests, which need tasks of their own
+                tokio::spawn(async { tokio::task::yield_now().await })
+                    .await
+                    .unwrap();
+                torn_down.store(true, Ordering::SeqCst);
+            }
+        });
+        runtime.spawn(std::future::pending::<()>());
+        let runtime_thread = std::thread::spawn({
+            let teardown = Arc::new(Mutex::new(Some(teardown)));
+            move || {
+                rx.blocking_recv();
+                shutdown_runtime(runtime, teardown);
+            }
+        });
+
+        tx.try_send(StopRuntime).unwrap();
+        runtime_thread.join().unwrap();
+
+        assert!(torn_down.load(Ordering::SeqCst));
     }
 }
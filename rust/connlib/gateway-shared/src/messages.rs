@@ -2,7 +2,8 @@ use std::net::IpAddr;
 
 use chrono::{serde::ts_seconds, DateTime, Utc};
 use connlib_shared::messages::{
-    ActorId, ClientId, Interface, Peer, Relay, ResourceDescription, ResourceId,
+    ActorId, ClientId, FlowId, Interface, Peer, Relay, ResourceDescription, ResourceId,
+    WildcardMatch,
 };
 use firezone_tunnel::RTCSessionDescription;
 use serde::{Deserialize, Serialize};
@@ -46,8 +47,12 @@ pub struct RequestConnection {
     pub client: Client,
     #[serde(rename = "ref")]
     pub reference: String,
+    pub flow_id: FlowId,
     #[serde(with = "ts_seconds")]
     pub expires_at: DateTime<Utc>,
+    /// Name the client resolved if the resource is a wildcard one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wildcard_match: Option<WildcardMatch>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -56,15 +61,21 @@ pub enum Destination {
     Ip(Vec<IpAddr>),
 }
 
+/// Traffic that went through the gateway between `started_at` and `ended_at`.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Metrics {
-    peers_metrics: Vec<Metric>,
+    #[serde(with = "ts_seconds")]
+    pub started_at: DateTime<Utc>,
+    #[serde(with = "ts_seconds")]
+    pub ended_at: DateTime<Utc>,
+    pub metrics: Vec<Metric>,
 }
 
+/// Traffic of a flow to one address, `rx` is what the client sent and `tx` what it received.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Metric {
-    pub client_id: ClientId,
-    pub resource_id: ResourceId,
+    pub flow_id: FlowId,
+    pub destination: IpAddr,
     pub rx_bytes: u32,
     pub tx_bytes: u32,
 }
@@ -78,8 +89,15 @@ pub struct RemoveResource {
 pub struct AllowAccess {
     pub client_id: ClientId,
     pub resource: ResourceDescription,
+    pub flow_id: FlowId,
     #[serde(with = "ts_seconds")]
     pub expires_at: DateTime<Utc>,
+    /// Name the client resolved if the resource is a wildcard one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wildcard_match: Option<WildcardMatch>,
+    /// Set when the client waits for [`EgressMessages::AccessAllowed`] before sending to the resource.
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
 }
 
 // These messages are the messages that can be received
@@ -121,6 +139,7 @@ pub struct ClientIceCandidates {
 #[allow(clippy::large_enum_variant)]
 pub enum EgressMessages {
     ConnectionReady(ConnectionReady),
+    AccessAllowed(AccessAllowed),
     Metrics(Metrics),
     BroadcastIceCandidates(BroadcastClientIceCandidates),
 }
@@ -132,11 +151,22 @@ pub struct ConnectionReady {
     pub gateway_rtc_session_description: RTCSessionDescription,
 }
 
+/// Tells the client that reused a connection that it can now reach the resource.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AccessAllowed {
+    #[serde(rename = "ref")]
+    pub reference: String,
+}
+
 #[cfg(test)]
 mod test {
-    use connlib_shared::{control::PhoenixMessage, messages::Interface};
+    use chrono::{TimeZone, Utc};
+    use connlib_shared::{
+        control::PhoenixMessage,
+        messages::{Filter, Interface, PortRange, ResourceDescription, WildcardMatch},
+    };
 
-    use super::{IngressMessages, InitGateway};
+    use super::{EgressMessages, IngressMessages, InitGateway, Metric, Metrics};
 
     #[test]
     fn request_connection_message() {
@@ -166,6 +196,7 @@ mod test {
                     "address": "172.20.0.0/16"
                 },
                 "ref": "78e1159d-9dc6-480d-b2ef-1fcec2cd5730",
+                "flow_id": "b8c6b2d4-22b4-4f0c-a8ed-8b0b4f1c6d3e",
                 "expires_at": 1719367575,
                 "actor": {
                     "id": "3b1d86a0-4737-4814-8add-cfec42669511"
@@ -188,6 +219,102 @@ mod test {
         // TODO: We are just testing we can deserialize for now.
         let _: PhoenixMessage<IngressMessages, ()> = serde_json::from_str(message).unwrap();
     }
+    #[test]
+    fn allow_access_wildcard_message() {
+        let message = r#"{
+            "event": "allow_access",
+            "payload": {
+                "client_id": "3a25ff38-f8d7-47de-9b30-c7c40c206083",
+                "flow_id": "b8c6b2d4-22b4-4f0c-a8ed-8b0b4f1c6d3e",
+                "resource": {
+                    "id": "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b",
+                    "name": "Internal services",
+                    "type": "dns",
+                    "address": "*.internal.example.com",
+                    "ipv4": "100.126.44.50",
+                    "ipv6": "fd00:2021:1111::e:7758"
+                },
+                "expires_at": 1719367575,
+                "wildcard_match": {
+                    "name": "gitlab.internal.example.com",
+                    "ipv4": "198.18.0.1",
+                    "ipv6": "fd00:2021:1111:8000::1"
+                }
+            }
+        }"#;
+        let IngressMessages::AllowAccess(allow_access) = serde_json::from_str(message).unwrap()
+        else {
+            panic!("expected an allow_access message");
+        };
+        let wildcard_match = allow_access.wildcard_match.unwrap();
+        let Some(ResourceDescription::Dns(resource)) =
+            allow_access.resource.with_match(&wildcard_match)
+        else {
+            panic!("expected the name to match the wildcard resource");
+        };
+
+        assert_eq!(resource.address, "gitlab.internal.example.com");
+        assert_eq!(resource.ipv4, wildcard_match.ipv4);
+        assert_eq!(resource.ipv6, wildcard_match.ipv6);
+        assert!(allow_access
+            .resource
+            .with_match(&WildcardMatch {
+                name: "internal.example.com".to_string(),
+                ..wildcard_match
+            })
+            .is_none());
+    }
+
+    #[test]
+    fn allow_access_filters_message() {
+        let message = r#"{
+            "event": "allow_access",
+            "payload": {
+                "client_id": "3a25ff38-f8d7-47de-9b30-c7c40c206083",
+                "flow_id": "b8c6b2d4-22b4-4f0c-a8ed-8b0b4f1c6d3e",
+                "resource": {
+                    "id": "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b",
+                    "name": "Database",
+                    "type": "cidr",
+                    "address": "172.20.0.0/16",
+                    "filters": [
+                        {
+                            "protocol": "tcp",
+                            "port_range_start": 5432,
+                            "port_range_end": 5432
+                        },
+                        {
+                            "protocol": "udp"
+                        },
+                        {
+                            "protocol": "icmp"
+                        }
+                    ]
+                },
+                "expires_at": 1719367575
+            }
+        }"#;
+        let IngressMessages::AllowAccess(allow_access) = serde_json::from_str(message).unwrap()
+        else {
+            panic!("expected an allow_access message");
+        };
+
+        assert_eq!(
+            allow_access.resource.filters(),
+            [
+                Filter::Tcp(PortRange {
+                    port_range_start: 5432,
+                    port_range_end: 5432,
+                }),
+                Filter::Udp(PortRange {
+                    port_range_start: 0,
+                    port_range_end: u16::MAX,
+                }),
+                Filter::Icmp,
+            ]
+        );
+    }
+
     #[test]
     fn init_phoenix_message() {
         let m = PhoenixMessage::new(
@@ -221,4 +348,49 @@ mod test {
             serde_json::from_str(message).unwrap();
         assert_eq!(m, ingress_message);
     }
+
+    #[test]
+    fn metrics_message() {
+        let message = EgressMessages::Metrics(Metrics {
+            started_at: Utc.timestamp_opt(1719367515, 0).unwrap(),
+            ended_at: Utc.timestamp_opt(1719367575, 0).unwrap(),
+            metrics: vec![
+                Metric {
+                    flow_id: "b8c6b2d4-22b4-4f0c-a8ed-8b0b4f1c6d3e".parse().unwrap(),
+                    destination: "172.20.0.1".parse().unwrap(),
+                    rx_bytes: 100,
+                    tx_bytes: 200,
+                },
+                Metric {
+                    flow_id: "b8c6b2d4-22b4-4f0c-a8ed-8b0b4f1c6d3e".parse().unwrap(),
+                    destination: "fd00:2021:1111::e:7758".parse().unwrap(),
+                    rx_bytes: 0,
+                    tx_bytes: 10,
+                },
+            ],
+        });
+
+        // What the portal's `metrics` handler takes
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            serde_json::json!({
+                "event": "metrics",
+                "payload": {
+                    "started_at": 1719367515,
+                    "ended_at": 1719367575,
+                    "metrics": [{
+                        "flow_id": "b8c6b2d4-22b4-4f0c-a8ed-8b0b4f1c6d3e",
+                        "destination": "172.20.0.1",
+                        "rx_bytes": 100,
+                        "tx_bytes": 200
+                    }, {
+                        "flow_id": "b8c6b2d4-22b4-4f0c-a8ed-8b0b4f1c6d3e",
+                        "destination": "fd00:2021:1111::e:7758",
+                        "rx_bytes": 0,
+                        "tx_bytes": 10
+                    }]
+                }
+            })
+        );
+    }
 }
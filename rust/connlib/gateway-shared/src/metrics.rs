@@ -0,0 +1,318 @@
+//! Works out the traffic of each flow to each address since the last report to the portal.
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+use chrono::{DateTime, Utc};
+use connlib_shared::messages::{ClientId, FlowId, ResourceId};
+use firezone_tunnel::{ConnId, ResourceTraffic, TrafficStats};
+
+use crate::messages::{Metric, Metrics};
+
+#[derive(Debug)]
+struct Flow {
+    id: FlowId,
+    /// Set since the flows were last pruned, the client may still be connecting.
+    fresh: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct TrafficReport {
+    /// The counters of each peer as they were in the last report.
+    ///
+    /// Keyed by the peer and not the client since the counters of a client that connects again start over.
+    reported: HashMap<(u32, ResourceId, IpAddr), TrafficStats>,
+    /// Traffic counted but not reported yet, the portal only takes 32 bits so the rest waits for the next report.
+    unreported: HashMap<(ClientId, ResourceId, IpAddr), TrafficStats>,
+    /// The flow the portal gave each client for each resource last, the traffic is reported for it.
+    flows: HashMap<(ClientId, ResourceId), Flow>,
+    /// Since when the unreported traffic was counted.
+    window_started_at: DateTime<Utc>,
+}
+
+impl Default for TrafficReport {
+    fn default() -> Self {
+        Self {
+            reported: Default::default(),
+            unreported: Default::default(),
+            flows: Default::default(),
+            window_started_at: Utc::now(),
+        }
+    }
+}
+
+impl TrafficReport {
+    /// Reports the traffic of `client_id` to `resource_id` from now on as part of `flow_id`.
+    pub(crate) fn set_flow(
+        &mut self,
+        client_id: ClientId,
+        resource_id: ResourceId,
+        flow_id: FlowId,
+    ) {
+        self.flows.insert(
+            (client_id, resource_id),
+            Flow {
+                id: flow_id,
+                fresh: true,
+            },
+        );
+    }
+
+    /// Forgets the flows of the clients that are gone, once all of their traffic was reported.
+    ///
+    /// Flows set since the last call are kept either way, their connection may not be up yet.
+    pub(crate) fn prune_flows(&mut self, connected: &HashSet<ClientId>) {
+        let unreported: HashSet<_> = self
+            .unreported
+            .keys()
+            .map(|&(client_id, resource_id, _)| (client_id, resource_id))
+            .collect();
+        self.flows.retain(|key, flow| {
+            let (client_id, _) = key;
+            let keep = std::mem::take(&mut flow.fresh)
+                || connected.contains(client_id)
+                || unreported.contains(key);
+            if !keep {
+                tracing::debug!(?client_id, flow_id = ?flow.id, "flow_forgotten");
+            }
+            keep
+        });
+    }
+
+    /// Counts the traffic since the last report, `removed` is the traffic of the peers removed since then.
+    pub(crate) fn update(&mut self, live: &[ResourceTraffic], removed: &[ResourceTraffic]) {
+        for (resource_traffic, is_removed) in live
+            .iter()
+            .map(|t| (t, false))
+            .chain(removed.iter().map(|t| (t, true)))
+        {
+            let ConnId::Client(client_id) = resource_traffic.conn_id else {
+                continue;
+            };
+            let key = (
+                resource_traffic.index,
+                resource_traffic.resource_id,
+                resource_traffic.destination,
+            );
+
+            let previous = if is_removed {
+                self.reported.remove(&key)
+            } else {
+                self.reported.insert(key, resource_traffic.traffic)
+            };
+            *self
+                .unreported
+                .entry((
+                    client_id,
+                    resource_traffic.resource_id,
+                    resource_traffic.destination,
+                ))
+                .or_default() += resource_traffic
+                .traffic
+                .since(&previous.unwrap_or_default());
+        }
+    }
+
+    /// Takes as much of the traffic counted so far as fits in the metrics, the window ends at `now`.
+    pub(crate) fn take_metrics(&mut self, now: DateTime<Utc>) -> Metrics {
+        let flows = &self.flows;
+        let metrics = self
+            .unreported
+            .iter_mut()
+            .filter_map(|(&(client_id, resource_id, destination), traffic)| {
+                let Some(flow_id) = flows.get(&(client_id, resource_id)).map(|f| f.id) else {
+                    // Nothing the portal could file it under
+                    tracing::debug!(?client_id, %resource_id, "traffic_without_flow");
+                    *traffic = TrafficStats::default();
+                    return None;
+                };
+
+                let rx_bytes = u32::try_from(traffic.rx_bytes).unwrap_or(u32::MAX);
+                let tx_bytes = u32::try_from(traffic.tx_bytes).unwrap_or(u32::MAX);
+                traffic.rx_bytes -= u64::from(rx_bytes);
+                traffic.tx_bytes -= u64::from(tx_bytes);
+
+                (rx_bytes != 0 || tx_bytes != 0).then_some(Metric {
+                    flow_id,
+                    destination,
+                    rx_bytes,
+                    tx_bytes,
+                })
+            })
+            .collect();
+        self.unreported
+            .retain(|_, traffic| traffic.rx_bytes != 0 || traffic.tx_bytes != 0);
+
+        Metrics {
+            started_at: std::mem::replace(&mut self.window_started_at, now),
+            ended_at: now,
+            metrics,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, net::IpAddr};
+
+    use chrono::{Duration, Utc};
+    use connlib_shared::messages::{ClientId, FlowId, ResourceId};
+    use firezone_tunnel::{ConnId, ResourceTraffic, TrafficStats};
+
+    use super::TrafficReport;
+
+    fn client() -> ClientId {
+        "3a25ff38-f8d7-47de-9b30-c7c40c206083".parse().unwrap()
+    }
+
+    fn resource() -> ResourceId {
+        "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b".parse().unwrap()
+    }
+
+    fn flow() -> FlowId {
+        "b8c6b2d4-22b4-4f0c-a8ed-8b0b4f1c6d3e".parse().unwrap()
+    }
+
+    fn destination() -> IpAddr {
+        "172.20.0.1".parse().unwrap()
+    }
+
+    fn traffic(index: u32, rx_bytes: u64, tx_bytes: u64) -> ResourceTraffic {
+        traffic_to(destination(), index, rx_bytes, tx_bytes)
+    }
+
+    fn traffic_to(
+        destination: IpAddr,
+        index: u32,
+        rx_bytes: u64,
+        tx_bytes: u64,
+    ) -> ResourceTraffic {
+        ResourceTraffic {
+            conn_id: ConnId::Client(client()),
+            index,
+            resource_id: resource(),
+            destination,
+            traffic: TrafficStats {
+                rx_bytes,
+                tx_bytes,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn report() -> TrafficReport {
+        let mut report = TrafficReport::default();
+        report.set_flow(client(), resource(), flow());
+        report
+    }
+
+    fn bytes(report: &mut TrafficReport) -> Vec<(u32, u32)> {
+        report
+            .take_metrics(Utc::now())
+            .metrics
+            .into_iter()
+            .map(|m| (m.rx_bytes, m.tx_bytes))
+            .collect()
+    }
+
+    #[test]
+    fn only_the_traffic_since_the_last_report_is_reported() {
+        let mut report = report();
+
+        report.update(&[traffic(1, 100, 10)], &[]);
+        assert_eq!(bytes(&mut report), [(100, 10)]);
+
+        report.update(&[traffic(1, 150, 10)], &[]);
+        assert_eq!(bytes(&mut report), [(50, 0)]);
+
+        report.update(&[traffic(1, 150, 10)], &[]);
+        assert!(bytes(&mut report).is_empty());
+    }
+
+    #[test]
+    fn traffic_of_removed_peers_is_reported() {
+        let mut report = report();
+        report.update(&[traffic(1, 100, 10)], &[]);
+        report.take_metrics(Utc::now());
+
+        // The client connected again, through a new peer
+        report.update(&[traffic(2, 500, 0)], &[traffic(1, 120, 10)]);
+
+        assert_eq!(bytes(&mut report), [(520, 0)]);
+    }
+
+    #[test]
+    fn traffic_that_does_not_fit_is_left_for_the_next_report() {
+        let mut report = report();
+        let big = u64::from(u32::MAX) + 5;
+
+        report.update(&[traffic(1, big, 1)], &[]);
+        assert_eq!(bytes(&mut report), [(u32::MAX, 1)]);
+        assert_eq!(bytes(&mut report), [(5, 0)]);
+        assert!(bytes(&mut report).is_empty());
+    }
+
+    #[test]
+    fn traffic_is_reported_for_the_flow_and_each_destination() {
+        let mut report = report();
+        let other_destination: IpAddr = "172.20.0.2".parse().unwrap();
+
+        report.update(
+            &[traffic(1, 100, 10), traffic_to(other_destination, 1, 5, 0)],
+            &[],
+        );
+        let mut metrics = report.take_metrics(Utc::now()).metrics;
+        metrics.sort_by_key(|m| m.destination);
+
+        assert!(metrics.iter().all(|m| m.flow_id == flow()));
+        assert_eq!(metrics[0].destination, destination());
+        assert_eq!((metrics[0].rx_bytes, metrics[0].tx_bytes), (100, 10));
+        assert_eq!(metrics[1].destination, other_destination);
+        assert_eq!((metrics[1].rx_bytes, metrics[1].tx_bytes), (5, 0));
+    }
+
+    #[test]
+    fn windows_follow_each_other() {
+        let mut report = report();
+        let first = Utc::now() + Duration::seconds(60);
+        let second = first + Duration::seconds(60);
+
+        let first_metrics = report.take_metrics(first);
+        let second_metrics = report.take_metrics(second);
+
+        assert_eq!(first_metrics.ended_at, first);
+        assert_eq!(second_metrics.started_at, first);
+        assert_eq!(second_metrics.ended_at, second);
+    }
+
+    #[test]
+    fn flows_of_clients_that_are_gone_are_forgotten() {
+        let mut report = report();
+        // Set since the last time, the client may still be connecting
+        report.prune_flows(&HashSet::new());
+
+        report.update(&[traffic(1, 100, 10)], &[]);
+        assert_eq!(bytes(&mut report), [(100, 10)]);
+        report.prune_flows(&HashSet::from([client()]));
+
+        // The traffic from before the client left is still reported under the flow
+        report.update(&[], &[traffic(1, 120, 10)]);
+        assert_eq!(bytes(&mut report), [(20, 0)]);
+        report.prune_flows(&HashSet::new());
+
+        assert!(report.flows.is_empty());
+    }
+
+    #[test]
+    fn traffic_without_a_flow_is_dropped() {
+        let mut report = TrafficReport::default();
+
+        report.update(&[traffic(1, 100, 10)], &[]);
+        assert!(bytes(&mut report).is_empty());
+
+        // It isn't reported once the flow is known either, since it wasn't part of it
+        report.set_flow(client(), resource(), flow());
+        assert!(bytes(&mut report).is_empty());
+    }
+}
@@ -1,4 +1,5 @@
-use crate::messages::ResourceDescription;
+use crate::messages::{ResourceDescription, ResourceId};
+use crate::stats::TunnelStats;
 use ip_network::IpNetwork;
 use std::error::Error;
 use std::fmt::{Debug, Display};
@@ -19,6 +20,7 @@ pub trait Callbacks: Clone + Send + Sync {
         _: Ipv4Addr,
         _: Ipv6Addr,
         _: Ipv4Addr,
+        _: Ipv6Addr,
         _: String,
     ) -> Result<RawFd, Self::Error> {
         Ok(-1)
@@ -49,6 +51,23 @@ pub trait Callbacks: Clone + Send + Sync {
         Ok(())
     }
 
+    /// Called when we gave up connecting to a resource, `reason` tells why.
+    ///
+    /// The next packet to the resource starts a new attempt.
+    fn on_resource_unreachable(
+        &self,
+        resource_id: ResourceId,
+        reason: &crate::Error,
+    ) -> Result<(), Self::Error> {
+        tracing::trace!(%resource_id, %reason, "resource_unreachable");
+        Ok(())
+    }
+
+    /// Called every [crate::SessionConfig::stats_interval] with a snapshot of the tunnel.
+    fn on_stats(&self, _: &TunnelStats) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Called when the tunnel is disconnected.
     ///
     /// If the tunnel disconnected due to a fatal error, `error` is the error
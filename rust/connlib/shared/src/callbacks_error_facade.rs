@@ -1,7 +1,9 @@
-use crate::messages::ResourceDescription;
+use crate::messages::{ResourceDescription, ResourceId};
+use crate::stats::TunnelStats;
 use crate::{Callbacks, Error, Result};
 use ip_network::IpNetwork;
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
 
 // Avoids having to map types for Windows
 type RawFd = i32;
@@ -16,7 +18,8 @@ impl<CB: Callbacks> Callbacks for CallbackErrorFacade<CB> {
         &self,
         tunnel_address_v4: Ipv4Addr,
         tunnel_address_v6: Ipv6Addr,
-        dns_address: Ipv4Addr,
+        dns_address_v4: Ipv4Addr,
+        dns_address_v6: Ipv6Addr,
         dns_fallback_strategy: String,
     ) -> Result<RawFd> {
         let result = self
@@ -24,7 +27,8 @@ impl<CB: Callbacks> Callbacks for CallbackErrorFacade<CB> {
             .on_set_interface_config(
                 tunnel_address_v4,
                 tunnel_address_v6,
-                dns_address,
+                dns_address_v4,
+                dns_address_v6,
                 dns_fallback_strategy,
             )
             .map_err(|err| Error::OnSetInterfaceConfigFailed(err.to_string()));
@@ -78,6 +82,28 @@ impl<CB: Callbacks> Callbacks for CallbackErrorFacade<CB> {
         result
     }
 
+    fn on_resource_unreachable(&self, resource_id: ResourceId, reason: &Error) -> Result<()> {
+        let result = self
+            .0
+            .on_resource_unreachable(resource_id, reason)
+            .map_err(|err| Error::OnResourceUnreachableFailed(err.to_string()));
+        if let Err(err) = result.as_ref() {
+            tracing::error!("{err}");
+        }
+        result
+    }
+
+    fn on_stats(&self, stats: &TunnelStats) -> Result<()> {
+        let result = self
+            .0
+            .on_stats(stats)
+            .map_err(|err| Error::OnStatsFailed(err.to_string()));
+        if let Err(err) = result.as_ref() {
+            tracing::error!("{err}");
+        }
+        result
+    }
+
     fn on_disconnect(&self, error: Option<&Error>) -> Result<()> {
         if let Err(err) = self.0.on_disconnect(error) {
             tracing::error!("`on_disconnect` failed: {err}");
@@ -0,0 +1,171 @@
+//! Identity a device logs into the portal with.
+//!
+//! Unless it's kept in a state dir, a new one is used on every connect and the portal sees a
+//! new device each time.
+use std::{fs, io::Write, path::Path};
+
+use boringtun::x25519::{PublicKey, StaticSecret};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{Key, SecretKey};
+use crate::Result;
+
+const STATE_FILE: &str = "device.json";
+
+pub struct DeviceIdentity {
+    pub device_id: String,
+    pub private_key: StaticSecret,
+    /// Tells apart the devices with the same name in the portal.
+    pub name_suffix: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    device_id: String,
+    private_key: SecretKey,
+    name_suffix: String,
+}
+
+impl DeviceIdentity {
+    /// A new identity that's forgotten once we exit.
+    pub fn generate(device_id: String) -> Self {
+        DeviceIdentity {
+            device_id,
+            private_key: StaticSecret::random_from_rng(rand::rngs::OsRng),
+            name_suffix: thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect(),
+        }
+    }
+
+    /// Loads the identity kept in `state_dir`, if it was created already.
+    pub fn load(state_dir: &Path) -> Result<Option<Self>> {
+        let path = state_dir.join(STATE_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let stored: StoredIdentity = serde_json::from_slice(&fs::read(path)?)?;
+        Ok(Some(DeviceIdentity {
+            device_id: stored.device_id,
+            private_key: StaticSecret::from(stored.private_key.expose_secret().0),
+            name_suffix: stored.name_suffix,
+        }))
+    }
+
+    /// Loads the identity kept in `state_dir`, it's created with `device_id` the first time.
+    pub fn load_or_create(state_dir: &Path, device_id: String) -> Result<Self> {
+        if let Some(identity) = DeviceIdentity::load(state_dir)? {
+            return Ok(identity);
+        }
+
+        let path = state_dir.join(STATE_FILE);
+        let identity = DeviceIdentity::generate(device_id);
+        identity.save(state_dir)?;
+        tracing::info!(path = %path.display(), "device_identity_created");
+
+        Ok(identity)
+    }
+
+    /// Replaces the private key kept in `state_dir`, the portal still sees the same device.
+    ///
+    /// Only takes effect the next time we connect.
+    pub fn rotate_key(state_dir: &Path, device_id: String) -> Result<Self> {
+        let mut identity = DeviceIdentity::load_or_create(state_dir, device_id)?;
+        identity.private_key = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        identity.save(state_dir)?;
+        tracing::info!(public_key = %identity.public_key(), "device_key_rotated");
+
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> Key {
+        Key(PublicKey::from(&self.private_key).to_bytes())
+    }
+
+    fn save(&self, state_dir: &Path) -> Result<()> {
+        create_dir(state_dir)?;
+
+        let stored = StoredIdentity {
+            device_id: self.device_id.clone(),
+            private_key: Secret::new(Key(self.private_key.to_bytes())),
+            name_suffix: self.name_suffix.clone(),
+        };
+        // Written aside and renamed so a crash can't leave us with half a key
+        let tmp_path = state_dir.join(format!("{STATE_FILE}.tmp"));
+        // The mode only applies to new files
+        let _ = fs::remove_file(&tmp_path);
+        let mut file = private_file(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(&stored)?)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, state_dir.join(STATE_FILE))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn create_dir(path: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_dir(path: &Path) -> Result<()> {
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn private_file(path: &Path) -> Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    Ok(fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?)
+}
+
+#[cfg(not(unix))]
+fn private_file(path: &Path) -> Result<fs::File> {
+    Ok(fs::File::create(path)?)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::{DeviceIdentity, STATE_FILE};
+
+    #[test]
+    fn identity_survives_restarts_and_rotation() {
+        let state_dir = std::env::temp_dir().join(format!("fz-state-{}", std::process::id()));
+
+        let created = DeviceIdentity::load_or_create(&state_dir, "device".to_owned()).unwrap();
+        let loaded = DeviceIdentity::load_or_create(&state_dir, "other".to_owned()).unwrap();
+        let rotated = DeviceIdentity::rotate_key(&state_dir, "other".to_owned()).unwrap();
+        let mode = fs::metadata(state_dir.join(STATE_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        fs::remove_dir_all(&state_dir).unwrap();
+
+        assert_eq!(loaded.device_id, "device");
+        assert_eq!(loaded.name_suffix, created.name_suffix);
+        assert_eq!(loaded.public_key(), created.public_key());
+        assert_eq!(rotated.device_id, "device");
+        assert_ne!(rotated.public_key(), created.public_key());
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
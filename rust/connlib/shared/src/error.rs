@@ -60,6 +60,21 @@ pub enum ConnlibError {
     /// Tried to access a resource which didn't exists.
     #[error("Tried to access an undefined resource")]
     UnknownResource,
+    /// None of the upstream DNS resolvers answered.
+    #[error("No upstream DNS resolver answered the query")]
+    DnsUpstreamUnreachable,
+    /// The name of a resource doesn't resolve to any address.
+    #[error("The resource's name doesn't resolve to any address")]
+    ResourceNameUnresolved,
+    /// None of the gateways that could serve the resource are online.
+    #[error("No gateway for the resource is online")]
+    GatewayOffline,
+    /// The portal didn't answer any of our requests to connect to a resource.
+    #[error("Gave up connecting to the resource after too many attempts")]
+    ConnectionAttemptsExhausted,
+    /// ICE couldn't find a path to the gateway.
+    #[error("Couldn't establish a connection with the gateway")]
+    IceConnectionFailed,
     /// One of the stored resources isn't a valid CIDR/DNS.
     #[error("Invalid resource")]
     InvalidResource,
@@ -79,12 +94,25 @@ pub enum ConnlibError {
     OnRemoveRouteFailed(String),
     #[error("`on_update_resources` failed: {0}")]
     OnUpdateResourcesFailed(String),
+    #[error("`on_resource_unreachable` failed: {0}")]
+    OnResourceUnreachableFailed(String),
+    #[error("`on_stats` failed: {0}")]
+    OnStatsFailed(String),
     /// Glob for errors without a type.
     #[error("Other error: {0}")]
     Other(&'static str),
     /// Invalid tunnel name
     #[error("Invalid tunnel name")]
     InvalidTunnelName,
+    /// The MTU is out of the range the tunnel works with.
+    #[error("Invalid MTU")]
+    InvalidMtu,
+    /// The route is already there but goes through another interface, e.g. the one of another session.
+    #[error("The route {0} goes through another interface")]
+    RouteConflict(ip_network::IpNetwork),
+    /// The DNS sentinels or wildcard addresses of the session are routed through another interface.
+    #[error("The DNS addresses number {} are used by another session, every client on the host needs its own", .0.index())]
+    DnsAddressesTaken(crate::DnsAddresses),
     #[cfg(target_os = "linux")]
     #[error(transparent)]
     NetlinkError(rtnetlink::Error),
@@ -120,6 +148,9 @@ pub enum ConnlibError {
     /// Invalid source address for peer
     #[error("Invalid source address")]
     InvalidSource,
+    /// The transport to a peer was closed, the peer can't be reached through it anymore.
+    #[error("The connection to the peer is closed")]
+    TransportClosed,
 }
 
 #[cfg(target_os = "linux")]
@@ -6,42 +6,68 @@
 mod callbacks;
 mod callbacks_error_facade;
 pub mod control;
+pub mod device_identity;
 pub mod error;
 pub mod messages;
+mod session_config;
+pub mod stats;
 
 pub use callbacks::Callbacks;
 pub use callbacks_error_facade::CallbackErrorFacade;
 pub use error::ConnlibError as Error;
 pub use error::Result;
+pub use session_config::{ConnectionRetryPolicy, DnsAddresses, SessionConfig, MAX_DNS_SESSIONS};
 
-use boringtun::x25519::{PublicKey, StaticSecret};
+use boringtun::x25519::StaticSecret;
+use device_identity::DeviceIdentity;
 use messages::Key;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use ring::digest::{Context, SHA256};
 use secrecy::{ExposeSecret, SecretString};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
 use url::Url;
 
+/// Sentinel of the first [DnsAddresses], the ones of the other sets come right after it.
 pub const DNS_SENTINEL: Ipv4Addr = Ipv4Addr::new(100, 100, 111, 1);
+/// Sentinel of the first [DnsAddresses], the ones of the other sets come right after it.
+pub const DNS_SENTINEL_V6: Ipv6Addr =
+    Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x8000, 0x100, 0x100, 0x111, 0x1);
+/// Name of the interface connlib creates on Linux unless [SessionConfig::interface_name] says otherwise.
+pub const DEFAULT_INTERFACE_NAME: &str = "tun-firezone";
+/// Other interface names have to start with it, so every session's tunnel on the host is recognized.
+pub const INTERFACE_NAME_PREFIX: &str = "tun-fz";
+/// Most queues the interface is read from on Linux, see [SessionConfig::tun_queues].
+// Note: `MAX_TAP_QUEUES` in the kernel
+pub const MAX_TUN_QUEUES: usize = 256;
+/// Smallest MTU [SessionConfig::mtu] can be, every IPv6 link has to carry packets this big.
+pub const MIN_MTU: usize = 1280;
+/// Biggest MTU [SessionConfig::mtu] can be, the size of the biggest IP packet.
+pub const MAX_MTU: usize = u16::MAX as usize;
+
+// Including the nul terminator
+const IFNAMSIZ: usize = 16;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const LIB_NAME: &str = "connlib";
+#[cfg(not(any(target_os = "ios", target_os = "android")))]
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
 
 /// Creates a new login URL to use with the portal.
+///
+/// The device's identity is kept in `state_dir` if there's one, see [DeviceIdentity::load_or_create].
 pub fn login_url(
     mode: Mode,
     portal_url: Url,
     portal_token: SecretString,
     device_id: String,
+    state_dir: Option<&Path>,
 ) -> Result<(Url, StaticSecret)> {
-    let private_key = StaticSecret::random_from_rng(rand::rngs::OsRng);
-    let name_suffix: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(8)
-        .map(char::from)
-        .collect();
-    let external_id = sha256(device_id);
+    let identity = match state_dir {
+        Some(state_dir) => DeviceIdentity::load_or_create(state_dir, device_id)?,
+        None => DeviceIdentity::generate(device_id),
+    };
+    let public_key = identity.public_key();
+    let external_id = sha256(identity.device_id);
 
     let url = get_websocket_path(
         portal_url,
@@ -50,12 +76,12 @@ pub fn login_url(
             Mode::Client => "client",
             Mode::Gateway => "gateway",
         },
-        &Key(PublicKey::from(&private_key).to_bytes()),
+        &public_key,
         &external_id,
-        &name_suffix,
+        &identity.name_suffix,
     )?;
 
-    Ok((url, private_key))
+    Ok((url, identity.private_key))
 }
 
 // FIXME: This is a terrible name :(
@@ -73,9 +99,15 @@ pub fn get_user_agent() -> String {
     format!("{os_type}/{os_version} {lib_name}/{lib_version}")
 }
 
-/// Returns the SMBios Serial of the device or a random UUIDv4 if the SMBios is not available.
+/// Returns the id of the identity kept in `state_dir` or else the SMBios Serial of the device.
+///
+/// Without either, a random UUIDv4 is used when there's a `state_dir` to keep it in, the machine id otherwise.
 #[cfg(not(any(target_os = "ios", target_os = "android")))]
-pub fn get_device_id() -> String {
+pub fn get_device_id(state_dir: Option<&Path>) -> String {
+    if let Some(identity) = stored_identity(state_dir) {
+        return identity.device_id;
+    }
+
     match smbioslib::table_load_from_device() {
         Ok(data) => {
             if let Some(uuid) =
@@ -90,12 +122,31 @@ pub fn get_device_id() -> String {
         }
     }
 
-    tracing::warn!("get_device_id() couldn't find a SMBios Serial. Using random UUIDv4 instead.");
+    // Containers made from the same image share their machine id, the state dir is ours alone
+    if state_dir.is_some() {
+        tracing::debug!("get_device_id() couldn't find a SMBios Serial. Using random UUIDv4 kept in the state dir instead.");
+        return uuid::Uuid::new_v4().to_string();
+    }
+
+    // Usually the case in containers
+    match std::fs::read_to_string(MACHINE_ID_PATH) {
+        Ok(machine_id) if !machine_id.trim().is_empty() => {
+            tracing::warn!("get_device_id() couldn't find a SMBios Serial. Using the machine id instead, set a state dir if it's shared with other devices.");
+            return machine_id.trim().to_owned();
+        }
+        _ => {}
+    }
+
+    tracing::warn!("get_device_id() couldn't find a SMBios Serial or a machine id. Using random UUIDv4 instead.");
     uuid::Uuid::new_v4().to_string()
 }
 
 #[cfg(any(target_os = "ios", target_os = "android"))]
-pub fn get_device_id() -> String {
+pub fn get_device_id(state_dir: Option<&Path>) -> String {
+    if let Some(identity) = stored_identity(state_dir) {
+        return identity.device_id;
+    }
+
     tracing::warn!(
         "get_device_id() is not implemented for this platform. Using random UUIDv4 instead."
     );
@@ -103,6 +154,38 @@ pub fn get_device_id() -> String {
     uuid::Uuid::new_v4().to_string()
 }
 
+/// Whether the interface is the tunnel of a connlib session, ours or another one on the same host.
+pub fn is_tunnel_interface(name: &str) -> bool {
+    name == DEFAULT_INTERFACE_NAME || name.starts_with(INTERFACE_NAME_PREFIX)
+}
+
+/// Checks that the name can be given to an interface, which is what `ip link` checks as well, and that it's recognized as a tunnel.
+pub fn validate_interface_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() >= IFNAMSIZ
+        || name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace())
+        || !is_tunnel_interface(name)
+    {
+        return Err(Error::InvalidTunnelName);
+    }
+
+    Ok(())
+}
+
+/// Checks that the MTU is one the tunnel can work with.
+pub fn validate_mtu(mtu: usize) -> Result<()> {
+    if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+        return Err(Error::InvalidMtu);
+    }
+
+    Ok(())
+}
+
+fn stored_identity(state_dir: Option<&Path>) -> Option<DeviceIdentity> {
+    // A broken state file is reported once we log in with it
+    DeviceIdentity::load(state_dir?).ok().flatten()
+}
+
 fn set_ws_scheme(url: &mut Url) -> Result<()> {
     let scheme = match url.scheme() {
         "http" | "ws" => "ws",
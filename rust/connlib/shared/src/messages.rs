@@ -20,6 +20,35 @@ pub struct ResourceId(Uuid);
 pub struct ClientId(Uuid);
 #[derive(Hash, Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 pub struct ActorId(Uuid);
+/// The portal's record of a client's access to a resource, the traffic is reported for it.
+#[derive(Hash, Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct FlowId(Uuid);
+
+/// Identifies the other end of a connection, a peer or a resource we are connecting to.
+#[derive(Hash, Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ConnId {
+    Gateway(GatewayId),
+    Client(ClientId),
+    Resource(ResourceId),
+}
+
+impl From<GatewayId> for ConnId {
+    fn from(id: GatewayId) -> Self {
+        Self::Gateway(id)
+    }
+}
+
+impl From<ClientId> for ConnId {
+    fn from(id: ClientId) -> Self {
+        Self::Client(id)
+    }
+}
+
+impl From<ResourceId> for ConnId {
+    fn from(id: ResourceId) -> Self {
+        Self::Resource(id)
+    }
+}
 
 impl FromStr for ResourceId {
     type Err = uuid::Error;
@@ -37,6 +66,22 @@ impl FromStr for GatewayId {
     }
 }
 
+impl FromStr for ClientId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ClientId(Uuid::parse_str(s)?))
+    }
+}
+
+impl FromStr for FlowId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(FlowId(Uuid::parse_str(s)?))
+    }
+}
+
 impl fmt::Display for ResourceId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -81,6 +126,9 @@ pub struct RequestConnection {
     pub client_preshared_key: SecretKey,
     /// Client's local RTC Session Description that the client will use for this connection.
     pub client_rtc_session_description: RTCSessionDescription,
+    /// Name the client resolved if the resource is a wildcard one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wildcard_match: Option<WildcardMatch>,
 }
 
 /// Represent a request to reuse an existing gateway connection from a client to a given resource.
@@ -93,6 +141,9 @@ pub struct ReuseConnection {
     pub resource_id: ResourceId,
     /// Id of the gateway we want to re-use
     pub gateway_id: GatewayId,
+    /// Name the client resolved if the resource is a wildcard one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wildcard_match: Option<WildcardMatch>,
 }
 
 // Custom implementation of partial eq to ignore client_rtc_sdp
@@ -132,9 +183,126 @@ pub struct ResourceDescriptionDns {
     ///
     /// Used only for display.
     pub name: String,
+    /// Records for the resource's domain name other than its addresses.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub records: Vec<DnsRecord>,
+    /// Traffic allowed to the resource, if empty everything is allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<Filter>,
+}
+
+/// A DNS record that the client answers for a resource's domain name.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DnsRecord {
+    /// Alias of the resource's domain name.
+    ///
+    /// Only used to answer CNAME queries, A and AAAA queries are always answered with the resource's
+    /// addresses so that its traffic goes through the tunnel.
+    Cname { target: String },
+    /// Location of a service, for resources like `_ldap._tcp.example.com`.
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// Free-form text.
+    Txt { value: String },
+}
+
+/// Allows the traffic of a protocol to a resource.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "protocol", rename_all = "snake_case")]
+pub enum Filter {
+    Tcp(PortRange),
+    Udp(PortRange),
+    /// Both ICMP and ICMPv6.
+    Icmp,
+}
+
+/// An inclusive range of destination ports, if the bounds are missing it covers every port.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    #[serde(default = "PortRange::min_port")]
+    pub port_range_start: u16,
+    #[serde(default = "PortRange::max_port")]
+    pub port_range_end: u16,
+}
+
+impl PortRange {
+    fn min_port() -> u16 {
+        u16::MIN
+    }
+
+    fn max_port() -> u16 {
+        u16::MAX
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.port_range_start..=self.port_range_end).contains(&port)
+    }
+}
+
+/// A name that matched a wildcard DNS resource along with the addresses the client mapped it to.
+///
+/// The client picks these addresses from its own pool, so they play the same role as a regular
+/// DNS resource's `ipv4` and `ipv6`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct WildcardMatch {
+    /// The full domain name the client resolved.
+    pub name: String,
+    /// Ipv4 the client mapped the name to.
+    pub ipv4: Ipv4Addr,
+    /// Ipv6 the client mapped the name to.
+    pub ipv6: Ipv6Addr,
+}
+
+impl ResourceDescriptionDns {
+    /// Tells you if the address is a wildcard, e.g. `*.example.com`, that covers all its subdomains.
+    pub fn is_wildcard(&self) -> bool {
+        self.address.starts_with("*.")
+    }
+
+    /// Tells you if `name` is covered by this resource's wildcard address.
+    pub fn matches(&self, name: &str) -> bool {
+        let Some(suffix) = self.address.strip_prefix('*') else {
+            return false;
+        };
+
+        name.len() > suffix.len()
+            && name.is_char_boundary(name.len() - suffix.len())
+            && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+    }
+
+    /// Gets the resource for a name matched by this wildcard resource.
+    ///
+    /// Returns `None` if this isn't a wildcard resource or if it doesn't cover the name.
+    pub fn with_match(&self, wildcard_match: &WildcardMatch) -> Option<ResourceDescriptionDns> {
+        self.matches(&wildcard_match.name)
+            .then(|| ResourceDescriptionDns {
+                id: self.id,
+                address: wildcard_match.name.clone(),
+                ipv4: wildcard_match.ipv4,
+                ipv6: wildcard_match.ipv6,
+                name: self.name.clone(),
+                records: self.records.clone(),
+                filters: self.filters.clone(),
+            })
+    }
 }
 
 impl ResourceDescription {
+    /// Same as [ResourceDescriptionDns::with_match], CIDR resources never match a name.
+    pub fn with_match(&self, wildcard_match: &WildcardMatch) -> Option<ResourceDescription> {
+        match self {
+            ResourceDescription::Dns(r) => {
+                r.with_match(wildcard_match).map(ResourceDescription::Dns)
+            }
+            ResourceDescription::Cidr(_) => None,
+        }
+    }
+
     pub fn dns_name(&self) -> Option<&str> {
         match self {
             ResourceDescription::Dns(r) => Some(&r.name),
@@ -176,6 +344,13 @@ impl ResourceDescription {
             ResourceDescription::Cidr(r) => r.address.contains(ip),
         }
     }
+
+    pub fn filters(&self) -> &[Filter] {
+        match self {
+            ResourceDescription::Dns(r) => &r.filters,
+            ResourceDescription::Cidr(r) => &r.filters,
+        }
+    }
 }
 
 /// Description of a resource that maps to a CIDR.
@@ -189,6 +364,9 @@ pub struct ResourceDescriptionCidr {
     ///
     /// Used only for display.
     pub name: String,
+    /// Traffic allowed to the resource, if empty everything is allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<Filter>,
 }
 
 /// Represents a wireguard interface configuration.
@@ -0,0 +1,93 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{DNS_SENTINEL, DNS_SENTINEL_V6};
+
+/// Most sessions that can intercept DNS on the same host, each with its own [DnsAddresses].
+pub const MAX_DNS_SESSIONS: u8 = 8;
+
+/// How many times and how often we ask the portal to connect us to a resource before giving up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionRetryPolicy {
+    /// Number of connection requests sent for a resource, including the first one.
+    pub max_attempts: usize,
+    /// Time we wait for a response before the first retry, doubled after every attempt.
+    pub initial_interval: Duration,
+    /// Upper bound for the time between attempts.
+    pub max_interval: Duration,
+}
+
+impl Default for ConnectionRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_interval: Duration::from_secs(2),
+            max_interval: Duration::from_secs(16),
+        }
+    }
+}
+
+/// The addresses a client answers DNS queries on and hands out to the names of wildcard resources.
+///
+/// They are routed through the session's interface, so every session intercepting DNS on the same host needs its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DnsAddresses(u8);
+
+impl DnsAddresses {
+    /// The `index`th set of addresses, there are [MAX_DNS_SESSIONS] of them and the first is the default.
+    pub fn nth(index: u8) -> Option<Self> {
+        (index < MAX_DNS_SESSIONS).then_some(Self(index))
+    }
+
+    /// Every set of addresses, whether a session uses it or not.
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..MAX_DNS_SESSIONS).map(Self)
+    }
+
+    pub fn index(&self) -> u8 {
+        self.0
+    }
+
+    /// The sentinels follow each other, starting with [DNS_SENTINEL].
+    pub fn sentinel(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(DNS_SENTINEL) + u32::from(self.0))
+    }
+
+    /// The sentinels follow each other, starting with [DNS_SENTINEL_V6].
+    pub fn sentinel_v6(&self) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(DNS_SENTINEL_V6) + u128::from(self.0))
+    }
+
+    pub fn is_sentinel(&self, ip: IpAddr) -> bool {
+        ip == IpAddr::from(self.sentinel()) || ip == IpAddr::from(self.sentinel_v6())
+    }
+}
+
+/// Settings that stay the same for as long as a session runs.
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfig {
+    /// Name of the interface connlib creates on Linux, `None` for [crate::DEFAULT_INTERFACE_NAME].
+    ///
+    /// Every session running at the same time needs its own, starting with [crate::INTERFACE_NAME_PREFIX].
+    pub interface_name: Option<String>,
+    /// Number of queues the Linux interface is read from, each on its own task, `None` for one per core.
+    pub tun_queues: Option<usize>,
+    /// Biggest packet sent through the tunnel, `None` to work it out from the interface's MTU and the tunnel's overhead.
+    ///
+    /// Bigger packets are answered with an ICMP packet too big instead of being sent. It's used as is, even above
+    /// the interface's MTU, and the interface's MTU isn't changed. Has to be between [crate::MIN_MTU] and [crate::MAX_MTU].
+    pub mtu: Option<usize>,
+    /// Where the device's id and private key are kept across restarts, `None` to use new ones on every connect.
+    pub state_dir: Option<PathBuf>,
+    /// Resolvers for the DNS queries that aren't for a resource, `None` to use the ones set in the portal.
+    pub upstream_dns: Option<Vec<IpAddr>>,
+    /// How often [crate::Callbacks::on_stats] is called, `None` to never call it.
+    pub stats_interval: Option<Duration>,
+    /// How connecting to resources is retried, `None` for [ConnectionRetryPolicy::default].
+    pub connection_retry_policy: Option<ConnectionRetryPolicy>,
+    /// Addresses of the clients' DNS sentinels and wildcard resources, `None` for [DnsAddresses::default].
+    ///
+    /// Every client running on the same host at the same time needs its own, gateways don't use them.
+    pub dns_addresses: Option<DnsAddresses>,
+}
@@ -0,0 +1,168 @@
+//! Snapshots of the state of a tunnel, see [crate::Callbacks::on_stats].
+use std::{collections::HashMap, net::IpAddr};
+
+use ip_network::IpNetwork;
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{ConnId, GatewayId, ResourceId};
+
+/// State of the tunnel and of each of its peers at a point in time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelStats {
+    /// Our wireguard public key.
+    pub public_key: String,
+    pub peers: Vec<PeerStats>,
+    /// The gateway that serves each resource we are connected to.
+    pub resource_gateways: HashMap<ResourceId, GatewayId>,
+    /// Connections we asked the portal for and are still waiting on.
+    pub awaiting_connection: Vec<ConnId>,
+    /// The traffic of every peer for each of its resources since the peer connected.
+    pub resource_traffic: Vec<ResourceTraffic>,
+}
+
+/// State of the connection with a single peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub index: u32,
+    pub conn_id: ConnId,
+    pub allowed_ips: Vec<IpNetwork>,
+    /// Resources the peer serves, only known by gateways.
+    pub resources: Vec<ResourceId>,
+    /// Seconds since the last wireguard handshake, `None` if it didn't happen yet.
+    pub handshake_age_secs: Option<u64>,
+    /// Round trip time as estimated by wireguard.
+    pub rtt_ms: Option<u32>,
+    pub traffic: TrafficStats,
+    pub resource_traffic: Vec<DestinationTraffic>,
+    /// `None` if the peer connection is already gone.
+    pub ice: Option<IceStats>,
+}
+
+/// State of the ICE connection that carries the wireguard traffic of a peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IceStats {
+    /// The connection state, e.g. `connected` or `failed`.
+    pub state: String,
+    pub selected_candidate_pair: Option<CandidatePairStats>,
+    /// Address of the TURN relay the traffic goes through, `None` for a direct connection.
+    pub relay: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CandidatePairStats {
+    pub local: CandidateStats,
+    pub remote: CandidateStats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CandidateStats {
+    /// One of `host`, `srflx`, `prflx` or `relay`.
+    pub candidate_type: String,
+    pub ip: String,
+    pub port: u16,
+}
+
+impl CandidateStats {
+    pub fn is_relay(&self) -> bool {
+        self.candidate_type == "relay"
+    }
+}
+
+/// Traffic that went through a peer for one of its resources.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ResourceTraffic {
+    pub conn_id: ConnId,
+    /// The peer the traffic went through, a client that connects again gets a new one.
+    pub index: u32,
+    pub resource_id: ResourceId,
+    /// The address of the resource the traffic went to, see [DestinationTraffic::destination].
+    pub destination: IpAddr,
+    pub traffic: TrafficStats,
+}
+
+/// Traffic of a peer to one of the addresses of a resource.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DestinationTraffic {
+    pub resource_id: ResourceId,
+    /// The real address, the one a DNS resource's name resolved to and not the one the client sees.
+    pub destination: IpAddr,
+    pub traffic: TrafficStats,
+}
+
+/// Bytes and packets that went through a peer, counted as the ip packets before encryption.
+///
+/// `rx` is what the peer sent to us and `tx` what we sent to the peer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+impl TrafficStats {
+    /// The traffic since `earlier` was taken from the same counters.
+    ///
+    /// If the counters went back, e.g. because the peer reconnected, all of it is new traffic.
+    pub fn since(&self, earlier: &TrafficStats) -> TrafficStats {
+        if self.rx_bytes < earlier.rx_bytes
+            || self.rx_packets < earlier.rx_packets
+            || self.tx_bytes < earlier.tx_bytes
+            || self.tx_packets < earlier.tx_packets
+        {
+            return *self;
+        }
+
+        TrafficStats {
+            rx_bytes: self.rx_bytes - earlier.rx_bytes,
+            rx_packets: self.rx_packets - earlier.rx_packets,
+            tx_bytes: self.tx_bytes - earlier.tx_bytes,
+            tx_packets: self.tx_packets - earlier.tx_packets,
+        }
+    }
+}
+
+impl std::ops::AddAssign for TrafficStats {
+    fn add_assign(&mut self, other: TrafficStats) {
+        self.rx_bytes += other.rx_bytes;
+        self.rx_packets += other.rx_packets;
+        self.tx_bytes += other.tx_bytes;
+        self.tx_packets += other.tx_packets;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TrafficStats;
+
+    #[test]
+    fn traffic_since_reset_counters() {
+        let earlier = TrafficStats {
+            rx_bytes: 100,
+            rx_packets: 2,
+            tx_bytes: 50,
+            tx_packets: 1,
+        };
+        let later = TrafficStats {
+            rx_bytes: 150,
+            rx_packets: 3,
+            tx_bytes: 50,
+            tx_packets: 1,
+        };
+        let reset = TrafficStats {
+            rx_bytes: 10,
+            rx_packets: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            later.since(&earlier),
+            TrafficStats {
+                rx_bytes: 50,
+                rx_packets: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(reset.since(&later), reset);
+    }
+}
@@ -0,0 +1,112 @@
+//! Buffers for the packets going through the tunnel.
+//!
+//! Packets are written straight into a pooled slab and handed to webrtc as [Bytes] split off of it,
+//! so they are never copied. A slab's memory is reused once webrtc dropped every packet in it,
+//! which is why there are a few slabs used in turns instead of just one. New slabs are only
+//! allocated while webrtc holds on to packets of every slab we have.
+use std::collections::VecDeque;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::MAX_UDP_SIZE;
+
+const SLAB_SIZE: usize = 4 * MAX_UDP_SIZE;
+// Enough for webrtc to be done with the oldest slab under bulk transfers, only allocated as they're needed.
+const MAX_SLABS: usize = 8;
+
+/// Pool of buffers owned by a single packet handler.
+pub(crate) struct BufferPool {
+    current: BytesMut,
+    used: VecDeque<BytesMut>,
+}
+
+impl BufferPool {
+    pub(crate) fn new() -> Self {
+        BufferPool {
+            current: BytesMut::zeroed(SLAB_SIZE),
+            used: VecDeque::with_capacity(MAX_SLABS - 1),
+        }
+    }
+
+    /// Room for the biggest packet, whatever is written in it is only kept by [BufferPool::take].
+    pub(crate) fn buffer(&mut self) -> &mut [u8] {
+        if self.current.len() < MAX_UDP_SIZE {
+            self.next_slab();
+        }
+
+        &mut self.current[..MAX_UDP_SIZE]
+    }
+
+    /// Takes the first `len` bytes written to the [BufferPool::buffer] out of the pool, without copying them.
+    pub(crate) fn take(&mut self, len: usize) -> Bytes {
+        self.current.split_to(len).freeze()
+    }
+
+    fn next_slab(&mut self) {
+        let reclaimed = self
+            .used
+            .iter_mut()
+            .position(|slab| slab.try_reclaim(SLAB_SIZE));
+        let next = match reclaimed {
+            Some(index) => {
+                let mut slab = self.used.remove(index).expect("the index was just found");
+                // Reclaiming never allocates: a slab with nothing left in it and no packet pointing
+                // to it is moved back to the start of the allocation it was split from, see
+                // `BytesMut::reserve_inner`. That's the one `BytesMut::zeroed` made, so it's at least
+                // `SLAB_SIZE` long and every byte of it was initialized.
+                assert!(slab.capacity() >= SLAB_SIZE);
+                // SAFETY: The first `SLAB_SIZE` bytes are initialized, see above.
+                unsafe { slab.set_len(SLAB_SIZE) };
+                slab
+            }
+            None => {
+                if self.used.len() == MAX_SLABS - 1 {
+                    // Left to webrtc, its memory is freed once it dropped the packets
+                    self.used.pop_front();
+                }
+                BytesMut::zeroed(SLAB_SIZE)
+            }
+        };
+
+        let mut used = std::mem::replace(&mut self.current, next);
+        used.clear();
+        self.used.push_back(used);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::MAX_UDP_SIZE;
+
+    use super::{BufferPool, MAX_SLABS, SLAB_SIZE};
+
+    #[test]
+    fn packets_outlive_slab_reuse() {
+        let mut pool = BufferPool::new();
+        let packets: Vec<_> = (0..MAX_SLABS * SLAB_SIZE / 1000)
+            .map(|i| {
+                let buffer = pool.buffer();
+                buffer[..1000].fill(i as u8);
+                pool.take(1000)
+            })
+            .collect();
+
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.iter().all(|b| *b == i as u8));
+        }
+    }
+
+    #[test]
+    fn slabs_are_reused_once_their_packets_are_dropped() {
+        let mut pool = BufferPool::new();
+        let first_slab = pool.buffer().as_ptr();
+
+        // Goes through a few slabs worth of packets, dropping them right away
+        for _ in 0..MAX_SLABS * SLAB_SIZE / MAX_UDP_SIZE {
+            pool.buffer();
+            pool.take(MAX_UDP_SIZE);
+        }
+
+        assert_eq!(pool.buffer().as_ptr(), first_slab);
+    }
+}
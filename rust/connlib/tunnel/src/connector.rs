@@ -0,0 +1,395 @@
+//! Sets up the connections that carry the packets to the peers.
+//!
+//! Outside of tests that's webrtc, negotiating an ICE connection with the offers, answers and candidates
+//! relayed through the portal. The tunnel only hears about what happened to the connection.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+};
+
+use async_trait::async_trait;
+use connlib_shared::{
+    is_tunnel_interface,
+    messages::{Relay, ResourceDescription},
+    stats::IceStats,
+    CallbackErrorFacade, Callbacks, Error, Result,
+};
+use futures::{future::BoxFuture, FutureExt};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::mpsc;
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors, media_engine::MediaEngine,
+        setting_engine::SettingEngine, APIBuilder, API,
+    },
+    data_channel::{data_channel_init::RTCDataChannelInit, RTCDataChannel},
+    ice_transport::{
+        ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_credential_type::RTCIceCredentialType,
+        ice_server::RTCIceServer,
+    },
+    interceptor::registry::Registry,
+    peer_connection::{
+        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+};
+
+use crate::{
+    ice_stats::ice_stats, resource_table::ResourceTable, transport::PeerTransport, ConnId,
+};
+
+const ICE_CANDIDATE_BUFFER: usize = 100;
+
+/// Handles something that happened to a connection.
+pub(crate) type Event<T> = Box<dyn Fn(T) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// What the tunnel does as a connection comes up and goes down.
+pub(crate) struct ConnectionEvents {
+    /// The connection is up, the packets of the peer go through the transport.
+    pub(crate) opened: Event<Arc<dyn PeerTransport>>,
+    /// The connection couldn't be set up, it's never opened.
+    pub(crate) failed: Event<Error>,
+    /// The connection went down after it was opened.
+    pub(crate) closed: Event<()>,
+    /// A candidate the other end has to hear about.
+    pub(crate) candidate: Event<RTCIceCandidate>,
+}
+
+#[async_trait]
+pub(crate) trait PeerConnector: Send + Sync {
+    /// Starts connecting to `conn_id`, returns the offer the other end answers.
+    async fn offer(
+        &self,
+        conn_id: ConnId,
+        relays: Vec<Relay>,
+        events: ConnectionEvents,
+    ) -> Result<RTCSessionDescription>;
+
+    /// Accepts the offer of `conn_id`, returns the answer for it.
+    async fn answer(
+        &self,
+        conn_id: ConnId,
+        relays: Vec<Relay>,
+        offer: RTCSessionDescription,
+        events: ConnectionEvents,
+    ) -> Result<RTCSessionDescription>;
+
+    /// Finishes the connection to `conn_id` with the answer to our offer.
+    async fn accept_answer(&self, conn_id: ConnId, answer: RTCSessionDescription) -> Result<()>;
+
+    /// Adds a candidate the other end of the connection to `conn_id` sent us.
+    async fn add_ice_candidate(
+        &self,
+        conn_id: ConnId,
+        candidate: RTCIceCandidateInit,
+    ) -> Result<()>;
+
+    /// How the other end of the connection to `conn_id` is reached, `None` if there's no such connection.
+    async fn ice_stats(&self, conn_id: ConnId) -> Option<IceStats>;
+
+    /// Forgets the connection to `conn_id` right away, the returned future closes it.
+    fn close(&self, conn_id: ConnId) -> BoxFuture<'static, ()>;
+
+    /// Forgets the connection to `conn_id` without closing it.
+    fn remove(&self, conn_id: ConnId);
+}
+
+type PendingCandidates = (
+    mpsc::Receiver<Option<RTCIceCandidate>>,
+    Arc<ConnectionEvents>,
+);
+
+/// Connects through webrtc data channels, leaving the resources and the tunnel interfaces out of the candidates.
+pub(crate) struct WebRtcConnector<CB: Callbacks> {
+    api: API,
+    peer_connections: Mutex<HashMap<ConnId, Arc<RTCPeerConnection>>>,
+    // The candidates are only sent once the other end knows about the connection
+    ice_candidate_queue: Mutex<HashMap<ConnId, PendingCandidates>>,
+    callbacks: CallbackErrorFacade<CB>,
+}
+
+impl<CB: Callbacks + 'static> WebRtcConnector<CB> {
+    pub(crate) fn new(
+        resources: Arc<RwLock<ResourceTable<ResourceDescription>>>,
+        callbacks: CallbackErrorFacade<CB>,
+    ) -> Result<Self> {
+        let mut media_engine = MediaEngine::default();
+
+        // Register default codecs (TODO: We need this?)
+        media_engine.register_default_codecs()?;
+        let mut registry = Registry::new();
+        registry = register_default_interceptors(registry, &mut media_engine)?;
+        let mut setting_engine = SettingEngine::default();
+        setting_engine.detach_data_channels();
+        setting_engine.set_ip_filter(Box::new(move |ip| {
+            !resources.read().values().any(|res_ip| res_ip.contains(ip))
+        }));
+
+        // Leaves out our tunnel and the ones of other sessions on the same host, their names all share a prefix
+        setting_engine.set_interface_filter(Box::new(|name| {
+            !name.contains("utun") && !is_tunnel_interface(name)
+        }));
+
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(setting_engine)
+            .build();
+
+        Ok(Self {
+            api,
+            peer_connections: Default::default(),
+            ice_candidate_queue: Default::default(),
+            callbacks,
+        })
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, events))]
+    async fn new_peer_connection(
+        &self,
+        relays: Vec<Relay>,
+        conn_id: ConnId,
+        events: &Arc<ConnectionEvents>,
+    ) -> Result<Arc<RTCPeerConnection>> {
+        let config = RTCConfiguration {
+            ice_servers: relays
+                .into_iter()
+                .map(|srv| match srv {
+                    Relay::Stun(stun) => RTCIceServer {
+                        urls: vec![stun.uri],
+                        ..Default::default()
+                    },
+                    Relay::Turn(turn) => RTCIceServer {
+                        urls: vec![turn.uri],
+                        username: turn.username,
+                        credential: turn.password,
+                        // TODO: check what this is used for
+                        credential_type: RTCIceCredentialType::Password,
+                    },
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let peer_connection = Arc::new(self.api.new_peer_connection(config).await?);
+
+        let (ice_candidate_tx, ice_candidate_rx) = mpsc::channel(ICE_CANDIDATE_BUFFER);
+        self.ice_candidate_queue
+            .lock()
+            .insert(conn_id, (ice_candidate_rx, Arc::clone(events)));
+
+        let callbacks = self.callbacks.clone();
+        peer_connection.on_ice_candidate(Box::new(move |candidate| {
+            let ice_candidate_tx = ice_candidate_tx.clone();
+            let callbacks = callbacks.clone();
+            Box::pin(async move {
+                if let Err(e) = ice_candidate_tx.send(candidate).await {
+                    tracing::error!(err = ?e, "buffer_ice_candidate");
+                    let _ = callbacks.on_error(&e.into());
+                }
+            })
+        }));
+
+        // Until the channel is open a failure means it never will be
+        peer_connection.on_peer_connection_state_change(Box::new({
+            let events = Arc::clone(events);
+            move |state: RTCPeerConnectionState| {
+                tracing::trace!(?state, "peer_state");
+                let events = Arc::clone(&events);
+                Box::pin(async move {
+                    if state == RTCPeerConnectionState::Failed {
+                        (events.failed)(Error::IceConnectionFailed).await;
+                    }
+                })
+            }
+        }));
+
+        self.peer_connections
+            .lock()
+            .insert(conn_id, Arc::clone(&peer_connection));
+
+        Ok(peer_connection)
+    }
+
+    fn start_ice_candidate_handler(&self, conn_id: ConnId) -> Result<()> {
+        let (mut ice_candidate_rx, events) = self
+            .ice_candidate_queue
+            .lock()
+            .remove(&conn_id)
+            .ok_or(Error::ControlProtocolError)?;
+
+        tokio::spawn(async move {
+            while let Some(ice_candidate) = ice_candidate_rx.recv().await.flatten() {
+                (events.candidate)(ice_candidate).await;
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Detaches the data channel once it's open, from then on a failed connection or a closed channel closes it.
+#[tracing::instrument(level = "trace", skip_all)]
+async fn open(
+    data_channel: Arc<RTCDataChannel>,
+    peer_connection: Weak<RTCPeerConnection>,
+    events: Arc<ConnectionEvents>,
+) {
+    tracing::trace!("data_channel_open");
+    let transport: Arc<dyn PeerTransport> = match data_channel.detach().await {
+        Ok(transport) => transport,
+        Err(e) => return (events.failed)(e.into()).await,
+    };
+
+    if let Some(peer_connection) = peer_connection.upgrade() {
+        let events = Arc::clone(&events);
+        peer_connection.on_peer_connection_state_change(Box::new(
+            move |state: RTCPeerConnectionState| {
+                tracing::trace!(?state, "peer_state_update");
+                let events = Arc::clone(&events);
+                Box::pin(async move {
+                    if state == RTCPeerConnectionState::Failed {
+                        (events.closed)(()).await;
+                    }
+                })
+            },
+        ));
+    }
+
+    data_channel.on_close({
+        let events = Arc::clone(&events);
+        Box::new(move || {
+            tracing::debug!("channel_closed");
+            let events = Arc::clone(&events);
+            Box::pin(async move { (events.closed)(()).await })
+        })
+    });
+
+    (events.opened)(transport).await;
+}
+
+#[async_trait]
+impl<CB: Callbacks + 'static> PeerConnector for WebRtcConnector<CB> {
+    async fn offer(
+        &self,
+        conn_id: ConnId,
+        relays: Vec<Relay>,
+        events: ConnectionEvents,
+    ) -> Result<RTCSessionDescription> {
+        let events = Arc::new(events);
+        let peer_connection = self.new_peer_connection(relays, conn_id, &events).await?;
+
+        let data_channel = peer_connection
+            .create_data_channel(
+                "data",
+                Some(RTCDataChannelInit {
+                    ordered: Some(false),
+                    max_retransmits: Some(0),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+        let d = Arc::clone(&data_channel);
+        let weak_peer_connection = Arc::downgrade(&peer_connection);
+        data_channel.on_open(Box::new(move || {
+            tracing::trace!("new_data_channel_opened");
+            Box::pin(open(d, weak_peer_connection, events))
+        }));
+
+        let offer = peer_connection.create_offer(None).await?;
+        peer_connection.set_local_description(offer.clone()).await?;
+
+        Ok(offer)
+    }
+
+    async fn answer(
+        &self,
+        conn_id: ConnId,
+        relays: Vec<Relay>,
+        offer: RTCSessionDescription,
+        events: ConnectionEvents,
+    ) -> Result<RTCSessionDescription> {
+        let events = Arc::new(events);
+        let peer_connection = self.new_peer_connection(relays, conn_id, &events).await?;
+        self.start_ice_candidate_handler(conn_id)?;
+
+        let weak_peer_connection = Arc::downgrade(&peer_connection);
+        peer_connection.on_data_channel(Box::new(move |d| {
+            tracing::trace!("new_data_channel");
+            let data_channel = Arc::clone(&d);
+            let weak_peer_connection = weak_peer_connection.clone();
+            let events = Arc::clone(&events);
+            Box::pin(async move {
+                d.on_open(Box::new(move || {
+                    tracing::trace!("new_data_channel_open");
+                    Box::pin(open(data_channel, weak_peer_connection, events))
+                }))
+            })
+        }));
+
+        peer_connection.set_remote_description(offer).await?;
+
+        // TODO: remove tunnel IP from answer
+        let answer = peer_connection.create_answer(None).await?;
+        peer_connection.set_local_description(answer).await?;
+        let local_desc = peer_connection
+            .local_description()
+            .await
+            .ok_or(Error::ConnectionEstablishError)?;
+
+        Ok(local_desc)
+    }
+
+    async fn accept_answer(&self, conn_id: ConnId, answer: RTCSessionDescription) -> Result<()> {
+        let peer_connection = self
+            .peer_connections
+            .lock()
+            .get(&conn_id)
+            .ok_or(Error::UnknownResource)?
+            .clone();
+
+        peer_connection.set_remote_description(answer).await?;
+        self.start_ice_candidate_handler(conn_id)
+    }
+
+    async fn add_ice_candidate(
+        &self,
+        conn_id: ConnId,
+        candidate: RTCIceCandidateInit,
+    ) -> Result<()> {
+        let peer_connection = self
+            .peer_connections
+            .lock()
+            .get(&conn_id)
+            .ok_or(Error::ControlProtocolError)?
+            .clone();
+        peer_connection.add_ice_candidate(candidate).await?;
+        Ok(())
+    }
+
+    async fn ice_stats(&self, conn_id: ConnId) -> Option<IceStats> {
+        let peer_connection = self.peer_connections.lock().get(&conn_id).cloned()?;
+        Some(ice_stats(&peer_connection).await)
+    }
+
+    fn close(&self, conn_id: ConnId) -> BoxFuture<'static, ()> {
+        let peer_connection = self.peer_connections.lock().remove(&conn_id);
+        let callbacks = self.callbacks.clone();
+        async move {
+            let Some(peer_connection) = peer_connection else {
+                return;
+            };
+            // TODO: it seems that even closing the stream there are messages to the relay
+            // see where they come from.
+            if let Err(e) = peer_connection.close().await {
+                tracing::warn!(error = ?e, "Can't close peer");
+                let _ = callbacks.on_error(&e.into());
+            }
+        }
+        .boxed()
+    }
+
+    fn remove(&self, conn_id: ConnId) {
+        self.peer_connections.lock().remove(&conn_id);
+    }
+}
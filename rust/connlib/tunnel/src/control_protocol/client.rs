@@ -7,66 +7,20 @@ use connlib_shared::{
     control::Reference,
     messages::{
         ClientId, GatewayId, Key, Relay, RequestConnection, ResourceDescription, ResourceId,
-        ReuseConnection,
+        ReuseConnection, WildcardMatch,
     },
     Callbacks,
 };
+use futures::{future, FutureExt};
+use ip_network::IpNetwork;
 use rand_core::OsRng;
 use secrecy::Secret;
-use webrtc::{
-    data_channel::data_channel_init::RTCDataChannelInit,
-    peer_connection::{
-        peer_connection_state::RTCPeerConnectionState,
-        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
-    },
-};
-
-use crate::{ControlSignal, Error, PeerConfig, Request, Result, Tunnel};
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
-#[tracing::instrument(level = "trace", skip(tunnel))]
-fn handle_connection_state_update<C, CB>(
-    tunnel: &Arc<Tunnel<C, CB>>,
-    state: RTCPeerConnectionState,
-    gateway_id: GatewayId,
-    resource_id: ResourceId,
-) where
-    C: ControlSignal + Clone + Send + Sync + 'static,
-    CB: Callbacks + 'static,
-{
-    tracing::trace!("peer_state");
-    if state == RTCPeerConnectionState::Failed {
-        tunnel
-            .awaiting_connection
-            .lock()
-            .remove(&resource_id.into());
-        tunnel.peer_connections.lock().remove(&gateway_id.into());
-        tunnel
-            .gateway_awaiting_connection
-            .lock()
-            .remove(&gateway_id);
-    }
-}
-
-#[tracing::instrument(level = "trace", skip(tunnel))]
-fn set_connection_state_update<C, CB>(
-    tunnel: &Arc<Tunnel<C, CB>>,
-    peer_connection: &Arc<RTCPeerConnection>,
-    gateway_id: GatewayId,
-    resource_id: ResourceId,
-) where
-    C: ControlSignal + Clone + Send + Sync + 'static,
-    CB: Callbacks + 'static,
-{
-    let tunnel = Arc::clone(tunnel);
-    peer_connection.on_peer_connection_state_change(Box::new(
-        move |state: RTCPeerConnectionState| {
-            let tunnel = Arc::clone(&tunnel);
-            Box::pin(async move {
-                handle_connection_state_update(&tunnel, state, gateway_id, resource_id)
-            })
-        },
-    ));
-}
+use crate::{
+    connector::ConnectionEvents, transport::PeerTransport, ControlSignal, Error, PeerConfig,
+    Request, Result, Tunnel,
+};
 
 impl<C, CB> Tunnel<C, CB>
 where
@@ -93,7 +47,7 @@ where
         reference: Option<Reference>,
     ) -> Result<Request> {
         tracing::trace!("request_connection");
-        let resource_description = self
+        let mut resource_description = self
             .resources
             .read()
             .get_by_id(&resource_id)
@@ -104,22 +58,32 @@ where
             .ok_or(Error::InvalidReference)?
             .parse()
             .map_err(|_| Error::InvalidReference)?;
-        {
+        let wildcard_match = {
             let mut awaiting_connections = self.awaiting_connection.lock();
             let Some(awaiting_connection) = awaiting_connections.get_mut(&resource_id.into())
             else {
                 return Err(Error::UnexpectedConnectionDetails);
             };
             awaiting_connection.response_received = true;
+
+            // For wildcard resources we connect to the name that was resolved
+            if let Some(wildcard_match) = &awaiting_connection.wildcard_match {
+                resource_description = resource_description
+                    .with_match(wildcard_match)
+                    .ok_or(Error::UnexpectedConnectionDetails)?;
+            }
+
             if awaiting_connection.total_attemps != reference
                 || resource_description
                     .ips()
                     .iter()
-                    .any(|&ip| self.peers_by_ip.read().exact_match(ip).is_some())
+                    .any(|&ip| self.peers_by_ip.exact_match(ip).is_some())
             {
                 return Err(Error::UnexpectedConnectionDetails);
             }
-        }
+
+            awaiting_connection.wildcard_match.clone()
+        };
 
         self.resources_gateways
             .lock()
@@ -131,17 +95,17 @@ where
                 return Ok(Request::ReuseConnection(ReuseConnection {
                     resource_id,
                     gateway_id,
+                    wildcard_match,
                 }));
             } else {
                 gateway_awaiting_connection.insert(gateway_id, vec![]);
             }
         }
         {
-            let found = {
-                let mut peers_by_ip = self.peers_by_ip.write();
+            let found = self.peers_by_ip.update(|peers_by_ip| {
                 let peer = peers_by_ip
-                    .iter()
-                    .find_map(|(_, p)| (p.conn_id == gateway_id.into()).then_some(p))
+                    .values()
+                    .find(|p| p.conn_id == gateway_id.into())
                     .cloned();
                 if let Some(peer) = peer {
                     for ip in resource_description.ips() {
@@ -152,102 +116,100 @@ where
                 } else {
                     false
                 }
-            };
+            });
 
             if found {
+                // Packets waiting for this resource are sent once the gateway allows the access, see `access_allowed`
                 self.awaiting_connection.lock().remove(&resource_id.into());
                 return Ok(Request::ReuseConnection(ReuseConnection {
                     resource_id,
                     gateway_id,
+                    wildcard_match,
                 }));
             }
         }
-        let peer_connection = {
-            let peer_connection = Arc::new(
-                self.initialize_peer_request(relays, gateway_id.into())
-                    .await?,
-            );
-            let mut peer_connections = self.peer_connections.lock();
-            peer_connections.insert(gateway_id.into(), Arc::clone(&peer_connection));
-            peer_connection
+        let index = self.next_index();
+        let preshared_key = StaticSecret::random_from_rng(OsRng);
+        let events = ConnectionEvents {
+            opened: {
+                let tunnel = Arc::clone(self);
+                let preshared_key = preshared_key.clone();
+                Box::new(move |transport| {
+                    tunnel.gateway_channel_open(
+                        transport,
+                        index,
+                        gateway_id,
+                        resource_id,
+                        resource_description.ips(),
+                        SecretKey::new(Key(preshared_key.to_bytes())),
+                    );
+                    future::ready(()).boxed()
+                })
+            },
+            failed: {
+                let tunnel = Arc::clone(self);
+                Box::new(move |e| {
+                    tunnel.gateway_channel_failed(gateway_id, resource_id, &e);
+                    future::ready(()).boxed()
+                })
+            },
+            closed: self.stop_peer_on_close(index, gateway_id.into()),
+            candidate: self.signal_candidates(gateway_id.into()),
         };
-
-        set_connection_state_update(self, &peer_connection, gateway_id, resource_id);
-
-        let data_channel = peer_connection
-            .create_data_channel(
-                "data",
-                Some(RTCDataChannelInit {
-                    ordered: Some(false),
-                    max_retransmits: Some(0),
-                    ..Default::default()
-                }),
-            )
+        let offer = self
+            .connector
+            .offer(gateway_id.into(), relays, events)
             .await?;
-        let d = Arc::clone(&data_channel);
-
-        let tunnel = Arc::clone(self);
-
-        let preshared_key = StaticSecret::random_from_rng(OsRng);
-        let p_key = preshared_key.clone();
-        data_channel.on_open(Box::new(move || {
-            Box::pin(async move {
-                tracing::trace!("new_data_channel_opened");
-                let index = tunnel.next_index();
-                let Some(gateway_public_key) =
-                    tunnel.gateway_public_keys.lock().remove(&gateway_id)
-                else {
-                    tunnel
-                        .awaiting_connection
-                        .lock()
-                        .remove(&resource_id.into());
-                    tunnel.peer_connections.lock().remove(&gateway_id.into());
-                    tunnel
-                        .gateway_awaiting_connection
-                        .lock()
-                        .remove(&gateway_id);
-                    let e = Error::ControlProtocolError;
-                    tracing::warn!(err = ?e, "channel_open");
-                    let _ = tunnel.callbacks.on_error(&e);
-                    return;
-                };
-                let peer_config = PeerConfig {
-                    persistent_keepalive: None,
-                    public_key: gateway_public_key,
-                    ips: resource_description.ips(),
-                    preshared_key: SecretKey::new(Key(p_key.to_bytes())),
-                };
-
-                if let Err(e) = tunnel
-                    .handle_channel_open(d, index, peer_config, gateway_id.into(), None)
-                    .await
-                {
-                    tracing::error!(err = ?e, "channel_open");
-                    let _ = tunnel.callbacks.on_error(&e);
-                    tunnel.peer_connections.lock().remove(&gateway_id.into());
-                    tunnel
-                        .gateway_awaiting_connection
-                        .lock()
-                        .remove(&gateway_id);
-                }
-                tunnel
-                    .awaiting_connection
-                    .lock()
-                    .remove(&resource_id.into());
-            })
-        }));
-
-        let offer = peer_connection.create_offer(None).await?;
-        peer_connection.set_local_description(offer.clone()).await?;
 
         Ok(Request::NewConnection(RequestConnection {
             resource_id,
             gateway_id,
             client_preshared_key: Secret::new(Key(preshared_key.to_bytes())),
             client_rtc_session_description: offer,
+            wildcard_match,
         }))
     }
 
+    /// Adds the gateway as a peer once the channel to it is open and sends the packets that were waiting for it.
+    pub(crate) fn gateway_channel_open(
+        self: &Arc<Self>,
+        transport: Arc<dyn PeerTransport>,
+        index: u32,
+        gateway_id: GatewayId,
+        resource_id: ResourceId,
+        ips: Vec<IpNetwork>,
+        preshared_key: SecretKey,
+    ) {
+        let opened = self
+            .gateway_public_keys
+            .lock()
+            .remove(&gateway_id)
+            .ok_or(Error::ControlProtocolError)
+            .and_then(|public_key| {
+                let peer_config = PeerConfig {
+                    persistent_keepalive: None,
+                    public_key,
+                    ips,
+                    preshared_key,
+                };
+                self.open_peer(transport, index, peer_config, gateway_id.into(), None, None)
+            });
+
+        match opened {
+            Ok(()) => self.send_pending_packets(resource_id),
+            Err(e) => self.gateway_channel_failed(gateway_id, resource_id, &e),
+        }
+        self.awaiting_connection.lock().remove(&resource_id.into());
+    }
+
+    fn gateway_channel_failed(&self, gateway_id: GatewayId, resource_id: ResourceId, e: &Error) {
+        tracing::error!(err = ?e, "channel_open");
+        let _ = self.callbacks.on_error(e);
+        self.connector.remove(gateway_id.into());
+        self.gateway_awaiting_connection.lock().remove(&gateway_id);
+        self.resource_unreachable(resource_id, e);
+    }
+
     /// Called when a response to [Tunnel::request_connection] is ready.
     ///
     /// Once this is called, if everything goes fine, a new tunnel should be started between the 2 peers.
@@ -268,35 +230,46 @@ where
             .lock()
             .get(&resource_id)
             .ok_or(Error::UnknownResource)?;
-        let peer_connection = self
-            .peer_connections
-            .lock()
-            .get(&gateway_id.into())
-            .ok_or(Error::UnknownResource)?
-            .clone();
         self.gateway_public_keys
             .lock()
             .insert(gateway_id, gateway_public_key);
 
-        peer_connection.set_remote_description(rtc_sdp).await?;
-        self.start_ice_candidate_handler(gateway_id.into())?;
-
-        Ok(())
+        self.connector
+            .accept_answer(gateway_id.into(), rtc_sdp)
+            .await
     }
 
+    /// Lets a client that is already connected reach one more resource.
     pub fn allow_access(
-        &self,
+        self: &Arc<Self>,
         resource: ResourceDescription,
+        wildcard_match: Option<WildcardMatch>,
         client_id: ClientId,
         expires_at: DateTime<Utc>,
-    ) {
-        if let Some(peer) = self
+    ) -> Result<()> {
+        let resource_match = match wildcard_match.map(|m| resource.with_match(&m)) {
+            Some(Some(resource_match)) => Some(resource_match),
+            Some(None) => return Err(Error::InvalidResource),
+            None => None,
+        };
+
+        let peer = self
             .peers_by_ip
-            .write()
-            .iter_mut()
-            .find_map(|(_, p)| (p.conn_id == client_id.into()).then_some(p))
-        {
-            peer.add_resource(resource, expires_at);
+            .find(|p| p.conn_id == client_id.into())
+            .ok_or(Error::ControlProtocolError)?;
+        self.prefetch_resource_name(resource_match.as_ref().unwrap_or(&resource));
+        peer.add_resource(resource, expires_at);
+        if let Some(resource_match) = resource_match {
+            peer.add_resource_match(resource_match, expires_at);
         }
+
+        Ok(())
+    }
+
+    /// Sends the packets that were waiting for a resource reached through a connection we reused.
+    ///
+    /// Only called once the gateway allowed the access, it would drop them before that.
+    pub fn access_allowed(&self, resource_id: ResourceId) {
+        self.send_pending_packets(resource_id);
     }
 }
@@ -2,48 +2,15 @@ use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
 use connlib_shared::{
-    messages::{ClientId, Relay, ResourceDescription},
+    messages::{ClientId, Relay, ResourceDescription, WildcardMatch},
     Callbacks, Error, Result,
 };
-use webrtc::peer_connection::{
-    peer_connection_state::RTCPeerConnectionState, sdp::session_description::RTCSessionDescription,
-    RTCPeerConnection,
-};
-
-use crate::{ControlSignal, PeerConfig, Tunnel};
-
-#[tracing::instrument(level = "trace", skip(tunnel))]
-fn handle_connection_state_update<C, CB>(
-    tunnel: &Arc<Tunnel<C, CB>>,
-    state: RTCPeerConnectionState,
-    client_id: ClientId,
-) where
-    C: ControlSignal + Clone + Send + Sync + 'static,
-    CB: Callbacks + 'static,
-{
-    tracing::trace!(?state, "peer_state");
-    if state == RTCPeerConnectionState::Failed {
-        tunnel.peer_connections.lock().remove(&client_id.into());
-    }
-}
+use futures::FutureExt;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
-#[tracing::instrument(level = "trace", skip(tunnel))]
-fn set_connection_state_update<C, CB>(
-    tunnel: &Arc<Tunnel<C, CB>>,
-    peer_connection: &Arc<RTCPeerConnection>,
-    client_id: ClientId,
-) where
-    C: ControlSignal + Clone + Send + Sync + 'static,
-    CB: Callbacks + 'static,
-{
-    let tunnel = Arc::clone(tunnel);
-    peer_connection.on_peer_connection_state_change(Box::new(
-        move |state: RTCPeerConnectionState| {
-            let tunnel = Arc::clone(&tunnel);
-            Box::pin(async move { handle_connection_state_update(&tunnel, state, client_id) })
-        },
-    ));
-}
+use crate::{
+    connector::ConnectionEvents, transport::PeerTransport, ControlSignal, PeerConfig, Tunnel,
+};
 
 impl<C, CB> Tunnel<C, CB>
 where
@@ -60,9 +27,13 @@ where
     /// - `peer`: Configuration for the remote peer.
     /// - `relays`: List of relays to use with this connection.
     /// - `client_id`: UUID of the remote client.
+    /// - `expires_at`: When the client's access to the resource expires.
+    /// - `resource`: The resource the client is connecting to.
+    /// - `wildcard_match`: If `resource` is a wildcard resource, the name the client resolved through it.
     ///
     /// # Returns
     /// An [RTCSessionDescription] of the local sdp, with candidates gathered.
+    #[allow(clippy::too_many_arguments)]
     pub async fn set_peer_connection_request(
         self: &Arc<Self>,
         sdp_session: RTCSessionDescription,
@@ -71,82 +42,92 @@ where
         client_id: ClientId,
         expires_at: DateTime<Utc>,
         resource: ResourceDescription,
+        wildcard_match: Option<WildcardMatch>,
     ) -> Result<RTCSessionDescription> {
-        let peer_connection = self
-            .initialize_peer_request(relays, client_id.into())
-            .await?;
-        self.start_ice_candidate_handler(client_id.into())?;
-
+        let resource_match = wildcard_match
+            .map(|m| resource.with_match(&m).ok_or(Error::InvalidResource))
+            .transpose()?;
         let index = self.next_index();
-        let tunnel = Arc::clone(self);
-        self.peer_connections
-            .lock()
-            .insert(client_id.into(), Arc::clone(&peer_connection));
-
-        set_connection_state_update(self, &peer_connection, client_id);
-
-        peer_connection.on_data_channel(Box::new(move |d| {
-            tracing::trace!("new_data_channel");
-            let data_channel = Arc::clone(&d);
-            let peer = peer.clone();
-            let tunnel = Arc::clone(&tunnel);
-            let resource = resource.clone();
-            Box::pin(async move {
-                d.on_open(Box::new(move || {
-                    tracing::trace!("new_data_channel_open");
-                    Box::pin(async move {
-                        {
-                            let Some(iface_config) = tunnel.iface_config.read().clone() else {
-                                let e = Error::NoIface;
-                                tracing::error!(err = ?e, "channel_open");
-                                let _ = tunnel.callbacks().on_error(&e);
-                                return;
-                            };
-                            for &ip in &peer.ips {
-                                if let Err(e) = iface_config.add_route(ip, tunnel.callbacks()).await
-                                {
-                                    let _ = tunnel.callbacks.on_error(&e);
-                                }
-                            }
-                        }
-
-                        if let Err(e) = tunnel
-                            .handle_channel_open(
-                                data_channel,
+        let events = ConnectionEvents {
+            opened: {
+                let tunnel = Arc::clone(self);
+                Box::new(move |transport| {
+                    let tunnel = Arc::clone(&tunnel);
+                    let peer = peer.clone();
+                    let resource = resource.clone();
+                    let resource_match = resource_match.clone();
+                    async move {
+                        let opened = tunnel
+                            .client_channel_open(
+                                transport,
                                 index,
                                 peer,
-                                client_id.into(),
-                                Some((resource, expires_at)),
+                                client_id,
+                                resource,
+                                expires_at,
+                                resource_match,
                             )
-                            .await
-                        {
-                            let _ = tunnel.callbacks.on_error(&e);
-                            tracing::error!(err = ?e, "channel_open");
-                            // Note: handle_channel_open can only error out before insert to peers_by_ip
-                            // otherwise we would need to clean that up too!
-                            let conn = tunnel.peer_connections.lock().remove(&client_id.into());
-                            if let Some(conn) = conn {
-                                if let Err(e) = conn.close().await {
-                                    tracing::error!(error = ?e, "webrtc_close_channel");
-                                    let _ = tunnel.callbacks().on_error(&e.into());
-                                }
-                            }
+                            .await;
+                        if let Err(e) = opened {
+                            tunnel.client_channel_failed(client_id, e).await;
                         }
-                    })
-                }))
-            })
-        }));
+                    }
+                    .boxed()
+                })
+            },
+            failed: {
+                let tunnel = Arc::clone(self);
+                Box::new(move |e| {
+                    let tunnel = Arc::clone(&tunnel);
+                    async move { tunnel.client_channel_failed(client_id, e).await }.boxed()
+                })
+            },
+            closed: self.stop_peer_on_close(index, client_id.into()),
+            candidate: self.signal_candidates(client_id.into()),
+        };
 
-        peer_connection.set_remote_description(sdp_session).await?;
-
-        // TODO: remove tunnel IP from answer
-        let answer = peer_connection.create_answer(None).await?;
-        peer_connection.set_local_description(answer).await?;
-        let local_desc = peer_connection
-            .local_description()
+        self.connector
+            .answer(client_id.into(), relays, sdp_session, events)
             .await
-            .ok_or(Error::ConnectionEstablishError)?;
+    }
+
+    /// Adds the client as a peer once the channel to it is open, with routes to its addresses.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn client_channel_open(
+        self: &Arc<Self>,
+        transport: Arc<dyn PeerTransport>,
+        index: u32,
+        peer: PeerConfig,
+        client_id: ClientId,
+        resource: ResourceDescription,
+        expires_at: DateTime<Utc>,
+        resource_match: Option<ResourceDescription>,
+    ) -> Result<()> {
+        let Some(iface_config) = self.iface_config.read().clone() else {
+            return Err(Error::NoIface);
+        };
+        for &ip in &peer.ips {
+            if let Err(e) = iface_config.add_route(ip, self.callbacks()).await {
+                let _ = self.callbacks.on_error(&e);
+            }
+        }
+
+        self.prefetch_resource_name(resource_match.as_ref().unwrap_or(&resource));
+        self.open_peer(
+            transport,
+            index,
+            peer,
+            client_id.into(),
+            Some((resource, expires_at)),
+            resource_match,
+        )
+    }
 
-        Ok(local_desc)
+    // Note: client_channel_open can only error out before insert to peers_by_ip
+    // otherwise we would need to clean that up too!
+    async fn client_channel_failed(&self, client_id: ClientId, e: Error) {
+        let _ = self.callbacks.on_error(&e);
+        tracing::error!(err = ?e, "channel_open");
+        self.connector.close(client_id.into()).await;
     }
 }
@@ -1,5 +1,21 @@
 #![allow(clippy::module_inception)]
 
+use std::{
+    future::poll_fn,
+    io,
+    sync::{
+        atomic::{AtomicU16, Ordering::Relaxed},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use connlib_shared::{CallbackErrorFacade, Callbacks, Result};
+use ip_network::IpNetwork;
+
+use crate::ip_packet::IpPacket;
+
 #[cfg(target_family = "unix")]
 #[path = "device_channel/device_channel_unix.rs"]
 mod device_channel;
@@ -9,3 +25,149 @@ mod device_channel;
 mod device_channel;
 
 pub(crate) use device_channel::*;
+
+/// One of the interface's queues, each one is read on its own task.
+///
+/// Outside of tests it's a queue of the TUN device.
+pub(crate) trait DeviceIo: Send + Sync {
+    fn poll_read(&self, cx: &mut Context<'_>, out: &mut [u8]) -> Poll<io::Result<usize>>;
+
+    // Note: write is synchronous because it's non-blocking
+    // and some losiness is acceptable and increseases performance
+    // since we don't block the reading loops.
+    fn write4(&self, buf: &[u8]) -> io::Result<usize>;
+
+    fn write6(&self, buf: &[u8]) -> io::Result<usize>;
+}
+
+impl dyn DeviceIo {
+    pub(crate) async fn read(&self, out: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, out)).await
+    }
+}
+
+/// The routes and MTU of the interface.
+#[async_trait]
+pub(crate) trait IfaceConfig<CB: Callbacks>: Send + Sync {
+    fn mtu(&self) -> usize;
+
+    async fn refresh_mtu(&self) -> Result<usize>;
+
+    async fn add_route(&self, route: IpNetwork, callbacks: &CallbackErrorFacade<CB>) -> Result<()>;
+
+    async fn remove_route(
+        &self,
+        route: IpNetwork,
+        callbacks: &CallbackErrorFacade<CB>,
+    ) -> Result<()>;
+}
+
+/// Every queue of the interface.
+///
+/// The kernel hands us the packets of a flow always on the same queue, so we write the packets
+/// of a flow back to the queue it was last read from, which keeps them in order.
+#[derive(Clone)]
+pub(crate) struct DeviceQueues {
+    queues: Arc<[Arc<dyn DeviceIo>]>,
+    /// The queue each flow was last read from, plus one so zero is a flow we didn't read yet.
+    ///
+    /// Indexed by the flow's hash so it never grows, two flows that share a slot may end up written to the other's queue.
+    flows: Arc<[AtomicU16]>,
+}
+
+// Enough for the flows of a busy host to rarely share a slot
+const FLOW_SLOTS: usize = 4096;
+
+impl DeviceQueues {
+    // Windows doesn't create interfaces yet
+    #[cfg_attr(target_family = "windows", allow(dead_code))]
+    pub(crate) fn new(queues: Vec<Arc<dyn DeviceIo>>) -> Self {
+        debug_assert!(!queues.is_empty());
+        debug_assert!(queues.len() < u16::MAX as usize);
+        let flow_slots = if queues.len() == 1 { 0 } else { FLOW_SLOTS };
+
+        DeviceQueues {
+            queues: queues.into(),
+            flows: (0..flow_slots).map(|_| AtomicU16::new(0)).collect(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub(crate) fn get(&self, queue: usize) -> Arc<dyn DeviceIo> {
+        Arc::clone(&self.queues[queue])
+    }
+
+    /// Remembers that the kernel delivered the packet's flow on `queue`.
+    pub(crate) fn record_read(&self, queue: usize, packet: &[u8]) {
+        if self.queues.len() == 1 {
+            return;
+        }
+
+        if let Some(packet) = IpPacket::new(packet) {
+            self.flow_slot(packet.flow_hash())
+                .store(queue as u16 + 1, Relaxed);
+        }
+    }
+
+    fn flow_slot(&self, flow_hash: u64) -> &AtomicU16 {
+        &self.flows[(flow_hash % FLOW_SLOTS as u64) as usize]
+    }
+
+    fn queue_for(&self, packet: &[u8]) -> &dyn DeviceIo {
+        if self.queues.len() == 1 {
+            return &*self.queues[0];
+        }
+
+        let Some(flow_hash) = IpPacket::new(packet).map(|p| p.flow_hash()) else {
+            return &*self.queues[0];
+        };
+        let index = match self.flow_slot(flow_hash).load(Relaxed) {
+            // The flow started on the other side of the tunnel, it's spread by its hash until we read from it
+            0 => (flow_hash % self.queues.len() as u64) as usize,
+            queue => usize::from(queue) - 1,
+        };
+        &*self.queues[index]
+    }
+
+    pub fn write4(&self, buf: &[u8]) -> io::Result<usize> {
+        self.queue_for(buf).write4(buf)
+    }
+
+    pub fn write6(&self, buf: &[u8]) -> io::Result<usize> {
+        self.queue_for(buf).write6(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use crate::{ip_packet::udp_packet, memory::memory_queues};
+
+    #[test]
+    fn flows_are_written_to_the_queue_they_were_read_from() {
+        let (mut devices, queues) = memory_queues(4);
+        let client = (Ipv4Addr::new(100, 64, 0, 1), 40000);
+        let resources = (1..=16)
+            .map(|i| (Ipv4Addr::new(10, 0, 0, i), 443))
+            .collect::<Vec<_>>();
+
+        // Whatever their hash, each flow is read from the last queue
+        for &resource in &resources {
+            queues.record_read(3, &udp_packet(client, resource));
+        }
+        for &resource in &resources {
+            queues.write4(&udp_packet(resource, client)).unwrap();
+        }
+
+        for device in &mut devices[..3] {
+            assert!(device.try_recv().is_none());
+        }
+        for _ in &resources {
+            assert!(devices[3].try_recv().is_some());
+        }
+    }
+}
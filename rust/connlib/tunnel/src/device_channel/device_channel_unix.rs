@@ -1,75 +1,101 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering::Relaxed},
-    Arc,
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
+    task::{ready, Context, Poll},
 };
 
-use connlib_shared::{messages::Interface, CallbackErrorFacade, Callbacks, Result};
+use async_trait::async_trait;
+use connlib_shared::{messages::Interface, CallbackErrorFacade, Callbacks, DnsAddresses, Result};
 use ip_network::IpNetwork;
-use tokio::io::{unix::AsyncFd, Interest};
+use tokio::io::unix::AsyncFd;
 
+use super::{DeviceIo, DeviceQueues, IfaceConfig};
 use tun::{IfaceDevice, IfaceStream};
 
 mod tun;
 
-pub(crate) struct IfaceConfig {
+pub(crate) struct TunConfig {
     mtu: AtomicUsize,
     iface: IfaceDevice,
 }
 
-#[derive(Clone)]
-pub(crate) struct DeviceIo(Arc<AsyncFd<IfaceStream>>);
+/// One of the TUN device's queues.
+struct TunQueue(Arc<AsyncFd<IfaceStream>>);
 
-impl DeviceIo {
-    pub async fn read(&self, out: &mut [u8]) -> std::io::Result<usize> {
-        self.0
-            .async_io(Interest::READABLE, |inner| inner.read(out))
-            .await
+impl DeviceIo for TunQueue {
+    fn poll_read(&self, cx: &mut Context<'_>, out: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            match guard.try_io(|inner| inner.get_ref().read(out)) {
+                Ok(res) => return Poll::Ready(res),
+                // Spurious wakeup, the readiness was cleared so we wait again
+                Err(_would_block) => continue,
+            }
+        }
     }
 
-    // Note: write is synchronous because it's non-blocking
-    // and some losiness is acceptable and increseases performance
-    // since we don't block the reading loops.
-    pub fn write4(&self, buf: &[u8]) -> std::io::Result<usize> {
+    fn write4(&self, buf: &[u8]) -> io::Result<usize> {
         self.0.get_ref().write4(buf)
     }
 
-    pub fn write6(&self, buf: &[u8]) -> std::io::Result<usize> {
+    fn write6(&self, buf: &[u8]) -> io::Result<usize> {
         self.0.get_ref().write6(buf)
     }
 }
 
-impl IfaceConfig {
-    pub(crate) fn mtu(&self) -> usize {
+#[async_trait]
+impl<CB: Callbacks> IfaceConfig<CB> for TunConfig {
+    fn mtu(&self) -> usize {
         self.mtu.load(Relaxed)
     }
 
-    pub(crate) async fn refresh_mtu(&self) -> Result<usize> {
+    async fn refresh_mtu(&self) -> Result<usize> {
         let mtu = self.iface.mtu().await?;
         self.mtu.store(mtu, Relaxed);
         Ok(mtu)
     }
 
-    pub(crate) async fn add_route(
+    async fn add_route(&self, route: IpNetwork, callbacks: &CallbackErrorFacade<CB>) -> Result<()> {
+        self.iface.add_route(route, callbacks).await
+    }
+
+    async fn remove_route(
         &self,
         route: IpNetwork,
-        callbacks: &CallbackErrorFacade<impl Callbacks>,
+        callbacks: &CallbackErrorFacade<CB>,
     ) -> Result<()> {
-        self.iface.add_route(route, callbacks).await
+        self.iface.remove_route(route, callbacks).await
     }
 }
 
-pub(crate) async fn create_iface(
+/// Creates the interface and brings it up with exactly the given `routes` bound to it.
+///
+/// Only Linux opens more than one of the `queues` asked for.
+pub(crate) async fn create_iface<CB: Callbacks>(
     config: &Interface,
-    callbacks: &CallbackErrorFacade<impl Callbacks>,
-) -> Result<(IfaceConfig, DeviceIo)> {
-    let (iface, stream) = IfaceDevice::new(config, callbacks).await?;
+    name: &str,
+    queues: usize,
+    routes: &[IpNetwork],
+    dns_addresses: &DnsAddresses,
+    callbacks: &CallbackErrorFacade<CB>,
+) -> Result<(Arc<dyn IfaceConfig<CB>>, DeviceQueues)> {
+    let (iface, streams) = IfaceDevice::new(config, name, queues, dns_addresses, callbacks).await?;
     iface.up().await?;
-    let device_io = DeviceIo(stream);
+    iface.set_routes(routes, callbacks).await?;
+    let device_queues = DeviceQueues::new(
+        streams
+            .into_iter()
+            .map(|stream| Arc::new(TunQueue(stream)) as Arc<dyn DeviceIo>)
+            .collect(),
+    );
     let mtu = iface.mtu().await?;
-    let iface_config = IfaceConfig {
+    let iface_config = TunConfig {
         iface,
         mtu: AtomicUsize::new(mtu),
     };
 
-    Ok((iface_config, device_io))
+    Ok((Arc::new(iface_config), device_queues))
 }
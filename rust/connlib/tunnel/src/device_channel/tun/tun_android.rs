@@ -1,5 +1,5 @@
 use crate::InterfaceConfig;
-use connlib_shared::{CallbackErrorFacade, Callbacks, Error, Result, DNS_SENTINEL};
+use connlib_shared::{CallbackErrorFacade, Callbacks, DnsAddresses, Error, Result};
 use ip_network::IpNetwork;
 use libc::{
     close, ioctl, read, sockaddr, sockaddr_in, write, AF_INET, IFNAMSIZ, IPPROTO_IP, SIOCGIFMTU,
@@ -92,18 +92,24 @@ impl IfaceStream {
 impl IfaceDevice {
     pub async fn new(
         config: &InterfaceConfig,
+        // The interface is created by the OS, named as it likes
+        _: &str,
+        // There's only ever one queue
+        _: usize,
+        dns_addresses: &DnsAddresses,
         callbacks: &CallbackErrorFacade<impl Callbacks>,
-    ) -> Result<(Self, Arc<AsyncFd<IfaceStream>>)> {
+    ) -> Result<(Self, Vec<Arc<AsyncFd<IfaceStream>>>)> {
         let fd = callbacks.on_set_interface_config(
             config.ipv4,
             config.ipv6,
-            DNS_SENTINEL,
+            dns_addresses.sentinel(),
+            dns_addresses.sentinel_v6(),
             DNS_FALLBACK_STRATEGY.to_string(),
         )?;
         let iface_stream = Arc::new(AsyncFd::new(IfaceStream { fd: fd.into() })?);
         let this = Self(Arc::clone(&iface_stream));
 
-        Ok((this, iface_stream))
+        Ok((this, vec![iface_stream]))
     }
 
     fn name(&self) -> Result<String> {
@@ -155,6 +161,26 @@ impl IfaceDevice {
         callbacks.on_add_route(route)
     }
 
+    pub async fn remove_route(
+        &self,
+        route: IpNetwork,
+        callbacks: &CallbackErrorFacade<impl Callbacks>,
+    ) -> Result<()> {
+        callbacks.on_remove_route(route)
+    }
+
+    pub async fn set_routes(
+        &self,
+        routes: &[IpNetwork],
+        callbacks: &CallbackErrorFacade<impl Callbacks>,
+    ) -> Result<()> {
+        for &route in routes {
+            self.add_route(route, callbacks).await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn up(&self) -> Result<()> {
         Ok(())
     }
@@ -1,4 +1,4 @@
-use connlib_shared::{CallbackErrorFacade, Callbacks, Error, Result, DNS_SENTINEL};
+use connlib_shared::{CallbackErrorFacade, Callbacks, DnsAddresses, Error, Result};
 use ip_network::IpNetwork;
 use libc::{
     ctl_info, fcntl, getpeername, getsockopt, ioctl, iovec, msghdr, recvmsg, sendmsg, sockaddr,
@@ -99,7 +99,9 @@ impl IfaceStream {
 
         match unsafe { recvmsg(self.fd, &mut msg_hdr, 0) } {
             -1 => Err(io::Error::last_os_error()),
-            0..=4 => Ok(0),
+            0 => Ok(0),
+            // Not even the header, there's no packet to hand out but the interface is still there
+            1..=4 => Err(io::Error::from(io::ErrorKind::InvalidData)),
             n => Ok((n - 4) as usize),
         }
     }
@@ -137,8 +139,13 @@ impl IfaceStream {
 impl IfaceDevice {
     pub async fn new(
         config: &InterfaceConfig,
+        // The interface is created by the OS, named as it likes
+        _: &str,
+        // There's only ever one queue
+        _: usize,
+        dns_addresses: &DnsAddresses,
         callbacks: &CallbackErrorFacade<impl Callbacks>,
-    ) -> Result<(Self, Arc<AsyncFd<IfaceStream>>)> {
+    ) -> Result<(Self, Vec<Arc<AsyncFd<IfaceStream>>>)> {
         let mut info = ctl_info {
             ctl_id: 0,
             ctl_name: [0; 96],
@@ -198,7 +205,8 @@ impl IfaceDevice {
                 let _ = callbacks.on_set_interface_config(
                     config.ipv4,
                     config.ipv6,
-                    DNS_SENTINEL,
+                    dns_addresses.sentinel(),
+                    dns_addresses.sentinel_v6(),
                     "system_resolver".to_string(),
                 );
 
@@ -206,7 +214,7 @@ impl IfaceDevice {
 
                 return Ok((
                     Self { name: name(fd)? },
-                    Arc::new(AsyncFd::new(IfaceStream { fd })?),
+                    vec![Arc::new(AsyncFd::new(IfaceStream { fd })?)],
                 ));
             }
         }
@@ -245,6 +253,26 @@ impl IfaceDevice {
         callbacks.on_add_route(route)
     }
 
+    pub async fn remove_route(
+        &self,
+        route: IpNetwork,
+        callbacks: &CallbackErrorFacade<impl Callbacks>,
+    ) -> Result<()> {
+        callbacks.on_remove_route(route)
+    }
+
+    pub async fn set_routes(
+        &self,
+        routes: &[IpNetwork],
+        callbacks: &CallbackErrorFacade<impl Callbacks>,
+    ) -> Result<()> {
+        for &route in routes {
+            self.add_route(route, callbacks).await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn up(&self) -> Result<()> {
         Ok(())
     }
@@ -1,15 +1,16 @@
-use connlib_shared::{CallbackErrorFacade, Callbacks, Error, Result};
+use connlib_shared::{CallbackErrorFacade, Callbacks, DnsAddresses, Error, Result};
 use futures::TryStreamExt;
 use ip_network::IpNetwork;
 use libc::{
-    close, fcntl, ioctl, open, read, sockaddr, sockaddr_in, write, F_GETFL, F_SETFL,
-    IFF_MULTI_QUEUE, IFF_NO_PI, IFF_TUN, IFNAMSIZ, O_NONBLOCK, O_RDWR,
+    close, fcntl, ioctl, open, read, sockaddr, sockaddr_in, write, AF_INET, AF_INET6, F_GETFL,
+    F_SETFL, IFF_MULTI_QUEUE, IFF_NO_PI, IFF_TUN, IFNAMSIZ, O_NONBLOCK, O_RDWR,
 };
-use netlink_packet_route::{rtnl::link::nlas::Nla, RT_SCOPE_UNIVERSE};
-use rtnetlink::{new_connection, Handle};
+use netlink_packet_route::{rtnl::link::nlas::Nla, RouteMessage, RT_SCOPE_UNIVERSE};
+use rtnetlink::{new_connection, Handle, IpVersion};
 use std::{
     ffi::{c_int, c_short, c_uchar},
     io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     os::fd::{AsRawFd, RawFd},
     sync::Arc,
 };
@@ -17,7 +18,6 @@ use tokio::io::unix::AsyncFd;
 
 use crate::InterfaceConfig;
 
-const IFACE_NAME: &str = "tun-firezone";
 const TUNSETIFF: u64 = 0x4004_54ca;
 const TUN_FILE: &[u8] = b"/dev/net/tun\0";
 const RT_PROT_STATIC: u8 = 4;
@@ -100,36 +100,28 @@ impl IfaceStream {
 }
 
 impl IfaceDevice {
+    /// Creates the interface with `queues` queues, the kernel spreads the flows across them.
     pub async fn new(
         config: &InterfaceConfig,
+        name: &str,
+        queues: usize,
+        // The sentinels are routed like any other address
+        _: &DnsAddresses,
         cb: &CallbackErrorFacade<impl Callbacks>,
-    ) -> Result<(Self, Arc<AsyncFd<IfaceStream>>)> {
-        debug_assert!(IFACE_NAME.as_bytes().len() < IFNAMSIZ);
+    ) -> Result<(Self, Vec<Arc<AsyncFd<IfaceStream>>>)> {
+        debug_assert!(name.as_bytes().len() < IFNAMSIZ);
 
-        let fd = match unsafe { open(TUN_FILE.as_ptr() as _, O_RDWR) } {
-            -1 => return Err(get_last_error()),
-            fd => fd,
-        };
-
-        let mut ifr = ifreq {
-            ifr_name: [0; IFNAMSIZ],
-            ifr_ifru: IfrIfru {
-                ifru_flags: (IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE) as _,
-            },
-        };
-
-        ifr.ifr_name[..IFACE_NAME.as_bytes().len()].copy_from_slice(IFACE_NAME.as_bytes());
-
-        if unsafe { ioctl(fd, TUNSETIFF as _, &ifr) } < 0 {
-            return Err(get_last_error());
-        }
+        // The first queue creates the interface, the rest attach to it
+        let streams = (0..queues.max(1))
+            .map(|_| open_queue(name))
+            .collect::<Result<Vec<_>>>()?;
 
         let (connection, handle, _) = new_connection()?;
         let join_handle = tokio::spawn(connection);
         let interface_index = handle
             .link()
             .get()
-            .match_name(IFACE_NAME.to_string())
+            .match_name(name.to_string())
             .execute()
             .try_next()
             .await?
@@ -137,8 +129,6 @@ impl IfaceDevice {
             .header
             .index;
 
-        set_non_blocking(fd)?;
-
         let this = Self {
             handle,
             connection: join_handle,
@@ -147,7 +137,7 @@ impl IfaceDevice {
 
         this.set_iface_config(config, cb).await?;
 
-        Ok((this, Arc::new(AsyncFd::new(IfaceStream(fd))?)))
+        Ok((this, streams))
     }
 
     /// Get the current MTU value
@@ -183,37 +173,106 @@ impl IfaceDevice {
             .output_interface(self.interface_index)
             .protocol(RT_PROT_STATIC)
             .scope(RT_SCOPE_UNIVERSE);
-        match route {
+        let res = match route {
             IpNetwork::V4(ipnet) => {
                 req.v4()
                     .destination_prefix(ipnet.network_address(), ipnet.netmask())
                     .execute()
-                    .await?
+                    .await
             }
             IpNetwork::V6(ipnet) => {
                 req.v6()
                     .destination_prefix(ipnet.network_address(), ipnet.netmask())
                     .execute()
-                    .await?
+                    .await
             }
-        }
-        /*
-        TODO: This works for ignoring the error but the route isn't added afterwards
-        let's try removing all routes on init for the given interface I think that will work.
+        };
+
         match res {
-            Ok(_)
-            | Err(rtnetlink::Error::NetlinkError(netlink_packet_core::error::ErrorMessage {
-                code: NETLINK_ERROR_FILE_EXISTS,
-                ..
-            })) => Ok(()),
+            Ok(()) => Ok(()),
+            Err(rtnetlink::Error::NetlinkError(err))
+                if err.to_io().kind() == io::ErrorKind::AlreadyExists =>
+            {
+                // Either we added it already, which is exactly what we want,
+                // or it goes through another interface, e.g. the one of another session.
+                let ours = self
+                    .static_routes()
+                    .await?
+                    .iter()
+                    .any(|msg| route_destination(msg) == Some(route));
+                if !ours {
+                    return Err(Error::RouteConflict(route));
+                }
 
+                tracing::debug!(%route, "route_already_exists");
+                Ok(())
+            }
             Err(err) => Err(err.into()),
         }
-        */
+    }
+
+    pub async fn remove_route(
+        &self,
+        route: IpNetwork,
+        _callbacks: &CallbackErrorFacade<impl Callbacks>,
+    ) -> Result<()> {
+        for msg in self.static_routes().await? {
+            if route_destination(&msg) == Some(route) {
+                self.handle.route().del(msg).execute().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the routes bound to the interface match `routes`.
+    ///
+    /// Leftover routes, e.g. from a previous run that didn't exit cleanly, are removed
+    /// and the ones that are already there are kept as they are.
+    /// Only the routes through this interface are touched, the ones of other sessions are left alone.
+    #[tracing::instrument(level = "trace", skip(self, callbacks))]
+    pub async fn set_routes(
+        &self,
+        routes: &[IpNetwork],
+        callbacks: &CallbackErrorFacade<impl Callbacks>,
+    ) -> Result<()> {
+        let existing = self.static_routes().await?.into_iter().map(|msg| {
+            let route = route_destination(&msg);
+            (msg, route)
+        });
+        let (stale, missing) = route_changes(existing, routes);
+
+        for msg in stale {
+            tracing::debug!(route = ?route_destination(&msg), "remove_stale_route");
+            self.handle.route().del(msg).execute().await?;
+        }
+
+        // A route that goes through another session's interface is a conflict, and an error, since
+        // the traffic for it, e.g. to our DNS sentinel, would silently go to the other session.
+        for route in missing {
+            self.add_route(route, callbacks).await?;
+        }
 
         Ok(())
     }
 
+    /// Lists the routes that we added to the interface.
+    async fn static_routes(&self) -> Result<Vec<RouteMessage>> {
+        let mut routes = Vec::new();
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let mut msgs = self.handle.route().get(ip_version).execute();
+            while let Some(msg) = msgs.try_next().await? {
+                if msg.header.protocol == RT_PROT_STATIC
+                    && msg.output_interface() == Some(self.interface_index)
+                {
+                    routes.push(msg);
+                }
+            }
+        }
+
+        Ok(routes)
+    }
+
     #[tracing::instrument(level = "trace", skip(self, _callbacks))]
     pub async fn set_iface_config(
         &self,
@@ -257,6 +316,69 @@ impl IfaceDevice {
     }
 }
 
+fn route_destination(msg: &RouteMessage) -> Option<IpNetwork> {
+    let (addr, prefix) = match msg.destination_prefix() {
+        Some(destination) => destination,
+        // The kernel leaves out the destination of default routes
+        None if msg.header.destination_prefix_length == 0 => {
+            let addr: IpAddr = match c_int::from(msg.header.address_family) {
+                AF_INET => Ipv4Addr::UNSPECIFIED.into(),
+                AF_INET6 => Ipv6Addr::UNSPECIFIED.into(),
+                _ => return None,
+            };
+            (addr, 0)
+        }
+        None => return None,
+    };
+    IpNetwork::new(addr, prefix).ok()
+}
+
+/// Tells you which of the `existing` routes to remove and which of `routes` to add.
+fn route_changes<T>(
+    existing: impl IntoIterator<Item = (T, Option<IpNetwork>)>,
+    routes: &[IpNetwork],
+) -> (Vec<T>, Vec<IpNetwork>) {
+    let mut kept = Vec::new();
+    let mut stale = Vec::new();
+    for (msg, route) in existing {
+        match route {
+            Some(route) if routes.contains(&route) => kept.push(route),
+            _ => stale.push(msg),
+        }
+    }
+
+    let missing = routes
+        .iter()
+        .filter(|route| !kept.contains(route))
+        .copied()
+        .collect();
+    (stale, missing)
+}
+
+fn open_queue(name: &str) -> Result<Arc<AsyncFd<IfaceStream>>> {
+    let stream = match unsafe { open(TUN_FILE.as_ptr() as _, O_RDWR) } {
+        -1 => return Err(get_last_error()),
+        fd => IfaceStream(fd),
+    };
+
+    let mut ifr = ifreq {
+        ifr_name: [0; IFNAMSIZ],
+        ifr_ifru: IfrIfru {
+            ifru_flags: (IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE) as _,
+        },
+    };
+
+    ifr.ifr_name[..name.as_bytes().len()].copy_from_slice(name.as_bytes());
+
+    if unsafe { ioctl(stream.0, TUNSETIFF as _, &ifr) } < 0 {
+        return Err(get_last_error());
+    }
+
+    set_non_blocking(stream.0)?;
+
+    Ok(Arc::new(AsyncFd::new(stream)?))
+}
+
 fn get_last_error() -> Error {
     Error::Io(io::Error::last_os_error())
 }
@@ -270,3 +392,89 @@ fn set_non_blocking(fd: RawFd) -> Result<()> {
         },
     }
 }
+
+#[cfg(test)]
+mod test {
+    use ip_network::IpNetwork;
+    use libc::{AF_INET, AF_INET6};
+    use netlink_packet_route::{route::Nla, RouteMessage};
+
+    use super::{route_changes, route_destination};
+
+    fn route_message(destination: Vec<u8>, prefix: u8) -> RouteMessage {
+        let mut msg = RouteMessage::default();
+        msg.header.destination_prefix_length = prefix;
+        msg.nlas.push(Nla::Destination(destination));
+        msg
+    }
+
+    #[test]
+    fn route_destination_reads_the_prefix() {
+        let v4 = route_message(vec![10, 0, 0, 0], 24);
+        let v6 = route_message(vec![0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 64);
+
+        assert_eq!(route_destination(&v4), Some("10.0.0.0/24".parse().unwrap()));
+        assert_eq!(route_destination(&v6), Some("fd00::/64".parse().unwrap()));
+        assert_eq!(route_destination(&RouteMessage::default()), None);
+    }
+
+    #[test]
+    fn route_destination_of_default_routes_is_the_whole_family() {
+        let mut v4 = RouteMessage::default();
+        v4.header.address_family = AF_INET as u8;
+        let mut v6 = RouteMessage::default();
+        v6.header.address_family = AF_INET6 as u8;
+
+        assert_eq!(route_destination(&v4), Some("0.0.0.0/0".parse().unwrap()));
+        assert_eq!(route_destination(&v6), Some("::/0".parse().unwrap()));
+    }
+
+    #[test]
+    fn default_route_is_kept_when_it_is_a_resource() {
+        let default: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        let mut msg = RouteMessage::default();
+        msg.header.address_family = AF_INET as u8;
+
+        let (remove, add) = route_changes([("default", route_destination(&msg))], &[default]);
+
+        assert!(remove.is_empty());
+        assert!(add.is_empty());
+    }
+
+    #[test]
+    fn route_destination_rejects_host_bits() {
+        assert_eq!(
+            route_destination(&route_message(vec![10, 0, 0, 1], 24)),
+            None
+        );
+    }
+
+    #[test]
+    fn stale_routes_are_removed_and_missing_ones_added() {
+        let kept: IpNetwork = "10.0.0.0/24".parse().unwrap();
+        let stale: IpNetwork = "10.1.0.0/24".parse().unwrap();
+        let missing: IpNetwork = "fd00::/64".parse().unwrap();
+
+        let (remove, add) = route_changes(
+            [
+                ("kept", Some(kept)),
+                ("stale", Some(stale)),
+                ("unknown", None),
+            ],
+            &[kept, missing],
+        );
+
+        assert_eq!(remove, ["stale", "unknown"]);
+        assert_eq!(add, [missing]);
+    }
+
+    #[test]
+    fn matching_routes_are_left_alone() {
+        let route: IpNetwork = "10.0.0.0/24".parse().unwrap();
+
+        let (remove, add) = route_changes([("route", Some(route))], &[route]);
+
+        assert!(remove.is_empty());
+        assert!(add.is_empty());
+    }
+}
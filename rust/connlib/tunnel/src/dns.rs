@@ -1,28 +1,89 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    ip_packet::{to_dns, IpPacket, MutableIpPacket, Version},
+    device_channel::DeviceQueues,
+    ip_packet::{to_dns, IpPacket, MutableIpPacket, Version, DNS_PORT},
     ControlSignal, Tunnel,
 };
-use connlib_shared::{messages::ResourceDescription, Callbacks, DNS_SENTINEL};
+use async_trait::async_trait;
+use connlib_shared::{
+    messages::{DnsRecord, ResourceDescription, ResourceDescriptionDns, WildcardMatch},
+    Callbacks,
+};
 use domain::base::{
-    iana::{Class, Rcode, Rtype},
-    Dname, Message, MessageBuilder, ParsedDname, ToDname,
+    iana::{Class, Opcode, OptRcode, Rcode, Rtype},
+    message_builder::{AnswerBuilder, PushError},
+    Dname, Message, MessageBuilder, ToDname,
 };
 use pnet_packet::{udp::MutableUdpPacket, MutablePacket, Packet as UdpPacket, PacketSize};
 
 const DNS_TTL: u32 = 300;
 const UDP_HEADER_SIZE: usize = 8;
+// Biggest response we can send to a client that doesn't support EDNS0.
+const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+// Biggest response we send regardless of what the client advertises, see https://www.dnsflagday.net/2020/
+const MAX_UDP_PAYLOAD_SIZE: u16 = 1232;
 const REVERSE_DNS_ADDRESS_END: &str = "arpa";
 const REVERSE_DNS_ADDRESS_V4: &str = "in-addr";
 const REVERSE_DNS_ADDRESS_V6: &str = "ip6";
 
+mod address_pool;
+mod cache;
+mod forwarder;
+mod tcp;
+
+pub(crate) use address_pool::AddressPool;
+pub(crate) use cache::ResolverCache;
+pub(crate) use forwarder::DnsForwarder;
+use forwarder::{QueryId, QueryStart, Transport};
+use tcp::ConnectionId;
+pub(crate) use tcp::TcpDnsServer;
+
+/// Finds the addresses the names of the DNS resources stand for.
+#[async_trait]
+pub(crate) trait Resolver: Send + Sync {
+    /// The addresses of `name`, together with how long they can be kept.
+    async fn resolve(&self, name: &str) -> connlib_shared::Result<(Vec<IpAddr>, Duration)>;
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum SendPacket {
     Ipv4(Vec<u8>),
     Ipv6(Vec<u8>),
 }
 
+impl SendPacket {
+    fn new(version: Version, packet: Vec<u8>) -> SendPacket {
+        match version {
+            Version::Ipv4 => SendPacket::Ipv4(packet),
+            Version::Ipv6 => SendPacket::Ipv6(packet),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ResolveStrategy {
+    /// The query was for a resource and we already have the answer.
+    LocalResponse(SendPacket),
+    /// The query isn't for a resource, it should go to the upstream resolvers.
+    ForwardQuery(DnsQuery),
+}
+
+/// A query that needs to be forwarded upstream.
+#[derive(Debug, Clone)]
+pub(crate) struct DnsQuery {
+    id: QueryId,
+    version: Version,
+    /// The whole ip packet, needed to build the response.
+    packet: Vec<u8>,
+    /// Just the dns message.
+    message: Vec<u8>,
+}
+
 // We don't need to support multiple questions/qname in a single query because
 // nobody does it and since this run with each packet we want to squeeze as much optimization
 // as we can therefore we won't do it.
@@ -64,57 +125,10 @@ where
         Some(res_buf)
     }
 
-    fn build_dns_with_answer<N>(
-        self: &Arc<Self>,
-        message: &Message<[u8]>,
-        qname: &N,
-        qtype: Rtype,
-        resource: &ResourceDescription,
-    ) -> Option<Vec<u8>>
-    where
-        N: ToDname + ?Sized,
-    {
-        let msg_buf = Vec::with_capacity(message.as_slice().len() * 2);
-        let msg_builder = MessageBuilder::from_target(msg_buf).expect(
-            "Developer error: we should be always be able to create a MessageBuilder from a Vec",
-        );
-        let mut answer_builder = msg_builder.start_answer(message, Rcode::NoError).ok()?;
-        match qtype {
-            Rtype::A => answer_builder
-                .push((
-                    qname,
-                    Class::In,
-                    DNS_TTL,
-                    domain::rdata::A::from(resource.ipv4()?),
-                ))
-                .ok()?,
-            Rtype::Aaaa => answer_builder
-                .push((
-                    qname,
-                    Class::In,
-                    DNS_TTL,
-                    domain::rdata::Aaaa::from(resource.ipv6()?),
-                ))
-                .ok()?,
-            Rtype::Ptr => answer_builder
-                .push((
-                    qname,
-                    Class::In,
-                    DNS_TTL,
-                    domain::rdata::Ptr::<ParsedDname<_>>::new(
-                        resource.dns_name()?.parse::<Dname<Vec<u8>>>().ok()?.into(),
-                    ),
-                ))
-                .ok()?,
-            _ => return None,
-        }
-        Some(answer_builder.finish())
-    }
-
-    pub(crate) fn check_for_dns(self: &Arc<Self>, buf: &[u8]) -> Option<SendPacket> {
+    pub(crate) fn check_for_dns(self: &Arc<Self>, buf: &[u8]) -> Option<ResolveStrategy> {
         let packet = IpPacket::new(buf)?;
         let version = packet.version();
-        if packet.destination() != IpAddr::from(DNS_SENTINEL) {
+        if !self.dns_addresses.is_sentinel(packet.destination()) {
             return None;
         }
         let datagram = packet.as_udp()?;
@@ -122,58 +136,597 @@ where
         if message.header().qr() {
             return None;
         }
-        let question = message.first_question()?;
-        let resource = match question.qtype() {
-            Rtype::A | Rtype::Aaaa => self
-                .resources
-                .read()
-                .get_by_name(&ToDname::to_cow(question.qname()).to_string())
-                .cloned(),
-            Rtype::Ptr => {
-                let dns_parts = ToDname::to_cow(question.qname()).to_string();
-                let mut dns_parts = dns_parts.split('.').rev();
-                if !dns_parts
-                    .next()
-                    .is_some_and(|d| d == REVERSE_DNS_ADDRESS_END)
+
+        if let Some(response) = self
+            .resolve_or_refuse(message)
+            .and_then(|response| truncate_for_udp(message, response))
+        {
+            let response = self.build_response(buf, response)?;
+            return Some(ResolveStrategy::LocalResponse(SendPacket::new(
+                version, response,
+            )));
+        }
+
+        Some(ResolveStrategy::ForwardQuery(DnsQuery {
+            id: QueryId {
+                src: SocketAddr::new(packet.source(), datagram.get_source()),
+                transaction_id: message.header().id(),
+            },
+            version,
+            packet: buf.to_vec(),
+            message: message.as_slice().to_vec(),
+        }))
+    }
+
+    /// Handles the TCP segments sent to the DNS port of the sentinels.
+    ///
+    /// Returns `false` if the packet isn't one of them.
+    pub(crate) fn check_for_dns_tcp(
+        self: &Arc<Self>,
+        device_io: &DeviceQueues,
+        buf: &[u8],
+    ) -> bool {
+        let Some(packet) = IpPacket::new(buf) else {
+            return false;
+        };
+        if !self.dns_addresses.is_sentinel(packet.destination()) {
+            return false;
+        }
+        let Some(segment) = packet.as_tcp() else {
+            return false;
+        };
+        if segment.get_destination() != DNS_PORT {
+            return false;
+        }
+
+        let version = packet.version();
+        let (id, output) = self.dns_tcp_server.handle_segment(&packet, &segment);
+        for reply in output.replies {
+            self.write_dns_packet(device_io, SendPacket::new(version, reply));
+        }
+        for query in output.queries {
+            self.answer_dns_tcp_query(device_io, version, id, query);
+        }
+
+        true
+    }
+
+    fn answer_dns_tcp_query(
+        self: &Arc<Self>,
+        device_io: &DeviceQueues,
+        version: Version,
+        id: ConnectionId,
+        query: Vec<u8>,
+    ) {
+        let Ok(message) = Message::from_slice(&query[..]) else {
+            tracing::debug!("malformed_dns_tcp_query");
+            for reply in self.dns_tcp_server.respond(&id, None) {
+                self.write_dns_packet(device_io, SendPacket::new(version, reply));
+            }
+            return;
+        };
+
+        if let Some(response) = self.resolve_or_refuse(message) {
+            for reply in self.dns_tcp_server.respond(&id, Some(&response)) {
+                self.write_dns_packet(device_io, SendPacket::new(version, reply));
+            }
+            return;
+        }
+
+        let tunnel = Arc::clone(self);
+        let device_io = device_io.clone();
+        tokio::spawn(async move {
+            let response = match tunnel.dns_forwarder.forward(&query, Transport::Tcp).await {
+                Ok(response) => Some(response),
+                Err(e) => {
+                    tracing::debug!(error = ?e, "forward_dns_tcp_query");
+                    Message::from_slice(&query[..]).ok().and_then(|message| {
+                        build_dns_response(message, Rcode::ServFail.into(), |_| Ok(()))
+                    })
+                }
+            };
+
+            for reply in tunnel.dns_tcp_server.respond(&id, response.as_deref()) {
+                tunnel.write_dns_packet(&device_io, SendPacket::new(version, reply));
+            }
+        });
+    }
+
+    /// Same as [`Self::resolve_locally`] but if there are no upstream resolvers to forward the query to it's refused.
+    fn resolve_or_refuse(&self, message: &Message<[u8]>) -> Option<Vec<u8>> {
+        self.resolve_locally(message).or_else(|| {
+            (!self.dns_forwarder.has_upstreams())
+                .then(|| build_dns_response(message, Rcode::Refused.into(), |_| Ok(())))
+                .flatten()
+        })
+    }
+
+    /// Answers the query if it's ours to answer, otherwise it's up to the upstream resolvers.
+    ///
+    /// The names of the resources and the reverse names of the addresses we hand out are ours,
+    /// they must never leak upstream, so they always get an answer even if it's an error.
+    fn resolve_locally(&self, message: &Message<[u8]>) -> Option<Vec<u8>> {
+        if message.header().opcode() != Opcode::Query {
+            return build_dns_response(message, Rcode::NotImp.into(), |_| Ok(()));
+        }
+        if message.opt().is_some_and(|opt| opt.version() > 0) {
+            return build_dns_response(message, OptRcode::BadVers, |_| Ok(()));
+        }
+        let question = match message.first_question() {
+            Some(question) if message.header_counts().qdcount() == 1 => question,
+            _ => return build_dns_response(message, Rcode::FormErr.into(), |_| Ok(())),
+        };
+        if question.qclass() != Class::In {
+            return build_dns_response(message, Rcode::Refused.into(), |_| Ok(()));
+        }
+
+        // Names are case-insensitive, so that every spelling of a name gets the same addresses
+        let qname = ToDname::to_cow(question.qname())
+            .to_string()
+            .to_ascii_lowercase();
+        let qtype = question.qtype();
+
+        if qtype == Rtype::Ptr {
+            let ip = reverse_dns_addr(&qname)?;
+            let resource = self.resources.read().get_by_ip(ip).cloned();
+            return match resource {
+                Some(ResourceDescription::Dns(resource)) if !resource.is_wildcard() => {
+                    build_dns_response(message, Rcode::NoError.into(), |answer| {
+                        let Ok(name) = resource.address.parse::<Dname<Vec<u8>>>() else {
+                            return Ok(());
+                        };
+                        answer.push((
+                            question.qname(),
+                            Class::In,
+                            DNS_TTL,
+                            domain::rdata::Ptr::new(name),
+                        ))
+                    })
+                }
+                Some(ResourceDescription::Dns(_)) => {
+                    build_dns_response(message, Rcode::NXDomain.into(), |_| Ok(()))
+                }
+                None if self.address_pool.lock().contains(ip) => {
+                    build_dns_response(message, Rcode::NXDomain.into(), |_| Ok(()))
+                }
+                // These are real addresses, their names are up to the upstream resolvers
+                Some(ResourceDescription::Cidr(_)) | None => None,
+            };
+        }
+
+        let resource = match qtype {
+            // Only addresses need to be allocated for names that match a wildcard resource
+            Rtype::A | Rtype::Aaaa => self.resource_by_name(&qname),
+            _ => {
+                let resources = self.resources.read();
+                resources
+                    .get_by_name(&qname)
+                    .or_else(|| resources.get_by_wildcard(&qname))
+                    .cloned()
+            }
+        };
+        let Some(ResourceDescription::Dns(resource)) = resource else {
+            // The name is covered by a wildcard resource but we couldn't give it addresses
+            if self.resources.read().get_by_wildcard(&qname).is_some() {
+                return build_dns_response(message, Rcode::ServFail.into(), |_| Ok(()));
+            }
+            return None;
+        };
+
+        // If there's no record for the type this is a NODATA response
+        build_dns_response(message, Rcode::NoError.into(), |answer| {
+            push_resource_records(answer, question.qname(), qtype, &resource)
+        })
+    }
+
+    /// Gets the resource for the name.
+    ///
+    /// The first time a name that only matches a wildcard resource is resolved, a pair of addresses is allocated for it
+    /// and the resulting resource is inserted in the resource table, from then on it behaves like any other DNS resource.
+    fn resource_by_name(&self, name: &str) -> Option<ResourceDescription> {
+        let now = Instant::now();
+        if let Some(resource) = self.resources.read().get_by_name(name) {
+            self.address_pool.lock().resolved(name, now);
+            return Some(resource.clone());
+        }
+
+        let mut resources = self.resources.write();
+        // It might have been allocated while we weren't holding the lock
+        if let Some(resource) = resources.get_by_name(name) {
+            self.address_pool.lock().resolved(name, now);
+            return Some(resource.clone());
+        }
+
+        let wildcard = resources.get_by_wildcard(name)?.clone();
+        let Some((ipv4, ipv6)) = self
+            .address_pool
+            .lock()
+            .allocate(name, now, |ip| resources.get_by_ip(ip).is_some())
+        else {
+            tracing::warn!(%name, "wildcard_address_pool_exhausted");
+            return None;
+        };
+        let resource = wildcard.with_match(&WildcardMatch {
+            name: name.to_string(),
+            ipv4,
+            ipv6,
+        })?;
+
+        tracing::debug!(%name, %ipv4, %ipv6, "wildcard_name_resolved");
+        resources.insert_match(resource.clone()).then_some(resource)
+    }
+
+    /// Gives the addresses of the names that matched a wildcard resource back to the pool
+    /// once they stopped being resolved, unless there's still a connection to them.
+    pub(crate) fn release_expired_wildcard_matches(&self) {
+        let now = Instant::now();
+        let expired = self.address_pool.lock().expire(now);
+        if expired.is_empty() {
+            return;
+        }
+
+        let mut resources = self.resources.write();
+        for name in expired {
+            let Some(ResourceDescription::Dns(resource)) = resources.get_by_name(&name) else {
+                continue;
+            };
+            if self
+                .peers_by_ip
+                .longest_match(resource.ipv4.into())
+                .is_some()
+            {
+                self.address_pool.lock().keep(&name, now);
+                continue;
+            }
+
+            tracing::debug!(%name, "wildcard_name_expired");
+            resources.remove_match(&name);
+        }
+    }
+
+    /// Relays the query to the upstream resolvers in the background and writes the answer back to the interface.
+    ///
+    /// If no upstream answers, or too many queries are waiting for them already, the application gets a SERVFAIL
+    /// instead of having to wait for its own timeout.
+    pub(crate) fn forward_dns_query(self: &Arc<Self>, device_io: &DeviceQueues, query: DnsQuery) {
+        match self.dns_forwarder.start_query(query.id) {
+            QueryStart::Started => {}
+            QueryStart::AlreadyInFlight => {
+                tracing::trace!(src = %query.id.src, "dns_query_already_in_flight");
+                return;
+            }
+            QueryStart::Overloaded => {
+                tracing::warn!(src = %query.id.src, "too_many_dns_queries_in_flight");
+                if let Some(response) =
+                    servfail(&query.message).and_then(|r| self.build_response(&query.packet, r))
                 {
-                    return None;
+                    self.write_dns_packet(device_io, SendPacket::new(query.version, response));
                 }
-                let ip: IpAddr = match dns_parts.next() {
-                    Some(REVERSE_DNS_ADDRESS_V4) => {
-                        let mut ip = [0u8; 4];
-                        for i in ip.iter_mut() {
-                            *i = dns_parts.next()?.parse().ok()?;
-                        }
-                        ip.into()
-                    }
-                    Some(REVERSE_DNS_ADDRESS_V6) => {
-                        let mut ip = [0u8; 16];
-                        for i in ip.iter_mut() {
-                            *i = u8::from_str_radix(
-                                &format!("{}{}", dns_parts.next()?, dns_parts.next()?),
-                                16,
-                            )
-                            .ok()?;
-                        }
-                        ip.into()
-                    }
-                    _ => return None,
-                };
+                return;
+            }
+        }
 
-                if dns_parts.next().is_some() {
-                    return None;
+        let tunnel = Arc::clone(self);
+        let device_io = device_io.clone();
+        tokio::spawn(async move {
+            let response = match tunnel
+                .dns_forwarder
+                .forward(&query.message, Transport::Udp)
+                .await
+            {
+                Ok(response) => Some(response),
+                Err(e) => {
+                    tracing::debug!(error = ?e, "forward_dns_query");
+                    servfail(&query.message)
                 }
+            };
+            tunnel.dns_forwarder.finish_query(&query.id);
+
+            let Some(response) = response.and_then(|r| tunnel.build_response(&query.packet, r))
+            else {
+                return;
+            };
+            tunnel.write_dns_packet(&device_io, SendPacket::new(query.version, response));
+        });
+    }
+
+    pub(crate) fn write_dns_packet(&self, device_io: &DeviceQueues, packet: SendPacket) {
+        match packet {
+            SendPacket::Ipv4(r) => self.write4_device_infallible(device_io, &r[..]),
+            SendPacket::Ipv6(r) => self.write6_device_infallible(device_io, &r[..]),
+        }
+    }
+}
+
+/// A SERVFAIL answer to the query, telling the application to give up on it right away.
+fn servfail(query: &[u8]) -> Option<Vec<u8>> {
+    let message = Message::from_slice(query).ok()?;
+    build_dns_response(message, Rcode::ServFail.into(), |_| Ok(()))
+}
+
+/// Builds the response to the query with the answers `push_answers` adds.
+///
+/// If the query used EDNS0 so does the response.
+fn build_dns_response(
+    message: &Message<[u8]>,
+    rcode: OptRcode,
+    push_answers: impl FnOnce(&mut AnswerBuilder<Vec<u8>>) -> Result<(), PushError>,
+) -> Option<Vec<u8>> {
+    let mut answer = start_dns_response(message, rcode)?;
+    push_answers(&mut answer).ok()?;
+    finish_dns_response(answer, rcode, message.opt().is_some())
+}
+
+/// If the response doesn't fit in what the client can take over UDP the answers are left out
+/// and it's marked as truncated, so that the client retries over TCP.
+fn truncate_for_udp(message: &Message<[u8]>, response: Vec<u8>) -> Option<Vec<u8>> {
+    let max_size = message.opt().map_or(MIN_UDP_PAYLOAD_SIZE, |opt| {
+        opt.udp_payload_size()
+            .clamp(MIN_UDP_PAYLOAD_SIZE, MAX_UDP_PAYLOAD_SIZE)
+    });
+    if response.len() <= usize::from(max_size) {
+        return Some(response);
+    }
+
+    let rcode = Message::from_octets(response).ok()?.header().rcode();
+    let mut answer = start_dns_response(message, rcode.into())?;
+    answer.header_mut().set_tc(true);
+    finish_dns_response(answer, rcode.into(), message.opt().is_some())
+}
+
+fn start_dns_response(message: &Message<[u8]>, rcode: OptRcode) -> Option<AnswerBuilder<Vec<u8>>> {
+    let msg_buf = Vec::with_capacity(message.as_slice().len() * 2);
+    let msg_builder = MessageBuilder::from_target(msg_buf).expect(
+        "Developer error: we should be always be able to create a MessageBuilder from a Vec",
+    );
+    msg_builder.start_answer(message, rcode.rcode()).ok()
+}
+
+fn finish_dns_response(
+    answer: AnswerBuilder<Vec<u8>>,
+    rcode: OptRcode,
+    edns: bool,
+) -> Option<Vec<u8>> {
+    if !edns {
+        return Some(answer.finish());
+    }
+
+    let mut additional = answer.additional();
+    let opt_start = additional.as_builder().as_slice().len();
+    additional
+        .opt(|opt| {
+            opt.set_udp_payload_size(MAX_UDP_PAYLOAD_SIZE);
+            Ok(())
+        })
+        .ok()?;
+    let mut response = additional.finish();
+    // domain's `OptRcode::ext` is always 0 so the upper bits of the rcode are set by hand,
+    // they're the first byte of the OPT record's TTL, after its empty name, type and class.
+    response[opt_start + 5] = (rcode.to_int() >> 4) as u8;
+    Some(response)
+}
+
+fn push_resource_records<N>(
+    answer: &mut AnswerBuilder<Vec<u8>>,
+    qname: &N,
+    qtype: Rtype,
+    resource: &ResourceDescriptionDns,
+) -> Result<(), PushError>
+where
+    N: ToDname + ?Sized,
+{
+    match qtype {
+        Rtype::A => answer.push((
+            qname,
+            Class::In,
+            DNS_TTL,
+            domain::rdata::A::from(resource.ipv4),
+        ))?,
+        Rtype::Aaaa => answer.push((
+            qname,
+            Class::In,
+            DNS_TTL,
+            domain::rdata::Aaaa::from(resource.ipv6),
+        ))?,
+        _ => {}
+    }
+
+    for record in &resource.records {
+        match (record, qtype) {
+            (DnsRecord::Cname { target }, Rtype::Cname) => {
+                let Ok(target) = target.parse::<Dname<Vec<u8>>>() else {
+                    tracing::warn!(%target, "invalid_cname_target");
+                    continue;
+                };
+                answer.push((qname, Class::In, DNS_TTL, domain::rdata::Cname::new(target)))?;
+            }
+            (
+                DnsRecord::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                },
+                Rtype::Srv,
+            ) => {
+                let Ok(target) = target.parse::<Dname<Vec<u8>>>() else {
+                    tracing::warn!(%target, "invalid_srv_target");
+                    continue;
+                };
+                answer.push((
+                    qname,
+                    Class::In,
+                    DNS_TTL,
+                    domain::rdata::Srv::new(*priority, *weight, *port, target),
+                ))?;
+            }
+            (DnsRecord::Txt { value }, Rtype::Txt) => {
+                let Ok(txt) = domain::rdata::Txt::<Vec<u8>>::build_from_slice(value.as_bytes())
+                else {
+                    tracing::warn!(%value, "invalid_txt_value");
+                    continue;
+                };
+                answer.push((qname, Class::In, DNS_TTL, txt))?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
 
-                self.resources.read().get_by_ip(ip).cloned()
+fn reverse_dns_addr(name: &str) -> Option<IpAddr> {
+    let mut dns_parts = name.split('.').rev();
+    if !dns_parts
+        .next()
+        .is_some_and(|d| d == REVERSE_DNS_ADDRESS_END)
+    {
+        return None;
+    }
+    let ip: IpAddr = match dns_parts.next() {
+        Some(REVERSE_DNS_ADDRESS_V4) => {
+            let mut ip = [0u8; 4];
+            for i in ip.iter_mut() {
+                *i = dns_parts.next()?.parse().ok()?;
             }
-            _ => return None,
+            ip.into()
+        }
+        Some(REVERSE_DNS_ADDRESS_V6) => {
+            let mut ip = [0u8; 16];
+            for i in ip.iter_mut() {
+                *i = u8::from_str_radix(&format!("{}{}", dns_parts.next()?, dns_parts.next()?), 16)
+                    .ok()?;
+            }
+            ip.into()
+        }
+        _ => return None,
+    };
+
+    if dns_parts.next().is_some() {
+        return None;
+    }
+
+    Some(ip)
+}
+
+#[cfg(test)]
+mod test {
+    use domain::base::{
+        iana::{Class, OptRcode, Rcode, Rtype},
+        Dname, Message, MessageBuilder,
+    };
+
+    use super::{build_dns_response, truncate_for_udp, DNS_TTL, MAX_UDP_PAYLOAD_SIZE};
+
+    fn query(udp_payload_size: Option<u16>) -> Vec<u8> {
+        let mut question = MessageBuilder::new_vec().question();
+        let name: Dname<Vec<u8>> = "foo.example.com".parse().unwrap();
+        question.push((name, Rtype::Txt)).unwrap();
+        let Some(size) = udp_payload_size else {
+            return question.finish();
         };
-        let response =
-            self.build_dns_with_answer(message, question.qname(), question.qtype(), &resource?)?;
-        let response = self.build_response(buf, response);
-        response.map(|pkt| match version {
-            Version::Ipv4 => SendPacket::Ipv4(pkt),
-            Version::Ipv6 => SendPacket::Ipv6(pkt),
+
+        let mut additional = question.additional();
+        additional
+            .opt(|opt| {
+                opt.set_udp_payload_size(size);
+                Ok(())
+            })
+            .unwrap();
+        additional.finish()
+    }
+
+    // Each answer is a bit over 100 bytes
+    fn response(query: &[u8], answers: usize) -> Vec<u8> {
+        let message = Message::from_slice(query).unwrap();
+        let question = message.first_question().unwrap();
+        build_dns_response(message, Rcode::NoError.into(), |answer| {
+            for _ in 0..answers {
+                let txt = domain::rdata::Txt::<Vec<u8>>::build_from_slice(&[b'a'; 100]).unwrap();
+                answer.push((question.qname(), Class::In, DNS_TTL, txt))?;
+            }
+            Ok(())
         })
+        .unwrap()
+    }
+
+    fn is_truncated(response: &[u8]) -> bool {
+        let response = Message::from_slice(response).unwrap();
+        response.header().tc() && response.header_counts().ancount() == 0
+    }
+
+    #[test]
+    fn negative_answer_keeps_its_rcode() {
+        let query = query(None);
+        let message = Message::from_slice(&query).unwrap();
+
+        let response = build_dns_response(message, Rcode::NXDomain.into(), |_| Ok(())).unwrap();
+        let response = Message::from_octets(response).unwrap();
+
+        assert_eq!(response.header().rcode(), Rcode::NXDomain);
+        assert_eq!(response.header().id(), message.header().id());
+        assert_eq!(response.header_counts().ancount(), 0);
+        assert!(response.opt().is_none());
+    }
+
+    #[test]
+    fn edns_query_gets_an_edns_response() {
+        let query = query(Some(4096));
+        let message = Message::from_slice(&query).unwrap();
+
+        let response = build_dns_response(message, Rcode::NoError.into(), |_| Ok(())).unwrap();
+        let response = Message::from_octets(response).unwrap();
+        let opt = response.opt().unwrap();
+
+        assert_eq!(opt.udp_payload_size(), MAX_UDP_PAYLOAD_SIZE);
+        assert_eq!(
+            opt.rcode(response.header()).to_int(),
+            OptRcode::NoError.to_int()
+        );
+    }
+
+    #[test]
+    fn bad_version_is_an_extended_rcode() {
+        let query = query(Some(1232));
+        let message = Message::from_slice(&query).unwrap();
+
+        let response = build_dns_response(message, OptRcode::BadVers, |_| Ok(())).unwrap();
+        let response = Message::from_octets(response).unwrap();
+
+        assert_eq!(
+            response.opt().unwrap().rcode(response.header()).to_int(),
+            OptRcode::BadVers.to_int()
+        );
+    }
+
+    #[test]
+    fn response_is_truncated_past_512_bytes_without_edns() {
+        let query = query(None);
+        let response = response(&query, 8);
+        let message = Message::from_slice(&query).unwrap();
+
+        assert!(response.len() > 512);
+        assert!(is_truncated(&truncate_for_udp(message, response).unwrap()));
+    }
+
+    #[test]
+    fn response_fits_in_the_advertised_edns_size() {
+        let query = query(Some(4096));
+        let response = response(&query, 8);
+        let message = Message::from_slice(&query).unwrap();
+
+        let sent = truncate_for_udp(message, response.clone()).unwrap();
+
+        assert_eq!(sent, response);
+    }
+
+    #[test]
+    fn advertised_edns_size_is_capped() {
+        let query = query(Some(4096));
+        let response = response(&query, 12);
+        let message = Message::from_slice(&query).unwrap();
+
+        assert!(response.len() > usize::from(MAX_UDP_PAYLOAD_SIZE));
+        assert!(is_truncated(&truncate_for_udp(message, response).unwrap()));
     }
 }
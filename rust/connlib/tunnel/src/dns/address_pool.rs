@@ -0,0 +1,204 @@
+//! Addresses the client hands out to the names that match wildcard DNS resources.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use connlib_shared::DnsAddresses;
+use ip_network::IpNetwork;
+
+use super::DNS_TTL;
+
+// 198.18.0.0/15 is reserved for benchmarking so it's never going to be routed anywhere else,
+// it's split between the sets of DNS addresses.
+const IPV4_POOLS_START: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 0);
+const IPV4_POOL_PREFIX: u8 = 18;
+const IPV6_POOLS_START: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x8000, 0, 0, 0, 0);
+const IPV6_POOL_PREFIX: u8 = 114;
+// Both pools have the same size so the nth ipv4 and the nth ipv6 are handed out together.
+const POOL_SIZE: u32 = 1 << 14;
+// Twice the TTL of our answers, so that an application that caches an answer for as long as it's allowed can still use it.
+const ADDRESS_TTL: Duration = Duration::from_secs(2 * DNS_TTL as u64);
+
+/// Sequentially hands out pairs of ipv4/ipv6 addresses, wrapping around once it runs out.
+///
+/// The addresses of a name are given back once it hasn't been resolved for a while, see [AddressPool::expire].
+#[derive(Debug)]
+pub(crate) struct AddressPool {
+    ipv4_start: Ipv4Addr,
+    ipv6_start: Ipv6Addr,
+    next: u32,
+    // When each name that got addresses was last resolved
+    last_resolved: HashMap<String, Instant>,
+}
+
+impl AddressPool {
+    /// The pool of wildcard addresses of `addresses`, the pools of different sets don't overlap.
+    pub(crate) fn new(addresses: DnsAddresses) -> Self {
+        let offset = POOL_SIZE * u32::from(addresses.index());
+        Self {
+            ipv4_start: Ipv4Addr::from(u32::from(IPV4_POOLS_START) + offset),
+            ipv6_start: Ipv6Addr::from(u128::from(IPV6_POOLS_START) + u128::from(offset)),
+            next: 0,
+            last_resolved: HashMap::new(),
+        }
+    }
+
+    /// The routes that need to go through the tunnel so that the addresses in the pool are reachable.
+    pub(crate) fn routes(&self) -> [IpNetwork; 2] {
+        [
+            IpNetwork::new(IpAddr::from(self.ipv4_start), IPV4_POOL_PREFIX)
+                .expect("Developer error: the ipv4 pool should be a valid network"),
+            IpNetwork::new(IpAddr::from(self.ipv6_start), IPV6_POOL_PREFIX)
+                .expect("Developer error: the ipv6 pool should be a valid network"),
+        ]
+    }
+
+    /// Whether the address belongs to the pool, whether it's currently handed out or not.
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        self.routes().iter().any(|network| network.contains(ip))
+    }
+
+    /// Gets the next pair of addresses for which `is_taken` returns false for `name`.
+    ///
+    /// Returns `None` if every address in the pool is taken.
+    pub(crate) fn allocate(
+        &mut self,
+        name: &str,
+        now: Instant,
+        is_taken: impl Fn(IpAddr) -> bool,
+    ) -> Option<(Ipv4Addr, Ipv6Addr)> {
+        for _ in 0..POOL_SIZE {
+            let offset = self.next;
+            self.next = (self.next + 1) % POOL_SIZE;
+
+            // The network and broadcast addresses of the ipv4 pool
+            if offset == 0 || offset == POOL_SIZE - 1 {
+                continue;
+            }
+
+            let ipv4 = Ipv4Addr::from(u32::from(self.ipv4_start) + offset);
+            let ipv6 = Ipv6Addr::from(u128::from(self.ipv6_start) + u128::from(offset));
+            if !is_taken(ipv4.into()) && !is_taken(ipv6.into()) {
+                self.last_resolved.insert(name.to_owned(), now);
+                return Some((ipv4, ipv6));
+            }
+        }
+
+        None
+    }
+
+    /// Keeps the addresses of the name for another [ADDRESS_TTL], does nothing if it has none.
+    pub(crate) fn resolved(&mut self, name: &str, now: Instant) {
+        if let Some(last_resolved) = self.last_resolved.get_mut(name) {
+            *last_resolved = now;
+        }
+    }
+
+    /// Keeps the addresses of a name that expired for another [ADDRESS_TTL].
+    pub(crate) fn keep(&mut self, name: &str, now: Instant) {
+        self.last_resolved.insert(name.to_owned(), now);
+    }
+
+    /// Forgets the names that weren't resolved for [ADDRESS_TTL] and returns them.
+    ///
+    /// Their addresses can be handed out again as soon as they're removed from the resource table.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<_> = self
+            .last_resolved
+            .iter()
+            .filter(|(_, &last_resolved)| now.duration_since(last_resolved) >= ADDRESS_TTL)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            self.last_resolved.remove(name);
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Instant};
+
+    use connlib_shared::{DnsAddresses, MAX_DNS_SESSIONS};
+    use ip_network::IpNetwork;
+
+    use super::{AddressPool, ADDRESS_TTL};
+
+    #[test]
+    fn allocate_skips_taken_addresses() {
+        let mut pool = AddressPool::new(DnsAddresses::default());
+        let taken: IpAddr = "198.18.0.2".parse().unwrap();
+
+        let now = Instant::now();
+
+        let first = pool
+            .allocate("a.example.com", now, |ip| ip == taken)
+            .unwrap();
+        let second = pool
+            .allocate("b.example.com", now, |ip| ip == taken)
+            .unwrap();
+
+        assert_eq!(
+            first,
+            (
+                "198.18.0.1".parse().unwrap(),
+                "fd00:2021:1111:8000::1".parse().unwrap()
+            )
+        );
+        assert_eq!(
+            second,
+            (
+                "198.18.0.3".parse().unwrap(),
+                "fd00:2021:1111:8000::3".parse().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn allocate_fails_once_exhausted() {
+        let mut pool = AddressPool::new(DnsAddresses::default());
+
+        assert_eq!(
+            pool.allocate("a.example.com", Instant::now(), |_| true),
+            None
+        );
+    }
+
+    #[test]
+    fn names_expire_once_they_stop_being_resolved() {
+        let mut pool = AddressPool::new(DnsAddresses::default());
+        let now = Instant::now();
+        pool.allocate("a.example.com", now, |_| false).unwrap();
+        pool.allocate("b.example.com", now, |_| false).unwrap();
+
+        pool.resolved("b.example.com", now + ADDRESS_TTL / 2);
+
+        assert!(pool.expire(now + ADDRESS_TTL / 2).is_empty());
+        assert_eq!(pool.expire(now + ADDRESS_TTL), ["a.example.com"]);
+        assert_eq!(pool.expire(now + ADDRESS_TTL * 3 / 2), ["b.example.com"]);
+        assert!(pool.expire(now + ADDRESS_TTL * 2).is_empty());
+    }
+
+    #[test]
+    fn pools_of_different_sets_dont_overlap() {
+        let pools: Vec<_> = DnsAddresses::all().map(AddressPool::new).collect();
+
+        for (i, pool) in pools.iter().enumerate() {
+            for other in &pools[i + 1..] {
+                for route in other.routes() {
+                    assert!(!pool.contains(route.network_address()));
+                }
+            }
+        }
+        // They all fit in the range reserved for benchmarking
+        let last = AddressPool::new(DnsAddresses::nth(MAX_DNS_SESSIONS - 1).unwrap());
+        assert!("198.18.0.0/15"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains(last.routes()[0].network_address()));
+    }
+}
@@ -0,0 +1,351 @@
+//! Addresses the names of DNS resources resolve to, used by the gateway to translate packets.
+//!
+//! Names are resolved in the background, the data path only ever reads from the cache.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use connlib_shared::Result;
+use parking_lot::Mutex;
+
+const MIN_TTL: Duration = Duration::from_secs(5);
+const MAX_TTL: Duration = Duration::from_secs(3600);
+// How long until we retry after a resolution failed.
+const NEGATIVE_TTL: Duration = Duration::from_secs(5);
+// A flow is considered active if it sent a packet this recently.
+const ACTIVE_FLOW_TIMEOUT: Duration = Duration::from_secs(30);
+// Active flows keep using an address that the name no longer resolves to for at most this long.
+const MAX_STALE: Duration = Duration::from_secs(300);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The result of looking up a name in the cache.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Lookup {
+    /// Where packets to the name should be sent, if we know it yet.
+    pub addr: Option<IpAddr>,
+    /// The name needs to be resolved, the result has to be given back with [`ResolverCache::update`].
+    pub resolve: bool,
+}
+
+/// An address that packets are being sent to.
+struct Selected {
+    addr: IpAddr,
+    last_used: Instant,
+    /// When the name stopped resolving to this address.
+    stale_since: Option<Instant>,
+}
+
+struct Entry {
+    addresses: Vec<IpAddr>,
+    expires_at: Instant,
+    resolving: bool,
+    last_used: Instant,
+    ipv4: Option<Selected>,
+    ipv6: Option<Selected>,
+}
+
+#[derive(Default)]
+pub(crate) struct ResolverCache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl ResolverCache {
+    /// Gets the address of `name` with the same ip version as `version_of`.
+    ///
+    /// Once an address is picked it's kept while the name resolves to it or while packets keep going to it,
+    /// so that refreshing the name doesn't break established connections.
+    pub(crate) fn get(&self, name: &str, version_of: IpAddr) -> Lookup {
+        self.get_at(name, version_of, Instant::now())
+    }
+
+    /// Tells you if `name` has to be resolved, so that it's known before the first packet for it.
+    ///
+    /// Like with [`ResolverCache::get`], the result has to be given back with [`ResolverCache::update`].
+    pub(crate) fn prefetch(&self, name: &str) -> bool {
+        self.prefetch_at(name, Instant::now())
+    }
+
+    /// Runs `f` if `name` is being resolved, before the result can be stored.
+    ///
+    /// Returns whether it did, this lets the caller wait for the result without missing it.
+    pub(crate) fn if_resolving(&self, name: &str, f: impl FnOnce()) -> bool {
+        let entries = self.entries.lock();
+        let resolving = entries.get(name).is_some_and(|e| e.resolving);
+        if resolving {
+            f();
+        }
+        resolving
+    }
+
+    /// Stores the result of resolving `name`, the addresses and the lowest TTL of their records.
+    pub(crate) fn update(&self, name: &str, result: &Result<(Vec<IpAddr>, Duration)>) {
+        self.update_at(name, result, Instant::now())
+    }
+
+    fn prefetch_at(&self, name: &str, now: Instant) -> bool {
+        let mut entries = self.entries.lock();
+        start_resolving(entry_at(&mut entries, name, now), now)
+    }
+
+    fn get_at(&self, name: &str, version_of: IpAddr, now: Instant) -> Lookup {
+        let mut entries = self.entries.lock();
+        let entry = entry_at(&mut entries, name, now);
+        let resolve = start_resolving(entry, now);
+
+        let selected = match version_of {
+            IpAddr::V4(_) => &mut entry.ipv4,
+            IpAddr::V6(_) => &mut entry.ipv6,
+        };
+        if let Some(current) = selected.as_mut().filter(|s| {
+            s.stale_since
+                .map_or(true, |t| now.duration_since(t) < MAX_STALE)
+        }) {
+            current.last_used = now;
+            return Lookup {
+                addr: Some(current.addr),
+                resolve,
+            };
+        }
+
+        *selected = entry
+            .addresses
+            .iter()
+            .find(|a| a.is_ipv4() == version_of.is_ipv4())
+            .map(|&addr| Selected {
+                addr,
+                last_used: now,
+                stale_since: None,
+            });
+        Lookup {
+            addr: selected.as_ref().map(|s| s.addr),
+            resolve,
+        }
+    }
+
+    fn update_at(&self, name: &str, result: &Result<(Vec<IpAddr>, Duration)>, now: Instant) {
+        let mut entries = self.entries.lock();
+        entries.retain(|_, e| e.resolving || now.duration_since(e.last_used) < IDLE_TIMEOUT);
+        let Some(entry) = entries.get_mut(name) else {
+            return;
+        };
+        entry.resolving = false;
+
+        // On failure we keep what we had, it's better than nothing
+        let Ok((addresses, ttl)) = result else {
+            entry.expires_at = now + NEGATIVE_TTL;
+            return;
+        };
+
+        entry.addresses = addresses.clone();
+        entry.expires_at = now + (*ttl).clamp(MIN_TTL, MAX_TTL);
+        for selected in [&mut entry.ipv4, &mut entry.ipv6] {
+            let Some(current) = selected else {
+                continue;
+            };
+            if addresses.contains(&current.addr) {
+                current.stale_since = None;
+            } else if now.duration_since(current.last_used) < ACTIVE_FLOW_TIMEOUT {
+                current.stale_since.get_or_insert(now);
+            } else {
+                *selected = None;
+            }
+        }
+    }
+}
+
+/// Gets the entry of `name`, marking it as used.
+fn entry_at<'a>(
+    entries: &'a mut HashMap<String, Entry>,
+    name: &str,
+    now: Instant,
+) -> &'a mut Entry {
+    let entry = entries.entry(name.to_string()).or_insert_with(|| Entry {
+        addresses: Vec::new(),
+        expires_at: now,
+        resolving: false,
+        last_used: now,
+        ipv4: None,
+        ipv6: None,
+    });
+    entry.last_used = now;
+    entry
+}
+
+/// Tells you if the entry has to be resolved, and if so marks it as being resolved.
+fn start_resolving(entry: &mut Entry, now: Instant) -> bool {
+    // Expired addresses keep being used until the refreshed ones arrive
+    let resolve = !entry.resolving && entry.expires_at <= now;
+    entry.resolving |= resolve;
+    resolve
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use connlib_shared::Error;
+
+    use super::{Lookup, ResolverCache};
+
+    const NAME: &str = "example.com";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn resolves_once_until_expired() {
+        let cache = ResolverCache::default();
+        let now = Instant::now();
+        let client = ip("100.64.0.1");
+
+        assert_eq!(
+            cache.get_at(NAME, client, now),
+            Lookup {
+                addr: None,
+                resolve: true
+            }
+        );
+        assert_eq!(
+            cache.get_at(NAME, client, now),
+            Lookup {
+                addr: None,
+                resolve: false
+            }
+        );
+
+        cache.update_at(
+            NAME,
+            &Ok((
+                vec![ip("2001:db8::1"), ip("10.0.0.1")],
+                Duration::from_secs(60),
+            )),
+            now,
+        );
+        assert_eq!(
+            cache.get_at(NAME, client, now + Duration::from_secs(30)),
+            Lookup {
+                addr: Some(ip("10.0.0.1")),
+                resolve: false
+            }
+        );
+        assert_eq!(
+            cache.get_at(NAME, client, now + Duration::from_secs(60)),
+            Lookup {
+                addr: Some(ip("10.0.0.1")),
+                resolve: true
+            }
+        );
+    }
+
+    #[test]
+    fn active_flow_keeps_its_address() {
+        let cache = ResolverCache::default();
+        let now = Instant::now();
+        let client = ip("100.64.0.1");
+
+        cache.get_at(NAME, client, now);
+        cache.update_at(
+            NAME,
+            &Ok((vec![ip("10.0.0.1")], Duration::from_secs(5))),
+            now,
+        );
+        cache.get_at(NAME, client, now + Duration::from_secs(5));
+        cache.update_at(
+            NAME,
+            &Ok((vec![ip("10.0.0.2")], Duration::from_secs(5))),
+            now + Duration::from_secs(5),
+        );
+
+        assert_eq!(
+            cache
+                .get_at(NAME, client, now + Duration::from_secs(6))
+                .addr,
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(
+            cache
+                .get_at(NAME, client, now + Duration::from_secs(400))
+                .addr,
+            Some(ip("10.0.0.2"))
+        );
+    }
+
+    #[test]
+    fn failure_keeps_previous_addresses() {
+        let cache = ResolverCache::default();
+        let now = Instant::now();
+        let client = ip("100.64.0.1");
+
+        cache.get_at(NAME, client, now);
+        cache.update_at(
+            NAME,
+            &Ok((vec![ip("10.0.0.1")], Duration::from_secs(5))),
+            now,
+        );
+        cache.get_at(NAME, client, now + Duration::from_secs(5));
+        cache.update_at(
+            NAME,
+            &Err(Error::DnsUpstreamUnreachable),
+            now + Duration::from_secs(5),
+        );
+
+        assert_eq!(
+            cache.get_at(NAME, client, now + Duration::from_secs(6)),
+            Lookup {
+                addr: Some(ip("10.0.0.1")),
+                resolve: false
+            }
+        );
+    }
+
+    #[test]
+    fn prefetched_names_are_resolved_once() {
+        let cache = ResolverCache::default();
+        let now = Instant::now();
+        let client = ip("100.64.0.1");
+
+        assert!(cache.prefetch_at(NAME, now));
+        assert!(!cache.prefetch_at(NAME, now));
+        assert_eq!(
+            cache.get_at(NAME, client, now),
+            Lookup {
+                addr: None,
+                resolve: false
+            }
+        );
+
+        cache.update_at(
+            NAME,
+            &Ok((vec![ip("10.0.0.1")], Duration::from_secs(60))),
+            now,
+        );
+        assert!(!cache.prefetch_at(NAME, now + Duration::from_secs(30)));
+        assert_eq!(
+            cache
+                .get_at(NAME, client, now + Duration::from_secs(30))
+                .addr,
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn runs_only_while_resolving() {
+        let cache = ResolverCache::default();
+        let now = Instant::now();
+        let mut runs = 0;
+
+        assert!(!cache.if_resolving(NAME, || runs += 1));
+        cache.prefetch_at(NAME, now);
+        assert!(cache.if_resolving(NAME, || runs += 1));
+        cache.update_at(NAME, &Err(Error::DnsUpstreamUnreachable), now);
+        assert!(!cache.if_resolving(NAME, || runs += 1));
+
+        assert_eq!(runs, 1);
+    }
+}
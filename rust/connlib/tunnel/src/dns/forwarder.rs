@@ -0,0 +1,412 @@
+//! Forwarding of the DNS queries that don't match any resource to the upstream resolvers.
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use connlib_shared::{DnsAddresses, Error, Result};
+use domain::{
+    base::{
+        iana::{Rcode, Rtype},
+        Dname, Message, MessageBuilder,
+    },
+    rdata::{Aaaa, A},
+};
+use parking_lot::{Mutex, RwLock};
+use rand_core::{OsRng, RngCore};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use super::Resolver;
+use crate::MAX_UDP_SIZE;
+
+const DNS_PORT: u16 = 53;
+const DNS_HEADER_SIZE: usize = 12;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_IN_FLIGHT_QUERIES: usize = 512;
+// The system resolver doesn't tell us the TTL of the records it found
+const SYSTEM_RESOLVER_TTL: Duration = Duration::from_secs(60);
+// Nothing to refresh, the cache keeps them for as long as it keeps anything
+const IP_LITERAL_TTL: Duration = Duration::MAX;
+#[cfg(unix)]
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+/// Identifies a query by who sent it.
+///
+/// Used so that retransmissions of a query that's still being resolved aren't forwarded again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct QueryId {
+    pub src: SocketAddr,
+    pub transaction_id: u16,
+}
+
+/// How the query reached us, it's forwarded the same way.
+///
+/// Queries that came over TCP usually did because the answer didn't fit in a datagram,
+/// so forwarding them over UDP would get them truncated again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// What became of a query we were asked to forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QueryStart {
+    Started,
+    /// A retransmission, the answer to the first one answers it too.
+    AlreadyInFlight,
+    /// Too many queries are waiting for the upstreams already.
+    Overloaded,
+}
+
+#[derive(Default)]
+pub(crate) struct DnsForwarder {
+    upstreams: RwLock<Vec<SocketAddr>>,
+    // Whether the upstreams were given to us instead of read from the system's configuration
+    configured_upstreams: AtomicBool,
+    // Index of the last upstream that answered, queries start from it.
+    preferred_upstream: AtomicUsize,
+    in_flight: Mutex<HashSet<QueryId>>,
+}
+
+impl DnsForwarder {
+    /// Sets the resolvers queries are forwarded to.
+    ///
+    /// If `upstream_dns` is empty the system resolvers are used instead.
+    pub(crate) fn set_upstreams(&self, upstream_dns: &[IpAddr]) {
+        let upstreams = if upstream_dns.is_empty() {
+            system_resolvers()
+        } else {
+            upstream_dns
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, DNS_PORT))
+                .collect()
+        };
+
+        tracing::debug!(?upstreams, "dns_upstreams");
+        *self.upstreams.write() = upstreams;
+        self.configured_upstreams
+            .store(!upstream_dns.is_empty(), Relaxed);
+        self.preferred_upstream.store(0, Relaxed);
+    }
+
+    pub(crate) fn has_upstreams(&self) -> bool {
+        !self.upstreams.read().is_empty()
+    }
+
+    /// Marks the query as in flight, unless it already was or there are too many queries in flight.
+    pub(crate) fn start_query(&self, id: QueryId) -> QueryStart {
+        let mut in_flight = self.in_flight.lock();
+        if in_flight.contains(&id) {
+            return QueryStart::AlreadyInFlight;
+        }
+        if in_flight.len() >= MAX_IN_FLIGHT_QUERIES {
+            return QueryStart::Overloaded;
+        }
+
+        in_flight.insert(id);
+        QueryStart::Started
+    }
+
+    pub(crate) fn finish_query(&self, id: &QueryId) {
+        self.in_flight.lock().remove(id);
+    }
+
+    /// Sends the query to each upstream, one after the other, until one of them answers.
+    pub(crate) async fn forward(&self, query: &[u8], transport: Transport) -> Result<Vec<u8>> {
+        let upstreams = self.upstreams.read().clone();
+        let preferred = self.preferred_upstream.load(Relaxed);
+
+        for i in 0..upstreams.len() {
+            let index = (preferred + i) % upstreams.len();
+            let upstream = upstreams[index];
+            let response = match transport {
+                Transport::Udp => {
+                    tokio::time::timeout(UPSTREAM_TIMEOUT, query_upstream(upstream, query)).await
+                }
+                Transport::Tcp => {
+                    tokio::time::timeout(UPSTREAM_TIMEOUT, query_upstream_tcp(upstream, query))
+                        .await
+                }
+            };
+            match response {
+                Ok(Ok(response)) => {
+                    self.preferred_upstream.store(index, Relaxed);
+                    return Ok(response);
+                }
+                Ok(Err(e)) => tracing::debug!(%upstream, error = ?e, "dns_upstream_failed"),
+                Err(_) => tracing::debug!(%upstream, "dns_upstream_timeout"),
+            }
+        }
+
+        Err(Error::DnsUpstreamUnreachable)
+    }
+
+    /// Resolves the name to its addresses.
+    ///
+    /// Unless upstream resolvers were configured this goes through the system resolver, like any other
+    /// application on the host would, so that the hosts file and the search domains apply too.
+    /// Returns the addresses together with the lowest TTL of their records.
+    pub(crate) async fn resolve(&self, name: &str) -> Result<(Vec<IpAddr>, Duration)> {
+        if let Ok(ip) = name.parse::<IpAddr>() {
+            return Ok((vec![ip], IP_LITERAL_TTL));
+        }
+        if !self.configured_upstreams.load(Relaxed) {
+            return resolve_with_system(name).await;
+        }
+
+        let name = name
+            .parse::<Dname<Vec<u8>>>()
+            .map_err(|_| Error::InvalidResource)?;
+        let (ipv4, ipv6) = futures::future::join(
+            self.resolve_rtype(&name, Rtype::A),
+            self.resolve_rtype(&name, Rtype::Aaaa),
+        )
+        .await;
+
+        let (addresses, ttl): (Vec<IpAddr>, Vec<u32>) = match (ipv4, ipv6) {
+            (Err(e), Err(_)) => return Err(e),
+            (Ok(records), Err(_)) | (Err(_), Ok(records)) => records.into_iter().unzip(),
+            (Ok(ipv4), Ok(ipv6)) => ipv4.into_iter().chain(ipv6).unzip(),
+        };
+        let Some(ttl) = ttl.into_iter().min() else {
+            return Err(Error::ResourceNameUnresolved);
+        };
+
+        Ok((addresses, Duration::from_secs(ttl.into())))
+    }
+
+    async fn resolve_rtype(
+        &self,
+        name: &Dname<Vec<u8>>,
+        rtype: Rtype,
+    ) -> Result<Vec<(IpAddr, u32)>> {
+        let mut builder = MessageBuilder::from_target(Vec::new()).expect(
+            "Developer error: we should be always be able to create a MessageBuilder from a Vec",
+        );
+        builder.header_mut().set_rd(true);
+        let mut question = builder.question();
+        question
+            .push((name, rtype))
+            .map_err(|_| Error::InvalidResource)?;
+        let response = self.forward(&question.finish(), Transport::Udp).await?;
+
+        let response = Message::from_octets(response).map_err(|_| Error::BadPacket)?;
+        if response.header().rcode() != Rcode::NoError {
+            return Ok(Vec::new());
+        }
+        let answer = response.answer().map_err(|_| Error::BadPacket)?;
+        // The records for the aliases in the chain are there too, with the addresses at the end of it
+        let records = match rtype {
+            Rtype::A => answer
+                .limit_to::<A>()
+                .filter_map(|r| r.ok())
+                .map(|r| (IpAddr::from(r.data().addr()), r.ttl().as_secs()))
+                .collect(),
+            _ => answer
+                .limit_to::<Aaaa>()
+                .filter_map(|r| r.ok())
+                .map(|r| (IpAddr::from(r.data().addr()), r.ttl().as_secs()))
+                .collect(),
+        };
+
+        Ok(records)
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsForwarder {
+    async fn resolve(&self, name: &str) -> Result<(Vec<IpAddr>, Duration)> {
+        DnsForwarder::resolve(self, name).await
+    }
+}
+
+async fn resolve_with_system(name: &str) -> Result<(Vec<IpAddr>, Duration)> {
+    let addresses: Vec<_> = tokio::net::lookup_host((name, 0))
+        .await
+        .map_err(|_| Error::ResourceNameUnresolved)?
+        .map(|addr| addr.ip())
+        .collect();
+    if addresses.is_empty() {
+        return Err(Error::ResourceNameUnresolved);
+    }
+
+    Ok((addresses, SYSTEM_RESOLVER_TTL))
+}
+
+async fn query_upstream(upstream: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    if query.len() < DNS_HEADER_SIZE {
+        return Err(Error::BadPacket);
+    }
+
+    let bind_addr: SocketAddr = match upstream {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    // A socket per query means the source port is random too, and since it's connected
+    // we only ever receive datagrams from the upstream.
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(upstream).await?;
+
+    // We don't reuse the application's transaction id so that the upstream's answers can't be guessed from it.
+    let transaction_id = (OsRng.next_u32() as u16).to_be_bytes();
+    let mut query = query.to_vec();
+    let original_id = [query[0], query[1]];
+    query[..2].copy_from_slice(&transaction_id);
+    socket.send(&query).await?;
+
+    let mut response = vec![0u8; MAX_UDP_SIZE];
+    loop {
+        let len = socket.recv(&mut response).await?;
+        if len < DNS_HEADER_SIZE || response[..2] != transaction_id {
+            tracing::debug!(%upstream, "unexpected_dns_response");
+            continue;
+        }
+
+        response.truncate(len);
+        response[..2].copy_from_slice(&original_id);
+        return Ok(response);
+    }
+}
+
+async fn query_upstream_tcp(upstream: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    if query.len() < DNS_HEADER_SIZE || query.len() > usize::from(u16::MAX) {
+        return Err(Error::BadPacket);
+    }
+
+    // Each connection carries a single query so there's no need to rewrite the transaction id.
+    let mut stream = TcpStream::connect(upstream).await?;
+    let mut request = Vec::with_capacity(2 + query.len());
+    request.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    request.extend_from_slice(query);
+    stream.write_all(&request).await?;
+
+    let len = stream.read_u16().await?;
+    let mut response = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut response).await?;
+    if response.len() < DNS_HEADER_SIZE || response[..2] != query[..2] {
+        tracing::debug!(%upstream, "unexpected_dns_response");
+        return Err(Error::BadPacket);
+    }
+
+    Ok(response)
+}
+
+#[cfg(unix)]
+fn system_resolvers() -> Vec<SocketAddr> {
+    match std::fs::read_to_string(RESOLV_CONF_PATH) {
+        Ok(resolv_conf) => parse_resolv_conf(&resolv_conf),
+        Err(e) => {
+            tracing::warn!(error = ?e, "read_resolv_conf");
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn system_resolvers() -> Vec<SocketAddr> {
+    Vec::new()
+}
+
+// The sentinels of every session are skipped, otherwise we would be forwarding queries to ourselves or in circles.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn parse_resolv_conf(resolv_conf: &str) -> Vec<SocketAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            if parts.next()? != "nameserver" {
+                return None;
+            }
+            parts.next()?.parse::<IpAddr>().ok()
+        })
+        .filter(|&ip| !DnsAddresses::all().any(|addresses| addresses.is_sentinel(ip)))
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use super::{parse_resolv_conf, DnsForwarder, QueryId, QueryStart, MAX_IN_FLIGHT_QUERIES};
+
+    fn query_id(port: u16) -> QueryId {
+        QueryId {
+            src: SocketAddr::from((Ipv4Addr::new(100, 64, 0, 1), port)),
+            transaction_id: 1,
+        }
+    }
+
+    #[test]
+    fn queries_past_the_limit_are_told_apart_from_retransmissions() {
+        let forwarder = DnsForwarder::default();
+        for port in 0..MAX_IN_FLIGHT_QUERIES as u16 {
+            assert_eq!(forwarder.start_query(query_id(port)), QueryStart::Started);
+        }
+
+        assert_eq!(
+            forwarder.start_query(query_id(0)),
+            QueryStart::AlreadyInFlight
+        );
+        let overflow = query_id(MAX_IN_FLIGHT_QUERIES as u16);
+        assert_eq!(forwarder.start_query(overflow), QueryStart::Overloaded);
+
+        forwarder.finish_query(&query_id(0));
+        assert_eq!(forwarder.start_query(overflow), QueryStart::Started);
+    }
+
+    #[tokio::test]
+    async fn ip_literals_resolve_to_themselves() {
+        let forwarder = DnsForwarder::default();
+        // Literals never reach the upstreams
+        forwarder.set_upstreams(&[Ipv4Addr::new(192, 0, 2, 1).into()]);
+
+        let (v4, _) = forwarder.resolve("10.0.0.1").await.unwrap();
+        let (v6, _) = forwarder.resolve("fd00::1").await.unwrap();
+
+        assert_eq!(v4, [IpAddr::from(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert_eq!(v6, ["fd00::1".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn hosts_file_names_resolve_without_upstreams() {
+        let forwarder = DnsForwarder::default();
+
+        let (addresses, _) = forwarder.resolve("localhost").await.unwrap();
+
+        assert!(addresses
+            .iter()
+            .all(|ip| *ip == IpAddr::from(Ipv4Addr::LOCALHOST)
+                || *ip == IpAddr::from(Ipv6Addr::LOCALHOST)));
+        assert!(!addresses.is_empty());
+    }
+
+    #[test]
+    fn resolv_conf_nameservers() {
+        let resolv_conf = r#"
+# Generated by NetworkManager
+search mycorp.com
+nameserver 100.100.111.1
+nameserver fd00:2021:1111:8000:100:100:111:1
+nameserver 100.100.111.2
+nameserver 1.1.1.1
+nameserver 2606:4700:4700::1111
+options edns0 trust-ad
+"#;
+
+        assert_eq!(
+            parse_resolv_conf(resolv_conf),
+            vec![
+                "1.1.1.1:53".parse().unwrap(),
+                "[2606:4700:4700::1111]:53".parse().unwrap()
+            ]
+        );
+    }
+}
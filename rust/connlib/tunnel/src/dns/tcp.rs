@@ -0,0 +1,433 @@
+//! A minimal userspace TCP server, just enough to answer DNS queries sent over TCP to the sentinels.
+//!
+//! The segments never leave the host so they are never lost or reordered, that means there are no
+//! retransmission timers and out of order segments are simply acknowledged again.
+//! Responses are at most 64KiB so we don't keep track of the client's window either.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use pnet_packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::MutableIpv4Packet,
+    ipv6::MutableIpv6Packet,
+    tcp::{MutableTcpPacket, TcpFlags, TcpOption, TcpOptionNumbers, TcpPacket},
+    Packet,
+};
+use rand_core::{OsRng, RngCore};
+
+use crate::ip_packet::{IpPacket, MutableIpPacket};
+
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const TCP_HEADER_SIZE: usize = 20;
+const MSS_OPTION_SIZE: usize = 4;
+const DEFAULT_TTL: u8 = 64;
+// Segments this big fit in the minimum ipv6 mtu.
+const MAX_SEGMENT_SIZE: u16 = 1220;
+// What we assume if the client doesn't send the MSS option, see RFC 9293 section 3.7.1.
+const DEFAULT_SEGMENT_SIZE_V4: u16 = 536;
+const DEFAULT_SEGMENT_SIZE_V6: u16 = 1220;
+const MAX_CONNECTIONS: usize = 64;
+const MAX_PENDING_QUERIES: usize = 16;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identifies a connection by both of its ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ConnectionId {
+    client: SocketAddr,
+    server: SocketAddr,
+}
+
+struct Connection {
+    /// Next sequence number we send.
+    send_next: u32,
+    /// Next sequence number we expect from the client.
+    receive_next: u32,
+    max_segment_size: u16,
+    /// Data received that doesn't make a whole query yet.
+    buffer: Vec<u8>,
+    /// Queries handed out that haven't been answered yet.
+    pending_queries: usize,
+    /// The client won't send anything else, we close our side once every query is answered.
+    fin_received: bool,
+    last_seen: Instant,
+}
+
+/// What came out of a segment.
+#[derive(Default)]
+pub(crate) struct SegmentOutput {
+    /// Segments that need to be written back to the client.
+    pub replies: Vec<Vec<u8>>,
+    /// DNS messages received, each of them must be answered with [`TcpDnsServer::respond`].
+    pub queries: Vec<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub(crate) struct TcpDnsServer {
+    connections: Mutex<HashMap<ConnectionId, Connection>>,
+}
+
+impl TcpDnsServer {
+    /// Handles a segment the client sent to the DNS port.
+    pub(crate) fn handle_segment(
+        &self,
+        packet: &IpPacket,
+        segment: &TcpPacket,
+    ) -> (ConnectionId, SegmentOutput) {
+        let id = ConnectionId {
+            client: SocketAddr::new(packet.source(), segment.get_source()),
+            server: SocketAddr::new(packet.destination(), segment.get_destination()),
+        };
+        let mut output = SegmentOutput::default();
+        let flags = segment.get_flags();
+        let sequence = segment.get_sequence();
+        let payload = segment.payload();
+        let mut connections = self.connections.lock();
+
+        if flags & TcpFlags::RST != 0 {
+            connections.remove(&id);
+            return (id, output);
+        }
+
+        if flags & TcpFlags::SYN != 0 {
+            // A retransmitted SYN gets the same SYN-ACK, anything else starts over
+            let retransmitted = connections
+                .get(&id)
+                .is_some_and(|c| c.receive_next == sequence.wrapping_add(1));
+            if !retransmitted {
+                let now = Instant::now();
+                connections.retain(|_, c| now.duration_since(c.last_seen) < IDLE_TIMEOUT);
+                if connections.len() >= MAX_CONNECTIONS && !connections.contains_key(&id) {
+                    tracing::debug!(client = %id.client, "too_many_dns_tcp_connections");
+                    output.replies.extend(reset(&id, segment));
+                    return (id, output);
+                }
+
+                connections.insert(
+                    id,
+                    Connection {
+                        send_next: OsRng.next_u32().wrapping_add(1),
+                        receive_next: sequence.wrapping_add(1),
+                        max_segment_size: max_segment_size(segment, id.client.ip()),
+                        buffer: Vec::new(),
+                        pending_queries: 0,
+                        fin_received: false,
+                        last_seen: now,
+                    },
+                );
+            }
+
+            let connection = &connections[&id];
+            output.replies.extend(build_segment(
+                &id,
+                connection.send_next.wrapping_sub(1),
+                connection.receive_next,
+                TcpFlags::SYN | TcpFlags::ACK,
+                Some(MAX_SEGMENT_SIZE),
+                &[],
+            ));
+            return (id, output);
+        }
+
+        let Some(connection) = connections.get_mut(&id) else {
+            // Pure ACKs are expected after we closed the connection
+            if !payload.is_empty() || flags & TcpFlags::FIN != 0 {
+                output.replies.extend(reset(&id, segment));
+            }
+            return (id, output);
+        };
+        connection.last_seen = Instant::now();
+
+        if sequence != connection.receive_next {
+            if !payload.is_empty() || flags & TcpFlags::FIN != 0 {
+                output.replies.extend(connection.ack(&id));
+            }
+            return (id, output);
+        }
+
+        if !payload.is_empty() {
+            connection.receive_next = connection.receive_next.wrapping_add(payload.len() as u32);
+            connection.buffer.extend_from_slice(payload);
+            output.queries = connection.take_queries();
+
+            if connection.pending_queries > MAX_PENDING_QUERIES {
+                tracing::debug!(client = %id.client, "dns_tcp_connection_misbehaving");
+                output.replies.extend(reset(&id, segment));
+                output.queries.clear();
+                connections.remove(&id);
+                return (id, output);
+            }
+        }
+
+        if flags & TcpFlags::FIN != 0 {
+            connection.receive_next = connection.receive_next.wrapping_add(1);
+            connection.fin_received = true;
+        }
+
+        if connection.fin_received && connection.pending_queries == 0 {
+            output.replies.extend(connection.fin(&id));
+            connections.remove(&id);
+        } else if !payload.is_empty() || flags & TcpFlags::FIN != 0 {
+            output.replies.extend(connection.ack(&id));
+        }
+
+        (id, output)
+    }
+
+    /// Answers one of the queries of the connection, `None` means the query is dropped without an answer.
+    ///
+    /// Returns the segments that need to be written back to the client, if the connection is gone there are none.
+    pub(crate) fn respond(&self, id: &ConnectionId, response: Option<&[u8]>) -> Vec<Vec<u8>> {
+        let mut connections = self.connections.lock();
+        let Some(connection) = connections.get_mut(id) else {
+            return Vec::new();
+        };
+        connection.pending_queries = connection.pending_queries.saturating_sub(1);
+
+        let mut replies = Vec::new();
+        if let Some(response) = response.filter(|r| r.len() <= usize::from(u16::MAX)) {
+            let mut data = Vec::with_capacity(2 + response.len());
+            data.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            data.extend_from_slice(response);
+
+            for chunk in data.chunks(usize::from(connection.max_segment_size)) {
+                replies.extend(build_segment(
+                    id,
+                    connection.send_next,
+                    connection.receive_next,
+                    TcpFlags::PSH | TcpFlags::ACK,
+                    None,
+                    chunk,
+                ));
+                connection.send_next = connection.send_next.wrapping_add(chunk.len() as u32);
+            }
+        }
+
+        if connection.fin_received && connection.pending_queries == 0 {
+            replies.extend(connection.fin(id));
+            connections.remove(id);
+        }
+
+        replies
+    }
+}
+
+impl Connection {
+    /// Takes every whole length-prefixed message out of the buffer.
+    fn take_queries(&mut self) -> Vec<Vec<u8>> {
+        let mut queries = Vec::new();
+        let mut start = 0;
+        while let Some(len) = self.buffer.get(start..start + 2) {
+            let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+            let Some(query) = self.buffer.get(start + 2..start + 2 + len) else {
+                break;
+            };
+            queries.push(query.to_vec());
+            start += 2 + len;
+        }
+
+        self.buffer.drain(..start);
+        self.pending_queries += queries.len();
+        queries
+    }
+
+    fn ack(&self, id: &ConnectionId) -> Option<Vec<u8>> {
+        build_segment(
+            id,
+            self.send_next,
+            self.receive_next,
+            TcpFlags::ACK,
+            None,
+            &[],
+        )
+    }
+
+    fn fin(&mut self, id: &ConnectionId) -> Option<Vec<u8>> {
+        let segment = build_segment(
+            id,
+            self.send_next,
+            self.receive_next,
+            TcpFlags::FIN | TcpFlags::ACK,
+            None,
+            &[],
+        );
+        self.send_next = self.send_next.wrapping_add(1);
+        segment
+    }
+}
+
+fn max_segment_size(syn: &TcpPacket, client: IpAddr) -> u16 {
+    let advertised = syn
+        .get_options_iter()
+        .find(|option| option.get_number() == TcpOptionNumbers::MSS)
+        .and_then(|option| {
+            Some(u16::from_be_bytes(
+                option.payload().get(..2)?.try_into().ok()?,
+            ))
+        });
+    let default = match client {
+        IpAddr::V4(_) => DEFAULT_SEGMENT_SIZE_V4,
+        IpAddr::V6(_) => DEFAULT_SEGMENT_SIZE_V6,
+    };
+
+    advertised.unwrap_or(default).min(MAX_SEGMENT_SIZE)
+}
+
+fn reset(id: &ConnectionId, segment: &TcpPacket) -> Option<Vec<u8>> {
+    // See RFC 9293 section 3.10.7.1
+    if segment.get_flags() & TcpFlags::ACK != 0 {
+        build_segment(
+            id,
+            segment.get_acknowledgement(),
+            0,
+            TcpFlags::RST,
+            None,
+            &[],
+        )
+    } else {
+        let mut segment_len = segment.payload().len() as u32;
+        if segment.get_flags() & (TcpFlags::SYN | TcpFlags::FIN) != 0 {
+            segment_len += 1;
+        }
+        build_segment(
+            id,
+            0,
+            segment.get_sequence().wrapping_add(segment_len),
+            TcpFlags::RST | TcpFlags::ACK,
+            None,
+            &[],
+        )
+    }
+}
+
+/// Builds an ip packet with a segment from the server to the client.
+fn build_segment(
+    id: &ConnectionId,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    max_segment_size: Option<u16>,
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    let options_len = max_segment_size.map_or(0, |_| MSS_OPTION_SIZE);
+    let tcp_len = TCP_HEADER_SIZE + options_len + payload.len();
+
+    let (mut buf, ip_header_len) = match (id.server.ip(), id.client.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut buf = vec![0u8; IPV4_HEADER_SIZE + tcp_len];
+            let mut packet = MutableIpv4Packet::new(&mut buf)?;
+            packet.set_version(4);
+            packet.set_header_length((IPV4_HEADER_SIZE / 4) as u8);
+            packet.set_total_length((IPV4_HEADER_SIZE + tcp_len) as u16);
+            packet.set_ttl(DEFAULT_TTL);
+            packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+            packet.set_source(src);
+            packet.set_destination(dst);
+            (buf, IPV4_HEADER_SIZE)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut buf = vec![0u8; IPV6_HEADER_SIZE + tcp_len];
+            let mut packet = MutableIpv6Packet::new(&mut buf)?;
+            packet.set_version(6);
+            packet.set_payload_length(tcp_len as u16);
+            packet.set_next_header(IpNextHeaderProtocols::Tcp);
+            packet.set_hop_limit(DEFAULT_TTL);
+            packet.set_source(src);
+            packet.set_destination(dst);
+            (buf, IPV6_HEADER_SIZE)
+        }
+        _ => return None,
+    };
+
+    let mut segment = MutableTcpPacket::new(&mut buf[ip_header_len..])?;
+    segment.set_source(id.server.port());
+    segment.set_destination(id.client.port());
+    segment.set_sequence(sequence);
+    segment.set_acknowledgement(acknowledgement);
+    segment.set_data_offset(((TCP_HEADER_SIZE + options_len) / 4) as u8);
+    segment.set_flags(flags);
+    segment.set_window(u16::MAX);
+    if let Some(max_segment_size) = max_segment_size {
+        segment.set_options(&[TcpOption::mss(max_segment_size)]);
+    }
+    segment.set_payload(payload);
+
+    MutableIpPacket::new(&mut buf)?.update_checksum();
+    Some(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use pnet_packet::{tcp::TcpFlags, Packet};
+
+    use super::{build_segment, ConnectionId, TcpDnsServer};
+    use crate::ip_packet::IpPacket;
+
+    fn client_segment(sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        // The client's segments are the server's with the ends swapped
+        let id = ConnectionId {
+            client: "100.100.111.1:53".parse().unwrap(),
+            server: "100.64.0.1:40000".parse().unwrap(),
+        };
+        build_segment(&id, sequence, 0, flags, None, payload).unwrap()
+    }
+
+    fn handle(server: &TcpDnsServer, buf: &[u8]) -> (ConnectionId, super::SegmentOutput) {
+        let packet = IpPacket::new(buf).unwrap();
+        let segment = packet.as_tcp().unwrap();
+        server.handle_segment(&packet, &segment)
+    }
+
+    #[test]
+    fn query_over_tcp() {
+        let server = TcpDnsServer::default();
+
+        let (_, output) = handle(&server, &client_segment(100, TcpFlags::SYN, &[]));
+        assert_eq!(output.replies.len(), 1);
+        let syn_ack = IpPacket::new(&output.replies[0]).unwrap();
+        let syn_ack = syn_ack.as_tcp().unwrap();
+        assert_eq!(syn_ack.get_flags(), TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(syn_ack.get_acknowledgement(), 101);
+        let server_sequence = syn_ack.get_sequence().wrapping_add(1);
+
+        // The query is split in two segments
+        let (_, output) = handle(&server, &client_segment(101, TcpFlags::ACK, &[0, 3, 1]));
+        assert!(output.queries.is_empty());
+        let (id, output) = handle(&server, &client_segment(104, TcpFlags::ACK, &[2, 3]));
+        assert_eq!(output.queries, vec![vec![1, 2, 3]]);
+
+        let replies = server.respond(&id, Some(&[4, 5]));
+        assert_eq!(replies.len(), 1);
+        let response = IpPacket::new(&replies[0]).unwrap();
+        let response = response.as_tcp().unwrap();
+        assert_eq!(response.get_sequence(), server_sequence);
+        assert_eq!(response.get_acknowledgement(), 106);
+        assert_eq!(response.payload(), &[0, 2, 4, 5]);
+    }
+
+    #[test]
+    fn close_after_pending_queries_are_answered() {
+        let server = TcpDnsServer::default();
+        handle(&server, &client_segment(0, TcpFlags::SYN, &[]));
+
+        let (id, output) = handle(
+            &server,
+            &client_segment(1, TcpFlags::ACK | TcpFlags::FIN, &[0, 1, 1]),
+        );
+        assert_eq!(output.queries.len(), 1);
+        let ack = IpPacket::new(&output.replies[0]).unwrap();
+        assert_eq!(ack.as_tcp().unwrap().get_flags(), TcpFlags::ACK);
+
+        let replies = server.respond(&id, Some(&[2]));
+        let fin = IpPacket::new(replies.last().unwrap()).unwrap();
+        assert_eq!(
+            fin.as_tcp().unwrap().get_flags(),
+            TcpFlags::FIN | TcpFlags::ACK
+        );
+        assert!(server.respond(&id, Some(&[2])).is_empty());
+    }
+}
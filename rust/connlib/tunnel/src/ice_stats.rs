@@ -0,0 +1,44 @@
+//! The parts of webrtc's stats report that tell how a peer is reached.
+use connlib_shared::stats::{CandidatePairStats, CandidateStats, IceStats};
+use webrtc::{
+    peer_connection::RTCPeerConnection,
+    stats::{ICECandidateStats, StatsReportType},
+};
+
+pub(crate) async fn ice_stats(peer_connection: &RTCPeerConnection) -> IceStats {
+    let reports = peer_connection.get_stats().await.reports;
+    let candidate = |id: &str| match reports.get(id) {
+        Some(StatsReportType::LocalCandidate(c) | StatsReportType::RemoteCandidate(c)) => {
+            Some(candidate_stats(c))
+        }
+        _ => None,
+    };
+
+    let selected_candidate_pair = reports.values().find_map(|report| match report {
+        StatsReportType::CandidatePair(pair) if pair.nominated => Some(CandidatePairStats {
+            local: candidate(&pair.local_candidate_id)?,
+            remote: candidate(&pair.remote_candidate_id)?,
+        }),
+        _ => None,
+    });
+    let relay = selected_candidate_pair.as_ref().and_then(|pair| {
+        [&pair.local, &pair.remote]
+            .into_iter()
+            .find(|c| c.is_relay())
+            .map(|c| format!("{}:{}", c.ip, c.port))
+    });
+
+    IceStats {
+        state: peer_connection.connection_state().to_string(),
+        selected_candidate_pair,
+        relay,
+    }
+}
+
+fn candidate_stats(candidate: &ICECandidateStats) -> CandidateStats {
+    CandidateStats {
+        candidate_type: candidate.candidate_type.to_string(),
+        ip: candidate.ip.clone(),
+        port: candidate.port,
+    }
+}
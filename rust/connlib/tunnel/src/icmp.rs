@@ -0,0 +1,136 @@
+//! ICMP destination unreachable and packet too big replies to the packets we drop.
+//!
+//! Without them applications only give up on their own timeouts, which are usually long,
+//! and path MTU discovery never finds out how big the packets through the tunnel can be.
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+use boringtun::noise::Tunn;
+use connlib_shared::{Callbacks, MIN_MTU};
+use pnet_packet::ipv4::Ipv4Flags;
+
+use crate::{
+    buffer_pool::BufferPool,
+    device_channel::DeviceQueues,
+    ip_packet::{icmp_error, IcmpError, IpPacket, Unreachable, Version},
+    peer::Peer,
+    ControlSignal, Tunnel,
+};
+
+// Replies sent per `RESET_PACKET_COUNT_INTERVAL`, so that a flood of dropped packets doesn't turn into a flood of replies.
+const ICMP_RATE_LIMIT: usize = 100;
+
+#[derive(Default)]
+pub(crate) struct IcmpRateLimiter {
+    count: AtomicUsize,
+}
+
+impl IcmpRateLimiter {
+    pub(crate) fn reset_count(&self) {
+        self.count.store(0, Relaxed);
+    }
+
+    fn allow(&self) -> bool {
+        self.count.fetch_add(1, Relaxed) < ICMP_RATE_LIMIT
+    }
+}
+
+impl<C: ControlSignal, CB: Callbacks> Tunnel<C, CB> {
+    fn icmp_error_reply(&self, packet: &[u8], error: IcmpError) -> Option<Vec<u8>> {
+        let reply = icmp_error(packet, error)?;
+        if !self.icmp_rate_limiter.allow() {
+            tracing::trace!(target: "wire", action = "dropped", ?error, "icmp_rate_limited");
+            return None;
+        }
+
+        Some(reply)
+    }
+
+    /// Builds the reply telling the application behind the peer that sent the packet that it can't be delivered.
+    ///
+    /// The packet is still in the buffers the reply is sent with, so it's sent later with [Tunnel::icmp_error_to_peer].
+    pub(crate) fn unreachable_reply(&self, packet: &[u8], reason: Unreachable) -> Option<Vec<u8>> {
+        tracing::trace!(target: "wire", action = "dropped", ?reason, "destination_unreachable");
+        self.icmp_error_reply(packet, reason.into())
+    }
+
+    /// Tells the application on this host that sent the packet that it can't be delivered.
+    pub(crate) fn unreachable_to_device(
+        &self,
+        device_io: &DeviceQueues,
+        packet: &[u8],
+        reason: Unreachable,
+    ) {
+        self.icmp_error_to_device(device_io, packet, reason.into());
+    }
+
+    /// Tells you if the packet fits through the tunnel, when it doesn't the application that sent it is told how big it can be.
+    ///
+    /// IPv4 packets that can be fragmented and IPv6 packets up to the minimum MTU are always sent,
+    /// the data channel splits them if needed.
+    pub(crate) fn check_mtu(&self, device_io: &DeviceQueues, packet: &[u8], mtu: usize) -> bool {
+        if packet.len() <= mtu {
+            return true;
+        }
+
+        let mtu = match IpPacket::new(packet) {
+            Some(IpPacket::Ipv4Packet(p)) if p.get_flags() & Ipv4Flags::DontFragment == 0 => {
+                return true
+            }
+            Some(IpPacket::Ipv4Packet(_)) => mtu,
+            Some(IpPacket::Ipv6Packet(_)) if packet.len() <= MIN_MTU => return true,
+            Some(IpPacket::Ipv6Packet(_)) => mtu.max(MIN_MTU),
+            None => return true,
+        };
+
+        tracing::trace!(target: "wire", action = "dropped", bytes = packet.len(), mtu, "packet_too_big");
+        self.icmp_error_to_device(device_io, packet, IcmpError::PacketTooBig { mtu });
+        false
+    }
+
+    fn icmp_error_to_device(&self, device_io: &DeviceQueues, packet: &[u8], error: IcmpError) {
+        let Some(reply) = self.icmp_error_reply(packet, error) else {
+            return;
+        };
+
+        tracing::trace!(target: "wire", action = "writing", to = "iface", ?error, "icmp_error");
+        let res = match IpPacket::new(&reply).map(|p| p.version()) {
+            Some(Version::Ipv4) => device_io.write4(&reply),
+            Some(Version::Ipv6) => device_io.write6(&reply),
+            None => return,
+        };
+        if let Err(e) = res {
+            tracing::error!(?e, "iface_write");
+            let _ = self.callbacks.on_error(&e.into());
+        }
+    }
+}
+
+impl<C, CB> Tunnel<C, CB>
+where
+    C: ControlSignal + Send + Sync + 'static,
+    CB: Callbacks + 'static,
+{
+    /// Sends an ICMP error to the peer like any other packet for it.
+    pub(crate) async fn icmp_error_to_peer(
+        &self,
+        peer: &Peer,
+        mut reply: Vec<u8>,
+        buffers: &mut BufferPool,
+    ) {
+        let Some(dst_addr) = Tunn::dst_address(&reply) else {
+            return;
+        };
+        let encapsulated_packet = match peer.encapsulate(&mut reply, buffers) {
+            Ok(encapsulated_packet) => encapsulated_packet,
+            Err(e) => {
+                tracing::warn!(error = ?e, "icmp_error");
+                return;
+            }
+        };
+
+        tracing::trace!(target: "wire", action = "writing", to = "peer", "icmp_error");
+        let _ = self
+            .handle_encapsulated_packet(encapsulated_packet, &dst_addr)
+            .await;
+    }
+}
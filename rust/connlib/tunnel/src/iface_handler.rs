@@ -1,27 +1,35 @@
-use std::{net::IpAddr, sync::Arc, time::Duration};
+use std::{net::IpAddr, sync::Arc};
 
-use boringtun::noise::{errors::WireGuardError, Tunn, TunnResult};
-use bytes::Bytes;
-use connlib_shared::{Callbacks, Error, Result};
+use boringtun::noise::{errors::WireGuardError, Tunn};
+use connlib_shared::{messages::ResourceId, Callbacks, Error, Result};
 
 use crate::{
-    device_channel::{DeviceIo, IfaceConfig},
+    buffer_pool::BufferPool,
+    device_channel::{DeviceQueues, IfaceConfig},
     dns,
+    ip_packet::Unreachable,
+    mtu,
     peer::EncapsulatedPacket,
-    ConnId, ControlSignal, Tunnel, MAX_UDP_SIZE,
+    AwaitingConnectionDetails, ConnId, ControlSignal, Tunnel, MAX_UDP_SIZE,
 };
 
-const MAX_SIGNAL_CONNECTION_DELAY: Duration = Duration::from_secs(2);
-
 impl<C, CB> Tunnel<C, CB>
 where
     C: ControlSignal + Send + Sync + 'static,
     CB: Callbacks + 'static,
 {
     #[inline(always)]
-    fn connection_intent(self: &Arc<Self>, src: &[u8], dst_addr: &IpAddr) {
-        // We can buffer requests here but will drop them for now and let the upper layer reliability protocol handle this
+    fn connection_intent(
+        self: &Arc<Self>,
+        device_writer: &DeviceQueues,
+        src: &[u8],
+        dst_addr: &IpAddr,
+    ) {
         if let Some(resource) = self.get_resource(src) {
+            // Sent once we're connected, see `send_pending_packets`
+            self.pending_packets.push(resource.id(), src);
+
+            let wildcard_match = self.wildcard_match(&resource);
             // We have awaiting connection to prevent a race condition where
             // create_peer_connection hasn't added the thing to peer_connections
             // and we are finding another packet to the same address (otherwise we would just use peer_connections here)
@@ -33,7 +41,13 @@ where
                     "resource_connection_intent",
                 );
 
-                awaiting_connection.insert(conn_id, Default::default());
+                awaiting_connection.insert(
+                    conn_id,
+                    AwaitingConnectionDetails {
+                        wildcard_match,
+                        ..Default::default()
+                    },
+                );
                 let dev = Arc::clone(self);
 
                 let mut connected_gateway_ids: Vec<_> = dev
@@ -49,97 +63,111 @@ where
                     "connected_gateways"
                 );
                 tokio::spawn(async move {
-                    let mut interval = tokio::time::interval(MAX_SIGNAL_CONNECTION_DELAY);
+                    let policy = dev.connection_retry_policy;
+                    let mut interval = policy.initial_interval;
                     loop {
-                        interval.tick().await;
                         let reference = {
                             let mut awaiting_connections = dev.awaiting_connection.lock();
-                            let Some(awaiting_connection) =
-                                awaiting_connections.get_mut(&ConnId::from(resource.id()))
+                            let Some(awaiting_connection) = awaiting_connections.get_mut(&conn_id)
                             else {
                                 break;
                             };
                             if awaiting_connection.response_received {
                                 break;
                             }
-                            awaiting_connection.total_attemps += 1;
-                            awaiting_connection.total_attemps
+                            if awaiting_connection.total_attemps >= policy.max_attempts {
+                                // Removed under the lock so a response arriving now is ignored instead of racing the report
+                                awaiting_connections.remove(&conn_id);
+                                None
+                            } else {
+                                awaiting_connection.total_attemps += 1;
+                                Some(awaiting_connection.total_attemps)
+                            }
+                        };
+                        let Some(reference) = reference else {
+                            dev.resource_unreachable(
+                                resource.id(),
+                                &Error::ConnectionAttemptsExhausted,
+                            );
+                            break;
                         };
+
                         if let Err(e) = dev
                             .control_signaler
                             .signal_connection_to(&resource, &connected_gateway_ids, reference)
                             .await
                         {
-                            // Not a deadlock because this is a different task
-                            dev.awaiting_connection.lock().remove(&conn_id);
                             tracing::error!(error = ?e, "start_resource_connection");
                             let _ = dev.callbacks.on_error(&e);
+                            // Not a deadlock because this is a different task
+                            dev.resource_unreachable(resource.id(), &e);
+                            break;
                         }
+
+                        tokio::time::sleep(interval).await;
+                        interval = interval.saturating_mul(2).min(policy.max_interval);
                     }
                 });
             }
+        } else {
+            tracing::trace!(target: "wire", action = "dropped", to = %dst_addr, "no_route");
+            self.unreachable_to_device(device_writer, src, Unreachable::Host);
         }
     }
 
     #[inline(always)]
-    async fn handle_encapsulated_packet<'a>(
+    pub(crate) async fn handle_encapsulated_packet(
         &self,
-        encapsulated_packet: EncapsulatedPacket<'a>,
+        encapsulated_packet: EncapsulatedPacket,
         dst_addr: &IpAddr,
     ) -> Result<()> {
         match encapsulated_packet.encapsulate_result {
-            TunnResult::Done => Ok(()),
-            TunnResult::Err(WireGuardError::ConnectionExpired)
-            | TunnResult::Err(WireGuardError::NoCurrentSession) => {
+            Ok(None) => Ok(()),
+            Err(WireGuardError::ConnectionExpired) | Err(WireGuardError::NoCurrentSession) => {
                 self.stop_peer(encapsulated_packet.index, encapsulated_packet.conn_id)
                     .await;
                 Ok(())
             }
 
-            TunnResult::Err(e) => {
+            Err(e) => {
                 tracing::error!(resource_address = %dst_addr, error = ?e, "resource_connection");
                 let err = e.into();
                 let _ = self.callbacks.on_error(&err);
                 Err(err)
             }
-            TunnResult::WriteToNetwork(packet) => {
+            Ok(Some(packet)) => {
                 tracing::trace!(target: "wire", action = "writing", from = "iface", to = %dst_addr);
-                if let Err(e) = encapsulated_packet
-                    .channel
-                    .write(&Bytes::copy_from_slice(packet))
-                    .await
-                {
-                    tracing::error!(?e, "webrtc_write");
-                    if matches!(
-                        e,
-                        webrtc::data::Error::ErrStreamClosed
-                            | webrtc::data::Error::Sctp(webrtc::sctp::Error::ErrStreamClosed)
-                    ) {
+                if let Err(e) = encapsulated_packet.transport.send(&packet).await {
+                    tracing::error!(?e, "peer_write");
+                    if matches!(e, Error::TransportClosed) {
                         self.stop_peer(encapsulated_packet.index, encapsulated_packet.conn_id)
                             .await;
                     }
-                    let err = e.into();
-                    let _ = self.callbacks.on_error(&err);
-                    Err(err)
+                    let _ = self.callbacks.on_error(&e);
+                    Err(e)
                 } else {
                     Ok(())
                 }
             }
-            _ => panic!("Unexpected result from encapsulate"),
         }
     }
 
     #[inline(always)]
     async fn handle_iface_packet(
         self: &Arc<Self>,
-        device_writer: &DeviceIo,
+        device_writer: &DeviceQueues,
         src: &mut [u8],
-        dst: &mut [u8],
+        mtu: usize,
+        buffers: &mut BufferPool,
     ) -> Result<()> {
+        if self.check_for_dns_tcp(device_writer, src) {
+            return Ok(());
+        }
+
         if let Some(r) = self.check_for_dns(src) {
             match r {
-                dns::SendPacket::Ipv4(r) => self.write4_device_infallible(device_writer, &r[..]),
-                dns::SendPacket::Ipv6(r) => self.write6_device_infallible(device_writer, &r[..]),
+                dns::ResolveStrategy::LocalResponse(r) => self.write_dns_packet(device_writer, r),
+                dns::ResolveStrategy::ForwardQuery(q) => self.forward_dns_query(device_writer, q),
             }
             return Ok(());
         }
@@ -149,13 +177,15 @@ where
             None => return Err(Error::BadPacket),
         };
 
-        let encapsulated_packet = {
-            match self.peers_by_ip.read().longest_match(dst_addr).map(|p| p.1) {
-                Some(peer) => peer.encapsulate(src, dst)?,
-                None => {
-                    self.connection_intent(src, &dst_addr);
-                    return Ok(());
-                }
+        if !self.check_mtu(device_writer, src, mtu) {
+            return Ok(());
+        }
+
+        let encapsulated_packet = match self.peers_by_ip.longest_match(dst_addr) {
+            Some(peer) => peer.encapsulate(src, buffers)?,
+            None => {
+                self.connection_intent(device_writer, src, &dst_addr);
+                return Ok(());
             }
         };
 
@@ -163,35 +193,98 @@ where
             .await
     }
 
-    #[tracing::instrument(level = "trace", skip(self, iface_config, device_io))]
+    /// Has one of the interface's tasks send the packets that were waiting for a connection to the resource.
+    pub(crate) fn send_pending_packets(&self, resource: ResourceId) {
+        self.pending_packets.mark_sendable(resource);
+    }
+
+    /// Sends the packets that were waiting for a connection to the resource, if it can be reached now.
+    async fn flush_pending_packets(
+        self: &Arc<Self>,
+        resource: ResourceId,
+        buffers: &mut BufferPool,
+    ) {
+        let packets = self.pending_packets.take_ready(&resource, |packet| {
+            Tunn::dst_address(packet)
+                .is_some_and(|addr| self.peers_by_ip.longest_match(addr).is_some())
+        });
+
+        for mut packet in packets {
+            let Some(dst_addr) = Tunn::dst_address(&packet) else {
+                continue;
+            };
+            let Some(peer) = self.peers_by_ip.longest_match(dst_addr) else {
+                continue;
+            };
+            let encapsulated_packet = match peer.encapsulate(&mut packet, buffers) {
+                Ok(encapsulated_packet) => encapsulated_packet,
+                Err(e) => {
+                    tracing::warn!(error = ?e, "send_pending_packet");
+                    continue;
+                }
+            };
+
+            let _ = self
+                .handle_encapsulated_packet(encapsulated_packet, &dst_addr)
+                .await;
+        }
+    }
+
+    /// Handles the packets read from one of the interface's queues, every queue has its own.
+    #[tracing::instrument(level = "trace", skip(self, iface_config, device_writer))]
     pub(crate) async fn iface_handler(
         self: &Arc<Self>,
-        iface_config: Arc<IfaceConfig>,
-        device_io: DeviceIo,
+        iface_config: Arc<dyn IfaceConfig<CB>>,
+        queue: usize,
+        device_writer: DeviceQueues,
     ) {
-        let device_writer = device_io.clone();
+        let device_io = device_writer.get(queue);
+        let mut src = vec![0u8; MAX_UDP_SIZE];
+        let mut buffers = BufferPool::new();
         loop {
-            let mut src = [0u8; MAX_UDP_SIZE];
-            let mut dst = [0u8; MAX_UDP_SIZE];
-            let res = {
-                // TODO: We should check here if what we read is a whole packet
-                // there's no docs on tun device on when a whole packet is read, is it \n or another thing?
-                // found some comments saying that a single read syscall represents a single packet but no docs on that
-                // See https://stackoverflow.com/questions/18461365/how-to-read-packet-by-packet-from-linux-tun-tap
-                match device_io.read(&mut src[..iface_config.mtu()]).await {
-                    Ok(res) => res,
-                    Err(e) => {
-                        tracing::error!(error = ?e, from = "iface", action = "read");
-                        let _ = self.callbacks.on_error(&e.into());
-                        continue;
+            // TODO: We should check here if what we read is a whole packet
+            // there's no docs on tun device on when a whole packet is read, is it \n or another thing?
+            // found some comments saying that a single read syscall represents a single packet but no docs on that
+            // See https://stackoverflow.com/questions/18461365/how-to-read-packet-by-packet-from-linux-tun-tap
+            // The whole buffer is read so that packets too big for the tunnel can be answered instead of cut short
+            let read = tokio::select! {
+                read = device_io.read(&mut src) => read,
+                // Sent from here so they go through the same buffers as the packets read from the interface
+                resources = self.pending_packets.sendable() => {
+                    for resource in resources {
+                        self.flush_pending_packets(resource, &mut buffers).await;
                     }
+                    continue;
+                }
+            };
+            let res = match read {
+                Ok(0) => {
+                    tracing::debug!(queue, "iface_closed");
+                    break;
+                }
+                Ok(res) => res,
+                Err(e) => {
+                    tracing::error!(error = ?e, from = "iface", action = "read");
+                    let _ = self.callbacks.on_error(&e.into());
+                    continue;
                 }
             };
 
             tracing::trace!(target: "wire", action = "read", bytes = res, from = "iface");
+            device_writer.record_read(queue, &src[..res]);
+            // The interface never hands out packets bigger than its MTU, so it was raised since we last read it
+            if res > iface_config.mtu() {
+                if let Err(e) = iface_config.refresh_mtu().await {
+                    tracing::error!(error = ?e, "refresh_mtu");
+                    let _ = self.callbacks.on_error(&e);
+                }
+            }
+            let mtu = self
+                .mtu
+                .unwrap_or_else(|| mtu::effective_mtu(iface_config.mtu()));
             // TODO
             let _ = self
-                .handle_iface_packet(&device_writer, &mut src[..res], &mut dst)
+                .handle_iface_packet(&device_writer, &mut src[..res], mtu, &mut buffers)
                 .await;
         }
     }
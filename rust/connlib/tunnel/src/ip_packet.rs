@@ -1,8 +1,13 @@
-use std::net::IpAddr;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+};
 
 use domain::base::message::Message;
 use pnet_packet::{
-    icmpv6::{self, MutableIcmpv6Packet},
+    icmp::{self, destination_unreachable::IcmpCodes, IcmpPacket, IcmpTypes, MutableIcmpPacket},
+    icmpv6::{self, Icmpv6Code, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet},
     ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
     ipv4::{self, Ipv4Packet, MutableIpv4Packet},
     ipv6::{Ipv6Packet, MutableIpv6Packet},
@@ -11,7 +16,44 @@ use pnet_packet::{
     MutablePacket, Packet, PacketSize,
 };
 
-const DNS_PORT: u16 = 53;
+pub(crate) const DNS_PORT: u16 = 53;
+
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const ICMP_HEADER_SIZE: usize = 8;
+// Biggest ICMP errors we send, as much of the original packet is quoted as fits. See RFC 1812 4.3.2.3 and RFC 4443 2.4
+const MAX_ICMPV4_ERROR_SIZE: usize = 576;
+const MAX_ICMPV6_ERROR_SIZE: usize = 1280;
+// pnet has no codes for ICMPv6 destination unreachable, see RFC 4443 3.1
+const ICMPV6_ADMINISTRATIVELY_PROHIBITED: Icmpv6Code = Icmpv6Code(1);
+const ICMPV6_ADDRESS_UNREACHABLE: Icmpv6Code = Icmpv6Code(3);
+// Lower types are errors, see RFC 4443 2.1
+const ICMPV6_FIRST_INFORMATIONAL_TYPE: u8 = 128;
+
+/// Why a packet can't be delivered, told to whoever sent it with an ICMP destination unreachable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Unreachable {
+    /// There's no way to the destination.
+    Host,
+    /// There's a way to the destination but the packet isn't allowed to take it.
+    Prohibited,
+}
+
+/// The ICMP errors we send back for the packets we can't deliver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IcmpError {
+    Unreachable(Unreachable),
+    /// The packet doesn't fit through the tunnel, the sender should retry with packets up to `mtu` bytes.
+    PacketTooBig {
+        mtu: usize,
+    },
+}
+
+impl From<Unreachable> for IcmpError {
+    fn from(reason: Unreachable) -> Self {
+        IcmpError::Unreachable(reason)
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum MutableIpPacket<'a> {
@@ -42,6 +84,7 @@ impl<'a> MutableIpPacket<'a> {
     #[inline]
     pub(crate) fn update_checksum(&mut self) {
         // Note: neither ipv6 nor icmp have a checksum.
+        self.set_icmp_checksum();
         self.set_icmpv6_checksum();
         self.set_udp_checksum();
         self.set_tcp_checksum();
@@ -101,6 +144,19 @@ impl<'a> MutableIpPacket<'a> {
             .flatten()
     }
 
+    fn set_icmp_checksum(&mut self) {
+        if let Some(mut pkt) = self.as_icmp() {
+            let checksum = icmp::checksum(&pkt.to_immutable());
+            pkt.set_checksum(checksum);
+        }
+    }
+
+    fn as_icmp(&mut self) -> Option<MutableIcmpPacket> {
+        (self.to_immutable().next_header() == IpNextHeaderProtocols::Icmp)
+            .then(|| MutableIcmpPacket::new(self.payload_mut()))
+            .flatten()
+    }
+
     fn set_icmpv6_checksum(&mut self) {
         let (src_addr, dst_addr) = match self {
             MutableIpPacket::MutableIpv4Packet(_) => return,
@@ -175,7 +231,7 @@ pub(crate) enum IpPacket<'a> {
 
 impl<'a> IpPacket<'a> {
     pub(crate) fn new(data: &[u8]) -> Option<IpPacket> {
-        match data[0] >> 4 {
+        match data.first()? >> 4 {
             4 => Ipv4Packet::new(data).map(Into::into),
             6 => Ipv6Packet::new(data).map(Into::into),
             _ => None,
@@ -193,6 +249,11 @@ impl<'a> IpPacket<'a> {
         self.next_header() == IpNextHeaderProtocols::Icmpv6
     }
 
+    /// Tells you if the packet is either ICMP or ICMPv6.
+    pub(crate) fn is_icmp(&self) -> bool {
+        self.next_header() == IpNextHeaderProtocols::Icmp || self.is_icmpv6()
+    }
+
     pub(crate) fn next_header(&self) -> IpNextHeaderProtocol {
         match self {
             Self::Ipv4Packet(p) => p.get_next_level_protocol(),
@@ -214,6 +275,12 @@ impl<'a> IpPacket<'a> {
             .flatten()
     }
 
+    pub(crate) fn as_tcp(&self) -> Option<TcpPacket> {
+        self.is_tcp()
+            .then(|| TcpPacket::new(self.payload()))
+            .flatten()
+    }
+
     pub(crate) fn destination(&self) -> IpAddr {
         match self {
             Self::Ipv4Packet(p) => p.get_destination().into(),
@@ -228,6 +295,26 @@ impl<'a> IpPacket<'a> {
         }
     }
 
+    /// Hash of the packet's flow, the same for the packets going either way.
+    pub(crate) fn flow_hash(&self) -> u64 {
+        let (source_port, destination_port) = self
+            .as_tcp()
+            .map(|p| (p.get_source(), p.get_destination()))
+            .or_else(|| self.as_udp().map(|p| (p.get_source(), p.get_destination())))
+            .unwrap_or_default();
+        let source = (self.source(), source_port);
+        let destination = (self.destination(), destination_port);
+
+        let mut hasher = DefaultHasher::new();
+        (
+            source.min(destination),
+            source.max(destination),
+            self.next_header().0,
+        )
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub(crate) fn udp_checksum(&self, dgm: &UdpPacket<'_>) -> u16 {
         match self {
             Self::Ipv4Packet(p) => udp::ipv4_checksum(dgm, &p.get_source(), &p.get_destination()),
@@ -243,6 +330,117 @@ impl<'a> IpPacket<'a> {
     }
 }
 
+/// Builds the ICMP error for the packet, sent from its destination back to its source.
+///
+/// A packet too big is sent as an ICMP fragmentation needed for IPv4.
+/// Returns `None` for the packets that must never get one, like other ICMP errors or multicast packets.
+pub(crate) fn icmp_error(packet: &[u8], error: IcmpError) -> Option<Vec<u8>> {
+    let original = IpPacket::new(packet)?;
+    if !can_get_icmp_error(&original) {
+        return None;
+    }
+
+    let version = original.version();
+    let (header_size, max_size) = match version {
+        Version::Ipv4 => (IPV4_HEADER_SIZE, MAX_ICMPV4_ERROR_SIZE),
+        Version::Ipv6 => (IPV6_HEADER_SIZE, MAX_ICMPV6_ERROR_SIZE),
+    };
+    let quoted = &packet[..packet.len().min(max_size - header_size - ICMP_HEADER_SIZE)];
+    let payload_len = ICMP_HEADER_SIZE + quoted.len();
+    let mut buf = vec![0u8; header_size + payload_len];
+
+    match original {
+        IpPacket::Ipv4Packet(original) => {
+            let mut ip = MutableIpv4Packet::new(&mut buf)?;
+            ip.set_version(4);
+            ip.set_header_length((IPV4_HEADER_SIZE / 4) as u8);
+            // The payload is only as big as the total length says
+            ip.set_total_length((header_size + payload_len) as u16);
+            ip.set_ttl(64);
+            ip.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+            ip.set_source(original.get_destination());
+            ip.set_destination(original.get_source());
+
+            let code = match error {
+                IcmpError::Unreachable(Unreachable::Host) => IcmpCodes::DestinationHostUnreachable,
+                IcmpError::Unreachable(Unreachable::Prohibited) => {
+                    IcmpCodes::CommunicationAdministrativelyProhibited
+                }
+                IcmpError::PacketTooBig { .. } => IcmpCodes::FragmentationRequiredAndDFFlagSet,
+            };
+            let mut icmp = MutableIcmpPacket::new(ip.payload_mut())?;
+            icmp.set_icmp_type(IcmpTypes::DestinationUnreachable);
+            icmp.set_icmp_code(code);
+        }
+        IpPacket::Ipv6Packet(original) => {
+            let mut ip = MutableIpv6Packet::new(&mut buf)?;
+            ip.set_version(6);
+            ip.set_payload_length(payload_len as u16);
+            ip.set_hop_limit(64);
+            ip.set_next_header(IpNextHeaderProtocols::Icmpv6);
+            ip.set_source(original.get_destination());
+            ip.set_destination(original.get_source());
+
+            let mut icmp = MutableIcmpv6Packet::new(ip.payload_mut())?;
+            match error {
+                IcmpError::Unreachable(reason) => {
+                    let code = match reason {
+                        Unreachable::Host => ICMPV6_ADDRESS_UNREACHABLE,
+                        Unreachable::Prohibited => ICMPV6_ADMINISTRATIVELY_PROHIBITED,
+                    };
+                    icmp.set_icmpv6_type(Icmpv6Types::DestinationUnreachable);
+                    icmp.set_icmpv6_code(code);
+                }
+                IcmpError::PacketTooBig { .. } => {
+                    icmp.set_icmpv6_type(Icmpv6Types::PacketTooBig);
+                    icmp.set_icmpv6_code(Icmpv6Code(0));
+                }
+            }
+        }
+    }
+    // The 4 bytes after the ICMP header's checksum are unused, except for the MTU of a packet too big.
+    // ICMP only has room for it in the lower 2 bytes, see RFC 1191 4 and RFC 4443 3.2
+    if let IcmpError::PacketTooBig { mtu } = error {
+        let rest = &mut buf[header_size + 4..header_size + ICMP_HEADER_SIZE];
+        match version {
+            Version::Ipv4 => {
+                rest[2..].copy_from_slice(&(mtu.min(u16::MAX as usize) as u16).to_be_bytes())
+            }
+            Version::Ipv6 => rest.copy_from_slice(&(mtu as u32).to_be_bytes()),
+        }
+    }
+    buf[header_size + ICMP_HEADER_SIZE..].copy_from_slice(quoted);
+
+    MutableIpPacket::new(&mut buf)?.update_checksum();
+    Some(buf)
+}
+
+/// Tells you if an ICMP error can be sent for the packet, see RFC 1122 3.2.2 and RFC 4443 2.4.
+fn can_get_icmp_error(packet: &IpPacket) -> bool {
+    let (source, destination) = (packet.source(), packet.destination());
+    if source.is_unspecified() || source.is_multicast() || destination.is_multicast() {
+        return false;
+    }
+
+    match packet {
+        IpPacket::Ipv4Packet(p) => {
+            // Only the first fragment is answered
+            if p.get_destination().is_broadcast() || p.get_fragment_offset() != 0 {
+                return false;
+            }
+            // Never answer an error with another error
+            p.get_next_level_protocol() != IpNextHeaderProtocols::Icmp
+                || IcmpPacket::new(p.payload())
+                    .is_some_and(|icmp| icmp.get_icmp_type() == IcmpTypes::EchoRequest)
+        }
+        IpPacket::Ipv6Packet(p) => {
+            !packet.is_icmpv6()
+                || Icmpv6Packet::new(p.payload())
+                    .is_some_and(|icmp| icmp.get_icmpv6_type().0 >= ICMPV6_FIRST_INFORMATIONAL_TYPE)
+        }
+    }
+}
+
 pub(crate) fn to_dns<'a>(pkt: &'a UdpPacket<'a>) -> Option<&'a Message<[u8]>> {
     (pkt.get_destination() == DNS_PORT)
         .then(|| Message::from_slice(pkt.payload()).ok())
@@ -329,3 +527,126 @@ impl<'a> From<MutableIpv6Packet<'a>> for MutableIpPacket<'a> {
         Self::MutableIpv6Packet(pkt)
     }
 }
+
+/// An empty IPv4 UDP datagram, for tests.
+#[cfg(test)]
+pub(crate) fn udp_packet(
+    src: (std::net::Ipv4Addr, u16),
+    dst: (std::net::Ipv4Addr, u16),
+) -> Vec<u8> {
+    let mut buf = vec![0u8; IPV4_HEADER_SIZE + 8];
+    let mut ip = MutableIpv4Packet::new(&mut buf).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length(28);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    ip.set_source(src.0);
+    ip.set_destination(dst.0);
+    let mut udp = MutableUdpPacket::new(&mut buf[IPV4_HEADER_SIZE..]).unwrap();
+    udp.set_source(src.1);
+    udp.set_destination(dst.1);
+    udp.set_length(8);
+    buf
+}
+
+/// An empty IPv6 UDP datagram, for tests.
+#[cfg(test)]
+pub(crate) fn udp6_packet(
+    src: (std::net::Ipv6Addr, u16),
+    dst: (std::net::Ipv6Addr, u16),
+) -> Vec<u8> {
+    let mut buf = vec![0u8; IPV6_HEADER_SIZE + 8];
+    let mut ip = MutableIpv6Packet::new(&mut buf).unwrap();
+    ip.set_version(6);
+    ip.set_payload_length(8);
+    ip.set_hop_limit(64);
+    ip.set_next_header(IpNextHeaderProtocols::Udp);
+    ip.set_source(src.0);
+    ip.set_destination(dst.0);
+    let mut udp = MutableUdpPacket::new(&mut buf[IPV6_HEADER_SIZE..]).unwrap();
+    udp.set_source(src.1);
+    udp.set_destination(dst.1);
+    udp.set_length(8);
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use pnet_packet::{
+        icmp::{self, IcmpPacket, IcmpTypes},
+        ipv4::{self, Ipv4Packet},
+        Packet,
+    };
+
+    use super::{icmp_error, udp_packet, IcmpError, IpPacket, Unreachable};
+
+    fn flow_hash(buf: &[u8]) -> u64 {
+        IpPacket::from(Ipv4Packet::new(buf).unwrap()).flow_hash()
+    }
+
+    #[test]
+    fn flow_hash_is_the_same_both_ways() {
+        let client = (Ipv4Addr::new(100, 64, 0, 1), 40000);
+        let resource = (Ipv4Addr::new(10, 0, 0, 1), 443);
+        let other_port = (Ipv4Addr::new(100, 64, 0, 1), 40001);
+
+        let request = flow_hash(&udp_packet(client, resource));
+        let response = flow_hash(&udp_packet(resource, client));
+
+        assert_eq!(request, response);
+        assert_ne!(request, flow_hash(&udp_packet(other_port, resource)));
+    }
+
+    #[test]
+    fn unreachable_is_sent_back_quoting_the_packet() {
+        let client = (Ipv4Addr::new(100, 64, 0, 1), 40000);
+        let resource = (Ipv4Addr::new(10, 0, 0, 1), 443);
+        let packet = udp_packet(client, resource);
+
+        let reply = icmp_error(&packet, Unreachable::Prohibited.into()).unwrap();
+        let ip = Ipv4Packet::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(ip.payload()).unwrap();
+
+        assert_eq!(ip.get_source(), resource.0);
+        assert_eq!(ip.get_destination(), client.0);
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+        assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(icmp.get_icmp_code().0, 13);
+        assert_eq!(icmp.get_checksum(), icmp::checksum(&icmp));
+        assert_eq!(&icmp.payload()[4..], &packet[..]);
+    }
+
+    #[test]
+    fn unreachable_is_never_sent_for_another_unreachable() {
+        let client = (Ipv4Addr::new(100, 64, 0, 1), 40000);
+        let resource = (Ipv4Addr::new(10, 0, 0, 1), 443);
+        let reply = icmp_error(&udp_packet(client, resource), Unreachable::Host.into()).unwrap();
+
+        assert!(icmp_error(&reply, Unreachable::Host.into()).is_none());
+    }
+
+    #[test]
+    fn packet_too_big_tells_the_mtu() {
+        let client = (Ipv4Addr::new(100, 64, 0, 1), 40000);
+        let resource = (Ipv4Addr::new(10, 0, 0, 1), 443);
+        let packet = udp_packet(client, resource);
+
+        let reply = icmp_error(&packet, IcmpError::PacketTooBig { mtu: 1280 }).unwrap();
+        let ip = Ipv4Packet::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(ip.payload()).unwrap();
+
+        assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(icmp.get_icmp_code().0, 4);
+        assert_eq!(icmp.get_checksum(), icmp::checksum(&icmp));
+        assert_eq!(&icmp.payload()[..4], &[0, 0, 0x05, 0x00]);
+        assert_eq!(&icmp.payload()[4..], &packet[..]);
+    }
+
+    #[test]
+    fn empty_packet_is_not_a_packet() {
+        assert!(IpPacket::new(&[]).is_none());
+    }
+}
@@ -6,90 +6,80 @@ use boringtun::{
     noise::{errors::WireGuardError, rate_limiter::RateLimiter, Tunn, TunnResult},
     x25519::{PublicKey, StaticSecret},
 };
-use bytes::Bytes;
 
-use connlib_shared::{messages::Key, CallbackErrorFacade, Callbacks, Error, DNS_SENTINEL};
+use connlib_shared::{
+    messages::Key, validate_interface_name, validate_mtu, CallbackErrorFacade, Callbacks,
+    ConnectionRetryPolicy, DnsAddresses, Error, SessionConfig, DEFAULT_INTERFACE_NAME,
+    MAX_TUN_QUEUES,
+};
 use ip_network::IpNetwork;
-use ip_network_table::IpNetworkTable;
-use serde::{Deserialize, Serialize};
 
 use async_trait::async_trait;
+use buffer_pool::BufferPool;
+use connector::{PeerConnector, WebRtcConnector};
+use dns::{AddressPool, DnsForwarder, Resolver, ResolverCache, TcpDnsServer};
+use icmp::IcmpRateLimiter;
+use ip_packet::Unreachable;
 use itertools::Itertools;
+use masquerade::Masquerade;
 use parking_lot::{Mutex, RwLock};
-use peer::{Peer, PeerStats};
+use peer::Peer;
+use peer_table::PeerTable;
+use pending_packets::PendingPackets;
 use resource_table::ResourceTable;
 use tokio::time::MissedTickBehavior;
-use webrtc::{
-    api::{
-        interceptor_registry::register_default_interceptors, media_engine::MediaEngine,
-        setting_engine::SettingEngine, APIBuilder, API,
-    },
-    ice_transport::ice_candidate::RTCIceCandidate,
-    interceptor::registry::Registry,
-    peer_connection::RTCPeerConnection,
-};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
 
 use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
 
 use connlib_shared::{
     messages::{
-        ClientId, GatewayId, Interface as InterfaceConfig, ResourceDescription, ResourceId,
+        GatewayId, Interface as InterfaceConfig, ResourceDescription, ResourceId, WildcardMatch,
     },
     Result,
 };
 
-use device_channel::{create_iface, DeviceIo, IfaceConfig};
+use device_channel::{create_iface, DeviceQueues, IfaceConfig};
 
+pub use connlib_shared::messages::ConnId;
+pub use connlib_shared::stats::{ResourceTraffic, TrafficStats, TunnelStats};
 pub use control_protocol::Request;
 pub use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
 use connlib_shared::messages::SecretKey;
 use index::IndexLfsr;
 
+mod buffer_pool;
+mod connector;
 mod control_protocol;
 mod device_channel;
 mod dns;
+mod ice_stats;
+mod icmp;
 mod iface_handler;
 mod index;
 mod ip_packet;
+mod masquerade;
+#[cfg(test)]
+mod memory;
+mod mtu;
 mod peer;
 mod peer_handler;
+mod peer_table;
+mod pending_packets;
 mod resource_sender;
 mod resource_table;
+mod transport;
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 const RESET_PACKET_COUNT_INTERVAL: Duration = Duration::from_secs(1);
 const REFRESH_PEERS_TIMERS_INTERVAL: Duration = Duration::from_secs(1);
+const EXPIRE_WILDCARD_MATCHES_INTERVAL: Duration = Duration::from_secs(30);
 const REFRESH_MTU_INTERVAL: Duration = Duration::from_secs(30);
 
 // Note: Taken from boringtun
 const HANDSHAKE_RATE_LIMIT: u64 = 100;
 
-#[derive(Hash, Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
-pub enum ConnId {
-    Gateway(GatewayId),
-    Client(ClientId),
-    Resource(ResourceId),
-}
-
-impl From<GatewayId> for ConnId {
-    fn from(id: GatewayId) -> Self {
-        Self::Gateway(id)
-    }
-}
-
-impl From<ClientId> for ConnId {
-    fn from(id: ClientId) -> Self {
-        Self::Client(id)
-    }
-}
-
-impl From<ResourceId> for ConnId {
-    fn from(id: ResourceId) -> Self {
-        Self::Resource(id)
-    }
-}
-
 /// Represent's the tunnel actual peer's config
 /// Obtained from connlib_shared's Peer
 #[derive(Clone)]
@@ -134,10 +124,12 @@ pub trait ControlSignal {
     ) -> Result<()>;
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 struct AwaitingConnectionDetails {
     pub total_attemps: usize,
     pub response_received: bool,
+    /// Set when the connection is for a name resolved through a wildcard resource.
+    pub wildcard_match: Option<WildcardMatch>,
 }
 
 // TODO: We should use newtypes for each kind of Id
@@ -147,39 +139,113 @@ pub struct Tunnel<C: ControlSignal, CB: Callbacks> {
     next_index: Mutex<IndexLfsr>,
     // We use a tokio's mutex here since it makes things easier and we only need it
     // during init, so the performance hit is neglibile
-    iface_config: RwLock<Option<Arc<IfaceConfig>>>,
-    device_io: RwLock<Option<DeviceIo>>,
+    iface_config: RwLock<Option<Arc<dyn IfaceConfig<CB>>>>,
+    device_io: RwLock<Option<DeviceQueues>>,
     rate_limiter: Arc<RateLimiter>,
+    icmp_rate_limiter: Arc<IcmpRateLimiter>,
     private_key: StaticSecret,
     public_key: PublicKey,
-    peers_by_ip: RwLock<IpNetworkTable<Arc<Peer>>>,
-    peer_connections: Mutex<HashMap<ConnId, Arc<RTCPeerConnection>>>,
-    ice_candidate_queue:
-        Mutex<HashMap<ConnId, tokio::sync::mpsc::Receiver<Option<RTCIceCandidate>>>>,
+    peers_by_ip: PeerTable,
+    connector: Arc<dyn PeerConnector>,
     awaiting_connection: Mutex<HashMap<ConnId, AwaitingConnectionDetails>>,
     gateway_awaiting_connection: Mutex<HashMap<GatewayId, Vec<IpNetwork>>>,
     resources_gateways: Mutex<HashMap<ResourceId, GatewayId>>,
-    webrtc_api: API,
     resources: Arc<RwLock<ResourceTable<ResourceDescription>>>,
     control_signaler: C,
     gateway_public_keys: Mutex<HashMap<GatewayId, PublicKey>>,
+    dns_forwarder: Arc<DnsForwarder>,
+    // Resolves the names of DNS resources, through the forwarder unless a test replaced it
+    resolver: Arc<dyn Resolver>,
+    dns_tcp_server: TcpDnsServer,
+    resolver_cache: ResolverCache,
+    dns_addresses: DnsAddresses,
+    address_pool: Mutex<AddressPool>,
+    // Held across the netlink requests that set or remove the rules
+    masquerade: tokio::sync::Mutex<Masquerade>,
+    pending_packets: PendingPackets,
+    connection_retry_policy: ConnectionRetryPolicy,
+    interface_name: String,
+    tun_queues: usize,
+    // Replaces the MTU worked out from the interface's, see `SessionConfig::mtu`
+    mtu: Option<usize>,
+    upstream_dns: Option<Vec<IpAddr>>,
+    intercept_dns: bool,
     callbacks: CallbackErrorFacade<CB>,
 }
 
-// TODO: For now we only use these fields with debug
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub struct TunnelStats {
-    public_key: String,
-    peers_by_ip: HashMap<IpNetwork, PeerStats>,
-    peer_connections: Vec<ConnId>,
-    resource_gateways: HashMap<ResourceId, GatewayId>,
-    dns_resources: HashMap<String, ResourceDescription>,
-    network_resources: HashMap<IpNetwork, ResourceDescription>,
-    gateway_public_keys: HashMap<GatewayId, String>,
-
-    awaiting_connection: HashMap<ConnId, AwaitingConnectionDetails>,
-    gateway_awaiting_connection: HashMap<GatewayId, Vec<IpNetwork>>,
+/// Tells you if both resources route to the same place, meaning that only the display data differs.
+fn has_same_destination(a: &ResourceDescription, b: &ResourceDescription) -> bool {
+    match (a, b) {
+        (ResourceDescription::Dns(a), ResourceDescription::Dns(b)) => {
+            a.address == b.address && a.ipv4 == b.ipv4 && a.ipv6 == b.ipv6
+        }
+        (ResourceDescription::Cidr(a), ResourceDescription::Cidr(b)) => a.address == b.address,
+        _ => false,
+    }
+}
+
+impl<C: ControlSignal, CB: Callbacks> Tunnel<C, CB> {
+    /// Masquerades the packets that leave the tunnel towards the resources, for each enabled ip version.
+    ///
+    /// Calling it again replaces the previous configuration.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub async fn set_masquerade(&self, ipv4: bool, ipv6: bool) -> Result<()> {
+        self.masquerade
+            .lock()
+            .await
+            .set(self.interface_name(), ipv4, ipv6)
+            .await
+    }
+
+    /// Removes the masquerading rules set with [Tunnel::set_masquerade].
+    ///
+    /// Has to be awaited before the runtime shuts down, nothing spawned from then on gets to run.
+    pub async fn remove_masquerade(&self) {
+        self.masquerade.lock().await.remove().await
+    }
+
+    /// The name of the tunnel interface, packets coming out of it are the ones masqueraded.
+    pub fn interface_name(&self) -> &str {
+        &self.interface_name
+    }
+
+    /// Whether the DNS sentinels and the addresses handed out for DNS resources are routed through the tunnel, they are by default.
+    ///
+    /// Gateways don't resolve names for anyone, leaving the routes to a client running on the same host.
+    pub fn with_dns_interception(mut self, enabled: bool) -> Self {
+        self.intercept_dns = enabled;
+        self
+    }
+
+    /// Connects to the peers through `connector` instead of webrtc.
+    #[cfg(test)]
+    pub(crate) fn with_connector(mut self, connector: Arc<dyn PeerConnector>) -> Self {
+        self.connector = connector;
+        self
+    }
+
+    /// Resolves the names of the DNS resources through `resolver` instead of the forwarder.
+    #[cfg(test)]
+    pub(crate) fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Gives up connecting to a resource and tells the callbacks why.
+    ///
+    /// The packets waiting for the connection are answered with an ICMP host unreachable and the next one starts a new connection intent.
+    pub fn resource_unreachable(&self, resource_id: ResourceId, reason: &Error) {
+        self.awaiting_connection.lock().remove(&resource_id.into());
+        let packets = self.pending_packets.discard(&resource_id);
+        let device_io = self.device_io.read().clone();
+        if let Some(device_io) = device_io {
+            for packet in packets {
+                self.unreachable_to_device(&device_io, &packet, Unreachable::Host);
+            }
+        }
+        tracing::warn!(%resource_id, %reason, "resource_unreachable");
+        let _ = self.callbacks.on_resource_unreachable(resource_id, reason);
+    }
 }
 
 impl<C, CB> Tunnel<C, CB>
@@ -187,40 +253,47 @@ where
     C: ControlSignal + Send + Sync + 'static,
     CB: Callbacks + 'static,
 {
-    pub fn stats(&self) -> TunnelStats {
-        let peers_by_ip = self
-            .peers_by_ip
-            .read()
+    /// A snapshot of the tunnel and the connection with each of its peers.
+    pub async fn stats(&self) -> TunnelStats {
+        let peers = self.peers_by_ip.peers();
+
+        let mut peers_stats = Vec::with_capacity(peers.len());
+        for peer in peers {
+            let mut stats = peer.stats();
+            stats.ice = self.connector.ice_stats(peer.conn_id).await;
+            peers_stats.push(stats);
+        }
+
+        let resource_traffic = peers_stats
             .iter()
-            .map(|(ip, peer)| (ip, peer.stats()))
+            .flat_map(|peer| {
+                peer.resource_traffic.iter().map(|traffic| ResourceTraffic {
+                    conn_id: peer.conn_id,
+                    index: peer.index,
+                    resource_id: traffic.resource_id,
+                    destination: traffic.destination,
+                    traffic: traffic.traffic,
+                })
+            })
             .collect();
-        let peer_connections = self.peer_connections.lock().keys().cloned().collect();
-        let awaiting_connection = self.awaiting_connection.lock().clone();
-        let gateway_awaiting_connection = self.gateway_awaiting_connection.lock().clone();
+        let awaiting_connection = self.awaiting_connection.lock().keys().copied().collect();
         let resource_gateways = self.resources_gateways.lock().clone();
-        let (network_resources, dns_resources) = {
-            let resources = self.resources.read();
-            (resources.network_resources(), resources.dns_resources())
-        };
 
-        let gateway_public_keys = self
-            .gateway_public_keys
-            .lock()
-            .iter()
-            .map(|(&id, &k)| (id, Key::from(k).to_string()))
-            .collect();
         TunnelStats {
             public_key: Key::from(self.public_key).to_string(),
-            peers_by_ip,
-            peer_connections,
-            awaiting_connection,
-            gateway_awaiting_connection,
+            peers: peers_stats,
             resource_gateways,
-            dns_resources,
-            network_resources,
-            gateway_public_keys,
+            awaiting_connection,
+            resource_traffic,
         }
     }
+
+    /// The traffic of the peers removed since the last call, as it was counted when they were removed.
+    ///
+    /// Only the peers with resources are kept track of, meaning the clients of a gateway.
+    pub fn take_removed_traffic(&self) -> Vec<ResourceTraffic> {
+        self.peers_by_ip.take_removed_traffic()
+    }
 }
 
 impl<C, CB> Tunnel<C, CB>
@@ -233,17 +306,19 @@ where
     /// # Parameters
     /// - `private_key`: wireguard's private key.
     /// -  `control_signaler`: this is used to send SDP from the tunnel to the control plane.
+    /// - `config`: the settings of the session the tunnel is part of.
     #[tracing::instrument(level = "trace", skip(private_key, control_signaler, callbacks))]
     pub async fn new(
         private_key: StaticSecret,
         control_signaler: C,
         callbacks: CB,
+        config: &SessionConfig,
     ) -> Result<Self> {
         let public_key = (&private_key).into();
         let rate_limiter = Arc::new(RateLimiter::new(&public_key, HANDSHAKE_RATE_LIMIT));
-        let peers_by_ip = RwLock::new(IpNetworkTable::new());
+        let icmp_rate_limiter = Default::default();
+        let peers_by_ip = Default::default();
         let next_index = Default::default();
-        let peer_connections = Default::default();
         let resources: Arc<RwLock<ResourceTable<ResourceDescription>>> = Default::default();
         let awaiting_connection = Default::default();
         let gateway_public_keys = Default::default();
@@ -251,50 +326,68 @@ where
         let gateway_awaiting_connection = Default::default();
         let iface_config = Default::default();
         let device_io = Default::default();
-        let ice_candidate_queue = Default::default();
-
-        // ICE
-        let mut media_engine = MediaEngine::default();
-
-        // Register default codecs (TODO: We need this?)
-        media_engine.register_default_codecs()?;
-        let mut registry = Registry::new();
-        registry = register_default_interceptors(registry, &mut media_engine)?;
-        let mut setting_engine = SettingEngine::default();
-        setting_engine.detach_data_channels();
-        setting_engine.set_ip_filter(Box::new({
-            let resources = Arc::clone(&resources);
-            move |ip| !resources.read().values().any(|res_ip| res_ip.contains(ip))
-        }));
-
-        setting_engine.set_interface_filter(Box::new({
-            |name| !name.contains("utun") && name != "tun-firezone"
-        }));
-
-        let webrtc_api = APIBuilder::new()
-            .with_media_engine(media_engine)
-            .with_interceptor_registry(registry)
-            .with_setting_engine(setting_engine)
-            .build();
+        let dns_forwarder: Arc<DnsForwarder> = Default::default();
+        let resolver = Arc::clone(&dns_forwarder) as Arc<dyn Resolver>;
+        let dns_tcp_server = Default::default();
+        let resolver_cache = Default::default();
+        let dns_addresses = config.dns_addresses.unwrap_or_default();
+        let address_pool = Mutex::new(AddressPool::new(dns_addresses));
+        let pending_packets = Default::default();
+        let connection_retry_policy = config.connection_retry_policy.unwrap_or_default();
+        let interface_name = config
+            .interface_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_INTERFACE_NAME.to_owned());
+        validate_interface_name(&interface_name)?;
+        let masquerade = Default::default();
+        let tun_queues = config
+            .tun_queues
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+            })
+            .clamp(1, MAX_TUN_QUEUES);
+        let mtu = config.mtu;
+        if let Some(mtu) = mtu {
+            validate_mtu(mtu)?;
+        }
+
+        let callbacks = CallbackErrorFacade(callbacks);
+        let connector = Arc::new(WebRtcConnector::new(
+            Arc::clone(&resources),
+            callbacks.clone(),
+        )?);
 
         Ok(Self {
             gateway_public_keys,
             rate_limiter,
+            icmp_rate_limiter,
             private_key,
-            peer_connections,
             public_key,
             peers_by_ip,
+            connector,
             next_index,
-            webrtc_api,
             resources,
             iface_config,
             device_io,
             awaiting_connection,
             gateway_awaiting_connection,
             control_signaler,
+            dns_forwarder,
+            resolver,
+            dns_tcp_server,
+            resolver_cache,
+            dns_addresses,
+            address_pool,
+            masquerade,
+            pending_packets,
+            connection_retry_policy,
+            interface_name,
+            tun_queues,
+            mtu,
+            upstream_dns: config.upstream_dns.clone(),
+            intercept_dns: true,
             resources_gateways,
-            ice_candidate_queue,
-            callbacks: CallbackErrorFacade(callbacks),
+            callbacks,
         })
     }
 
@@ -333,20 +426,220 @@ where
         Ok(())
     }
 
+    /// Removes the given resource from the tunnel.
+    ///
+    /// Its routes are withdrawn and, if the gateway connection that carried it has no resources left, it's closed.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub async fn remove_resource(&self, id: ResourceId) -> Result<()> {
+        if self.withdraw_resource(id, &[]).await.is_none() {
+            return Err(Error::UnknownResource);
+        }
+
+        let resource_list = self.resources.read().resource_list();
+        self.callbacks.on_update_resources(resource_list)?;
+        Ok(())
+    }
+
+    /// Updates the given resource.
+    ///
+    /// If only the display data changed the resource is updated in place,
+    /// otherwise the old resource is replaced by the new one, the next packet for it will trigger a new connection intent.
+    /// Connections to other resources aren't affected either way.
+    ///
+    /// The resource list is handed to the callbacks even if the update fails, it then still holds the old resource.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub async fn update_resource(&self, resource_description: ResourceDescription) -> Result<()> {
+        let current = self
+            .resources
+            .read()
+            .get_by_id(&resource_description.id())
+            .cloned();
+
+        match current {
+            Some(current) if has_same_destination(&current, &resource_description) => {
+                let resource_list = {
+                    let mut resources = self.resources.write();
+                    // Inserting drops the names resolved through a wildcard resource, they are still valid though
+                    let wildcard_matches = resources.wildcard_matches(&current.id());
+                    resources.insert(resource_description);
+                    for wildcard_match in wildcard_matches {
+                        resources.insert_match(wildcard_match);
+                    }
+                    resources.resource_list()
+                };
+
+                self.callbacks.on_update_resources(resource_list)?;
+                Ok(())
+            }
+            Some(current) => {
+                let result = self.replace_resource(&current, resource_description).await;
+                let resource_list = self.resources.read().resource_list();
+                self.callbacks.on_update_resources(resource_list)?;
+                result
+            }
+            None => self.add_resource(resource_description).await,
+        }
+    }
+
+    /// Moves a resource to another destination.
+    ///
+    /// The new routes are added before anything is torn down, if none of them can be added the resource is left as it was.
+    /// Only the routes the new destination doesn't need anymore are removed.
+    async fn replace_resource(
+        &self,
+        current: &ResourceDescription,
+        resource_description: ResourceDescription,
+    ) -> Result<()> {
+        let Some(iface_config) = self.iface_config.read().clone() else {
+            tracing::error!("update_resource_before_initialization");
+            return Err(Error::ControlProtocolError);
+        };
+
+        let current_routes = current.ips();
+        let mut routes = Vec::new();
+        for ip in resource_description.ips() {
+            if current_routes.contains(&ip) {
+                routes.push(ip);
+                continue;
+            }
+
+            if let Err(e) = iface_config.add_route(ip, self.callbacks()).await {
+                tracing::warn!(route = %ip, error = ?e, "add_route");
+                let _ = self.callbacks().on_error(&e);
+            } else {
+                routes.push(ip);
+            }
+        }
+        if routes.is_empty() {
+            return Err(Error::InvalidResource);
+        }
+
+        self.withdraw_resource(current.id(), &routes).await;
+        self.resources.write().insert(resource_description);
+        Ok(())
+    }
+
+    /// Removes a resource and the connection state that comes with it, along with its routes but for `keep_routes`.
+    async fn withdraw_resource(
+        &self,
+        id: ResourceId,
+        keep_routes: &[IpNetwork],
+    ) -> Option<ResourceDescription> {
+        let (resource_description, matched_ips) = {
+            let mut resources = self.resources.write();
+            let matched_ips: Vec<_> = resources
+                .wildcard_matches(&id)
+                .iter()
+                .flat_map(ResourceDescription::ips)
+                .collect();
+            (resources.remove_by_id(&id)?, matched_ips)
+        };
+        // Names resolved through a wildcard resource are covered by the address pool's routes
+        let routes = resource_description.ips();
+        let ips: Vec<_> = routes.iter().copied().chain(matched_ips).collect();
+
+        self.awaiting_connection.lock().remove(&id.into());
+        self.pending_packets.discard(&id);
+        if let Some(gateway_id) = self.resources_gateways.lock().remove(&id) {
+            if let Some(awaiting_ips) = self
+                .gateway_awaiting_connection
+                .lock()
+                .get_mut(&gateway_id)
+            {
+                awaiting_ips.retain(|ip| !ips.contains(ip));
+            }
+        }
+
+        let orphaned_peers: Vec<_> = self.peers_by_ip.update(|peers_by_ip| {
+            ips.iter()
+                .filter_map(|ip| {
+                    let peer = peers_by_ip.remove(ip)?;
+                    peer.remove_allowed_ip(*ip);
+                    peer.has_no_allowed_ips().then_some(peer)
+                })
+                .unique_by(|p| p.index)
+                .collect()
+        });
+
+        for peer in orphaned_peers {
+            tracing::trace!(index = peer.index, "peer_without_resources");
+            self.stop_peer(peer.index, peer.conn_id).await;
+            let _ = peer.shutdown().await;
+        }
+
+        let iface_config = self.iface_config.read().clone();
+        if let Some(iface_config) = iface_config {
+            for ip in routes.into_iter().filter(|ip| !keep_routes.contains(ip)) {
+                if let Err(e) = iface_config.remove_route(ip, self.callbacks()).await {
+                    tracing::warn!(route = %ip, error = ?e, "remove_route");
+                    let _ = self.callbacks().on_error(&e);
+                }
+            }
+        }
+
+        Some(resource_description)
+    }
+
     /// Sets the interface configuration and starts background tasks.
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn set_interface(self: &Arc<Self>, config: &InterfaceConfig) -> Result<()> {
-        let (iface_config, device_io) = create_iface(config, self.callbacks()).await?;
-        iface_config
-            .add_route(DNS_SENTINEL.into(), self.callbacks())
-            .await?;
-        let iface_config = Arc::new(iface_config);
+        let dns_routes: Vec<IpNetwork> = [
+            IpNetwork::from(self.dns_addresses.sentinel()),
+            IpNetwork::from(self.dns_addresses.sentinel_v6()),
+        ]
+        .into_iter()
+        .chain(self.address_pool.lock().routes())
+        .filter(|_| self.intercept_dns)
+        .collect();
+        let routes: Vec<IpNetwork> = dns_routes
+            .iter()
+            .copied()
+            .chain(self.resources.read().values().flat_map(|r| r.ips()))
+            .collect();
+        let (iface_config, device_queues) = create_iface(
+            config,
+            &self.interface_name,
+            self.tun_queues,
+            &routes,
+            &self.dns_addresses,
+            self.callbacks(),
+        )
+        .await
+        .map_err(|e| match e {
+            // Most likely another client on the host left the DNS addresses at their default
+            Error::RouteConflict(route) if dns_routes.contains(&route) => {
+                Error::DnsAddressesTaken(self.dns_addresses)
+            }
+            e => e,
+        })?;
 
-        *self.device_io.write() = Some(device_io.clone());
+        self.start_device(config, iface_config, device_queues)
+    }
+
+    /// Starts the background tasks for an interface that's already up with the routes in place.
+    pub(crate) fn start_device(
+        self: &Arc<Self>,
+        config: &InterfaceConfig,
+        iface_config: Arc<dyn IfaceConfig<CB>>,
+        device_queues: DeviceQueues,
+    ) -> Result<()> {
+        let upstream_dns = self
+            .upstream_dns
+            .clone()
+            .unwrap_or_else(|| config.upstream_dns.clone());
+        self.dns_forwarder.set_upstreams(&upstream_dns);
+
+        *self.device_io.write() = Some(device_queues.clone());
         *self.iface_config.write() = Some(Arc::clone(&iface_config));
-        self.start_timers()?;
-        let dev = Arc::clone(self);
-        tokio::spawn(async move { dev.iface_handler(iface_config, device_io).await });
+        self.start_timers(&iface_config);
+        for queue in 0..device_queues.len() {
+            let dev = Arc::clone(self);
+            let iface_config = Arc::clone(&iface_config);
+            let device_writer = device_queues.clone();
+            tokio::spawn(
+                async move { dev.iface_handler(iface_config, queue, device_writer).await },
+            );
+        }
 
         self.callbacks.on_tunnel_ready()?;
 
@@ -357,18 +650,13 @@ where
 
     #[tracing::instrument(level = "trace", skip(self))]
     async fn stop_peer(&self, index: u32, conn_id: ConnId) {
-        self.peers_by_ip.write().retain(|_, p| p.index != index);
-        let conn = self.peer_connections.lock().remove(&conn_id);
-        if let Some(conn) = conn {
-            if let Err(e) = conn.close().await {
-                tracing::warn!(error = ?e, "Can't close peer");
-                let _ = self.callbacks().on_error(&e.into());
-            }
-        }
+        self.peers_by_ip
+            .update(|peers_by_ip| peers_by_ip.retain(|_, p| p.index != index));
+        self.connector.close(conn_id).await;
     }
 
-    async fn peer_refresh(&self, peer: &Peer, dst_buf: &mut [u8; MAX_UDP_SIZE]) {
-        let update_timers_result = peer.update_timers(&mut dst_buf[..]);
+    async fn peer_refresh(&self, peer: &Peer, buffers: &mut BufferPool) {
+        let update_timers_result = peer.update_timers(buffers.buffer());
 
         match update_timers_result {
             TunnResult::Done => {}
@@ -379,8 +667,10 @@ where
             }
             TunnResult::Err(e) => tracing::error!(error = ?e, "timer_error"),
             TunnResult::WriteToNetwork(packet) => {
-                let bytes = Bytes::copy_from_slice(packet);
-                peer.send_infallible(bytes, &self.callbacks).await
+                // The packet is always written at the start of the buffer
+                let len = packet.len();
+                peer.send_infallible(buffers.take(len), &self.callbacks)
+                    .await
             }
 
             _ => panic!("Unexpected result from update_timers"),
@@ -389,39 +679,39 @@ where
 
     fn start_rate_limiter_refresh_timer(self: &Arc<Self>) {
         let rate_limiter = self.rate_limiter.clone();
+        let icmp_rate_limiter = self.icmp_rate_limiter.clone();
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(RESET_PACKET_COUNT_INTERVAL);
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
             loop {
                 rate_limiter.reset_count();
+                icmp_rate_limiter.reset_count();
                 interval.tick().await;
             }
         });
     }
 
     fn remove_expired_peers(self: &Arc<Self>) {
-        let mut peers_by_ip = self.peers_by_ip.write();
-
-        for (_, peer) in peers_by_ip.iter() {
+        let mut any_expired = false;
+        for peer in self.peers_by_ip.peers() {
             peer.expire_resources();
             if peer.is_emptied() {
                 tracing::trace!(index = peer.index, "peer_expired");
-                let conn = self.peer_connections.lock().remove(&peer.conn_id);
-                let p = peer.clone();
+                any_expired = true;
+                let close = self.connector.close(peer.conn_id);
 
-                // We are holding a Mutex, particularly a write one, we don't want to make a blocking call
                 tokio::spawn(async move {
-                    let _ = p.shutdown().await;
-                    if let Some(conn) = conn {
-                        // TODO: it seems that even closing the stream there are messages to the relay
-                        // see where they come from.
-                        let _ = conn.close().await;
-                    }
+                    let _ = peer.shutdown().await;
+                    close.await;
                 });
             }
         }
 
-        peers_by_ip.retain(|_, p| !p.is_emptied());
+        // Most of the time nothing expired and the lookups don't need a new table
+        if any_expired {
+            self.peers_by_ip
+                .update(|peers_by_ip| peers_by_ip.retain(|_, p| !p.is_emptied()));
+        }
     }
 
     fn start_peers_refresh_timer(self: &Arc<Self>) {
@@ -430,22 +720,13 @@ where
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(REFRESH_PEERS_TIMERS_INTERVAL);
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-            let mut dst_buf = [0u8; MAX_UDP_SIZE];
+            let mut buffers = BufferPool::new();
 
             loop {
                 tunnel.remove_expired_peers();
 
-                let peers: Vec<_> = tunnel
-                    .peers_by_ip
-                    .read()
-                    .iter()
-                    .map(|p| p.1)
-                    .unique_by(|p| p.index)
-                    .cloned()
-                    .collect();
-
-                for peer in peers {
-                    tunnel.peer_refresh(&peer, &mut dst_buf).await;
+                for peer in tunnel.peers_by_ip.peers() {
+                    tunnel.peer_refresh(&peer, &mut buffers).await;
                 }
 
                 interval.tick().await;
@@ -453,11 +734,21 @@ where
         });
     }
 
-    fn start_refresh_mtu_timer(self: &Arc<Self>) -> Result<()> {
-        let Some(iface_config) = self.iface_config.read().clone() else {
-            return Err(Error::NoIface);
-        };
-        let callbacks = self.callbacks().clone();
+    fn start_wildcard_matches_expiry_timer(self: &Arc<Self>) {
+        let tunnel = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRE_WILDCARD_MATCHES_INTERVAL);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                tunnel.release_expired_wildcard_matches();
+            }
+        });
+    }
+
+    // A raised MTU shows up as a bigger read, but a lowered one can only be noticed by reading it again
+    fn start_mtu_refresh_timer(self: &Arc<Self>, iface_config: Arc<dyn IfaceConfig<CB>>) {
+        let callbacks = self.callbacks.clone();
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(REFRESH_MTU_INTERVAL);
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -465,23 +756,24 @@ where
                 interval.tick().await;
                 if let Err(e) = iface_config.refresh_mtu().await {
                     tracing::error!(error = ?e, "refresh_mtu");
-                    let _ = callbacks.0.on_error(&e);
+                    let _ = callbacks.on_error(&e);
                 }
             }
         });
-
-        Ok(())
     }
 
-    fn start_timers(self: &Arc<Self>) -> Result<()> {
-        self.start_refresh_mtu_timer()?;
+    fn start_timers(self: &Arc<Self>, iface_config: &Arc<dyn IfaceConfig<CB>>) {
         self.start_rate_limiter_refresh_timer();
         self.start_peers_refresh_timer();
-        Ok(())
+        self.start_wildcard_matches_expiry_timer();
+        // Nothing to keep up to date if the MTU is set by hand
+        if self.mtu.is_none() {
+            self.start_mtu_refresh_timer(Arc::clone(iface_config));
+        }
     }
 
     #[inline(always)]
-    fn write4_device_infallible(&self, device_io: &DeviceIo, packet: &[u8]) {
+    fn write4_device_infallible(&self, device_io: &DeviceQueues, packet: &[u8]) {
         if let Err(e) = device_io.write4(packet) {
             tracing::error!(?e, "iface_write");
             let _ = self.callbacks().on_error(&e.into());
@@ -489,13 +781,33 @@ where
     }
 
     #[inline(always)]
-    fn write6_device_infallible(&self, device_io: &DeviceIo, packet: &[u8]) {
+    fn write6_device_infallible(&self, device_io: &DeviceQueues, packet: &[u8]) {
         if let Err(e) = device_io.write6(packet) {
             tracing::error!(?e, "iface_write");
             let _ = self.callbacks().on_error(&e.into());
         }
     }
 
+    /// Gets the name the resource was resolved from if it's a name that matched a wildcard resource.
+    fn wildcard_match(&self, resource: &ResourceDescription) -> Option<WildcardMatch> {
+        let ResourceDescription::Dns(resource) = resource else {
+            return None;
+        };
+        if resource.is_wildcard() {
+            return None;
+        }
+
+        let resources = self.resources.read();
+        let ResourceDescription::Dns(wildcard) = resources.get_by_id(&resource.id)? else {
+            return None;
+        };
+        wildcard.matches(&resource.address).then(|| WildcardMatch {
+            name: resource.address.clone(),
+            ipv4: resource.ipv4,
+            ipv6: resource.ipv6,
+        })
+    }
+
     fn get_resource(&self, buff: &[u8]) -> Option<ResourceDescription> {
         let addr = Tunn::dst_address(buff)?;
         let resources = self.resources.read();
@@ -0,0 +1,92 @@
+//! Source NAT for the packets the gateway forwards from the tunnel to the resources.
+//!
+//! The rules live in a table that belongs to us, one per ip version and interface, which is replaced as a whole
+//! every time it's configured so that it never holds duplicated or leftover rules.
+use std::mem;
+
+use connlib_shared::Result;
+
+#[cfg(target_os = "linux")]
+mod nftables;
+
+#[derive(Debug, Clone, Copy)]
+enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+/// Keeps track of the ip versions we masquerade, the rules stay until [Masquerade::remove] is awaited.
+#[derive(Debug, Default)]
+pub(crate) struct Masquerade {
+    /// Only the packets coming out of this interface are masqueraded.
+    interface_name: String,
+    ipv4: bool,
+    ipv6: bool,
+}
+
+impl Masquerade {
+    /// Masquerades exactly the given ip versions for the packets coming out of the interface.
+    ///
+    /// Rules of a previous run that didn't exit cleanly are replaced as well.
+    pub(crate) async fn set(&mut self, interface_name: &str, ipv4: bool, ipv6: bool) -> Result<()> {
+        self.interface_name = interface_name.to_owned();
+        set_family(Family::Ipv4, interface_name, ipv4).await?;
+        self.ipv4 = ipv4;
+        set_family(Family::Ipv6, interface_name, ipv6).await?;
+        self.ipv6 = ipv6;
+
+        tracing::debug!(ipv4, ipv6, "masquerade_set");
+
+        Ok(())
+    }
+
+    /// Removes the rules we installed, if any.
+    pub(crate) async fn remove(&mut self) {
+        remove_families(
+            &self.interface_name,
+            mem::take(&mut self.ipv4),
+            mem::take(&mut self.ipv6),
+        )
+        .await;
+    }
+}
+
+impl Drop for Masquerade {
+    fn drop(&mut self) {
+        // Nothing spawned from here would run if the runtime is shutting down, so all we can do is tell.
+        // Whatever is left over is replaced the next time the interface is masqueraded.
+        if self.ipv4 || self.ipv6 {
+            tracing::warn!(ipv4 = self.ipv4, ipv6 = self.ipv6, "masquerade_left_over");
+        }
+    }
+}
+
+async fn remove_families(interface_name: &str, ipv4: bool, ipv6: bool) {
+    for (family, enabled) in [(Family::Ipv4, ipv4), (Family::Ipv6, ipv6)] {
+        if !enabled {
+            continue;
+        }
+
+        if let Err(e) = set_family(family, interface_name, false).await {
+            tracing::warn!(?family, error = ?e, "masquerade_cleanup_failed");
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn set_family(family: Family, interface_name: &str, enabled: bool) -> Result<()> {
+    nftables::set_masquerade(family, interface_name, enabled)
+        .await
+        .map_err(connlib_shared::Error::NetlinkErrorIo)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn set_family(_: Family, _: &str, enabled: bool) -> Result<()> {
+    if enabled {
+        return Err(connlib_shared::Error::Other(
+            "Masquerading is only supported on linux",
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,415 @@
+//! Just enough of the nftables netlink protocol to masquerade the packets coming out of the tunnel.
+//!
+//! nftables is configured through `NETLINK_NETFILTER`, a different netlink protocol than the one
+//! rtnetlink speaks, so it gets its own short-lived socket.
+//! Every change is sent as a single batch, which the kernel applies atomically.
+use std::{io, time::Duration};
+
+use netlink_packet_core::{
+    NetlinkBuffer, NetlinkDeserializable, NetlinkHeader, NetlinkMessage, NetlinkPayload,
+    NetlinkSerializable, NLM_F_ACK, NLM_F_APPEND, NLM_F_CREATE, NLM_F_REQUEST,
+};
+use netlink_packet_utils::{
+    nla::{Nla, NLA_F_NESTED},
+    DecodeError, Emitable,
+};
+use netlink_sys::{
+    protocols::NETLINK_NETFILTER, AsyncSocket, AsyncSocketExt, SocketAddr, TokioSocket,
+};
+
+use super::Family;
+
+const TABLE_NAME: &str = "firezone";
+const CHAIN_NAME: &str = "postrouting";
+
+// From linux/netfilter.h and linux/netfilter/nfnetlink.h
+const NFPROTO_UNSPEC: u8 = 0;
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+const NFNETLINK_V0: u8 = 0;
+const NFGENMSG_LEN: usize = 4;
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NF_ACCEPT: u32 = 1;
+const NF_INET_POST_ROUTING: u32 = 4;
+const NF_IP_PRI_NAT_SRC: i32 = 100;
+
+// From linux/netfilter/nf_tables.h
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_REG_1: u32 = 1;
+
+// The kernel answers right away, this only guards against waiting forever on a reply that never comes.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Replaces our table for the family with one that masquerades the packets coming out of the tunnel,
+/// or just removes it if `enabled` is false.
+pub(super) async fn set_masquerade(
+    family: Family,
+    interface_name: &str,
+    enabled: bool,
+) -> io::Result<()> {
+    let batch = masquerade_batch(family, interface_name, enabled);
+    let acks = batch
+        .iter()
+        .filter(|message| message.header.flags & NLM_F_ACK != 0)
+        .count();
+
+    let mut socket = TokioSocket::new(NETLINK_NETFILTER)?;
+    // Port 0 is the kernel
+    socket
+        .send_to(&serialize(batch), &SocketAddr::new(0, 0))
+        .await?;
+
+    tokio::time::timeout(REPLY_TIMEOUT, wait_for_acks(&mut socket, acks))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Every interface gets its own table so that the sessions running at the same time don't replace each other's.
+fn table_name(interface_name: &str) -> String {
+    // Keeps the name used before the interface could be chosen, so its tables are still cleaned up
+    if interface_name == connlib_shared::DEFAULT_INTERFACE_NAME {
+        return TABLE_NAME.to_owned();
+    }
+
+    format!("{TABLE_NAME}-{interface_name}")
+}
+
+/// Builds the batch of messages, including the messages that begin and end it.
+///
+/// Deleting a table that doesn't exist fails, so the table is always added before being deleted.
+fn masquerade_batch(
+    family: Family,
+    interface_name: &str,
+    enabled: bool,
+) -> Vec<NetlinkMessage<NftMessage>> {
+    let family = match family {
+        Family::Ipv4 => NFPROTO_IPV4,
+        Family::Ipv6 => NFPROTO_IPV6,
+    };
+    let table = || Attr::Str(NFTA_TABLE_NAME, table_name(interface_name));
+
+    let mut batch = vec![
+        NftMessage::batch(NFNL_MSG_BATCH_BEGIN),
+        NftMessage::new(NFT_MSG_NEWTABLE, family, NLM_F_CREATE, vec![table()]),
+        NftMessage::new(NFT_MSG_DELTABLE, family, 0, vec![table()]),
+    ];
+
+    if enabled {
+        // The name is compared as the whole kernel buffer, padded with nul bytes
+        let mut name = [0; libc::IFNAMSIZ];
+        name[..interface_name.len()].copy_from_slice(interface_name.as_bytes());
+
+        batch.extend([
+            NftMessage::new(NFT_MSG_NEWTABLE, family, NLM_F_CREATE, vec![table()]),
+            NftMessage::new(
+                NFT_MSG_NEWCHAIN,
+                family,
+                NLM_F_CREATE,
+                vec![
+                    Attr::Str(NFTA_CHAIN_TABLE, table_name(interface_name)),
+                    Attr::Str(NFTA_CHAIN_NAME, CHAIN_NAME.to_owned()),
+                    Attr::Nested(
+                        NFTA_CHAIN_HOOK,
+                        vec![
+                            Attr::U32(NFTA_HOOK_HOOKNUM, NF_INET_POST_ROUTING),
+                            Attr::U32(NFTA_HOOK_PRIORITY, NF_IP_PRI_NAT_SRC as u32),
+                        ],
+                    ),
+                    Attr::U32(NFTA_CHAIN_POLICY, NF_ACCEPT),
+                    Attr::Str(NFTA_CHAIN_TYPE, "nat".to_owned()),
+                ],
+            ),
+            // meta iifname "<interface_name>" masquerade
+            NftMessage::new(
+                NFT_MSG_NEWRULE,
+                family,
+                NLM_F_CREATE | NLM_F_APPEND,
+                vec![
+                    Attr::Str(NFTA_RULE_TABLE, table_name(interface_name)),
+                    Attr::Str(NFTA_RULE_CHAIN, CHAIN_NAME.to_owned()),
+                    Attr::Nested(
+                        NFTA_RULE_EXPRESSIONS,
+                        vec![
+                            expr(
+                                "meta",
+                                vec![
+                                    Attr::U32(NFTA_META_DREG, NFT_REG_1),
+                                    Attr::U32(NFTA_META_KEY, NFT_META_IIFNAME),
+                                ],
+                            ),
+                            expr(
+                                "cmp",
+                                vec![
+                                    Attr::U32(NFTA_CMP_SREG, NFT_REG_1),
+                                    Attr::U32(NFTA_CMP_OP, NFT_CMP_EQ),
+                                    Attr::Nested(
+                                        NFTA_CMP_DATA,
+                                        vec![Attr::Bytes(NFTA_DATA_VALUE, name.to_vec())],
+                                    ),
+                                ],
+                            ),
+                            expr("masq", vec![]),
+                        ],
+                    ),
+                ],
+            ),
+        ]);
+    }
+
+    batch.push(NftMessage::batch(NFNL_MSG_BATCH_END));
+    for (seq, message) in batch.iter_mut().enumerate() {
+        message.header.sequence_number = seq as u32;
+        message.finalize();
+    }
+
+    batch
+}
+
+fn expr(name: &str, data: Vec<Attr>) -> Attr {
+    Attr::Nested(
+        NFTA_LIST_ELEM,
+        vec![
+            Attr::Str(NFTA_EXPR_NAME, name.to_owned()),
+            Attr::Nested(NFTA_EXPR_DATA, data),
+        ],
+    )
+}
+
+/// The batch has to reach the kernel in a single datagram for it to be applied as a whole.
+fn serialize(batch: Vec<NetlinkMessage<NftMessage>>) -> Vec<u8> {
+    let mut buf = vec![0; batch.iter().map(NetlinkMessage::buffer_len).sum()];
+    let mut start = 0;
+    for message in batch {
+        let end = start + message.buffer_len();
+        message.serialize(&mut buf[start..end]);
+        start = end;
+    }
+
+    buf
+}
+
+/// Waits until every message in the batch is acknowledged, failing on the first error.
+async fn wait_for_acks(socket: &mut TokioSocket, mut acks: usize) -> io::Result<()> {
+    while acks > 0 {
+        let (buf, _) = socket.recv_from_full().await?;
+
+        let mut msgs = buf.as_slice();
+        while !msgs.is_empty() {
+            let len = NetlinkBuffer::new_checked(msgs)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .length() as usize;
+            let message = NetlinkMessage::<NftMessage>::deserialize(&msgs[..len])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            // An ack is an error message without an error code
+            if let NetlinkPayload::Error(e) = message.payload {
+                if e.code.is_some() {
+                    return Err(e.to_io());
+                }
+                acks = acks.saturating_sub(1);
+            }
+
+            msgs = &msgs[((len + 3) & !3).min(msgs.len())..];
+        }
+    }
+
+    Ok(())
+}
+
+/// A message of the nftables subsystem, a netfilter header followed by its attributes.
+#[derive(Debug)]
+struct NftMessage {
+    message_type: u16,
+    family: u8,
+    // The batch messages carry the subsystem they're for here
+    res_id: u16,
+    attrs: Vec<Attr>,
+}
+
+impl NftMessage {
+    /// An nftables message that asks to be acknowledged.
+    fn new(
+        message_type: u16,
+        family: u8,
+        flags: u16,
+        attrs: Vec<Attr>,
+    ) -> NetlinkMessage<NftMessage> {
+        let message = NftMessage {
+            message_type: (NFNL_SUBSYS_NFTABLES << 8) | message_type,
+            family,
+            res_id: 0,
+            attrs,
+        };
+        Self::message(message, flags | NLM_F_ACK)
+    }
+
+    fn batch(message_type: u16) -> NetlinkMessage<NftMessage> {
+        let message = NftMessage {
+            message_type,
+            family: NFPROTO_UNSPEC,
+            res_id: NFNL_SUBSYS_NFTABLES,
+            attrs: Vec::new(),
+        };
+        Self::message(message, 0)
+    }
+
+    fn message(message: NftMessage, flags: u16) -> NetlinkMessage<NftMessage> {
+        let mut header = NetlinkHeader::default();
+        header.flags = NLM_F_REQUEST | flags;
+        NetlinkMessage::new(header, NetlinkPayload::InnerMessage(message))
+    }
+}
+
+impl NetlinkSerializable for NftMessage {
+    fn message_type(&self) -> u16 {
+        self.message_type
+    }
+
+    fn buffer_len(&self) -> usize {
+        NFGENMSG_LEN + self.attrs.as_slice().buffer_len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8]) {
+        buffer[0] = self.family;
+        buffer[1] = NFNETLINK_V0;
+        buffer[2..NFGENMSG_LEN].copy_from_slice(&self.res_id.to_be_bytes());
+        self.attrs.as_slice().emit(&mut buffer[NFGENMSG_LEN..]);
+    }
+}
+
+// The kernel only sends acks back, which aren't parsed as an `NftMessage`
+impl NetlinkDeserializable for NftMessage {
+    type Error = DecodeError;
+
+    fn deserialize(header: &NetlinkHeader, _: &[u8]) -> Result<Self, Self::Error> {
+        Err(format!("unexpected nftables message {}", header.message_type).into())
+    }
+}
+
+#[derive(Debug)]
+enum Attr {
+    Str(u16, String),
+    // nftables wants its integers in network order
+    U32(u16, u32),
+    Bytes(u16, Vec<u8>),
+    Nested(u16, Vec<Attr>),
+}
+
+impl Nla for Attr {
+    fn value_len(&self) -> usize {
+        match self {
+            Attr::Str(_, value) => value.len() + 1,
+            Attr::U32(..) => 4,
+            Attr::Bytes(_, value) => value.len(),
+            Attr::Nested(_, attrs) => attrs.as_slice().buffer_len(),
+        }
+    }
+
+    fn kind(&self) -> u16 {
+        match self {
+            Attr::Str(kind, _) | Attr::U32(kind, _) | Attr::Bytes(kind, _) => *kind,
+            Attr::Nested(kind, _) => kind | NLA_F_NESTED,
+        }
+    }
+
+    fn emit_value(&self, buffer: &mut [u8]) {
+        match self {
+            Attr::Str(_, value) => {
+                buffer[..value.len()].copy_from_slice(value.as_bytes());
+                buffer[value.len()] = 0;
+            }
+            Attr::U32(_, value) => buffer.copy_from_slice(&value.to_be_bytes()),
+            Attr::Bytes(_, value) => buffer.copy_from_slice(value),
+            Attr::Nested(_, attrs) => attrs.as_slice().emit(buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use connlib_shared::DEFAULT_INTERFACE_NAME;
+    use netlink_packet_core::NLM_F_ACK;
+
+    use super::{
+        masquerade_batch, serialize, table_name, Family, NFNL_MSG_BATCH_BEGIN, NFNL_MSG_BATCH_END,
+    };
+
+    fn message_types(batch: &[u8]) -> Vec<u16> {
+        let mut types = Vec::new();
+        let mut msgs = batch;
+        while !msgs.is_empty() {
+            let len = u32::from_ne_bytes(msgs[0..4].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            types.push(u16::from_ne_bytes(msgs[4..6].try_into().unwrap()));
+            msgs = &msgs[len..];
+        }
+
+        types
+    }
+
+    #[test]
+    fn enabling_replaces_the_table() {
+        let batch = masquerade_batch(Family::Ipv4, "tun-fz-corp", true);
+        let acks = batch
+            .iter()
+            .filter(|message| message.header.flags & NLM_F_ACK != 0)
+            .count();
+
+        assert_eq!(
+            message_types(&serialize(batch)),
+            [
+                NFNL_MSG_BATCH_BEGIN,
+                0xa00, // new table
+                0xa02, // delete table
+                0xa00, // new table
+                0xa03, // new chain
+                0xa06, // new rule
+                NFNL_MSG_BATCH_END
+            ]
+        );
+        assert_eq!(acks, 5);
+    }
+
+    #[test]
+    fn disabling_only_removes_the_table() {
+        let batch = masquerade_batch(Family::Ipv6, "tun-fz-corp", false);
+
+        assert_eq!(
+            message_types(&serialize(batch)),
+            [NFNL_MSG_BATCH_BEGIN, 0xa00, 0xa02, NFNL_MSG_BATCH_END]
+        );
+    }
+
+    #[test]
+    fn tables_are_per_interface() {
+        assert_eq!(table_name(DEFAULT_INTERFACE_NAME), "firezone");
+        assert_eq!(table_name("tun-fz-corp"), "firezone-tun-fz-corp");
+    }
+}
@@ -0,0 +1,1158 @@
+//! In-memory transport, connector and interface, so a client and a gateway [Tunnel] can talk to each other within a single process.
+//!
+//! Nothing here needs root or the network, the packets only go through channels.
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use connlib_shared::{
+    messages::Relay, stats::IceStats, CallbackErrorFacade, Callbacks, Error, Result,
+};
+use futures::{future::BoxFuture, FutureExt};
+use ip_network::IpNetwork;
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::sdp::{sdp_type::RTCSdpType, session_description::RTCSessionDescription},
+};
+
+use crate::{
+    connector::{ConnectionEvents, PeerConnector},
+    device_channel::{DeviceIo, DeviceQueues, IfaceConfig},
+    transport::PeerTransport,
+    ConnId,
+};
+
+const MTU: usize = 1280;
+
+/// One end of a connection between two peers.
+pub(crate) struct MemoryTransport {
+    tx: Mutex<Option<mpsc::UnboundedSender<Bytes>>>,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Bytes>>,
+}
+
+impl MemoryTransport {
+    pub(crate) fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+
+        (
+            MemoryTransport {
+                tx: Mutex::new(Some(a_tx)),
+                rx: tokio::sync::Mutex::new(b_rx),
+            },
+            MemoryTransport {
+                tx: Mutex::new(Some(b_tx)),
+                rx: tokio::sync::Mutex::new(a_rx),
+            },
+        )
+    }
+}
+
+#[async_trait]
+impl PeerTransport for MemoryTransport {
+    async fn send(&self, packet: &Bytes) -> Result<()> {
+        let sent = self
+            .tx
+            .lock()
+            .as_ref()
+            .is_some_and(|tx| tx.send(packet.clone()).is_ok());
+        if !sent {
+            return Err(Error::TransportClosed);
+        }
+
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let Some(packet) = self.rx.lock().await.recv().await else {
+            return Ok(0);
+        };
+        buf[..packet.len()].copy_from_slice(&packet);
+
+        Ok(packet.len())
+    }
+
+    // The other end reads the transport as closed once it got what was already sent
+    async fn close(&self) -> Result<()> {
+        self.tx.lock().take();
+        Ok(())
+    }
+}
+
+/// One end of a connection that was offered.
+struct Offered {
+    conn_id: ConnId,
+    events: ConnectionEvents,
+}
+
+/// Connects the tunnels that share it without webrtc, the offers and answers only say which connection they're for.
+#[derive(Default)]
+pub(crate) struct MemoryConnector {
+    offers: AtomicUsize,
+    /// The connections nobody answered yet, by their offer.
+    offered: Mutex<HashMap<String, Offered>>,
+    /// The connections waiting for the offering end to accept the answer, with the offering and the answering end.
+    answered: Mutex<HashMap<String, (Offered, Offered)>>,
+    /// The ends of the open connections.
+    opened: Mutex<HashMap<ConnId, Arc<MemoryTransport>>>,
+}
+
+fn session_description(sdp_type: RTCSdpType, sdp: String) -> RTCSessionDescription {
+    let mut description = RTCSessionDescription::default();
+    description.sdp_type = sdp_type;
+    description.sdp = sdp;
+    description
+}
+
+impl MemoryConnector {
+    /// Fails the connection offered to `conn_id`, like webrtc does when ICE finds no way through.
+    pub(crate) async fn fail(&self, conn_id: ConnId, error: Error) {
+        let offered = {
+            let mut offered = self.offered.lock();
+            let offer = offered
+                .iter()
+                .find(|(_, o)| o.conn_id == conn_id)
+                .map(|(offer, _)| offer.clone())
+                .expect("a connection offered to conn_id");
+            offered.remove(&offer).unwrap()
+        };
+        (offered.events.failed)(error).await;
+    }
+}
+
+#[async_trait]
+impl PeerConnector for MemoryConnector {
+    async fn offer(
+        &self,
+        conn_id: ConnId,
+        _: Vec<Relay>,
+        events: ConnectionEvents,
+    ) -> Result<RTCSessionDescription> {
+        let offer = self.offers.fetch_add(1, Relaxed).to_string();
+        self.offered
+            .lock()
+            .insert(offer.clone(), Offered { conn_id, events });
+
+        Ok(session_description(RTCSdpType::Offer, offer))
+    }
+
+    async fn answer(
+        &self,
+        conn_id: ConnId,
+        _: Vec<Relay>,
+        offer: RTCSessionDescription,
+        events: ConnectionEvents,
+    ) -> Result<RTCSessionDescription> {
+        let offered = self
+            .offered
+            .lock()
+            .remove(&offer.sdp)
+            .ok_or(Error::ControlProtocolError)?;
+        self.answered
+            .lock()
+            .insert(offer.sdp.clone(), (offered, Offered { conn_id, events }));
+
+        Ok(session_description(RTCSdpType::Answer, offer.sdp))
+    }
+
+    async fn accept_answer(&self, conn_id: ConnId, answer: RTCSessionDescription) -> Result<()> {
+        let (offering, answering) = self
+            .answered
+            .lock()
+            .remove(&answer.sdp)
+            .filter(|(offering, _)| offering.conn_id == conn_id)
+            .ok_or(Error::ControlProtocolError)?;
+
+        let (offering_end, answering_end) = MemoryTransport::pair();
+        let (offering_end, answering_end) = (Arc::new(offering_end), Arc::new(answering_end));
+        {
+            let mut opened = self.opened.lock();
+            opened.insert(offering.conn_id, Arc::clone(&offering_end));
+            opened.insert(answering.conn_id, Arc::clone(&answering_end));
+        }
+        // The answering end has to know about the peer before the packets waiting on the offering end arrive
+        (answering.events.opened)(answering_end).await;
+        (offering.events.opened)(offering_end).await;
+
+        Ok(())
+    }
+
+    async fn add_ice_candidate(&self, _: ConnId, _: RTCIceCandidateInit) -> Result<()> {
+        Ok(())
+    }
+
+    async fn ice_stats(&self, _: ConnId) -> Option<IceStats> {
+        None
+    }
+
+    fn close(&self, conn_id: ConnId) -> BoxFuture<'static, ()> {
+        let transport = self.opened.lock().remove(&conn_id);
+        async move {
+            if let Some(transport) = transport {
+                let _ = transport.close().await;
+            }
+        }
+        .boxed()
+    }
+
+    fn remove(&self, conn_id: ConnId) {
+        self.opened.lock().remove(&conn_id);
+    }
+}
+
+/// The outside of an in-memory interface, what's sent to it is read by the tunnel and the other way around.
+pub(crate) struct MemoryDevice {
+    to_tunnel: mpsc::UnboundedSender<Vec<u8>>,
+    from_tunnel: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+struct MemoryQueue {
+    from_device: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    to_device: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Creates an interface with a single queue.
+pub(crate) fn memory_device() -> (MemoryDevice, DeviceQueues) {
+    let (mut devices, queues) = memory_queues(1);
+    (devices.remove(0), queues)
+}
+
+/// Creates an interface with `count` queues, each one with its own outside.
+pub(crate) fn memory_queues(count: usize) -> (Vec<MemoryDevice>, DeviceQueues) {
+    let (devices, queues) = (0..count)
+        .map(|_| {
+            let (to_tunnel, from_device) = mpsc::unbounded_channel();
+            let (to_device, from_tunnel) = mpsc::unbounded_channel();
+            let queue = MemoryQueue {
+                from_device: Mutex::new(from_device),
+                to_device,
+            };
+
+            (
+                MemoryDevice {
+                    to_tunnel,
+                    from_tunnel,
+                },
+                Arc::new(queue) as Arc<dyn DeviceIo>,
+            )
+        })
+        .unzip();
+
+    (devices, DeviceQueues::new(queues))
+}
+
+impl MemoryDevice {
+    pub(crate) fn send(&self, packet: Vec<u8>) {
+        self.to_tunnel
+            .send(packet)
+            .expect("the tunnel is reading the device");
+    }
+
+    /// The packet the tunnel wrote, if there's one waiting already.
+    pub(crate) fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.from_tunnel.try_recv().ok()
+    }
+
+    /// The next packet the tunnel wrote, `None` if the tunnel is gone.
+    pub(crate) async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.from_tunnel.recv().await
+    }
+}
+
+impl DeviceIo for MemoryQueue {
+    fn poll_read(&self, cx: &mut Context<'_>, out: &mut [u8]) -> Poll<io::Result<usize>> {
+        match ready!(self.from_device.lock().poll_recv(cx)) {
+            Some(packet) => {
+                let len = packet.len().min(out.len());
+                out[..len].copy_from_slice(&packet[..len]);
+                Poll::Ready(Ok(len))
+            }
+            // The outside is gone, like a closed file there's nothing left to read
+            None => Poll::Ready(Ok(0)),
+        }
+    }
+
+    fn write4(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
+    }
+
+    fn write6(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
+    }
+}
+
+impl MemoryQueue {
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.to_device
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+}
+
+/// Keeps the routes instead of setting them anywhere.
+pub(crate) struct MemoryIface {
+    routes: Mutex<Vec<IpNetwork>>,
+    /// Routes that can't be added, like the ones another interface already has.
+    rejected_routes: Mutex<Vec<IpNetwork>>,
+    /// What the interface is set to, the tunnel only sees a change once it refreshes the MTU.
+    configured_mtu: AtomicUsize,
+    mtu: AtomicUsize,
+}
+
+impl Default for MemoryIface {
+    fn default() -> Self {
+        Self {
+            routes: Default::default(),
+            rejected_routes: Default::default(),
+            configured_mtu: AtomicUsize::new(MTU),
+            mtu: AtomicUsize::new(MTU),
+        }
+    }
+}
+
+impl MemoryIface {
+    /// Changes the MTU like someone else setting it on the interface would.
+    #[cfg(test)]
+    pub(crate) fn set_mtu(&self, mtu: usize) {
+        self.configured_mtu.store(mtu, Relaxed);
+    }
+
+    /// Fails to add `route` from now on, as if it went through another interface.
+    #[cfg(test)]
+    pub(crate) fn reject_route(&self, route: IpNetwork) {
+        self.rejected_routes.lock().push(route);
+    }
+}
+
+#[async_trait]
+impl<CB: Callbacks> IfaceConfig<CB> for MemoryIface {
+    fn mtu(&self) -> usize {
+        self.mtu.load(Relaxed)
+    }
+
+    async fn refresh_mtu(&self) -> Result<usize> {
+        let mtu = self.configured_mtu.load(Relaxed);
+        self.mtu.store(mtu, Relaxed);
+        Ok(mtu)
+    }
+
+    async fn add_route(&self, route: IpNetwork, _: &CallbackErrorFacade<CB>) -> Result<()> {
+        if self.rejected_routes.lock().contains(&route) {
+            return Err(Error::RouteConflict(route));
+        }
+        self.routes.lock().push(route);
+        Ok(())
+    }
+
+    async fn remove_route(&self, route: IpNetwork, _: &CallbackErrorFacade<CB>) -> Result<()> {
+        self.routes.lock().retain(|r| *r != route);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use async_trait::async_trait;
+    use boringtun::x25519::StaticSecret;
+    use chrono::{DateTime, Utc};
+    use connlib_shared::{
+        messages::{
+            ClientId, Filter, GatewayId, Interface, ResourceDescription, ResourceDescriptionCidr,
+            ResourceDescriptionDns, ResourceId,
+        },
+        Callbacks, ConnectionRetryPolicy, DnsAddresses, Error, Result, SessionConfig, DNS_SENTINEL,
+    };
+    use domain::{
+        base::{iana::Rtype, Dname, Message, MessageBuilder},
+        rdata::A,
+    };
+    use ip_network::IpNetwork;
+    use parking_lot::Mutex;
+    use pnet_packet::{
+        icmp::{destination_unreachable::IcmpCodes, IcmpPacket, IcmpTypes},
+        icmpv6::{Icmpv6Packet, Icmpv6Types},
+        ipv4::{Ipv4Flags, MutableIpv4Packet},
+        ipv6::MutableIpv6Packet,
+        udp::MutableUdpPacket,
+        Packet,
+    };
+    use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+
+    use super::{memory_device, MemoryConnector, MemoryDevice, MemoryIface};
+    use crate::{
+        dns::Resolver,
+        ip_packet::{udp6_packet, udp_packet, IpPacket, DNS_PORT},
+        ConnId, ControlSignal, PeerConfig, Request, Tunnel, REFRESH_MTU_INTERVAL,
+    };
+
+    const CLIENT_IPV4: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 1);
+    const CLIENT_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 1);
+    const GATEWAY_IPV4: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 2);
+    const GATEWAY_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0, 0, 0, 0, 2);
+    const RESOURCE_IP: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 10);
+    // The address the client hands out for the DNS resource, the gateway sends its packets to what the name resolves to
+    const DNS_RESOURCE_IPV4: Ipv4Addr = Ipv4Addr::new(100, 96, 0, 1);
+    const DNS_RESOURCE_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x8000, 0, 0, 0, 1);
+    const DNS_RESOURCE_NAME: &str = "resource.test";
+    const DNS_RESOURCE_TARGET: Ipv4Addr = Ipv4Addr::new(172, 16, 1, 10);
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const RETRY_POLICY: ConnectionRetryPolicy = ConnectionRetryPolicy {
+        max_attempts: 4,
+        initial_interval: Duration::from_millis(10),
+        max_interval: Duration::from_millis(30),
+    };
+
+    type TestTunnel = Tunnel<TestSignal, TestCallbacks>;
+
+    /// Never answers, only remembers when a connection was asked for.
+    #[derive(Clone, Default)]
+    struct TestSignal {
+        connection_requests: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    #[async_trait]
+    impl ControlSignal for TestSignal {
+        async fn signal_connection_to(
+            &self,
+            _: &ResourceDescription,
+            _: &[GatewayId],
+            _: usize,
+        ) -> Result<()> {
+            self.connection_requests.lock().push(Instant::now());
+            Ok(())
+        }
+
+        async fn signal_ice_candidate(&self, _: RTCIceCandidate, _: ConnId) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TestCallbacks {
+        unreachable_resources: Arc<Mutex<Vec<ResourceId>>>,
+        resource_lists: Arc<Mutex<Vec<Vec<ResourceDescription>>>>,
+    }
+
+    impl Callbacks for TestCallbacks {
+        type Error = std::convert::Infallible;
+
+        fn on_resource_unreachable(
+            &self,
+            resource_id: ResourceId,
+            _: &Error,
+        ) -> std::result::Result<(), Self::Error> {
+            self.unreachable_resources.lock().push(resource_id);
+            Ok(())
+        }
+
+        fn on_update_resources(
+            &self,
+            resource_list: Vec<ResourceDescription>,
+        ) -> std::result::Result<(), Self::Error> {
+            self.resource_lists.lock().push(resource_list);
+            Ok(())
+        }
+    }
+
+    /// Knows the name of the DNS resource and nothing else.
+    struct TestResolver;
+
+    #[async_trait]
+    impl Resolver for TestResolver {
+        async fn resolve(&self, name: &str) -> Result<(Vec<IpAddr>, Duration)> {
+            if name != DNS_RESOURCE_NAME {
+                return Err(Error::ResourceNameUnresolved);
+            }
+
+            Ok((vec![DNS_RESOURCE_TARGET.into()], Duration::from_secs(60)))
+        }
+    }
+
+    async fn tunnel(ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> (Arc<TestTunnel>, MemoryDevice) {
+        tunnel_on(
+            ipv4,
+            ipv6,
+            TestSignal::default(),
+            TestCallbacks::default(),
+            SessionConfig::default(),
+            Arc::default(),
+            Arc::default(),
+        )
+        .await
+    }
+
+    /// A client and a gateway that connect to each other through the same connector.
+    async fn client_and_gateway() -> (
+        (Arc<TestTunnel>, MemoryDevice),
+        (Arc<TestTunnel>, MemoryDevice),
+    ) {
+        let connector = Arc::new(MemoryConnector::default());
+        let client = tunnel_on(
+            CLIENT_IPV4,
+            CLIENT_IPV6,
+            TestSignal::default(),
+            TestCallbacks::default(),
+            SessionConfig::default(),
+            Arc::clone(&connector),
+            Arc::default(),
+        )
+        .await;
+        let gateway = tunnel_on(
+            GATEWAY_IPV4,
+            GATEWAY_IPV6,
+            TestSignal::default(),
+            TestCallbacks::default(),
+            SessionConfig::default(),
+            connector,
+            Arc::default(),
+        )
+        .await;
+
+        (client, gateway)
+    }
+
+    async fn tunnel_on(
+        ipv4: Ipv4Addr,
+        ipv6: Ipv6Addr,
+        signal: TestSignal,
+        callbacks: TestCallbacks,
+        session_config: SessionConfig,
+        connector: Arc<MemoryConnector>,
+        iface: Arc<MemoryIface>,
+    ) -> (Arc<TestTunnel>, MemoryDevice) {
+        let private_key = StaticSecret::random_from_rng(rand_core::OsRng);
+        let tunnel = Arc::new(
+            TestTunnel::new(private_key, signal, callbacks, &session_config)
+                .await
+                .unwrap()
+                .with_connector(connector)
+                .with_resolver(Arc::new(TestResolver)),
+        );
+        let (device, device_queues) = memory_device();
+        let config = Interface {
+            ipv4,
+            ipv6,
+            upstream_dns: vec![],
+        };
+        tunnel.start_device(&config, iface, device_queues).unwrap();
+
+        (tunnel, device)
+    }
+
+    fn resource(filters: Vec<Filter>) -> ResourceDescription {
+        ResourceDescription::Cidr(ResourceDescriptionCidr {
+            id: "73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap(),
+            address: IpNetwork::new(Ipv4Addr::new(172, 16, 0, 0), 24).unwrap(),
+            name: "test".to_owned(),
+            filters,
+        })
+    }
+
+    fn dns_resource() -> ResourceDescription {
+        ResourceDescription::Dns(ResourceDescriptionDns {
+            id: "03000143-e25e-45c7-aafb-144990e57dcd".parse().unwrap(),
+            address: DNS_RESOURCE_NAME.to_owned(),
+            ipv4: DNS_RESOURCE_IPV4,
+            ipv6: DNS_RESOURCE_IPV6,
+            name: "test".to_owned(),
+            records: vec![],
+            filters: vec![],
+        })
+    }
+
+    fn gateway_id() -> GatewayId {
+        "9d4b79f6-1db7-4cb3-a077-712102204d73".parse().unwrap()
+    }
+
+    /// Waits for the client to ask for a connection, returns the reference of the request.
+    async fn connection_requested(client: &TestTunnel) -> usize {
+        let requested = async {
+            while client
+                .control_signaler
+                .connection_requests
+                .lock()
+                .is_empty()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, requested)
+            .await
+            .expect("a connection request before the timeout");
+        client.control_signaler.connection_requests.lock().len()
+    }
+
+    /// Connects the client to the gateway for the resource once the client asked for it.
+    ///
+    /// Goes through the control protocol like the portal would, the connector opens the channel once the client has the answer.
+    async fn connect(
+        client: &Arc<TestTunnel>,
+        gateway: &Arc<TestTunnel>,
+        resource: ResourceDescription,
+        expires_at: DateTime<Utc>,
+    ) {
+        let client_id: ClientId = "f8a9c0d1-3c3e-4d5a-8e27-2f5b0e1f4c6b".parse().unwrap();
+        let reference = connection_requested(client).await;
+
+        let request = client
+            .request_connection(
+                resource.id(),
+                gateway_id(),
+                vec![],
+                Some(reference.to_string()),
+            )
+            .await
+            .unwrap();
+        let Request::NewConnection(request) = request else {
+            panic!("expected a new connection, got {request:?}");
+        };
+        let client_peer = PeerConfig {
+            persistent_keepalive: None,
+            public_key: client.public_key,
+            ips: vec![CLIENT_IPV4.into(), CLIENT_IPV6.into()],
+            preshared_key: request.client_preshared_key,
+        };
+        let answer = gateway
+            .set_peer_connection_request(
+                request.client_rtc_session_description,
+                client_peer,
+                vec![],
+                client_id,
+                expires_at,
+                resource.clone(),
+                None,
+            )
+            .await
+            .unwrap();
+        client
+            .received_offer_response(resource.id(), answer, gateway.public_key)
+            .await
+            .unwrap();
+    }
+
+    /// A query for the A record of `name` sent to `sentinel`.
+    fn dns_query(sentinel: Ipv4Addr, src: (Ipv4Addr, u16), name: &str) -> Vec<u8> {
+        let mut question = MessageBuilder::new_vec().question();
+        let name: Dname<Vec<u8>> = name.parse().unwrap();
+        question.push((name, Rtype::A)).unwrap();
+        let message = question.finish();
+
+        let mut packet = udp_packet(src, (sentinel, DNS_PORT));
+        packet.extend_from_slice(&message);
+        let len = packet.len() as u16;
+        MutableIpv4Packet::new(&mut packet)
+            .unwrap()
+            .set_total_length(len);
+        MutableUdpPacket::new(&mut packet[20..])
+            .unwrap()
+            .set_length(len - 20);
+        packet
+    }
+
+    async fn recv(device: &mut MemoryDevice) -> Vec<u8> {
+        tokio::time::timeout(TIMEOUT, device.recv())
+            .await
+            .expect("a packet before the timeout")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_reaches_cidr_resource() {
+        let ((client, mut client_device), (gateway, mut gateway_device)) =
+            client_and_gateway().await;
+        let resource = resource(vec![]);
+        client.add_resource(resource.clone()).await.unwrap();
+
+        // Waits for the connection and is sent once it's there
+        client_device.send(udp_packet((CLIENT_IPV4, 40000), (RESOURCE_IP, 8080)));
+        connect(
+            &client,
+            &gateway,
+            resource,
+            Utc::now() + chrono::Duration::hours(1),
+        )
+        .await;
+
+        let request = recv(&mut gateway_device).await;
+        let request = IpPacket::new(&request).unwrap();
+        assert_eq!(request.source(), IpAddr::from(CLIENT_IPV4));
+        assert_eq!(request.destination(), IpAddr::from(RESOURCE_IP));
+        assert_eq!(request.as_udp().unwrap().get_destination(), 8080);
+
+        gateway_device.send(udp_packet((RESOURCE_IP, 8080), (CLIENT_IPV4, 40000)));
+        let response = recv(&mut client_device).await;
+        let response = IpPacket::new(&response).unwrap();
+        assert_eq!(response.source(), IpAddr::from(RESOURCE_IP));
+        assert_eq!(response.destination(), IpAddr::from(CLIENT_IPV4));
+
+        let stats = client.stats().await;
+        assert_eq!(stats.peers.len(), 1);
+        assert_eq!(stats.peers[0].traffic.rx_packets, 1);
+    }
+
+    #[tokio::test]
+    async fn filtered_packets_leave_no_translation() {
+        let ((client, mut client_device), (gateway, _gateway_device)) = client_and_gateway().await;
+        let ResourceDescription::Dns(mut resource) = dns_resource() else {
+            unreachable!()
+        };
+        resource.filters = vec![Filter::Icmp];
+        let resource = ResourceDescription::Dns(resource);
+        client.add_resource(resource.clone()).await.unwrap();
+
+        client_device.send(dns_query(
+            DNS_SENTINEL,
+            (CLIENT_IPV4, 40000),
+            DNS_RESOURCE_NAME,
+        ));
+        recv(&mut client_device).await;
+
+        client_device.send(udp_packet((CLIENT_IPV4, 40000), (DNS_RESOURCE_IPV4, 8080)));
+        connect(
+            &client,
+            &gateway,
+            resource,
+            Utc::now() + chrono::Duration::hours(1),
+        )
+        .await;
+
+        // The gateway holds the packet until it resolved the name, then it prohibits it
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(reply.payload()).unwrap();
+        assert_eq!(
+            icmp.get_icmp_code(),
+            IcmpCodes::CommunicationAdministrativelyProhibited
+        );
+        assert!(gateway
+            .peers_by_ip
+            .peers()
+            .iter()
+            .all(|peer| peer.translated_resource_addresses.read().is_empty()));
+    }
+
+    #[tokio::test]
+    async fn filtered_packet_is_answered_with_prohibited() {
+        let ((client, mut client_device), (gateway, _gateway_device)) = client_and_gateway().await;
+        let resource = resource(vec![Filter::Icmp]);
+        client.add_resource(resource.clone()).await.unwrap();
+
+        client_device.send(udp_packet((CLIENT_IPV4, 40000), (RESOURCE_IP, 8080)));
+        connect(
+            &client,
+            &gateway,
+            resource,
+            Utc::now() + chrono::Duration::hours(1),
+        )
+        .await;
+
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(reply.payload()).unwrap();
+        assert_eq!(reply.source(), IpAddr::from(RESOURCE_IP));
+        assert_eq!(reply.destination(), IpAddr::from(CLIENT_IPV4));
+        assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(
+            icmp.get_icmp_code(),
+            IcmpCodes::CommunicationAdministrativelyProhibited
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_packet_is_answered_with_fragmentation_needed() {
+        let (_client, mut client_device) = tunnel(CLIENT_IPV4, CLIENT_IPV6).await;
+
+        let mut packet = udp_packet((CLIENT_IPV4, 40000), (RESOURCE_IP, 8080));
+        packet.resize(1400, 0);
+        let mut ip = MutableIpv4Packet::new(&mut packet).unwrap();
+        ip.set_total_length(1400);
+        ip.set_flags(Ipv4Flags::DontFragment);
+        client_device.send(packet);
+
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(reply.payload()).unwrap();
+        assert_eq!(reply.destination(), IpAddr::from(CLIENT_IPV4));
+        assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(
+            icmp.get_icmp_code(),
+            IcmpCodes::FragmentationRequiredAndDFFlagSet
+        );
+        assert_eq!(&icmp.payload()[2..4], &1280u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn oversized_ipv6_packet_is_answered_with_packet_too_big() {
+        let (_client, mut client_device) = tunnel(CLIENT_IPV4, CLIENT_IPV6).await;
+        let resource_ipv6 = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 10);
+
+        // IPv6 packets are never fragmented on the way, only by whoever sends them
+        let mut packet = udp6_packet((CLIENT_IPV6, 40000), (resource_ipv6, 8080));
+        packet.resize(1400, 0);
+        MutableIpv6Packet::new(&mut packet)
+            .unwrap()
+            .set_payload_length(1400 - 40);
+        client_device.send(packet);
+
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = Icmpv6Packet::new(reply.payload()).unwrap();
+        assert_eq!(reply.source(), IpAddr::from(resource_ipv6));
+        assert_eq!(reply.destination(), IpAddr::from(CLIENT_IPV6));
+        assert_eq!(icmp.get_icmpv6_type(), Icmpv6Types::PacketTooBig);
+        assert_eq!(&icmp.payload()[..4], &1280u32.to_be_bytes());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lowered_mtu_is_picked_up() {
+        let iface = Arc::new(MemoryIface::default());
+        let (_client, mut client_device) = tunnel_on(
+            CLIENT_IPV4,
+            CLIENT_IPV6,
+            TestSignal::default(),
+            TestCallbacks::default(),
+            SessionConfig::default(),
+            Arc::default(),
+            Arc::clone(&iface),
+        )
+        .await;
+
+        // Packets only ever get smaller, so reading them can't tell us about it
+        iface.set_mtu(1200);
+        tokio::time::sleep(REFRESH_MTU_INTERVAL * 2).await;
+        let mut packet = udp_packet((CLIENT_IPV4, 40000), (RESOURCE_IP, 8080));
+        packet.resize(1250, 0);
+        let mut ip = MutableIpv4Packet::new(&mut packet).unwrap();
+        ip.set_total_length(1250);
+        ip.set_flags(Ipv4Flags::DontFragment);
+        client_device.send(packet);
+
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(reply.payload()).unwrap();
+        assert_eq!(
+            icmp.get_icmp_code(),
+            IcmpCodes::FragmentationRequiredAndDFFlagSet
+        );
+        assert_eq!(&icmp.payload()[2..4], &1200u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn connection_attempts_back_off_and_give_up() {
+        let signal = TestSignal::default();
+        let callbacks = TestCallbacks::default();
+        let session_config = SessionConfig {
+            connection_retry_policy: Some(RETRY_POLICY),
+            ..Default::default()
+        };
+        let (client, mut client_device) = tunnel_on(
+            CLIENT_IPV4,
+            CLIENT_IPV6,
+            signal.clone(),
+            callbacks.clone(),
+            session_config,
+            Arc::default(),
+            Arc::default(),
+        )
+        .await;
+        let resource = resource(vec![]);
+        client.add_resource(resource.clone()).await.unwrap();
+
+        client_device.send(udp_packet((CLIENT_IPV4, 40000), (RESOURCE_IP, 8080)));
+
+        // Nobody answers, so the packet waiting for the connection is answered once we give up
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(reply.payload()).unwrap();
+        assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(icmp.get_icmp_code(), IcmpCodes::DestinationHostUnreachable);
+
+        let requests = signal.connection_requests.lock().clone();
+        assert_eq!(requests.len(), RETRY_POLICY.max_attempts);
+        let expected_intervals = [10, 20, 30].map(Duration::from_millis);
+        for (requests, expected) in requests.windows(2).zip(expected_intervals) {
+            assert!(requests[1] - requests[0] >= expected);
+        }
+        assert_eq!(*callbacks.unreachable_resources.lock(), [resource.id()]);
+        assert!(client.awaiting_connection.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn packet_outside_the_resources_is_answered_with_host_unreachable() {
+        let (client, mut client_device) = tunnel(CLIENT_IPV4, CLIENT_IPV6).await;
+        client.add_resource(resource(vec![])).await.unwrap();
+
+        let outside = Ipv4Addr::new(10, 0, 0, 1);
+        client_device.send(udp_packet((CLIENT_IPV4, 40000), (outside, 8080)));
+
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(reply.payload()).unwrap();
+        assert_eq!(reply.source(), IpAddr::from(outside));
+        assert_eq!(reply.destination(), IpAddr::from(CLIENT_IPV4));
+        assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(icmp.get_icmp_code(), IcmpCodes::DestinationHostUnreachable);
+        assert!(client
+            .control_signaler
+            .connection_requests
+            .lock()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn channel_failing_to_open_answers_the_waiting_packets() {
+        let callbacks = TestCallbacks::default();
+        let connector = Arc::new(MemoryConnector::default());
+        let (client, mut client_device) = tunnel_on(
+            CLIENT_IPV4,
+            CLIENT_IPV6,
+            TestSignal::default(),
+            callbacks.clone(),
+            SessionConfig::default(),
+            Arc::clone(&connector),
+            Arc::default(),
+        )
+        .await;
+        let resource = resource(vec![]);
+        client.add_resource(resource.clone()).await.unwrap();
+
+        client_device.send(udp_packet((CLIENT_IPV4, 40000), (RESOURCE_IP, 8080)));
+        let reference = connection_requested(&client).await;
+        client
+            .request_connection(
+                resource.id(),
+                gateway_id(),
+                vec![],
+                Some(reference.to_string()),
+            )
+            .await
+            .unwrap();
+
+        // ICE found no way to the gateway
+        connector
+            .fail(gateway_id().into(), Error::IceConnectionFailed)
+            .await;
+
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(reply.payload()).unwrap();
+        assert_eq!(reply.source(), IpAddr::from(RESOURCE_IP));
+        assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+        assert_eq!(icmp.get_icmp_code(), IcmpCodes::DestinationHostUnreachable);
+        assert_eq!(*callbacks.unreachable_resources.lock(), [resource.id()]);
+        assert!(client.peers_by_ip.peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_resource_disconnects_the_client() {
+        let ((client, client_device), (gateway, _gateway_device)) = client_and_gateway().await;
+        let resource = resource(vec![]);
+        client.add_resource(resource.clone()).await.unwrap();
+
+        client_device.send(udp_packet((CLIENT_IPV4, 40000), (RESOURCE_IP, 8080)));
+        connect(
+            &client,
+            &gateway,
+            resource,
+            Utc::now() - chrono::Duration::seconds(1),
+        )
+        .await;
+
+        // Expired resources are only checked every so often
+        let disconnected = async {
+            while !client.peers_by_ip.peers().is_empty() || !gateway.peers_by_ip.peers().is_empty()
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, disconnected)
+            .await
+            .expect("both peers removed before the timeout");
+    }
+
+    #[tokio::test]
+    async fn moved_resource_swaps_its_routes() {
+        let iface = Arc::new(MemoryIface::default());
+        let callbacks = TestCallbacks::default();
+        let (client, _client_device) = tunnel_on(
+            CLIENT_IPV4,
+            CLIENT_IPV6,
+            TestSignal::default(),
+            callbacks.clone(),
+            SessionConfig::default(),
+            Arc::default(),
+            Arc::clone(&iface),
+        )
+        .await;
+        let resource = resource(vec![]);
+        client.add_resource(resource.clone()).await.unwrap();
+        let ResourceDescription::Cidr(mut moved) = resource.clone() else {
+            unreachable!()
+        };
+        moved.address = "10.0.0.0/24".parse().unwrap();
+
+        client
+            .update_resource(ResourceDescription::Cidr(moved.clone()))
+            .await
+            .unwrap();
+
+        let routes = iface.routes.lock().clone();
+        assert!(routes.contains(&moved.address));
+        assert!(!routes.contains(&resource.ips()[0]));
+        assert_eq!(
+            callbacks.resource_lists.lock().last().unwrap(),
+            &[ResourceDescription::Cidr(moved)]
+        );
+    }
+
+    #[tokio::test]
+    async fn resource_that_cant_be_moved_is_kept() {
+        let iface = Arc::new(MemoryIface::default());
+        let callbacks = TestCallbacks::default();
+        let (client, _client_device) = tunnel_on(
+            CLIENT_IPV4,
+            CLIENT_IPV6,
+            TestSignal::default(),
+            callbacks.clone(),
+            SessionConfig::default(),
+            Arc::default(),
+            Arc::clone(&iface),
+        )
+        .await;
+        let resource = resource(vec![]);
+        client.add_resource(resource.clone()).await.unwrap();
+        let ResourceDescription::Cidr(mut moved) = resource.clone() else {
+            unreachable!()
+        };
+        moved.address = "10.0.0.0/24".parse().unwrap();
+        iface.reject_route(moved.address);
+
+        let result = client
+            .update_resource(ResourceDescription::Cidr(moved))
+            .await;
+
+        assert!(matches!(result, Err(Error::InvalidResource)));
+        assert!(iface.routes.lock().contains(&resource.ips()[0]));
+        assert_eq!(client.resources.read().resource_list(), [resource.clone()]);
+        // The apps are told about the outcome either way
+        assert_eq!(callbacks.resource_lists.lock().len(), 2);
+        assert_eq!(callbacks.resource_lists.lock().last().unwrap(), &[resource]);
+    }
+
+    #[tokio::test]
+    async fn client_reaches_dns_resource_through_the_sentinel() {
+        let ((client, mut client_device), (gateway, mut gateway_device)) =
+            client_and_gateway().await;
+        let resource = dns_resource();
+        client.add_resource(resource.clone()).await.unwrap();
+
+        client_device.send(dns_query(
+            DNS_SENTINEL,
+            (CLIENT_IPV4, 40000),
+            DNS_RESOURCE_NAME,
+        ));
+        let response = recv(&mut client_device).await;
+        let response = IpPacket::new(&response).unwrap();
+        let response = response.as_udp().unwrap();
+        let message = Message::from_slice(response.payload()).unwrap();
+        let answer = message
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(answer.data().addr(), DNS_RESOURCE_IPV4);
+
+        client_device.send(udp_packet((CLIENT_IPV4, 40000), (DNS_RESOURCE_IPV4, 8080)));
+        connect(
+            &client,
+            &gateway,
+            resource,
+            Utc::now() + chrono::Duration::hours(1),
+        )
+        .await;
+
+        // The gateway holds the packet until it resolved the name
+        let request = recv(&mut gateway_device).await;
+        let request = IpPacket::new(&request).unwrap();
+        assert_eq!(request.source(), IpAddr::from(CLIENT_IPV4));
+        assert_eq!(request.destination(), IpAddr::from(DNS_RESOURCE_TARGET));
+
+        // The response comes from the address the client resolved
+        gateway_device.send(udp_packet(
+            (DNS_RESOURCE_TARGET, 8080),
+            (CLIENT_IPV4, 40000),
+        ));
+        let response = recv(&mut client_device).await;
+        let response = IpPacket::new(&response).unwrap();
+        assert_eq!(response.source(), IpAddr::from(DNS_RESOURCE_IPV4));
+        assert_eq!(response.destination(), IpAddr::from(CLIENT_IPV4));
+    }
+
+    #[tokio::test]
+    async fn dns_is_answered_on_the_sentinel_of_the_session() {
+        let dns_addresses = DnsAddresses::nth(1).unwrap();
+        let session_config = SessionConfig {
+            dns_addresses: Some(dns_addresses),
+            ..Default::default()
+        };
+        let (client, mut client_device) = tunnel_on(
+            CLIENT_IPV4,
+            CLIENT_IPV6,
+            TestSignal::default(),
+            TestCallbacks::default(),
+            session_config,
+            Arc::default(),
+            Arc::default(),
+        )
+        .await;
+        client.add_resource(dns_resource()).await.unwrap();
+
+        // The default sentinel belongs to another session
+        client_device.send(dns_query(
+            DNS_SENTINEL,
+            (CLIENT_IPV4, 40000),
+            DNS_RESOURCE_NAME,
+        ));
+        let reply = recv(&mut client_device).await;
+        let reply = IpPacket::new(&reply).unwrap();
+        let icmp = IcmpPacket::new(reply.payload()).unwrap();
+        assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+
+        client_device.send(dns_query(
+            dns_addresses.sentinel(),
+            (CLIENT_IPV4, 40000),
+            DNS_RESOURCE_NAME,
+        ));
+        let response = recv(&mut client_device).await;
+        let response = IpPacket::new(&response).unwrap();
+        assert_eq!(response.source(), IpAddr::from(dns_addresses.sentinel()));
+        let message = Message::from_slice(response.as_udp().unwrap().payload()).unwrap();
+        let answer = message
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(answer.data().addr(), DNS_RESOURCE_IPV4);
+    }
+}
@@ -0,0 +1,39 @@
+//! How big the packets read from the interface can be so they still fit in a single packet on the network.
+//!
+//! Every packet goes through wireguard, then through a webrtc data channel, which is SCTP over
+//! DTLS over UDP, relayed over TURN when there's no direct path. We can't discover the path's MTU
+//! from inside the tunnel, so we assume the usual ethernet MTU and take out the worst case overhead.
+
+// Header, counter and tag of a wireguard data packet
+const WIREGUARD_OVERHEAD: usize = 32;
+// Common header and a DATA chunk header
+const SCTP_OVERHEAD: usize = 12 + 16;
+// Record header, explicit nonce and tag of AES-GCM, the biggest of the ciphers webrtc negotiates
+const DTLS_OVERHEAD: usize = 13 + 8 + 16;
+// A Send indication with its XOR-PEER-ADDRESS for an IPv6 peer, used when relayed without a channel
+const TURN_OVERHEAD: usize = 20 + 24 + 4;
+const UDP_OVERHEAD: usize = 8;
+// Assuming IPv6 between us and the peer or relay, which has the bigger header
+const IP_OVERHEAD: usize = 40;
+
+const TUNNEL_OVERHEAD: usize =
+    WIREGUARD_OVERHEAD + SCTP_OVERHEAD + DTLS_OVERHEAD + TURN_OVERHEAD + UDP_OVERHEAD + IP_OVERHEAD;
+
+// The MTU of most networks, we have nothing better to go on
+const PATH_MTU: usize = 1500;
+
+/// Biggest packet from the interface that fits through the tunnel without being split.
+pub(crate) fn effective_mtu(iface_mtu: usize) -> usize {
+    iface_mtu.min(PATH_MTU - TUNNEL_OVERHEAD)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{effective_mtu, PATH_MTU, TUNNEL_OVERHEAD};
+
+    #[test]
+    fn effective_mtu_leaves_room_for_the_tunnel() {
+        assert_eq!(effective_mtu(1500) + TUNNEL_OVERHEAD, PATH_MTU);
+        assert_eq!(effective_mtu(1200), 1200);
+    }
+}
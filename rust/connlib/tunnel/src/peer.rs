@@ -1,88 +1,208 @@
-use std::{collections::HashMap, net::IpAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use boringtun::noise::{Tunn, TunnResult};
+use boringtun::noise::{errors::WireGuardError, Tunn, TunnResult};
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use connlib_shared::{
     messages::{ResourceDescription, ResourceId},
+    stats::{DestinationTraffic, PeerStats, TrafficStats},
     Callbacks, Error, Result,
 };
 use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
+use itertools::Itertools;
 use parking_lot::{Mutex, RwLock};
-use webrtc::data::data_channel::DataChannel;
 
-use crate::{ip_packet::MutableIpPacket, resource_table::ResourceTable, ConnId};
+use crate::{
+    buffer_pool::BufferPool, ip_packet::MutableIpPacket, pending_packets::PendingPackets,
+    resource_table::ResourceTable, transport::PeerTransport, ConnId,
+};
 
 use super::PeerConfig;
 
 type ExpiryingResource = (ResourceDescription, DateTime<Utc>);
 
+// Translations that carried no traffic in either direction for this long are forgotten.
+const TRANSLATION_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A resource that had packets translated to one of the real addresses its name resolves to.
+#[derive(Debug)]
+pub(crate) struct TranslatedResource {
+    pub resource: ResourceDescription,
+    // Milliseconds since the peer was created, atomic so that marking it as used only takes a read lock.
+    last_used: AtomicU64,
+}
+
+/// Bytes and packets going one way, atomic so that counting a packet takes no lock.
+#[derive(Debug, Default)]
+struct Counter {
+    bytes: AtomicU64,
+    packets: AtomicU64,
+}
+
+impl Counter {
+    fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> (u64, u64) {
+        (
+            self.bytes.load(Ordering::Relaxed),
+            self.packets.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerTraffic {
+    total: Counter,
+    // Only gateways know which resource, and which of its addresses, a packet is for.
+    // The map is only written the first time a destination has traffic, every other packet just reads it.
+    destinations: RwLock<HashMap<(ResourceId, IpAddr), Counter>>,
+}
+
+impl PeerTraffic {
+    fn record(&self, destination: Option<(ResourceId, IpAddr)>, bytes: usize) {
+        self.total.add(bytes);
+        let Some(destination) = destination else {
+            return;
+        };
+
+        if let Some(counter) = self.destinations.read().get(&destination) {
+            counter.add(bytes);
+            return;
+        }
+        self.destinations
+            .write()
+            .entry(destination)
+            .or_default()
+            .add(bytes);
+    }
+}
+
+/// A peer's state, shared by the task reading from it and the ones sending to it.
+///
+/// Everything but the wireguard session is split by direction or read without an exclusive lock.
 pub(crate) struct Peer {
+    // Only held around the calls to boringtun, the packets are in pooled buffers outside of it.
+    // The timers and the stats never make packets wait for it, see `update_timers`.
+    // TODO: Packets going each way still wait on each other for the cryptography, boringtun 0.6 takes `&mut Tunn`
+    // for both and keeps its per-direction `Session` private. Splitting that needs a boringtun that hands them out.
     pub tunnel: Mutex<Tunn>,
+    // The handshake age and the round trip time boringtun gave us on the last timer tick.
+    session_stats: Mutex<(Option<Duration>, Option<u32>)>,
+    // Set when the last timer tick was skipped because packets were using the session.
+    timers_deferred: AtomicBool,
     pub index: u32,
     pub allowed_ips: RwLock<IpNetworkTable<()>>,
-    pub channel: Arc<DataChannel>,
+    pub transport: Arc<dyn PeerTransport>,
     pub conn_id: ConnId,
     pub resources: Option<RwLock<ResourceTable<ExpiryingResource>>>,
-    // Here we store the address that we obtained for the resource that the peer corresponds to.
-    // This can have the following problem:
-    // 1. Peer sends packet to address.com and it resolves to 1.1.1.1
-    // 2. Now Peer sends another packet to address.com but it resolves to 2.2.2.2
-    // 3. We receive an outstanding response(or push) from 1.1.1.1
-    // This response(or push) is ignored, since we store only the last.
-    // so, TODO: store multiple ips and expire them.
-    // Note that this case is quite an unlikely edge case so I wouldn't prioritize this fix
+    // Here we store the real addresses that we obtained for the resources of the peer,
+    // every address a resource was sent to is kept until it's idle so that responses (or pushes)
+    // from a previously resolved address still make it back, e.g. with round-robin DNS.
+    // We keep the whole resource and not just its id since names matched by a wildcard resource share its id.
+    // Many names can resolve to the same address, in that case we translate back to the one used last
+    // since we don't keep track of flows.
     // TODO: Also check if there's any case where we want to talk to ipv4 and ipv6 from the same peer.
-    pub translated_resource_addresses: RwLock<HashMap<IpAddr, ResourceId>>,
-}
-
-// TODO: For now we only use these fields with debug
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub(crate) struct PeerStats {
-    pub index: u32,
-    pub allowed_ips: Vec<IpNetwork>,
-    pub conn_id: ConnId,
-    pub dns_resources: HashMap<String, ExpiryingResource>,
-    pub network_resources: HashMap<IpNetwork, ExpiryingResource>,
-    pub translated_resource_addresses: HashMap<IpAddr, ResourceId>,
+    pub translated_resource_addresses: RwLock<HashMap<IpAddr, Vec<TranslatedResource>>>,
+    // Packets the peer sent to DNS resources whose name we were still resolving, by name.
+    // Sent by the peer's handler once the name is resolved.
+    pub unresolved_packets: PendingPackets<String>,
+    // What the peer sent us and what we sent to the peer.
+    rx_traffic: PeerTraffic,
+    tx_traffic: PeerTraffic,
+    created_at: Instant,
 }
 
-#[derive(Debug)]
-pub(crate) struct EncapsulatedPacket<'a> {
+pub(crate) struct EncapsulatedPacket {
     pub index: u32,
     pub conn_id: ConnId,
-    pub channel: Arc<DataChannel>,
-    pub encapsulate_result: TunnResult<'a>,
+    pub transport: Arc<dyn PeerTransport>,
+    /// The packet to send to the peer, `None` if there's nothing to send.
+    pub encapsulate_result: std::result::Result<Option<Bytes>, WireGuardError>,
 }
 
 impl Peer {
+    /// The stats of the peer, without the state of its ICE connection which the peer doesn't know about.
     pub(crate) fn stats(&self) -> PeerStats {
-        let (network_resources, dns_resources) = self.resources.as_ref().map_or_else(
-            || (HashMap::new(), HashMap::new()),
-            |resources| {
-                let resources = resources.read();
-                (resources.network_resources(), resources.dns_resources())
-            },
-        );
+        let resources = self.resources.as_ref().map_or_else(Vec::new, |resources| {
+            resources
+                .read()
+                .values()
+                .map(|(resource, _)| resource.id())
+                .unique()
+                .collect()
+        });
         let allowed_ips = self.allowed_ips.read().iter().map(|(ip, _)| ip).collect();
-        let translated_resource_addresses = self.translated_resource_addresses.read().clone();
+        let (handshake_age, rtt_ms) = *self.session_stats.lock();
+        let (rx_bytes, rx_packets) = self.rx_traffic.total.get();
+        let (tx_bytes, tx_packets) = self.tx_traffic.total.get();
+        let traffic = TrafficStats {
+            rx_bytes,
+            rx_packets,
+            tx_bytes,
+            tx_packets,
+        };
         PeerStats {
             index: self.index,
-            allowed_ips,
             conn_id: self.conn_id,
-            dns_resources,
-            network_resources,
-            translated_resource_addresses,
+            allowed_ips,
+            resources,
+            handshake_age_secs: handshake_age.map(|age| age.as_secs()),
+            rtt_ms,
+            traffic,
+            resource_traffic: self.resource_traffic(),
+            ice: None,
+        }
+    }
+
+    /// The traffic of the peer to each address of its resources, only gateways have any.
+    pub(crate) fn resource_traffic(&self) -> Vec<DestinationTraffic> {
+        let mut resource_traffic: HashMap<(ResourceId, IpAddr), TrafficStats> = HashMap::new();
+        for (destination, counter) in self.rx_traffic.destinations.read().iter() {
+            let traffic = resource_traffic.entry(*destination).or_default();
+            (traffic.rx_bytes, traffic.rx_packets) = counter.get();
+        }
+        for (destination, counter) in self.tx_traffic.destinations.read().iter() {
+            let traffic = resource_traffic.entry(*destination).or_default();
+            (traffic.tx_bytes, traffic.tx_packets) = counter.get();
         }
+
+        resource_traffic
+            .into_iter()
+            .map(|((resource_id, destination), traffic)| DestinationTraffic {
+                resource_id,
+                destination,
+                traffic,
+            })
+            .collect()
+    }
+
+    /// Counts a packet the peer sent to us, `destination` is the resource and address the packet was for, if known.
+    pub(crate) fn record_rx(&self, destination: Option<(ResourceId, IpAddr)>, bytes: usize) {
+        self.rx_traffic.record(destination, bytes);
+    }
+
+    /// Counts a packet we sent to the peer, `destination` is the resource and address the packet came from, if known.
+    pub(crate) fn record_tx(&self, destination: Option<(ResourceId, IpAddr)>, bytes: usize) {
+        self.tx_traffic.record(destination, bytes);
     }
 
     #[inline(always)]
     pub(crate) async fn send_infallible<CB: Callbacks>(&self, data: Bytes, callbacks: &CB) {
-        if let Err(e) = self.channel.write(&Bytes::copy_from_slice(&data)).await {
+        if let Err(e) = self.transport.send(&data).await {
             tracing::error!("Couldn't send packet to connected peer: {e}");
-            let _ = callbacks.on_error(&e.into());
+            let _ = callbacks.on_error(&e);
         }
     }
 
@@ -90,7 +210,7 @@ impl Peer {
         tunnel: Tunn,
         index: u32,
         config: &PeerConfig,
-        channel: Arc<DataChannel>,
+        transport: Arc<dyn PeerTransport>,
         conn_id: ConnId,
         resource: Option<(ResourceDescription, DateTime<Utc>)>,
     ) -> Self {
@@ -98,7 +218,7 @@ impl Peer {
             Mutex::new(tunnel),
             index,
             config.ips.clone(),
-            channel,
+            transport,
             conn_id,
             resource,
         )
@@ -108,7 +228,7 @@ impl Peer {
         tunnel: Mutex<Tunn>,
         index: u32,
         ips: Vec<IpNetwork>,
-        channel: Arc<DataChannel>,
+        transport: Arc<dyn PeerTransport>,
         conn_id: ConnId,
         resource: Option<(ResourceDescription, DateTime<Utc>)>,
     ) -> Peer {
@@ -124,33 +244,79 @@ impl Peer {
         });
         Peer {
             tunnel,
+            session_stats: Default::default(),
+            timers_deferred: Default::default(),
             index,
             allowed_ips,
-            channel,
+            transport,
             conn_id,
             resources,
             translated_resource_addresses: Default::default(),
+            unresolved_packets: Default::default(),
+            rx_traffic: Default::default(),
+            tx_traffic: Default::default(),
+            created_at: Instant::now(),
         }
     }
 
+    fn timestamp(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.created_at).as_millis() as u64
+    }
+
+    /// Gets the resource whose name resolved to the address, if any, and marks the translation as used.
     pub(crate) fn get_translation(&self, ip: IpAddr) -> Option<ResourceDescription> {
-        let id = self.translated_resource_addresses.read().get(&ip).cloned();
-        self.resources.as_ref().and_then(|resources| {
-            id.and_then(|id| resources.read().get_by_id(&id).map(|r| r.0.clone()))
-        })
+        self.get_translation_at(ip, Instant::now())
+    }
+
+    fn get_translation_at(&self, ip: IpAddr, now: Instant) -> Option<ResourceDescription> {
+        // Same lock order as `expire_resources`
+        let resources = self.resources.as_ref()?.read();
+        let translated_resource_addresses = self.translated_resource_addresses.read();
+        let translation = translated_resource_addresses
+            .get(&ip)?
+            .iter()
+            .filter(|t| resources.get_by_id(&t.resource.id()).is_some())
+            .max_by_key(|t| t.last_used.load(Ordering::Relaxed))?;
+        translation
+            .last_used
+            .fetch_max(self.timestamp(now), Ordering::Relaxed);
+        Some(translation.resource.clone())
     }
 
     pub(crate) fn add_allowed_ip(&self, ip: IpNetwork) {
         self.allowed_ips.write().insert(ip, ());
     }
 
+    pub(crate) fn remove_allowed_ip(&self, ip: IpNetwork) {
+        self.allowed_ips.write().remove(ip);
+    }
+
+    /// Tells you if the peer is no longer allowed to carry traffic for any ip
+    pub(crate) fn has_no_allowed_ips(&self) -> bool {
+        self.allowed_ips.read().iter().next().is_none()
+    }
+
+    /// Ticks the session's timers, and stores the stats for [Peer::stats].
+    ///
+    /// If packets are using the session the tick is skipped, the timers can wait for the next one but not for two.
     pub(crate) fn update_timers<'a>(&self, dst: &'a mut [u8]) -> TunnResult<'a> {
-        self.tunnel.lock().update_timers(dst)
+        let mut tunnel = match self.tunnel.try_lock() {
+            Some(tunnel) => tunnel,
+            None if !self.timers_deferred.swap(true, Ordering::Relaxed) => {
+                return TunnResult::Done;
+            }
+            None => self.tunnel.lock(),
+        };
+        self.timers_deferred.store(false, Ordering::Relaxed);
+
+        let result = tunnel.update_timers(dst);
+        let (handshake_age, _, _, _, rtt_ms) = tunnel.stats();
+        *self.session_stats.lock() = (handshake_age, rtt_ms);
+        result
     }
 
     pub(crate) async fn shutdown(&self) -> Result<()> {
-        self.channel.close().await?;
-        Ok(())
+        self.transport.close().await
     }
 
     pub(crate) fn is_emptied(&self) -> bool {
@@ -172,10 +338,25 @@ impl Peer {
                 let mut translated_resource_addresses = self.translated_resource_addresses.write();
                 for r in expire_resources {
                     resources.cleanup_resource(&r);
-                    translated_resource_addresses.retain(|_, &mut i| r.0.id() != i);
+                    for translations in translated_resource_addresses.values_mut() {
+                        translations.retain(|t| r.0.id() != t.resource.id());
+                    }
                 }
             }
         }
+
+        self.expire_idle_translations(Instant::now());
+    }
+
+    fn expire_idle_translations(&self, now: Instant) {
+        let now = self.timestamp(now);
+        let idle_timeout = TRANSLATION_IDLE_TIMEOUT.as_millis() as u64;
+        let mut translated_resource_addresses = self.translated_resource_addresses.write();
+        for translations in translated_resource_addresses.values_mut() {
+            translations
+                .retain(|t| now.saturating_sub(t.last_used.load(Ordering::Relaxed)) < idle_timeout);
+        }
+        translated_resource_addresses.retain(|_, translations| !translations.is_empty());
     }
 
     pub(crate) fn add_resource(&self, resource: ResourceDescription, expires_at: DateTime<Utc>) {
@@ -184,25 +365,72 @@ impl Peer {
         }
     }
 
+    /// Allows the peer to reach a name it resolved through a wildcard resource, the wildcard resource must already be added.
+    pub(crate) fn add_resource_match(
+        &self,
+        resource: ResourceDescription,
+        expires_at: DateTime<Utc>,
+    ) {
+        if let Some(resources) = &self.resources {
+            if !resources.write().insert_match((resource, expires_at)) {
+                tracing::warn!(
+                    "client tried to add a wildcard match that conflicts with its resources"
+                );
+            }
+        }
+    }
+
     pub(crate) fn is_allowed(&self, addr: IpAddr) -> bool {
         self.allowed_ips.read().longest_match(addr).is_some()
     }
 
-    pub(crate) fn update_translated_resource_address(&self, id: ResourceId, addr: IpAddr) {
-        self.translated_resource_addresses.write().insert(addr, id);
+    pub(crate) fn update_translated_resource_address(
+        &self,
+        resource: ResourceDescription,
+        addr: IpAddr,
+    ) {
+        let now = self.timestamp(Instant::now());
+        let mark_used = |translations: &[TranslatedResource]| {
+            translations
+                .iter()
+                .find(|t| t.resource == resource)
+                .map(|t| t.last_used.fetch_max(now, Ordering::Relaxed))
+                .is_some()
+        };
+        let known = self
+            .translated_resource_addresses
+            .read()
+            .get(&addr)
+            .is_some_and(|translations| mark_used(translations));
+        if known {
+            return;
+        }
+
+        let mut translated_resource_addresses = self.translated_resource_addresses.write();
+        let translations = translated_resource_addresses.entry(addr).or_default();
+        // Someone else could have added it while we weren't holding the lock
+        if !mark_used(translations) {
+            translations.push(TranslatedResource {
+                resource,
+                last_used: AtomicU64::new(now),
+            });
+        }
     }
 
-    pub(crate) fn encapsulate<'a>(
+    pub(crate) fn encapsulate(
         &self,
-        src: &'a mut [u8],
-        dst: &'a mut [u8],
-    ) -> Result<EncapsulatedPacket<'a>> {
+        src: &mut [u8],
+        buffers: &mut BufferPool,
+    ) -> Result<EncapsulatedPacket> {
+        let len = src.len();
         let Some(mut packet) = MutableIpPacket::new(src) else {
             debug_assert!(false, "Got non-ip packet from the tunnel interface");
             tracing::error!("Developer error: we should never see a packet through the tunnel wire that isn't ip");
             return Err(Error::BadPacket);
         };
-        if let Some(resource) = self.get_translation(packet.to_immutable().source()) {
+        let source = packet.to_immutable().source();
+        let destination = if let Some(resource) = self.get_translation(source) {
+            let resource_id = resource.id();
             let ResourceDescription::Dns(resource) = resource else {
                 tracing::error!(
                     "Control protocol error: only dns resources should have a resource_address"
@@ -216,28 +444,142 @@ impl Peer {
             }
 
             packet.update_checksum();
-        }
+            Some((resource_id, source))
+        } else {
+            self.resources
+                .as_ref()
+                .and_then(|r| r.read().get_by_ip(source).map(|r| (r.0.id(), source)))
+        };
+        self.record_tx(destination, len);
+
+        let encapsulate_result = match self.tunnel.lock().encapsulate(src, buffers.buffer()) {
+            TunnResult::Done => Ok(0),
+            TunnResult::Err(e) => Err(e),
+            TunnResult::WriteToNetwork(packet) => Ok(packet.len()),
+            _ => panic!("Unexpected result from encapsulate"),
+        };
+
         Ok(EncapsulatedPacket {
             index: self.index,
             conn_id: self.conn_id,
-            channel: self.channel.clone(),
-            encapsulate_result: self.tunnel.lock().encapsulate(src, dst),
+            transport: Arc::clone(&self.transport),
+            // The packet is always written at the start of the buffer
+            encapsulate_result: encapsulate_result.map(|len| (len > 0).then(|| buffers.take(len))),
         })
     }
 
+    /// Gets the resource the packet is for, [Error::InvalidSource] if the peer isn't allowed to reach it.
+    ///
+    /// The caller tells the peer that the packet is prohibited in that case.
     pub(crate) fn get_packet_resource(
         &self,
         packet: &mut [u8],
-    ) -> Option<(IpAddr, ResourceDescription)> {
-        let resources = self.resources.as_ref()?;
+    ) -> Result<(IpAddr, ResourceDescription)> {
+        let resources = self.resources.as_ref().ok_or(Error::ControlProtocolError)?;
 
-        let dst = Tunn::dst_address(packet)?;
+        let dst = Tunn::dst_address(packet).ok_or(Error::BadPacket)?;
 
         let Some(resource) = resources.read().get_by_ip(dst).map(|r| r.0.clone()) else {
-            tracing::warn!("client tried to hijack the tunnel for resource itsn't allowed.");
-            return None;
+            tracing::warn!(%dst, "client tried to hijack the tunnel for resource itsn't allowed.");
+            return Err(Error::InvalidSource);
         };
 
-        Some((dst, resource))
+        Ok((dst, resource))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::{atomic::Ordering, Arc},
+        time::Instant,
+    };
+
+    use boringtun::{
+        noise::{Tunn, TunnResult},
+        x25519::{PublicKey, StaticSecret},
+    };
+    use chrono::{Duration, Utc};
+    use connlib_shared::messages::{ResourceDescription, ResourceDescriptionDns};
+    use parking_lot::Mutex;
+
+    use super::{Peer, TRANSLATION_IDLE_TIMEOUT};
+    use crate::{memory::MemoryTransport, ConnId, MAX_UDP_SIZE};
+
+    const REAL_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+
+    fn resource() -> ResourceDescription {
+        ResourceDescription::Dns(ResourceDescriptionDns {
+            id: "73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap(),
+            address: "example.com".to_owned(),
+            ipv4: Ipv4Addr::new(100, 96, 0, 1),
+            ipv6: Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x8000, 0, 0, 0, 1),
+            name: "example".to_owned(),
+            records: Vec::new(),
+            filters: Vec::new(),
+        })
+    }
+
+    fn gateway_peer() -> Peer {
+        let private_key = StaticSecret::random_from_rng(rand_core::OsRng);
+        let public_key = PublicKey::from(&StaticSecret::random_from_rng(rand_core::OsRng));
+        let tunnel = Tunn::new(private_key, public_key, None, None, 0, None).unwrap();
+        let (transport, _) = MemoryTransport::pair();
+
+        Peer::new(
+            Mutex::new(tunnel),
+            0,
+            Vec::new(),
+            Arc::new(transport),
+            ConnId::Client("f8a9c0d1-3c3e-4d5a-8e27-2f5b0e1f4c6b".parse().unwrap()),
+            Some((resource(), Utc::now() + Duration::days(1))),
+        )
+    }
+
+    #[test]
+    fn idle_translations_expire() {
+        let peer = gateway_peer();
+        peer.update_translated_resource_address(resource(), REAL_ADDRESS);
+        let now = Instant::now();
+
+        peer.expire_idle_translations(now + TRANSLATION_IDLE_TIMEOUT / 2);
+        assert_eq!(peer.get_translation_at(REAL_ADDRESS, now), Some(resource()));
+
+        peer.expire_idle_translations(now + TRANSLATION_IDLE_TIMEOUT);
+        assert_eq!(peer.get_translation_at(REAL_ADDRESS, now), None);
+        assert!(peer.translated_resource_addresses.read().is_empty());
+    }
+
+    #[test]
+    fn used_translations_are_kept() {
+        let peer = gateway_peer();
+        peer.update_translated_resource_address(resource(), REAL_ADDRESS);
+        let now = Instant::now();
+
+        let later = now + TRANSLATION_IDLE_TIMEOUT / 2;
+        assert!(peer.get_translation_at(REAL_ADDRESS, later).is_some());
+        peer.expire_idle_translations(now + TRANSLATION_IDLE_TIMEOUT);
+
+        assert_eq!(
+            peer.get_translation_at(REAL_ADDRESS, later),
+            Some(resource())
+        );
+    }
+
+    #[test]
+    fn timers_and_stats_dont_wait_for_packets() {
+        let peer = gateway_peer();
+        let mut buffer = vec![0; MAX_UDP_SIZE];
+
+        {
+            let _encapsulating = peer.tunnel.lock();
+            assert_eq!(peer.stats().handshake_age_secs, None);
+            assert!(matches!(peer.update_timers(&mut buffer), TunnResult::Done));
+        }
+
+        // The next tick isn't skipped
+        peer.update_timers(&mut buffer);
+        assert!(!peer.timers_deferred.load(Ordering::Relaxed));
     }
 }
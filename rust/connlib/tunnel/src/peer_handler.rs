@@ -1,12 +1,11 @@
 use std::sync::Arc;
 
 use boringtun::noise::{handshake::parse_handshake_anon, Packet, TunnResult};
-use bytes::Bytes;
 use connlib_shared::{Callbacks, Error, Result};
 
 use crate::{
-    device_channel::DeviceIo, index::check_packet_index, peer::Peer, ControlSignal, Tunnel,
-    MAX_UDP_SIZE,
+    buffer_pool::BufferPool, device_channel::DeviceQueues, index::check_packet_index, peer::Peer,
+    ControlSignal, Tunnel, MAX_UDP_SIZE,
 };
 
 impl<C, CB> Tunnel<C, CB>
@@ -31,104 +30,136 @@ where
         self: &Arc<Self>,
         peer: &Arc<Peer>,
         src: &'a [u8],
-        dst: &'a mut [u8],
+        buffers: &mut BufferPool,
     ) -> Result<Packet<'a>> {
         // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
-        match self.rate_limiter.verify_packet(
+        let cookie_len = match self.rate_limiter.verify_packet(
             // TODO: Some(addr.ip()) webrtc doesn't expose easily the underlying data channel remote ip
             // so for now we don't use it. but we need it for rate limiter although we probably not need it since the data channel
             // will only be established to authenticated peers, so the portal could already prevent being ddos'd
             // but maybe in that cased we can drop this rate_limiter all together and just use decapsulate
-            None, src, dst,
+            None,
+            src,
+            buffers.buffer(),
         ) {
-            Ok(packet) => Ok(packet),
-            Err(TunnResult::WriteToNetwork(cookie)) => {
-                let bytes = Bytes::copy_from_slice(cookie);
-                peer.send_infallible(bytes, &self.callbacks).await;
-                Err(Error::UnderLoad)
-            }
+            Ok(packet) => return Ok(packet),
+            Err(TunnResult::WriteToNetwork(cookie)) => cookie.len(),
             Err(TunnResult::Err(e)) => {
                 tracing::error!(error = ?e, "wireguard_error");
                 let err = e.into();
                 let _ = self.callbacks().on_error(&err);
-                Err(err)
+                return Err(err);
             }
             Err(_) => {
                 tracing::error!(error = "unexpected", "wireguard_error");
-                Err(Error::BadPacket)
+                return Err(Error::BadPacket);
             }
-        }
+        };
+
+        // The cookie is always written at the start of the buffer
+        peer.send_infallible(buffers.take(cookie_len), &self.callbacks)
+            .await;
+        Err(Error::UnderLoad)
     }
 
+    /// Decapsulates a packet from the peer, returns whether it was a handshake that could let queued packets through.
     #[inline(always)]
-    async fn handle_decapsulated_packet<'a>(
+    async fn decapsulate_packet(
         self: &Arc<Self>,
         peer: &Arc<Peer>,
-        device_io: &DeviceIo,
-        decapsulate_result: TunnResult<'a>,
+        device_io: &DeviceQueues,
+        src: &[u8],
+        buffers: &mut BufferPool,
     ) -> bool {
-        match decapsulate_result {
-            TunnResult::Done => false,
+        // Packets for the interface are written to it right away, so the same buffer is reused for all of them
+        let decapsulate_result = peer.tunnel.lock().decapsulate(None, src, buffers.buffer());
+        let network_len = match decapsulate_result {
+            TunnResult::Done => return false,
             TunnResult::Err(e) => {
                 tracing::error!(error = ?e, "decapsulate_packet");
                 let _ = self.callbacks().on_error(&e.into());
-                false
-            }
-            TunnResult::WriteToNetwork(packet) => {
-                let bytes = Bytes::copy_from_slice(packet);
-                peer.send_infallible(bytes, &self.callbacks).await;
-                true
+                return false;
             }
+            TunnResult::WriteToNetwork(packet) => packet.len(),
             TunnResult::WriteToTunnelV4(packet, addr) => {
-                self.send_to_resource(device_io, peer, addr.into(), packet);
-                false
+                if let Some(reply) = self.send_to_resource(device_io, peer, addr.into(), packet) {
+                    self.icmp_error_to_peer(peer, reply, buffers).await;
+                }
+                return false;
             }
             TunnResult::WriteToTunnelV6(packet, addr) => {
-                self.send_to_resource(device_io, peer, addr.into(), packet);
-                false
+                if let Some(reply) = self.send_to_resource(device_io, peer, addr.into(), packet) {
+                    self.icmp_error_to_peer(peer, reply, buffers).await;
+                }
+                return false;
             }
-        }
+        };
+
+        // Packets for the network are always written at the start of the buffer
+        peer.send_infallible(buffers.take(network_len), &self.callbacks)
+            .await;
+        true
     }
 
     #[inline(always)]
     pub(crate) async fn handle_peer_packet(
         self: &Arc<Self>,
         peer: &Arc<Peer>,
-        device_writer: &DeviceIo,
+        device_writer: &DeviceQueues,
         src: &[u8],
-        dst: &mut [u8],
+        buffers: &mut BufferPool,
     ) -> Result<()> {
-        let parsed_packet = self.verify_packet(peer, src, dst).await?;
+        let parsed_packet = self.verify_packet(peer, src, buffers).await?;
         if !self.is_wireguard_packet_ok(&parsed_packet, peer) {
             tracing::error!("wireguard_verification");
             return Err(Error::BadPacket);
         }
 
-        let decapsulate_result = peer.tunnel.lock().decapsulate(None, src, dst);
-
         if self
-            .handle_decapsulated_packet(peer, device_writer, decapsulate_result)
+            .decapsulate_packet(peer, device_writer, src, buffers)
             .await
         {
-            // Flush pending queue
-            while let TunnResult::WriteToNetwork(packet) = {
-                let res = peer.tunnel.lock().decapsulate(None, &[], dst);
-                res
-            } {
-                let bytes = Bytes::copy_from_slice(packet);
-                let callbacks = self.callbacks.clone();
-                let peer = peer.clone();
-                tokio::spawn(async move { peer.send_infallible(bytes, &callbacks).await });
+            // Flush the packets boringtun queued while there was no session, in order.
+            // They're all taken under a single lock, instead of making the packets going the other way wait for each of them.
+            let mut queued = Vec::new();
+            {
+                let mut tunnel = peer.tunnel.lock();
+                loop {
+                    let res = tunnel.decapsulate(None, &[], buffers.buffer());
+                    let TunnResult::WriteToNetwork(packet) = res else {
+                        break;
+                    };
+                    let len = packet.len();
+                    queued.push(buffers.take(len));
+                }
+            }
+            for packet in queued {
+                peer.send_infallible(packet, &self.callbacks).await;
             }
         }
 
         Ok(())
     }
 
-    pub(crate) async fn peer_handler(self: &Arc<Self>, peer: Arc<Peer>, device_io: DeviceIo) {
-        let mut src_buf = [0u8; MAX_UDP_SIZE];
-        let mut dst_buf = [0u8; MAX_UDP_SIZE];
-        while let Ok(size) = peer.channel.read(&mut src_buf[..]).await {
+    pub(crate) async fn peer_handler(self: &Arc<Self>, peer: Arc<Peer>, device_io: DeviceQueues) {
+        let mut src_buf = vec![0u8; MAX_UDP_SIZE];
+        let mut buffers = BufferPool::new();
+        loop {
+            let received = tokio::select! {
+                received = peer.transport.recv(&mut src_buf[..]) => received,
+                // Sent from here so they go through the same buffers as the packets read from the peer
+                names = peer.unresolved_packets.sendable() => {
+                    for name in names {
+                        self.flush_unresolved_packets(&peer, &device_io, name, &mut buffers)
+                            .await;
+                    }
+                    continue;
+                }
+            };
+            let Ok(size) = received else {
+                break;
+            };
+
             // TODO: Double check that this can only happen on closed channel
             // I think it's possible to transmit a 0-byte message through the channel
             // but we would never use that.
@@ -139,7 +170,7 @@ where
 
             tracing::trace!(target: "wire", action = "read", bytes = size, from = "peer");
             let _ = self
-                .handle_peer_packet(&peer, &device_io, &src_buf[..size], &mut dst_buf)
+                .handle_peer_packet(&peer, &device_io, &src_buf[..size], &mut buffers)
                 .await;
         }
 
@@ -0,0 +1,199 @@
+//! Which peer each ip is routed to, looked up for every packet.
+//!
+//! Lookups read a snapshot of the table without taking any lock. Updates rebuild the whole table
+//! and swap it in, which is fine since peers come and go far less often than packets.
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use arc_swap::ArcSwap;
+use connlib_shared::stats::ResourceTraffic;
+use ip_network::IpNetwork;
+use ip_network_table::IpNetworkTable;
+use itertools::Itertools;
+use parking_lot::Mutex;
+
+use crate::peer::Peer;
+
+pub(crate) struct PeerTable {
+    snapshot: ArcSwap<IpNetworkTable<Arc<Peer>>>,
+    // What the snapshots are built from, the lock only serializes the updates
+    peers: Mutex<HashMap<IpNetwork, Arc<Peer>>>,
+    // So the traffic of a removed peer is still reported, kept until it's taken
+    removed_traffic: Mutex<Vec<ResourceTraffic>>,
+}
+
+impl Default for PeerTable {
+    fn default() -> Self {
+        PeerTable {
+            snapshot: ArcSwap::from_pointee(IpNetworkTable::new()),
+            peers: Default::default(),
+            removed_traffic: Default::default(),
+        }
+    }
+}
+
+impl PeerTable {
+    pub(crate) fn longest_match(&self, addr: IpAddr) -> Option<Arc<Peer>> {
+        self.snapshot
+            .load()
+            .longest_match(addr)
+            .map(|(_, peer)| Arc::clone(peer))
+    }
+
+    pub(crate) fn exact_match(&self, ip: IpNetwork) -> Option<Arc<Peer>> {
+        self.snapshot.load().exact_match(ip).cloned()
+    }
+
+    /// The first peer `f` is true for.
+    pub(crate) fn find(&self, f: impl Fn(&Peer) -> bool) -> Option<Arc<Peer>> {
+        self.snapshot
+            .load()
+            .iter()
+            .find(|(_, peer)| f(peer))
+            .map(|(_, peer)| Arc::clone(peer))
+    }
+
+    /// Every peer, only once even if it has many ips.
+    pub(crate) fn peers(&self) -> Vec<Arc<Peer>> {
+        self.snapshot
+            .load()
+            .iter()
+            .map(|(_, peer)| Arc::clone(peer))
+            .unique_by(|peer| peer.index)
+            .collect()
+    }
+
+    /// Changes the table, the lookups see either none or all of the changes.
+    pub(crate) fn update<R>(&self, f: impl FnOnce(&mut HashMap<IpNetwork, Arc<Peer>>) -> R) -> R {
+        let mut peers = self.peers.lock();
+        let res = f(&mut peers);
+
+        let mut table = IpNetworkTable::new();
+        for (ip, peer) in peers.iter() {
+            table.insert(*ip, Arc::clone(peer));
+        }
+        let previous = self.snapshot.swap(Arc::new(table));
+
+        let remaining: HashSet<_> = peers.values().map(|peer| peer.index).collect();
+        let removed = previous
+            .iter()
+            .map(|(_, peer)| peer)
+            .filter(|peer| peer.resources.is_some() && !remaining.contains(&peer.index))
+            .unique_by(|peer| peer.index);
+        let mut removed_traffic = self.removed_traffic.lock();
+        for peer in removed {
+            removed_traffic.extend(peer.resource_traffic().into_iter().map(|traffic| {
+                ResourceTraffic {
+                    conn_id: peer.conn_id,
+                    index: peer.index,
+                    resource_id: traffic.resource_id,
+                    destination: traffic.destination,
+                    traffic: traffic.traffic,
+                }
+            }));
+        }
+
+        res
+    }
+
+    pub(crate) fn take_removed_traffic(&self) -> Vec<ResourceTraffic> {
+        std::mem::take(&mut *self.removed_traffic.lock())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, sync::Arc};
+
+    use boringtun::{
+        noise::Tunn,
+        x25519::{PublicKey, StaticSecret},
+    };
+    use ip_network::IpNetwork;
+    use parking_lot::Mutex;
+
+    use super::PeerTable;
+    use crate::{memory::MemoryTransport, peer::Peer, ConnId};
+
+    fn peer(index: u32) -> Arc<Peer> {
+        let private_key = StaticSecret::random_from_rng(rand_core::OsRng);
+        let public_key = PublicKey::from(&StaticSecret::random_from_rng(rand_core::OsRng));
+        let tunnel = Tunn::new(private_key, public_key, None, None, index, None).unwrap();
+        let (transport, _) = MemoryTransport::pair();
+
+        Arc::new(Peer::new(
+            Mutex::new(tunnel),
+            index,
+            Vec::new(),
+            Arc::new(transport),
+            ConnId::Gateway("e0a21d5f-0b1c-4e4f-9b3a-7d2c6f8e9a10".parse().unwrap()),
+            None,
+        ))
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn network(network: &str) -> IpNetwork {
+        network.parse().unwrap()
+    }
+
+    fn index(peer: Option<Arc<Peer>>) -> Option<u32> {
+        peer.map(|peer| peer.index)
+    }
+
+    #[test]
+    fn the_most_specific_network_wins() {
+        let table = PeerTable::default();
+        table.update(|peers| {
+            peers.insert(network("10.0.0.0/8"), peer(1));
+            peers.insert(network("10.1.0.0/16"), peer(2));
+            peers.insert(network("10.1.2.3/32"), peer(3));
+        });
+
+        assert_eq!(index(table.longest_match(ip("10.1.2.3"))), Some(3));
+        assert_eq!(index(table.longest_match(ip("10.1.2.4"))), Some(2));
+        assert_eq!(index(table.longest_match(ip("10.2.0.1"))), Some(1));
+        assert_eq!(index(table.longest_match(ip("192.168.0.1"))), None);
+        assert_eq!(index(table.exact_match(network("10.1.0.0/16"))), Some(2));
+    }
+
+    #[test]
+    fn lookups_see_all_of_an_update_or_none_of_it() {
+        let table = PeerTable::default();
+        table.update(|peers| {
+            peers.insert(network("10.0.0.0/8"), peer(1));
+        });
+        let before = table.snapshot.load_full();
+
+        let second = peer(2);
+        let removed = table.update(|peers| {
+            let removed = peers.remove(&network("10.0.0.0/8"));
+            peers.insert(network("10.1.0.0/16"), Arc::clone(&second));
+            peers.insert(network("fd00::/64"), second);
+            removed
+        });
+
+        // A lookup that started before the update keeps seeing the table as it was
+        assert_eq!(
+            before.longest_match(ip("10.1.0.1")).map(|(_, p)| p.index),
+            Some(1)
+        );
+        assert_eq!(index(removed), Some(1));
+        assert_eq!(index(table.longest_match(ip("10.1.0.1"))), Some(2));
+        assert_eq!(index(table.longest_match(ip("10.2.0.1"))), None);
+        assert_eq!(index(table.longest_match(ip("fd00::1"))), Some(2));
+        assert_eq!(
+            table
+                .peers()
+                .iter()
+                .map(|peer| peer.index)
+                .collect::<Vec<_>>(),
+            [2]
+        );
+    }
+}
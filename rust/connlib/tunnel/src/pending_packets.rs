@@ -0,0 +1,234 @@
+//! Packets for resources that we are still connecting to, or whose name the gateway is still resolving.
+//!
+//! Without this the first packets to a resource are lost, which costs a retransmit for TCP
+//! and for most UDP protocols means the request just fails.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use connlib_shared::messages::ResourceId;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+// Enough for the first packets of a handful of flows, not meant to hold bulk transfers.
+const MAX_BYTES_PER_RESOURCE: usize = 64 * 1024;
+// Older packets would most likely be retransmitted by now or their request timed out.
+const MAX_PACKET_AGE: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Queue {
+    packets: VecDeque<(Instant, Vec<u8>)>,
+    bytes: usize,
+}
+
+impl Queue {
+    fn drop_expired(&mut self, now: Instant) {
+        while let Some((queued_at, packet)) = self.packets.front() {
+            if now.duration_since(*queued_at) < MAX_PACKET_AGE {
+                break;
+            }
+            self.bytes -= packet.len();
+            self.packets.pop_front();
+        }
+    }
+}
+
+/// Bounded queues of packets, one per resource (or per name), both in bytes and in age.
+pub(crate) struct PendingPackets<K = ResourceId> {
+    queues: Mutex<HashMap<K, Queue>>,
+    /// Resources we connected to since the last [PendingPackets::sendable].
+    sendable: Mutex<HashSet<K>>,
+    sendable_notify: Notify,
+}
+
+impl<K> Default for PendingPackets<K> {
+    fn default() -> Self {
+        Self {
+            queues: Default::default(),
+            sendable: Default::default(),
+            sendable_notify: Default::default(),
+        }
+    }
+}
+
+impl<K> PendingPackets<K>
+where
+    K: Eq + Hash + Display,
+{
+    /// Queues a packet for the resource, dropping the oldest ones if it's full.
+    pub(crate) fn push(&self, resource: K, packet: &[u8]) {
+        self.push_at(resource, packet, Instant::now())
+    }
+
+    /// Takes the queued packets that are still worth sending and for which `ready` returns true, oldest first.
+    ///
+    /// The rest stay queued.
+    pub(crate) fn take_ready(&self, resource: &K, ready: impl Fn(&[u8]) -> bool) -> Vec<Vec<u8>> {
+        self.take_ready_at(resource, ready, Instant::now())
+    }
+
+    /// Marks the packets queued for the resource as ready to be sent, by whoever waits on [PendingPackets::sendable].
+    pub(crate) fn mark_sendable(&self, resource: K) {
+        self.sendable.lock().insert(resource);
+        self.sendable_notify.notify_one();
+    }
+
+    /// Waits for resources to be marked with [PendingPackets::mark_sendable] and returns them.
+    ///
+    /// Every resource is only returned once, if the future is dropped before it's done another waiter gets them.
+    pub(crate) async fn sendable(&self) -> Vec<K> {
+        loop {
+            self.sendable_notify.notified().await;
+            let sendable: Vec<_> = self.sendable.lock().drain().collect();
+            if !sendable.is_empty() {
+                return sendable;
+            }
+        }
+    }
+
+    /// Drops the packets queued for the resource, e.g. because connecting to it failed.
+    ///
+    /// Returns the ones that were still worth sending, so their senders can be told.
+    pub(crate) fn discard(&self, resource: &K) -> Vec<Vec<u8>> {
+        let Some(mut queue) = self.queues.lock().remove(resource) else {
+            return Vec::new();
+        };
+        tracing::trace!(%resource, packets = queue.packets.len(), "pending_packets_discarded");
+        queue.drop_expired(Instant::now());
+
+        queue
+            .packets
+            .into_iter()
+            .map(|(_, packet)| packet)
+            .collect()
+    }
+
+    /// Tells you if there are packets queued for the resource, expired or not.
+    pub(crate) fn is_queued(&self, resource: &K) -> bool {
+        self.queues.lock().contains_key(resource)
+    }
+
+    fn push_at(&self, resource: K, packet: &[u8], now: Instant) {
+        if packet.len() > MAX_BYTES_PER_RESOURCE {
+            return;
+        }
+
+        let mut queues = self.queues.lock();
+        let queue = queues.entry(resource).or_default();
+        queue.drop_expired(now);
+        while queue.bytes + packet.len() > MAX_BYTES_PER_RESOURCE {
+            let Some((_, dropped)) = queue.packets.pop_front() else {
+                break;
+            };
+            queue.bytes -= dropped.len();
+        }
+
+        queue.bytes += packet.len();
+        queue.packets.push_back((now, packet.to_vec()));
+    }
+
+    fn take_ready_at(
+        &self,
+        resource: &K,
+        ready: impl Fn(&[u8]) -> bool,
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        let mut queues = self.queues.lock();
+        let Some(queue) = queues.get_mut(resource) else {
+            return Vec::new();
+        };
+        queue.drop_expired(now);
+
+        let (taken, kept) = queue
+            .packets
+            .drain(..)
+            .partition::<VecDeque<_>, _>(|(_, packet)| ready(packet));
+        queue.packets = kept;
+        queue.bytes = queue.packets.iter().map(|(_, p)| p.len()).sum();
+        if queue.packets.is_empty() {
+            queues.remove(resource);
+        }
+
+        taken.into_iter().map(|(_, packet)| packet).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use connlib_shared::messages::ResourceId;
+
+    use super::{PendingPackets, MAX_BYTES_PER_RESOURCE};
+
+    fn resource() -> ResourceId {
+        "ea6570d1-47c7-49d2-9dc3-efff1c0c9e0b".parse().unwrap()
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let pending = PendingPackets::default();
+        let now = Instant::now();
+        let packet = vec![0; MAX_BYTES_PER_RESOURCE / 2];
+
+        pending.push_at(resource(), &packet, now);
+        pending.push_at(resource(), &packet, now);
+        pending.push_at(resource(), &[1], now);
+
+        assert_eq!(
+            pending.take_ready_at(&resource(), |_| true, now),
+            vec![packet, vec![1]]
+        );
+        assert!(pending.take_ready_at(&resource(), |_| true, now).is_empty());
+    }
+
+    #[test]
+    fn drops_expired_packets() {
+        let pending = PendingPackets::default();
+        let now = Instant::now();
+
+        pending.push_at(resource(), &[1], now);
+        pending.push_at(resource(), &[2], now + Duration::from_secs(3));
+
+        assert_eq!(
+            pending.take_ready_at(&resource(), |_| true, now + Duration::from_secs(6)),
+            vec![vec![2]]
+        );
+    }
+
+    #[test]
+    fn keeps_packets_that_arent_ready() {
+        let pending = PendingPackets::default();
+        let now = Instant::now();
+
+        pending.push_at(resource(), &[1], now);
+        pending.push_at(resource(), &[2], now);
+
+        assert_eq!(
+            pending.take_ready_at(&resource(), |p| p == [2], now),
+            vec![vec![2]]
+        );
+        assert_eq!(
+            pending.take_ready_at(&resource(), |_| true, now),
+            vec![vec![1]]
+        );
+    }
+
+    #[tokio::test]
+    async fn sendable_resources_are_returned_once() {
+        let pending = PendingPackets::default();
+
+        pending.mark_sendable(resource());
+        pending.mark_sendable(resource());
+
+        assert_eq!(pending.sendable().await, vec![resource()]);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), pending.sendable())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,13 +1,17 @@
-use std::{
-    net::{IpAddr, ToSocketAddrs},
-    sync::Arc,
-};
+use std::{net::IpAddr, sync::Arc};
 
 use crate::{
-    device_channel::DeviceIo, ip_packet::MutableIpPacket, peer::Peer, ControlSignal, Tunnel,
+    buffer_pool::BufferPool,
+    device_channel::DeviceQueues,
+    ip_packet::{IpPacket, MutableIpPacket, Unreachable},
+    peer::Peer,
+    ControlSignal, Tunnel,
 };
 
-use connlib_shared::{messages::ResourceDescription, Callbacks, Error, Result};
+use connlib_shared::{
+    messages::{Filter, ResourceDescription},
+    Callbacks, Error, Result,
+};
 
 impl<C, CB> Tunnel<C, CB>
 where
@@ -24,7 +28,7 @@ where
     }
 
     #[inline(always)]
-    fn send_packet(&self, device_io: &DeviceIo, packet: &mut [u8], dst_addr: IpAddr) {
+    fn send_packet(&self, device_io: &DeviceQueues, packet: &mut [u8], dst_addr: IpAddr) {
         match dst_addr {
             IpAddr::V4(_) => {
                 self.write4_device_infallible(device_io, packet);
@@ -37,44 +41,231 @@ where
 
     #[inline(always)]
     pub(crate) fn packet_allowed(
-        &self,
-        device_io: &DeviceIo,
+        self: &Arc<Self>,
+        device_io: &DeviceQueues,
         peer: &Arc<Peer>,
         addr: IpAddr,
         packet: &mut [u8],
-    ) {
-        let Some((dst, resource)) = peer.get_packet_resource(packet) else {
-            // If there's no associated resource it means that we are in a client, then the packet comes from a gateway
-            // and we just trust gateways.
-            // In gateways this should never happen.
+    ) -> Option<Vec<u8>> {
+        // If there are no resources it means that we are in a client, then the packet comes from a gateway
+        // and we just trust gateways.
+        if peer.resources.is_none() {
             tracing::trace!(target: "wire", action = "writing", to = "iface", %addr, bytes = %packet.len());
+            peer.record_rx(None, packet.len());
             self.send_packet(device_io, packet, addr);
-            return;
+            return None;
+        }
+
+        let (dst, resource) = match peer.get_packet_resource(packet) {
+            Ok(dst_and_resource) => dst_and_resource,
+            Err(Error::InvalidSource) => {
+                return self.unreachable_reply(packet, Unreachable::Prohibited)
+            }
+            Err(_) => return None,
         };
 
-        match get_resource_addr_and_port(peer, &resource, &addr, &dst) {
-            Ok((dst_addr, _dst_port)) => {
+        match self.get_resource_addr_and_port(&resource, &addr, &dst) {
+            Ok(Some((_, dst_port))) if !is_allowed_by_filters(packet, &resource, dst_port) => {
+                tracing::debug!(%addr, resource = %resource.id(), "packet_outside_resource_filters");
+                self.unreachable_reply(packet, Unreachable::Prohibited)
+            }
+            Ok(Some((dst_addr, _))) => {
+                peer.record_rx(Some((resource.id(), dst_addr)), packet.len());
+                // Only once it's allowed, otherwise denied flows would keep their translation from going idle
+                if matches!(resource, ResourceDescription::Dns(_)) {
+                    peer.update_translated_resource_address(resource, dst_addr);
+                }
                 self.update_packet(packet, dst_addr);
                 self.send_packet(device_io, packet, addr);
+                None
+            }
+            Ok(None) => {
+                self.queue_unresolved_packet(peer, &resource, packet);
+                None
             }
             Err(e) => {
                 tracing::error!(err = ?e, "resource_parse");
                 let _ = self.callbacks().on_error(&e);
+                if matches!(e, Error::InvalidSource) {
+                    return self.unreachable_reply(packet, Unreachable::Prohibited);
+                }
+                None
             }
         }
     }
 
+    /// Writes the packet from the peer to the interface if the peer is allowed to send it.
+    ///
+    /// Returns the ICMP error for the peer if it's not, to be sent with [Self::icmp_error_to_peer].
     pub(crate) fn send_to_resource(
-        &self,
-        device_io: &DeviceIo,
+        self: &Arc<Self>,
+        device_io: &DeviceQueues,
         peer: &Arc<Peer>,
         addr: IpAddr,
         packet: &mut [u8],
-    ) {
+    ) -> Option<Vec<u8>> {
         if peer.is_allowed(addr) {
-            self.packet_allowed(device_io, peer, addr, packet);
+            self.packet_allowed(device_io, peer, addr, packet)
         } else {
             tracing::warn!(%addr, "Received packet from peer with an unallowed ip");
+            None
+        }
+    }
+
+    /// Gets where the packet needs to go, without remembering the translation.
+    ///
+    /// Returns `None` if the name of the resource hasn't been resolved yet, see [Self::queue_unresolved_packet].
+    fn get_resource_addr_and_port(
+        self: &Arc<Self>,
+        resource: &ResourceDescription,
+        addr: &IpAddr,
+        dst: &IpAddr,
+    ) -> Result<Option<(IpAddr, Option<u16>)>> {
+        match resource {
+            // Note: for now no translation is needed for the ip since we do a peer/connection per resource
+            ResourceDescription::Dns(r) => {
+                let (name, port) = split_dns_address(&r.address)?;
+                let lookup = self.resolver_cache.get(name, *addr);
+                if lookup.resolve {
+                    self.resolve_resource_name(name.to_string());
+                }
+                let Some(dst_addr) = lookup.addr else {
+                    return Ok(None);
+                };
+                Ok(Some((dst_addr, port)))
+            }
+            ResourceDescription::Cidr(r) => {
+                if r.address.contains(*dst) {
+                    Ok(Some((
+                        get_matching_version_ip(addr, dst).ok_or(Error::InvalidResource)?,
+                        None,
+                    )))
+                } else {
+                    tracing::warn!(
+                        "client tried to hijack the tunnel for range outside what it's allowed."
+                    );
+                    Err(Error::InvalidSource)
+                }
+            }
+        }
+    }
+
+    /// Queues the packet until the name of its resource is resolved, if that's what we are doing.
+    ///
+    /// Otherwise the name didn't resolve to an address of the packet's version and the packet is dropped.
+    fn queue_unresolved_packet(&self, peer: &Peer, resource: &ResourceDescription, packet: &[u8]) {
+        let ResourceDescription::Dns(r) = resource else {
+            return;
+        };
+        let Ok((name, _)) = split_dns_address(&r.address) else {
+            return;
+        };
+
+        // Queued under the cache's lock, so the resolution can't finish before the packet is there to be sent
+        let queued = self.resolver_cache.if_resolving(name, || {
+            peer.unresolved_packets.push(name.to_string(), packet)
+        });
+        if !queued {
+            tracing::trace!(target: "wire", action = "dropped", to = "iface", %name, "resource_name_unresolved");
+        }
+    }
+
+    /// Sends the packets from the peer that were waiting for the name to be resolved.
+    ///
+    /// The ones for which it still has no address are dropped this time.
+    pub(crate) async fn flush_unresolved_packets(
+        self: &Arc<Self>,
+        peer: &Arc<Peer>,
+        device_io: &DeviceQueues,
+        name: String,
+        buffers: &mut BufferPool,
+    ) {
+        for mut packet in peer.unresolved_packets.take_ready(&name, |_| true) {
+            let Some(addr) = IpPacket::new(&packet).map(|p| p.source()) else {
+                continue;
+            };
+            if let Some(reply) = self.send_to_resource(device_io, peer, addr, &mut packet) {
+                self.icmp_error_to_peer(peer, reply, buffers).await;
+            }
+        }
+    }
+
+    /// Starts resolving the name of the resource as soon as a peer is allowed to reach it, instead of on its first packet.
+    pub(crate) fn prefetch_resource_name(self: &Arc<Self>, resource: &ResourceDescription) {
+        let ResourceDescription::Dns(r) = resource else {
+            return;
+        };
+        // The names matched by a wildcard resource are prefetched on their own
+        if r.is_wildcard() {
+            return;
+        }
+        let Ok((name, _)) = split_dns_address(&r.address) else {
+            return;
+        };
+
+        if self.resolver_cache.prefetch(name) {
+            self.resolve_resource_name(name.to_string());
+        }
+    }
+
+    /// Resolves the name in the background, failures are reported through the callbacks.
+    ///
+    /// The packets the peers sent while we were at it are sent by their handlers once it's done.
+    fn resolve_resource_name(self: &Arc<Self>, name: String) {
+        let tunnel = Arc::clone(self);
+        tokio::spawn(async move {
+            let result = tunnel.resolver.resolve(&name).await;
+            if let Err(e) = &result {
+                tracing::warn!(%name, error = ?e, "resolve_resource_name");
+                let _ = tunnel.callbacks().on_error(e);
+            }
+            tunnel.resolver_cache.update(&name, &result);
+
+            for peer in tunnel.peers_by_ip.peers() {
+                if peer.unresolved_packets.is_queued(&name) {
+                    peer.unresolved_packets.mark_sendable(name.clone());
+                }
+            }
+        });
+    }
+}
+
+/// Tells you if the packet is allowed by the resource's filters and by the port in its address, if it has one.
+///
+/// ICMP has no ports so the port in the address doesn't apply to it, only the filters do.
+fn is_allowed_by_filters(packet: &[u8], resource: &ResourceDescription, port: Option<u16>) -> bool {
+    let Some(packet) = IpPacket::new(packet) else {
+        return false;
+    };
+    let tcp_port = packet.as_tcp().map(|p| p.get_destination());
+    let udp_port = packet.as_udp().map(|p| p.get_destination());
+
+    if !packet.is_icmp() && port.is_some_and(|port| tcp_port.or(udp_port) != Some(port)) {
+        return false;
+    }
+
+    let filters = resource.filters();
+    filters.is_empty()
+        || filters.iter().any(|filter| match filter {
+            Filter::Tcp(range) => tcp_port.is_some_and(|p| range.contains(p)),
+            Filter::Udp(range) => udp_port.is_some_and(|p| range.contains(p)),
+            Filter::Icmp => packet.is_icmp(),
+        })
+}
+
+/// Splits a DNS resource's address into its name and its port, if it has one.
+///
+/// A port that doesn't parse is an error rather than no port, which would open the whole host.
+fn split_dns_address(address: &str) -> Result<(&str, Option<u16>)> {
+    let mut parts = address.split(':');
+    let name = parts.next().unwrap_or_default();
+    let port = parts.next().map(str::parse::<u16>);
+    match (port, parts.next()) {
+        (None, None) => Ok((name, None)),
+        (Some(Ok(port)), None) => Ok((name, Some(port))),
+        _ => {
+            tracing::error!("invalid address for resource: {address}");
+            Err(Error::InvalidResource)
         }
     }
 }
@@ -83,50 +274,120 @@ fn get_matching_version_ip(addr: &IpAddr, ip: &IpAddr) -> Option<IpAddr> {
     ((addr.is_ipv4() && ip.is_ipv4()) || (addr.is_ipv6() && ip.is_ipv6())).then_some(*ip)
 }
 
-fn get_resource_addr_and_port(
-    peer: &Arc<Peer>,
-    resource: &ResourceDescription,
-    addr: &IpAddr,
-    dst: &IpAddr,
-) -> Result<(IpAddr, Option<u16>)> {
-    match resource {
-        // Note: for now no translation is needed for the ip since we do a peer/connection per resource
-        ResourceDescription::Dns(r) => {
-            let mut address = r.address.split(':');
-            let Some(dst_addr) = address.next() else {
-                tracing::error!("invalid DNS name for resource: {}", r.address);
-                return Err(Error::InvalidResource);
-            };
-            let Ok(mut dst_addr) = (dst_addr, 0).to_socket_addrs() else {
-                tracing::warn!(%addr, "Couldn't resolve name");
-                return Err(Error::InvalidResource);
-            };
-            let Some(dst_addr) = dst_addr.find_map(|d| get_matching_version_ip(addr, &d.ip()))
-            else {
-                tracing::warn!(%addr, "Couldn't resolve name addr");
-                return Err(Error::InvalidResource);
-            };
-            peer.update_translated_resource_address(r.id, dst_addr);
-            Ok((
-                dst_addr,
-                address
-                    .next()
-                    .map(str::parse::<u16>)
-                    .and_then(std::result::Result::ok),
-            ))
-        }
-        ResourceDescription::Cidr(r) => {
-            if r.address.contains(*dst) {
-                Ok((
-                    get_matching_version_ip(addr, dst).ok_or(Error::InvalidResource)?,
-                    None,
-                ))
-            } else {
-                tracing::warn!(
-                    "client tried to hijack the tunnel for range outside what it's allowed."
-                );
-                Err(Error::InvalidSource)
-            }
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use connlib_shared::messages::{
+        Filter, PortRange, ResourceDescription, ResourceDescriptionCidr,
+    };
+    use ip_network::IpNetwork;
+
+    use super::{is_allowed_by_filters, split_dns_address};
+    use crate::ip_packet::{icmp_error, udp_packet, Unreachable};
+
+    const CLIENT: (Ipv4Addr, u16) = (Ipv4Addr::new(100, 64, 0, 1), 40000);
+    const RESOURCE_IP: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 10);
+
+    fn resource(filters: Vec<Filter>) -> ResourceDescription {
+        ResourceDescription::Cidr(ResourceDescriptionCidr {
+            id: "73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap(),
+            address: IpNetwork::new(Ipv4Addr::new(172, 16, 0, 0), 24).unwrap(),
+            name: "test".to_owned(),
+            filters,
+        })
+    }
+
+    fn udp_to(port: u16) -> Vec<u8> {
+        udp_packet(CLIENT, (RESOURCE_IP, port))
+    }
+
+    fn icmp() -> Vec<u8> {
+        icmp_error(
+            &udp_packet((RESOURCE_IP, 53), CLIENT),
+            Unreachable::Host.into(),
+        )
+        .unwrap()
+    }
+
+    fn ports(start: u16, end: u16) -> PortRange {
+        PortRange {
+            port_range_start: start,
+            port_range_end: end,
         }
     }
+
+    #[test]
+    fn no_filters_allow_everything() {
+        let resource = resource(vec![]);
+
+        assert!(is_allowed_by_filters(&udp_to(8080), &resource, None));
+        assert!(is_allowed_by_filters(&icmp(), &resource, None));
+    }
+
+    #[test]
+    fn filters_allow_their_protocol_and_ports() {
+        let udp = resource(vec![
+            Filter::Udp(ports(8000, 8080)),
+            Filter::Tcp(ports(1, 65535)),
+        ]);
+        let icmp_only = resource(vec![Filter::Icmp]);
+
+        assert!(is_allowed_by_filters(&udp_to(8000), &udp, None));
+        assert!(is_allowed_by_filters(&udp_to(8080), &udp, None));
+        assert!(!is_allowed_by_filters(&udp_to(8081), &udp, None));
+        assert!(!is_allowed_by_filters(&icmp(), &udp, None));
+        assert!(is_allowed_by_filters(&icmp(), &icmp_only, None));
+        assert!(!is_allowed_by_filters(&udp_to(8000), &icmp_only, None));
+    }
+
+    #[test]
+    fn port_in_the_address_only_allows_that_port() {
+        let resource = resource(vec![]);
+
+        assert!(is_allowed_by_filters(&udp_to(443), &resource, Some(443)));
+        assert!(!is_allowed_by_filters(&udp_to(8080), &resource, Some(443)));
+    }
+
+    #[test]
+    fn port_in_the_address_does_not_apply_to_icmp() {
+        assert!(is_allowed_by_filters(&icmp(), &resource(vec![]), Some(443)));
+        assert!(!is_allowed_by_filters(
+            &icmp(),
+            &resource(vec![Filter::Udp(ports(443, 443))]),
+            Some(443)
+        ));
+    }
+
+    #[test]
+    fn port_in_the_address_and_filters_both_apply() {
+        let resource = resource(vec![Filter::Tcp(ports(443, 443))]);
+
+        assert!(!is_allowed_by_filters(&udp_to(443), &resource, Some(443)));
+    }
+
+    #[test]
+    fn dns_addresses_split_into_name_and_port() {
+        assert_eq!(
+            split_dns_address("db.internal").unwrap(),
+            ("db.internal", None)
+        );
+        assert_eq!(
+            split_dns_address("db.internal:5432").unwrap(),
+            ("db.internal", Some(5432))
+        );
+    }
+
+    #[test]
+    fn malformed_ports_are_invalid() {
+        assert!(split_dns_address("db.internal:abc").is_err());
+        assert!(split_dns_address("db.internal:99999").is_err());
+        assert!(split_dns_address("db.internal:").is_err());
+        assert!(split_dns_address("db.internal:5432:1").is_err());
+    }
+
+    #[test]
+    fn garbage_is_not_allowed() {
+        assert!(!is_allowed_by_filters(&[0xff; 4], &resource(vec![]), None));
+    }
 }
@@ -3,7 +3,6 @@ use std::{collections::HashMap, net::IpAddr, rc::Rc};
 
 use chrono::{DateTime, Utc};
 use connlib_shared::messages::{ResourceDescription, ResourceId};
-use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
 
 pub(crate) trait Resource {
@@ -28,7 +27,11 @@ impl Resource for (ResourceDescription, DateTime<Utc>) {
 pub(crate) struct ResourceTable<T> {
     id_table: HashMap<ResourceId, Rc<T>>,
     network_table: IpNetworkTable<Rc<T>>,
+    // Keyed by the lowercase name, since names are case-insensitive
     dns_name: HashMap<String, Rc<T>>,
+    // Names resolved through a wildcard resource, these are in `network_table` and `dns_name` but not in `id_table`
+    // since they share the id of the wildcard resource.
+    wildcard_matches: HashMap<ResourceId, Vec<Rc<T>>>,
 }
 
 // SAFETY: This type is send since you can't obtain the underlying `Rc` and the only way to clone it is using `insert` which requires an &mut self
@@ -49,6 +52,7 @@ impl<T> ResourceTable<T> {
             network_table: IpNetworkTable::new(),
             id_table: HashMap::new(),
             dns_name: HashMap::new(),
+            wildcard_matches: HashMap::new(),
         }
     }
 }
@@ -61,22 +65,6 @@ where
         self.id_table.values().map(AsRef::as_ref)
     }
 
-    pub fn network_resources(&self) -> HashMap<IpNetwork, T> {
-        // Safety: Due to internal consistency, since the value is stored the reference should be valid
-        self.network_table
-            .iter()
-            .map(|(wg_ip, res)| (wg_ip, res.as_ref().clone()))
-            .collect()
-    }
-
-    pub fn dns_resources(&self) -> HashMap<String, T> {
-        // Safety: Due to internal consistency, since the value is stored the reference should be valid
-        self.dns_name
-            .iter()
-            .map(|(name, res)| (name.clone(), res.as_ref().clone()))
-            .collect()
-    }
-
     /// Tells you if it's empty
     pub fn is_empty(&self) -> bool {
         self.id_table.is_empty()
@@ -94,14 +82,47 @@ where
 
     /// Gets the resource by name
     pub fn get_by_name(&self, name: impl AsRef<str>) -> Option<&T> {
-        self.dns_name.get(name.as_ref()).map(AsRef::as_ref)
+        self.dns_name
+            .get(&dns_key(name.as_ref()))
+            .map(AsRef::as_ref)
+    }
+
+    /// Gets the most specific wildcard resource that covers the name
+    pub fn get_by_wildcard(&self, name: impl AsRef<str>) -> Option<&T> {
+        let name = dns_key(name.as_ref());
+        let mut name = name.as_str();
+        while let Some((_, parent)) = name.split_once('.') {
+            if let Some(resource) = self.dns_name.get(&format!("*.{parent}")) {
+                return Some(resource.as_ref());
+            }
+            name = parent;
+        }
+
+        None
+    }
+
+    /// Gets the resources for the names resolved through the wildcard resource with the given id
+    pub fn wildcard_matches(&self, id: &ResourceId) -> Vec<T> {
+        self.wildcard_matches
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(|r| r.as_ref().clone())
+            .collect()
+    }
+
+    /// Removes the resource with the given id, returning it if it was present
+    pub fn remove_by_id(&mut self, id: &ResourceId) -> Option<T> {
+        let resource_description = self.get_by_id(id)?.clone();
+        self.cleanup_resource(&resource_description);
+        Some(resource_description)
     }
 
     fn remove_resource(&mut self, resource_description: &T) {
         let id = {
             match resource_description.description() {
                 ResourceDescription::Dns(r) => {
-                    self.dns_name.remove(&r.address);
+                    self.dns_name.remove(&dns_key(&r.address));
                     self.network_table.remove(r.ipv4);
                     self.network_table.remove(r.ipv6);
                     r.id
@@ -112,7 +133,21 @@ where
                 }
             }
         };
-        self.id_table.remove(&id);
+        self.remove_wildcard_matches(&id);
+        // If `resource_description` was a wildcard match the wildcard resource has to go too, otherwise it would be left dangling
+        if let Some(res) = self.id_table.remove(&id) {
+            self.remove_resource(res.as_ref());
+        }
+    }
+
+    fn remove_wildcard_matches(&mut self, id: &ResourceId) {
+        for res in self.wildcard_matches.remove(id).into_iter().flatten() {
+            if let ResourceDescription::Dns(r) = res.description() {
+                self.dns_name.remove(&dns_key(&r.address));
+                self.network_table.remove(r.ipv4);
+                self.network_table.remove(r.ipv6);
+            }
+        }
     }
 
     pub(crate) fn cleanup_resource(&mut self, resource_description: &T) {
@@ -122,7 +157,7 @@ where
                     self.remove_resource(res.as_ref());
                 }
 
-                if let Some(res) = self.dns_name.remove(&r.address) {
+                if let Some(res) = self.dns_name.remove(&dns_key(&r.address)) {
                     self.remove_resource(res.as_ref());
                 }
 
@@ -170,7 +205,7 @@ where
                 self.network_table
                     .insert(r.ipv6, Rc::clone(&resource_description));
                 self.dns_name
-                    .insert(r.address.clone(), resource_description);
+                    .insert(dns_key(&r.address), resource_description);
             }
             ResourceDescription::Cidr(r) => {
                 self.network_table.insert(r.address, resource_description);
@@ -178,6 +213,58 @@ where
         }
     }
 
+    /// Inserts the resource for a name resolved through the wildcard resource with the same id
+    ///
+    /// Unlike `insert` this never displaces other values, if the wildcard resource isn't in the table
+    /// or the name or any of the ips are already taken nothing is inserted and `false` is returned.
+    /// The match is removed along with its wildcard resource.
+    pub fn insert_match(&mut self, resource_description: T) -> bool {
+        let ResourceDescription::Dns(r) = resource_description.description() else {
+            return false;
+        };
+        let (id, address, ipv4, ipv6) = (r.id, dns_key(&r.address), r.ipv4, r.ipv6);
+
+        if !self.id_table.contains_key(&id)
+            || self.dns_name.contains_key(&address)
+            || self.network_table.exact_match(ipv4).is_some()
+            || self.network_table.exact_match(ipv6).is_some()
+        {
+            return false;
+        }
+
+        let resource_description = Rc::new(resource_description);
+        self.network_table
+            .insert(ipv4, Rc::clone(&resource_description));
+        self.network_table
+            .insert(ipv6, Rc::clone(&resource_description));
+        self.dns_name
+            .insert(address, Rc::clone(&resource_description));
+        self.wildcard_matches
+            .entry(id)
+            .or_default()
+            .push(resource_description);
+        true
+    }
+
+    /// Removes a name resolved through a wildcard resource, returning it if it was present
+    ///
+    /// The wildcard resource and its other matches are left alone.
+    pub fn remove_match(&mut self, name: &str) -> Option<T> {
+        let key = dns_key(name);
+        let res = Rc::clone(self.dns_name.get(&key)?);
+        let ResourceDescription::Dns(r) = res.description() else {
+            return None;
+        };
+        let matches = self.wildcard_matches.get_mut(&r.id)?;
+        let index = matches.iter().position(|m| Rc::ptr_eq(m, &res))?;
+        matches.swap_remove(index);
+
+        self.dns_name.remove(&key);
+        self.network_table.remove(r.ipv4);
+        self.network_table.remove(r.ipv6);
+        Some(res.as_ref().clone())
+    }
+
     pub fn resource_list(&self) -> Vec<ResourceDescription> {
         self.id_table
             .values()
@@ -186,3 +273,78 @@ where
             .collect()
     }
 }
+
+fn dns_key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use connlib_shared::messages::{ResourceDescription, ResourceDescriptionDns, WildcardMatch};
+
+    use super::ResourceTable;
+
+    fn wildcard() -> ResourceDescription {
+        ResourceDescription::Dns(ResourceDescriptionDns {
+            id: "73037362-715d-4a83-a749-f18eadd970e6".parse().unwrap(),
+            address: "*.Example.com".to_owned(),
+            ipv4: Ipv4Addr::new(100, 96, 0, 1),
+            ipv6: Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x8000, 0, 0, 0, 1),
+            name: "example".to_owned(),
+            records: Vec::new(),
+            filters: Vec::new(),
+        })
+    }
+
+    fn wildcard_match(name: &str, offset: u8) -> ResourceDescription {
+        wildcard()
+            .with_match(&WildcardMatch {
+                name: name.to_owned(),
+                ipv4: Ipv4Addr::new(198, 18, 0, offset),
+                ipv6: Ipv6Addr::new(0xfd00, 0x2021, 0x1111, 0x8001, 0, 0, 0, offset.into()),
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut table = ResourceTable::new();
+        table.insert(wildcard());
+        assert!(table.insert_match(wildcard_match("Foo.example.com", 1)));
+
+        assert!(table.get_by_name("foo.EXAMPLE.com").is_some());
+        assert!(table.get_by_wildcard("bar.example.COM").is_some());
+        assert!(!table.insert_match(wildcard_match("foo.example.com", 2)));
+    }
+
+    #[test]
+    fn removing_a_match_frees_its_addresses() {
+        let mut table = ResourceTable::new();
+        table.insert(wildcard());
+        table.insert_match(wildcard_match("foo.example.com", 1));
+        table.insert_match(wildcard_match("bar.example.com", 2));
+
+        assert!(table.remove_match("FOO.example.com").is_some());
+
+        assert!(table.get_by_name("foo.example.com").is_none());
+        assert!(table.get_by_ip(Ipv4Addr::new(198, 18, 0, 1)).is_none());
+        assert!(table.get_by_name("bar.example.com").is_some());
+        assert!(table.get_by_name("*.example.com").is_some());
+        assert!(table.remove_match("*.example.com").is_none());
+    }
+
+    #[test]
+    fn removing_the_wildcard_frees_the_addresses_of_its_matches() {
+        let mut table = ResourceTable::new();
+        let wildcard = wildcard();
+        table.insert(wildcard.clone());
+        table.insert_match(wildcard_match("foo.example.com", 1));
+
+        table.remove_by_id(&wildcard.id());
+
+        assert!(table.get_by_name("foo.example.com").is_none());
+        assert!(table.get_by_ip(Ipv4Addr::new(198, 18, 0, 1)).is_none());
+    }
+}
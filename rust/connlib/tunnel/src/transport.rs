@@ -0,0 +1,41 @@
+//! How the wireguard packets of a peer get to the other end.
+//!
+//! Outside of tests that's always a detached webrtc data channel, the tunnel only ever sees a [PeerTransport].
+use async_trait::async_trait;
+use bytes::Bytes;
+use connlib_shared::{Error, Result};
+use webrtc::data::data_channel::DataChannel;
+
+#[async_trait]
+pub(crate) trait PeerTransport: Send + Sync {
+    /// Sends a single packet to the peer.
+    async fn send(&self, packet: &Bytes) -> Result<()>;
+
+    /// Waits for the next packet from the peer, 0 means the transport is closed.
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize>;
+
+    async fn close(&self) -> Result<()>;
+}
+
+#[async_trait]
+impl PeerTransport for DataChannel {
+    async fn send(&self, packet: &Bytes) -> Result<()> {
+        match self.write(packet).await {
+            Ok(_) => Ok(()),
+            Err(
+                webrtc::data::Error::ErrStreamClosed
+                | webrtc::data::Error::Sctp(webrtc::sctp::Error::ErrStreamClosed),
+            ) => Err(Error::TransportClosed),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        Ok(self.read(buf).await?)
+    }
+
+    async fn close(&self) -> Result<()> {
+        DataChannel::close(self).await?;
+        Ok(())
+    }
+}
@@ -1,13 +1,12 @@
 #!/bin/bash
 
+# Masquerading itself is set up by the gateway as requested by the portal
 if [[ "${ENABLE_MASQUERADE}" == "1" ]]; then
   IFACE="tun-firezone"
   iptables -A FORWARD -i $IFACE -j ACCEPT
   iptables -A FORWARD -o $IFACE -j ACCEPT
-  iptables -t nat -A POSTROUTING -o eth+ -j MASQUERADE
   ip6tables -A FORWARD -i $IFACE -j ACCEPT
   ip6tables -A FORWARD -o $IFACE -j ACCEPT
-  ip6tables -t nat -A POSTROUTING -o eth+ -j MASQUERADE
 fi
 
 if [[ "${LISTEN_ADDRESS_DISCOVERY_METHOD}" == "gce_metadata" ]]; then
@@ -1,35 +1,93 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
-use connlib_gateway_shared::{get_device_id, Callbacks, Session};
-use headless_utils::{block_on_ctrl_c, setup_global_subscriber, CommonArgs};
-use secrecy::SecretString;
+use connlib_gateway_shared::{
+    get_device_id, stats::TunnelStats, Callbacks, DeviceIdentity, Error, Session,
+};
+use headless_utils::{block_on_ctrl_c_or, setup_global_subscriber, systemd, CommonArgs};
+use std::sync::{mpsc, Arc, Mutex};
 use tracing_subscriber::layer;
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    setup_global_subscriber(layer::Identity::new());
+    let config = cli.common.load_config()?;
+    // Only needs the state dir, the key can be rotated before the portal's url and secret are set
+    if cli.common.rotate_key {
+        let Some(state_dir) = cli.common.state_dir(&config) else {
+            bail!("There's no key to rotate without a state dir, set either `--state-dir` or `state_dir` in the config file");
+        };
+        let identity = DeviceIdentity::rotate_key(&state_dir, get_device_id(Some(&state_dir)))?;
+        println!("The new public key is {}", identity.public_key());
+        return Ok(());
+    }
 
-    let device_id = get_device_id();
+    let settings = cli.common.settings(&config)?;
+    if cli.common.check_config {
+        println!("The configuration is valid");
+        return Ok(());
+    }
+
+    setup_global_subscriber(layer::Identity::new(), &settings.log_filter);
+
+    let device_id = get_device_id(settings.state_dir.as_deref());
+    let (stop, stopped) = mpsc::channel();
+    let fatal_error = Arc::new(Mutex::new(None));
+    // The watchdog is fed with the stats
+    let session_config = settings.session_config(systemd::watchdog_interval());
     let mut session = Session::connect(
-        cli.common.url,
-        SecretString::from(cli.common.secret),
+        settings.url,
+        settings.secret,
         device_id,
-        CallbackHandler,
+        session_config,
+        CallbackHandler {
+            stop: stop.clone(),
+            fatal_error: Arc::clone(&fatal_error),
+        },
     )
     .unwrap();
     tracing::info!("new_session");
 
-    block_on_ctrl_c();
+    block_on_ctrl_c_or(stop, stopped);
 
+    systemd::notify_stopping();
     session.disconnect(None);
+
+    if let Some(error) = fatal_error.lock().unwrap().take() {
+        bail!("The session failed: {error}");
+    }
     Ok(())
 }
 
 #[derive(Clone)]
-struct CallbackHandler;
+struct CallbackHandler {
+    /// Wakes up `main` to tear the session down.
+    stop: mpsc::Sender<()>,
+    fatal_error: Arc<Mutex<Option<String>>>,
+}
 
 impl Callbacks for CallbackHandler {
     type Error = std::convert::Infallible;
+
+    fn on_tunnel_ready(&self) -> Result<(), Self::Error> {
+        tracing::info!("tunnel_connected");
+        systemd::notify_ready();
+        Ok(())
+    }
+
+    // Only called from the control plane's event loop, so it stops if the loop gets stuck
+    fn on_stats(&self, _: &TunnelStats) -> Result<(), Self::Error> {
+        systemd::notify_watchdog();
+        Ok(())
+    }
+
+    fn on_disconnect(&self, error: Option<&Error>) -> Result<(), Self::Error> {
+        if let Some(error) = error {
+            tracing::error!(?error, "tunnel_disconnected");
+            *self.fatal_error.lock().unwrap() = Some(error.to_string());
+        }
+        // Fails once `main` is already tearing the session down
+        let _ = self.stop.send(());
+        Ok(())
+    }
 }
 
 #[derive(Parser)]
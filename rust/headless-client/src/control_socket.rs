@@ -0,0 +1,347 @@
+//! Local control socket, lets a CLI on the same machine inspect and stop a running client.
+//!
+//! Every line sent to the socket is a JSON-RPC 2.0 request and gets a single line response.
+//! Only the user running the client can connect to it.
+//! There's no control socket outside of Unix yet.
+#[cfg(unix)]
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context as _};
+use clap::ValueEnum;
+use connlib_client_shared::file_logger;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::state::State;
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/firezone/headless-client.sock";
+
+const JSONRPC_VERSION: &str = "2.0";
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+
+// Each connection has its own thread, so one that goes quiet is closed instead of holding it forever
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    /// Whether the tunnel is up and how many resources are connected.
+    Status,
+    /// Every resource with the state of its connection.
+    Resources,
+    /// The last stats of the tunnel.
+    Stats,
+    /// Rolls the log file over, returns the path of the previous one.
+    RollLog,
+    /// Disconnects and stops the client.
+    Disconnect,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    id: Value,
+    method: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<ResponseError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+/// What the requests are answered from.
+#[derive(Clone)]
+pub struct Context {
+    pub state: Arc<Mutex<State>>,
+    pub log_handle: Option<file_logger::Handle>,
+    /// Stops the client.
+    pub stop: Sender<()>,
+}
+
+/// A listening control socket, the socket file is removed once this is dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+    /// Device and inode of the socket file, so we don't remove one another client put in its place.
+    file_id: (u64, u64),
+}
+
+#[cfg(unix)]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if fs::metadata(&self.path).is_ok_and(|m| (m.dev(), m.ino()) == self.file_id) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Starts answering requests on a socket at `path` in the background.
+#[cfg(unix)]
+pub fn serve(path: &Path, context: Context) -> io::Result<ControlSocket> {
+    if let Some(parent) = path.parent() {
+        // Only applies if we are the ones creating it
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    // Removing it would silently take it over from the client that's listening on it
+    match UnixStream::connect(path) {
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "Another client is listening on the control socket",
+            ))
+        }
+        // Left over by a previous run that didn't exit cleanly
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        // E.g. we aren't allowed to connect, so we can't tell if it's in use
+        Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let metadata = fs::metadata(path)?;
+    let control_socket = ControlSocket {
+        path: path.to_owned(),
+        file_id: (metadata.dev(), metadata.ino()),
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let context = context.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle_connection(stream, &context) {
+                            tracing::debug!(error = ?e, "control_connection");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!(error = ?e, "control_socket_accept"),
+            }
+        }
+    });
+
+    Ok(control_socket)
+}
+
+#[cfg(not(unix))]
+pub fn serve(_: &Path, _: Context) -> io::Result<ControlSocket> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sends a single request to the client listening at `path` and returns its result.
+#[cfg(unix)]
+pub fn call(path: &Path, method: Method) -> anyhow::Result<Value> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Couldn't connect to {}", path.display()))?;
+    let request = Request {
+        jsonrpc: JSONRPC_VERSION.to_owned(),
+        id: Value::from(1),
+        method: serde_json::to_value(method)?
+            .as_str()
+            .expect("methods serialize as strings")
+            .to_owned(),
+    };
+    writeln!(stream, "{}", serde_json::to_string(&request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Response = serde_json::from_str(&line)?;
+    if let Some(error) = response.error {
+        return Err(anyhow!("{} ({})", error.message, error.code));
+    }
+
+    Ok(response.result.unwrap_or_default())
+}
+
+#[cfg(not(unix))]
+pub fn call(_: &Path, _: Method) -> anyhow::Result<Value> {
+    Err(anyhow!("There's no control socket on this platform"))
+}
+
+#[cfg(unix)]
+fn handle_connection(stream: UnixStream, context: &Context) -> io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let response = handle_line(&line?, context);
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+
+    Ok(())
+}
+
+fn handle_line(line: &str, context: &Context) -> Response {
+    let Ok(request) = serde_json::from_str::<Request>(line) else {
+        return error_response(Value::Null, PARSE_ERROR, "Parse error".to_owned());
+    };
+    let Ok(method) = serde_json::from_value::<Method>(Value::String(request.method)) else {
+        return error_response(request.id, METHOD_NOT_FOUND, "Method not found".to_owned());
+    };
+
+    match handle_method(method, context) {
+        Ok(result) => Response {
+            jsonrpc: JSONRPC_VERSION.to_owned(),
+            id: request.id,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => error_response(request.id, INTERNAL_ERROR, e.to_string()),
+    }
+}
+
+fn handle_method(method: Method, context: &Context) -> anyhow::Result<Value> {
+    tracing::debug!(?method, "control_request");
+
+    let result = match method {
+        Method::Status => serde_json::to_value(state(context).status())?,
+        Method::Resources => serde_json::to_value(state(context).resources())?,
+        Method::Stats => serde_json::to_value(state(context).stats())?,
+        Method::RollLog => {
+            let handle = context
+                .log_handle
+                .as_ref()
+                .context("The client doesn't log to a file")?;
+            serde_json::to_value(handle.roll_to_new_file()?)?
+        }
+        Method::Disconnect => {
+            context
+                .stop
+                .send(())
+                .context("The client is already stopping")?;
+            Value::Null
+        }
+    };
+
+    Ok(result)
+}
+
+fn state(context: &Context) -> std::sync::MutexGuard<'_, State> {
+    // The state stays consistent even if a callback panicked while holding the lock
+    context
+        .state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Response {
+    Response {
+        jsonrpc: JSONRPC_VERSION.to_owned(),
+        id,
+        result: None,
+        error: Some(ResponseError { code, message }),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::{
+        io,
+        os::unix::net::UnixListener,
+        sync::{mpsc, Arc, Mutex},
+    };
+
+    use serde_json::json;
+
+    use super::{handle_line, serve, Context, METHOD_NOT_FOUND, PARSE_ERROR};
+
+    fn context() -> (Context, mpsc::Receiver<()>) {
+        let (stop, stopped) = mpsc::channel();
+        let context = Context {
+            state: Arc::new(Mutex::new(Default::default())),
+            log_handle: None,
+            stop,
+        };
+        (context, stopped)
+    }
+
+    #[test]
+    fn answers_status() {
+        let (context, _) = context();
+
+        let response = handle_line(r#"{"jsonrpc":"2.0","id":7,"method":"status"}"#, &context);
+
+        assert_eq!(response.id, json!(7));
+        assert_eq!(
+            response.result,
+            Some(json!({
+                "tunnel_ready": false,
+                "resources": 0,
+                "connected_resources": 0,
+                "last_error": null,
+            }))
+        );
+    }
+
+    #[test]
+    fn disconnect_stops_the_client() {
+        let (context, stopped) = context();
+
+        let response = handle_line(
+            r#"{"jsonrpc":"2.0","id":1,"method":"disconnect"}"#,
+            &context,
+        );
+
+        assert!(response.error.is_none());
+        assert!(stopped.try_recv().is_ok());
+    }
+
+    #[test]
+    fn replaces_only_stale_sockets() {
+        let dir = std::env::temp_dir().join(format!("firezone-control-{}", std::process::id()));
+        let path = dir.join("client.sock");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let live = UnixListener::bind(&path).unwrap();
+        let in_use = serve(&path, context().0).err().unwrap();
+        assert_eq!(in_use.kind(), io::ErrorKind::AddrInUse);
+
+        // The file is left behind with nobody listening on it
+        drop(live);
+        let control_socket = serve(&path, context().0).unwrap();
+        drop(control_socket);
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let (context, _) = context();
+
+        let unknown = handle_line(r#"{"jsonrpc":"2.0","id":1,"method":"reboot"}"#, &context);
+        let garbage = handle_line("status", &context);
+
+        assert_eq!(unknown.error.unwrap().code, METHOD_NOT_FOUND);
+        assert_eq!(garbage.error.unwrap().code, PARSE_ERROR);
+    }
+}
@@ -1,41 +1,181 @@
-use anyhow::Result;
-use clap::Parser;
-use connlib_client_shared::{file_logger, get_device_id, Callbacks, Error, Session};
-use headless_utils::{block_on_ctrl_c, setup_global_subscriber, CommonArgs};
-use secrecy::SecretString;
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand};
+use connlib_client_shared::{
+    file_logger, get_device_id, stats::TunnelStats, Callbacks, DeviceIdentity, Error,
+    ResourceDescription, ResourceId, Session, SessionConfig,
+};
+use control_socket::{Context, Method, DEFAULT_SOCKET_PATH};
+use headless_utils::{block_on_ctrl_c_or, setup_global_subscriber, systemd, CommonArgs};
+use state::State;
 use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+// Only the parts the stubs use are built outside of Unix
+#[cfg_attr(not(unix), allow(dead_code))]
+mod control_socket;
+mod state;
+
+// Keeps what the control socket reports reasonably fresh when stats aren't logged
+const CONTROL_SOCKET_STATS_INTERVAL: Duration = Duration::from_secs(5);
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    if let Some(Command::Ctl(args)) = cli.command {
+        return ctl(args);
+    }
+
+    let config = cli.common.load_config()?;
+    // Only needs the state dir, the key can be rotated before the portal's url and secret are set
+    if cli.common.rotate_key {
+        let Some(state_dir) = cli.common.state_dir(&config) else {
+            bail!("There's no key to rotate without a state dir, set either `--state-dir` or `state_dir` in the config file");
+        };
+        let identity = DeviceIdentity::rotate_key(&state_dir, get_device_id(Some(&state_dir)))?;
+        println!("The new public key is {}", identity.public_key());
+        return Ok(());
+    }
 
-    let (layer, handle) = cli.log_dir.as_deref().map(file_logger::layer).unzip();
-    setup_global_subscriber(layer);
+    let settings = cli.common.settings(&config)?;
+    let log_dir = cli.log_dir.or(config.log_dir);
+    let stats_interval = cli.stats_interval.or(config.stats_interval);
+    let dns_addresses = cli
+        .dns_addresses
+        .or(config.dns_addresses)
+        .map(headless_utils::config::dns_addresses)
+        .transpose()?;
+    if cli.common.check_config {
+        println!("The configuration is valid");
+        return Ok(());
+    }
+
+    let (layer, handle) = log_dir.as_deref().map(file_logger::layer).unzip();
+    setup_global_subscriber(layer, &settings.log_filter);
+
+    let device_id = get_device_id(settings.state_dir.as_deref());
+    let state = Arc::new(Mutex::new(State::default()));
+    let (stop, stopped) = mpsc::channel();
+    let fatal_error = Arc::new(Mutex::new(None));
 
-    let device_id = get_device_id();
+    let control_socket = match control_socket::serve(
+        &cli.control_socket,
+        Context {
+            state: Arc::clone(&state),
+            log_handle: handle.clone(),
+            stop: stop.clone(),
+        },
+    ) {
+        Ok(control_socket) => Some(control_socket),
+        Err(e) => {
+            tracing::warn!(path = %cli.control_socket.display(), error = ?e, "Couldn't open the control socket");
+            None
+        }
+    };
 
+    let log_stats = stats_interval.is_some();
+    let stats_interval = stats_interval.map(Duration::from_secs).or(control_socket
+        .is_some()
+        .then_some(CONTROL_SOCKET_STATS_INTERVAL));
+    let watchdog_interval = systemd::watchdog_interval();
+    // The watchdog is fed with the stats, so they have to come at least that often
+    let stats_interval = match (stats_interval, watchdog_interval) {
+        (Some(stats), Some(watchdog)) => Some(stats.min(watchdog)),
+        (stats, watchdog) => stats.or(watchdog),
+    };
+
+    let session_config = SessionConfig {
+        dns_addresses,
+        ..settings.session_config(stats_interval)
+    };
     let mut session = Session::connect(
-        cli.common.url,
-        SecretString::from(cli.common.secret),
+        settings.url,
+        settings.secret,
         device_id,
-        CallbackHandler { handle },
+        session_config,
+        CallbackHandler {
+            handle,
+            log_stats,
+            state,
+            stop: stop.clone(),
+            fatal_error: Arc::clone(&fatal_error),
+            watchdog: watchdog_interval.is_some(),
+        },
     )
     .unwrap();
     tracing::info!("new_session");
 
-    block_on_ctrl_c();
+    block_on_ctrl_c_or(stop, stopped);
 
+    systemd::notify_stopping();
+    drop(control_socket);
     session.disconnect(None);
+
+    if let Some(error) = fatal_error.lock().unwrap().take() {
+        bail!("The session failed: {error}");
+    }
+    Ok(())
+}
+
+fn ctl(args: CtlArgs) -> Result<()> {
+    let result = control_socket::call(&args.socket, args.method)?;
+    println!("{}", serde_json::to_string_pretty(&result)?);
     Ok(())
 }
 
 #[derive(Clone)]
 struct CallbackHandler {
     handle: Option<file_logger::Handle>,
+    log_stats: bool,
+    state: Arc<Mutex<State>>,
+    /// Wakes up `main` to tear the session down.
+    stop: mpsc::Sender<()>,
+    fatal_error: Arc<Mutex<Option<String>>>,
+    watchdog: bool,
+}
+
+impl CallbackHandler {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 }
 
 impl Callbacks for CallbackHandler {
     type Error = std::convert::Infallible;
 
+    fn on_tunnel_ready(&self) -> Result<(), Self::Error> {
+        tracing::info!("tunnel_connected");
+        self.state().set_tunnel_ready();
+        systemd::notify_ready();
+        Ok(())
+    }
+
+    fn on_update_resources(
+        &self,
+        resource_list: Vec<ResourceDescription>,
+    ) -> Result<(), Self::Error> {
+        tracing::debug!(?resource_list, "resource_updated");
+        self.state().set_resources(resource_list);
+        Ok(())
+    }
+
+    fn on_resource_unreachable(
+        &self,
+        resource_id: ResourceId,
+        reason: &Error,
+    ) -> Result<(), Self::Error> {
+        self.state()
+            .set_unreachable(resource_id, reason.to_string());
+        Ok(())
+    }
+
+    fn on_error(&self, error: &Error) -> Result<(), Self::Error> {
+        tracing::warn!(error = ?error);
+        self.state().set_error(error.to_string());
+        Ok(())
+    }
+
     fn roll_log_file(&self) -> Option<PathBuf> {
         self.handle
             .as_ref()?
@@ -47,15 +187,69 @@ impl Callbacks for CallbackHandler {
                 None
             })
     }
+
+    fn on_stats(&self, stats: &TunnelStats) -> Result<(), Self::Error> {
+        if self.log_stats {
+            tracing::info!(?stats, "tunnel_stats");
+        }
+        self.state().set_stats(stats.clone());
+        // Only called from the control plane's event loop, so it stops if the loop gets stuck
+        if self.watchdog {
+            systemd::notify_watchdog();
+        }
+        Ok(())
+    }
+
+    fn on_disconnect(&self, error: Option<&Error>) -> Result<(), Self::Error> {
+        if let Some(error) = error {
+            tracing::error!(?error, "tunnel_disconnected");
+            *self.fatal_error.lock().unwrap() = Some(error.to_string());
+        }
+        // Fails once `main` is already tearing the session down
+        let _ = self.stop.send(());
+        Ok(())
+    }
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[command(flatten)]
     common: CommonArgs,
 
     /// File logging directory.
     #[arg(short, long, env = "FZ_LOG_DIR")]
     log_dir: Option<PathBuf>,
+
+    /// Seconds between logs of the tunnel's stats, not logged if unset.
+    #[arg(long, env = "FZ_STATS_INTERVAL")]
+    stats_interval: Option<u64>,
+
+    /// Which set of DNS sentinels and wildcard addresses to use, 0 being the one with `100.100.111.1`.
+    /// Every client running at the same time needs its own
+    #[arg(long, env = "FZ_DNS_ADDRESSES")]
+    dns_addresses: Option<u8>,
+
+    /// Where to listen for `ctl` requests, every client running at the same time needs its own.
+    #[arg(long, env = "FZ_CONTROL_SOCKET", default_value = DEFAULT_SOCKET_PATH)]
+    control_socket: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Queries a running client through its control socket.
+    Ctl(CtlArgs),
+}
+
+#[derive(Args)]
+struct CtlArgs {
+    /// The running client's control socket.
+    #[arg(long, env = "FZ_CONTROL_SOCKET", default_value = DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+
+    #[arg(value_enum)]
+    method: Method,
 }
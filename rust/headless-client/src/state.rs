@@ -0,0 +1,124 @@
+//! What we know about the running session, as told by connlib's callbacks.
+use std::collections::HashMap;
+
+use connlib_client_shared::{
+    stats::TunnelStats, ConnId, GatewayId, ResourceDescription, ResourceId,
+};
+use serde::Serialize;
+
+#[derive(Debug, Default)]
+pub struct State {
+    tunnel_ready: bool,
+    last_error: Option<String>,
+    resources: Vec<ResourceDescription>,
+    // Why we last gave up connecting to a resource, until we try again
+    unreachable: HashMap<ResourceId, String>,
+    stats: Option<TunnelStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub tunnel_ready: bool,
+    pub resources: usize,
+    pub connected_resources: usize,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResourceStatus {
+    #[serde(flatten)]
+    pub resource: ResourceDescription,
+    #[serde(flatten)]
+    pub connection: ConnectionState,
+}
+
+/// As of the last stats, so it can lag behind by up to the stats interval.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// No packet was sent to the resource yet.
+    Idle,
+    Connecting,
+    Connected {
+        gateway_id: GatewayId,
+    },
+    Unreachable {
+        reason: String,
+    },
+}
+
+impl State {
+    pub fn set_tunnel_ready(&mut self) {
+        self.tunnel_ready = true;
+    }
+
+    pub fn set_error(&mut self, error: String) {
+        self.last_error = Some(error);
+    }
+
+    pub fn set_resources(&mut self, resources: Vec<ResourceDescription>) {
+        self.unreachable
+            .retain(|id, _| resources.iter().any(|r| r.id() == *id));
+        self.resources = resources;
+    }
+
+    pub fn set_unreachable(&mut self, resource_id: ResourceId, reason: String) {
+        self.unreachable.insert(resource_id, reason);
+    }
+
+    pub fn set_stats(&mut self, stats: TunnelStats) {
+        // A new attempt or a connection supersedes the last failure
+        self.unreachable.retain(|id, _| {
+            !stats.resource_gateways.contains_key(id)
+                && !stats.awaiting_connection.contains(&ConnId::from(*id))
+        });
+        self.stats = Some(stats);
+    }
+
+    pub fn stats(&self) -> Option<&TunnelStats> {
+        self.stats.as_ref()
+    }
+
+    pub fn status(&self) -> Status {
+        let resources = self.resources();
+        Status {
+            tunnel_ready: self.tunnel_ready,
+            resources: resources.len(),
+            connected_resources: resources
+                .iter()
+                .filter(|r| matches!(r.connection, ConnectionState::Connected { .. }))
+                .count(),
+            last_error: self.last_error.clone(),
+        }
+    }
+
+    pub fn resources(&self) -> Vec<ResourceStatus> {
+        self.resources
+            .iter()
+            .map(|resource| ResourceStatus {
+                resource: resource.clone(),
+                connection: self.connection_state(resource.id()),
+            })
+            .collect()
+    }
+
+    fn connection_state(&self, id: ResourceId) -> ConnectionState {
+        if let Some(reason) = self.unreachable.get(&id) {
+            return ConnectionState::Unreachable {
+                reason: reason.clone(),
+            };
+        }
+
+        let Some(stats) = &self.stats else {
+            return ConnectionState::Idle;
+        };
+
+        if let Some(&gateway_id) = stats.resource_gateways.get(&id) {
+            ConnectionState::Connected { gateway_id }
+        } else if stats.awaiting_connection.contains(&ConnId::from(id)) {
+            ConnectionState::Connecting
+        } else {
+            ConnectionState::Idle
+        }
+    }
+}
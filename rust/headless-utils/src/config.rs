@@ -0,0 +1,235 @@
+//! Configuration file of the headless apps.
+//!
+//! Every setting in it can be overridden by an env var or a flag, see [crate::CommonArgs].
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use connlib_shared::{
+    DnsAddresses, DEFAULT_INTERFACE_NAME, INTERFACE_NAME_PREFIX, MAX_DNS_SESSIONS, MAX_MTU,
+    MAX_TUN_QUEUES, MIN_MTU,
+};
+use secrecy::SecretString;
+use serde::Deserialize;
+use url::Url;
+
+/// Set by systemd when the unit has `LoadCredential=` or `SetCredential=`.
+const CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+/// Name of the credential the service token is read from.
+pub const SECRET_CREDENTIAL: &str = "firezone-secret";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Portal's websocket url.
+    pub url: Option<Url>,
+    /// File containing the service token, so it doesn't show up in `ps` or the environment.
+    pub secret_file: Option<PathBuf>,
+    /// File logging directory, only used by the headless client.
+    pub log_dir: Option<PathBuf>,
+    /// Log filter, in the same format as `RUST_LOG`.
+    pub log_filter: Option<String>,
+    /// Where the device's id and private key are kept, a restart shows up as a new device without it.
+    pub state_dir: Option<PathBuf>,
+    /// Defaults to `tun-firezone`, every app running at the same time needs its own starting with `tun-fz`.
+    pub interface_name: Option<String>,
+    /// Resolvers for the DNS queries that aren't for a resource, instead of the ones set in the portal.
+    #[serde(default)]
+    pub upstream_dns: Vec<IpAddr>,
+    /// Number of queues the tunnel interface is read from, defaults to one per core.
+    pub tun_queues: Option<usize>,
+    /// Biggest packet sent through the tunnel, only needed if the network's MTU is smaller than usual.
+    ///
+    /// Bigger packets are answered with an ICMP packet too big, the interface's MTU stays the same.
+    pub mtu: Option<usize>,
+    /// Seconds between logs of the tunnel's stats, only used by the headless client.
+    pub stats_interval: Option<u64>,
+    /// Which set of DNS sentinels and wildcard addresses to use, only used by the headless client.
+    ///
+    /// Defaults to 0, whose sentinels are `100.100.111.1` and `fd00:2021:1111:8000:100:100:111:1`, the next sets
+    /// count up from there. Every client running at the same time needs its own.
+    pub dns_addresses: Option<u8>,
+}
+
+impl Config {
+    /// Reads the config at `path`, an empty config if there's no path.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Config::default());
+        };
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read the config file {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+}
+
+/// Reads the service token from the first of `secret_file` or the systemd credential that exists.
+pub(crate) fn read_secret(secret_file: Option<&Path>) -> Result<SecretString> {
+    if let Some(secret_file) = secret_file {
+        return read_secret_file(secret_file);
+    }
+
+    if let Some(dir) = std::env::var_os(CREDENTIALS_DIRECTORY_ENV) {
+        let credential = Path::new(&dir).join(SECRET_CREDENTIAL);
+        if credential.exists() {
+            return read_secret_file(&credential);
+        }
+    }
+
+    bail!("No service token, set one of `--secret`, `--secret-file`, `secret_file` in the config file or the `{SECRET_CREDENTIAL}` systemd credential")
+}
+
+fn read_secret_file(path: &Path) -> Result<SecretString> {
+    let file = || format!("Couldn't read the secret file {}", path.display());
+    let metadata = fs::metadata(path).with_context(file)?;
+    check_secret_permissions(path, &metadata)?;
+
+    let secret = fs::read_to_string(path).with_context(file)?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        bail!("The secret file {} is empty", path.display());
+    }
+
+    Ok(SecretString::from(secret.to_owned()))
+}
+
+#[cfg(unix)]
+fn check_secret_permissions(path: &Path, metadata: &fs::Metadata) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // We run before logging is set up, so this can't just be a warning
+    if metadata.permissions().mode() & 0o007 != 0 {
+        bail!(
+            "The secret file {} can be accessed by every user, restrict it to 0600",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Files are only readable by everyone there if their ACL says so, which we leave to whoever installs the service.
+#[cfg(not(unix))]
+fn check_secret_permissions(_: &Path, _: &fs::Metadata) -> Result<()> {
+    Ok(())
+}
+
+pub(crate) fn validate_interface_name(name: &str) -> Result<()> {
+    connlib_shared::validate_interface_name(name).with_context(|| {
+        format!("The interface name has to be `{DEFAULT_INTERFACE_NAME}` or start with `{INTERFACE_NAME_PREFIX}`, be at most 15 bytes and can't contain `/`, `:` or whitespace")
+    })
+}
+
+pub(crate) fn validate_tun_queues(queues: usize) -> Result<()> {
+    if queues == 0 || queues > MAX_TUN_QUEUES {
+        bail!("The number of tun queues has to be between 1 and {MAX_TUN_QUEUES}");
+    }
+
+    Ok(())
+}
+
+/// The `index`th set of DNS addresses, see [Config::dns_addresses].
+pub fn dns_addresses(index: u8) -> Result<DnsAddresses> {
+    DnsAddresses::nth(index).with_context(|| {
+        format!(
+            "The DNS addresses have to be between 0 and {}",
+            MAX_DNS_SESSIONS - 1
+        )
+    })
+}
+
+pub(crate) fn validate_mtu(mtu: usize) -> Result<()> {
+    connlib_shared::validate_mtu(mtu)
+        .with_context(|| format!("The MTU has to be between {MIN_MTU} and {MAX_MTU}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    use secrecy::ExposeSecret;
+
+    use super::{read_secret, validate_interface_name, validate_mtu, Config};
+
+    #[test]
+    fn parses_config() {
+        let config: Config = toml::from_str(
+            r#"
+            url = "wss://api.firezone.dev"
+            secret_file = "/etc/firezone/secret"
+            log_filter = "firezone_tunnel=debug,warn"
+            upstream_dns = ["1.1.1.1", "2606:4700:4700::1111"]
+            stats_interval = 60
+            tun_queues = 4
+            mtu = 1280
+            dns_addresses = 1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.url.unwrap().as_str(), "wss://api.firezone.dev/");
+        assert_eq!(config.upstream_dns.len(), 2);
+        assert_eq!(config.stats_interval, Some(60));
+        assert_eq!(config.tun_queues, Some(4));
+        assert_eq!(config.mtu, Some(1280));
+        assert_eq!(config.dns_addresses, Some(1));
+        assert!(config.log_dir.is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        // Most likely a typo, which would otherwise silently be ignored
+        assert!(toml::from_str::<Config>(r#"secret = "token""#).is_err());
+    }
+
+    #[test]
+    fn reads_trimmed_secret_file() {
+        let path = std::env::temp_dir().join(format!("fz-secret-{}", std::process::id()));
+        fs::write(&path, "token\n").unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let secret = read_secret(Some(&path));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(secret.unwrap().expose_secret(), "token");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_world_readable_secret_file() {
+        let path = std::env::temp_dir().join(format!("fz-public-secret-{}", std::process::id()));
+        fs::write(&path, "token").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let secret = read_secret(Some(&path));
+        fs::remove_file(&path).unwrap();
+
+        assert!(secret.is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_interface_names() {
+        assert!(validate_interface_name("tun-firezone").is_ok());
+        assert!(validate_interface_name("tun-fz-corp").is_ok());
+        assert!(validate_interface_name("").is_err());
+        assert!(validate_interface_name("tun-firezone-too-long").is_err());
+        assert!(validate_interface_name("tun/firezone").is_err());
+        // Wouldn't be left out of the ICE candidates of other sessions
+        assert!(validate_interface_name("tun-corp").is_err());
+    }
+
+    #[test]
+    fn rejects_mtus_out_of_range() {
+        assert!(validate_mtu(1280).is_ok());
+        assert!(validate_mtu(u16::MAX as usize).is_ok());
+        assert!(validate_mtu(1279).is_err());
+        assert!(validate_mtu(u16::MAX as usize + 1).is_err());
+    }
+}
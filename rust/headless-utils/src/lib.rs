@@ -1,33 +1,191 @@
+use anyhow::{Context, Result};
 use clap::Args;
+use connlib_shared::SessionConfig;
+use secrecy::SecretString;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
 use tracing_subscriber::{
     fmt, prelude::__tracing_subscriber_SubscriberExt, EnvFilter, Layer, Registry,
 };
 use url::Url;
 
+pub mod config;
+pub mod systemd;
+
+pub use config::Config;
+
+/// Blocks until we are asked to stop, with Ctrl-C, `SIGTERM` or `SIGHUP`.
 pub fn block_on_ctrl_c() {
-    let (tx, rx) = std::sync::mpsc::channel();
-    ctrlc::set_handler(move || tx.send(()).expect("Could not send stop signal on channel."))
-        .expect("Error setting Ctrl-C handler");
+    let (tx, rx) = mpsc::channel();
+    block_on_ctrl_c_or(tx, rx);
+}
+
+/// Blocks until we are asked to stop, like [block_on_ctrl_c], or something is sent through a clone of `tx`.
+pub fn block_on_ctrl_c_or(tx: Sender<()>, rx: Receiver<()>) {
+    // Once we stopped waiting, further signals don't have anywhere to go
+    ctrlc::set_handler(move || {
+        let _ = tx.send(());
+    })
+    .expect("Error setting Ctrl-C handler");
     rx.recv().expect("Could not receive ctrl-c signal");
 }
 
-pub fn setup_global_subscriber<L>(additional_layer: L)
+/// Logs to stdout and `additional_layer`, filtered by `log_filter`, see [Settings::log_filter].
+pub fn setup_global_subscriber<L>(additional_layer: L, log_filter: &str)
 where
     L: Layer<Registry> + Send + Sync,
 {
     let subscriber = Registry::default()
-        .with(additional_layer.with_filter(EnvFilter::from_default_env()))
-        .with(fmt::layer().with_filter(EnvFilter::from_default_env()));
+        .with(additional_layer.with_filter(EnvFilter::new(log_filter)))
+        .with(fmt::layer().with_filter(EnvFilter::new(log_filter)));
     tracing::subscriber::set_global_default(subscriber).expect("Could not set global default");
 }
 
 /// Arguments common to all headless FZ apps.
+///
+/// They take precedence over the same settings in the config file.
 #[derive(Args, Clone)]
 pub struct CommonArgs {
+    /// TOML config file, overridden by env vars and flags
+    #[arg(short, long, env = "FZ_CONFIG")]
+    pub config: Option<PathBuf>,
     /// Portal's websocket url
     #[arg(short, long, env = "FZ_URL")]
+    pub url: Option<Url>,
+    /// Service token, prefer `--secret-file` since arguments are visible to every user
+    #[arg(short, long, env = "FZ_SECRET", hide_env_values = true)]
+    pub secret: Option<String>,
+    /// File containing the service token
+    #[arg(long, env = "FZ_SECRET_FILE")]
+    pub secret_file: Option<PathBuf>,
+    /// Log filter, in the same format as `RUST_LOG`
+    #[arg(long, env = "RUST_LOG")]
+    pub log_filter: Option<String>,
+    /// Directory to keep the device's id and private key in
+    #[arg(long, env = "FZ_STATE_DIR")]
+    pub state_dir: Option<PathBuf>,
+    /// Name of the tunnel interface, other than the default it has to start with `tun-fz`
+    #[arg(long, env = "FZ_INTERFACE_NAME")]
+    pub interface_name: Option<String>,
+    /// Comma separated resolvers for the DNS queries that aren't for a resource
+    #[arg(long, env = "FZ_UPSTREAM_DNS", value_delimiter = ',')]
+    pub upstream_dns: Vec<IpAddr>,
+    /// Number of queues the tunnel interface is read from, defaults to one per core
+    #[arg(long, env = "FZ_TUN_QUEUES")]
+    pub tun_queues: Option<usize>,
+    /// Biggest packet sent through the tunnel, defaults to what fits once the tunnel's overhead is added.
+    /// It doesn't change the interface's MTU
+    #[arg(long, env = "FZ_MTU")]
+    pub mtu: Option<usize>,
+    /// Validates the configuration and exits
+    #[arg(long)]
+    pub check_config: bool,
+    /// Replaces the private key in the state dir and exits, takes effect on the next start
+    #[arg(long, conflicts_with = "check_config")]
+    pub rotate_key: bool,
+}
+
+/// The settings of [CommonArgs] merged with the config file.
+pub struct Settings {
     pub url: Url,
-    /// Service token
-    #[arg(short, long, env = "FZ_SECRET")]
-    pub secret: String,
+    pub secret: SecretString,
+    pub log_filter: String,
+    pub state_dir: Option<PathBuf>,
+    pub interface_name: Option<String>,
+    /// Empty to use the resolvers set in the portal.
+    pub upstream_dns: Vec<IpAddr>,
+    pub tun_queues: Option<usize>,
+    pub mtu: Option<usize>,
+}
+
+impl Settings {
+    /// The settings connlib takes when connecting, calling [connlib_shared::Callbacks::on_stats] every `stats_interval`.
+    pub fn session_config(&self, stats_interval: Option<Duration>) -> SessionConfig {
+        SessionConfig {
+            interface_name: self.interface_name.clone(),
+            tun_queues: self.tun_queues,
+            mtu: self.mtu,
+            state_dir: self.state_dir.clone(),
+            upstream_dns: (!self.upstream_dns.is_empty()).then(|| self.upstream_dns.clone()),
+            stats_interval,
+            connection_retry_policy: None,
+            dns_addresses: None,
+        }
+    }
+}
+
+impl CommonArgs {
+    /// Reads the config file, if any.
+    pub fn load_config(&self) -> Result<Config> {
+        Config::load(self.config.as_deref())
+    }
+
+    /// Directory to keep the device's identity in, from the arguments or `config`.
+    pub fn state_dir(&self, config: &Config) -> Option<PathBuf> {
+        self.state_dir.clone().or_else(|| config.state_dir.clone())
+    }
+
+    /// Merges the arguments with `config` and validates the result.
+    pub fn settings(&self, config: &Config) -> Result<Settings> {
+        let url = self
+            .url
+            .clone()
+            .or_else(|| config.url.clone())
+            .context("No portal url, set either `--url` or `url` in the config file")?;
+
+        let secret = match &self.secret {
+            Some(secret) => SecretString::from(secret.clone()),
+            None => config::read_secret(
+                self.secret_file
+                    .as_deref()
+                    .or(config.secret_file.as_deref()),
+            )?,
+        };
+
+        let log_filter = self
+            .log_filter
+            .clone()
+            .or_else(|| config.log_filter.clone())
+            .unwrap_or_default();
+        EnvFilter::try_new(&log_filter).context("Invalid log filter")?;
+
+        let state_dir = self.state_dir(config);
+
+        let interface_name = self
+            .interface_name
+            .clone()
+            .or_else(|| config.interface_name.clone());
+        if let Some(interface_name) = &interface_name {
+            config::validate_interface_name(interface_name)?;
+        }
+
+        let upstream_dns = if self.upstream_dns.is_empty() {
+            config.upstream_dns.clone()
+        } else {
+            self.upstream_dns.clone()
+        };
+
+        let tun_queues = self.tun_queues.or(config.tun_queues);
+        if let Some(tun_queues) = tun_queues {
+            config::validate_tun_queues(tun_queues)?;
+        }
+
+        let mtu = self.mtu.or(config.mtu);
+        if let Some(mtu) = mtu {
+            config::validate_mtu(mtu)?;
+        }
+
+        Ok(Settings {
+            url,
+            secret,
+            log_filter,
+            state_dir,
+            interface_name,
+            upstream_dns,
+            tun_queues,
+            mtu,
+        })
+    }
 }
@@ -0,0 +1,51 @@
+//! Lets systemd supervise us when run as a `Type=notify` service.
+//!
+//! Everything here is a no-op when we weren't started by systemd, which is always the case outside of Linux.
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use sd_notify::NotifyState;
+
+/// Tells systemd we are up, i.e. that the tunnel is ready.
+pub fn notify_ready() {
+    #[cfg(target_os = "linux")]
+    notify(&[NotifyState::Ready]);
+}
+
+/// Tells systemd we are shutting down, so it doesn't take the time it takes as a hang.
+pub fn notify_stopping() {
+    #[cfg(target_os = "linux")]
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Tells systemd we are still healthy, has to be called every [watchdog_interval].
+pub fn notify_watchdog() {
+    #[cfg(target_os = "linux")]
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// How often we have to call [notify_watchdog], `None` if the service has no watchdog.
+#[cfg(not(target_os = "linux"))]
+pub fn watchdog_interval() -> Option<Duration> {
+    None
+}
+
+/// How often we have to call [notify_watchdog], `None` if the service has no watchdog.
+///
+/// Half of `WatchdogSec` so that a late notification doesn't get us killed.
+#[cfg(target_os = "linux")]
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return None;
+    }
+
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(target_os = "linux")]
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        tracing::warn!(error = ?e, "systemd_notify");
+    }
+}
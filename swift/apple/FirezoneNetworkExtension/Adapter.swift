@@ -85,6 +85,12 @@ public class Adapter {
   /// Keep track of resources
   private var displayableResources = DisplayableResources()
 
+  /// Latest snapshot of the tunnel, as the JSON connlib sends
+  private var stats: String?
+
+  /// App message asking for `stats` instead of the resources
+  public static let statsAppMessage = "stats"
+
   /// Starting parameters
   private var controlPlaneURLString: String
   private var token: String
@@ -214,6 +220,15 @@ public class Adapter {
       }
     }
   }
+
+  /// Get the latest stats of the tunnel in the completionHandler, nil until connlib sends some.
+  public func getStats(completionHandler: @escaping (String?) -> Void) {
+    workQueue.async { [weak self] in
+      guard let self = self else { return }
+
+      completionHandler(self.stats)
+    }
+  }
 }
 
 // MARK: Device unique identifiers
@@ -314,8 +329,8 @@ extension Adapter {
 
 extension Adapter: CallbackHandlerDelegate {
   public func onSetInterfaceConfig(
-    tunnelAddressIPv4: String, tunnelAddressIPv6: String, dnsAddress: String,
-    dnsFallbackStrategy: String
+    tunnelAddressIPv4: String, tunnelAddressIPv6: String, dnsAddressIPv4: String,
+    dnsAddressIPv6: String, dnsFallbackStrategy: String
   ) {
     workQueue.async { [weak self] in
       guard let self = self else { return }
@@ -326,7 +341,7 @@ extension Adapter: CallbackHandlerDelegate {
       case .startingTunnel:
         self.networkSettings = NetworkSettings(
           tunnelAddressIPv4: tunnelAddressIPv4, tunnelAddressIPv6: tunnelAddressIPv6,
-          dnsAddress: dnsAddress,
+          dnsAddressIPv4: dnsAddressIPv4, dnsAddressIPv6: dnsAddressIPv6,
           dnsFallbackStrategy: NetworkSettings.DNSFallbackStrategy(dnsFallbackStrategy))
       case .tunnelReady:
         if let networkSettings = self.networkSettings {
@@ -496,4 +511,18 @@ extension Adapter: CallbackHandlerDelegate {
   public func onError(error: String) {
     self.logger.error("Internal connlib error: \(error, privacy: .public)")
   }
+
+  public func onResourceUnreachable(resourceId: String, reason: String) {
+    self.logger.error(
+      "Adapter.onResourceUnreachable: \(resourceId, privacy: .public) \(reason, privacy: .public)")
+  }
+
+  public func onStats(stats: String) {
+    workQueue.async { [weak self] in
+      guard let self = self else { return }
+
+      self.logger.debug("Adapter.onStats: \(stats, privacy: .public)")
+      self.stats = stats
+    }
+  }
 }
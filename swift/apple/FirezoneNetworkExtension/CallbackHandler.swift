@@ -19,15 +19,18 @@ public protocol CallbackHandlerDelegate: AnyObject {
   func onSetInterfaceConfig(
     tunnelAddressIPv4: String,
     tunnelAddressIPv6: String,
-    dnsAddress: String,
+    dnsAddressIPv4: String,
+    dnsAddressIPv6: String,
     dnsFallbackStrategy: String
   )
   func onTunnelReady()
   func onAddRoute(_: String)
   func onRemoveRoute(_: String)
   func onUpdateResources(resourceList: String)
+  func onResourceUnreachable(resourceId: String, reason: String)
   func onDisconnect(error: String?)
   func onError(error: String)
+  func onStats(stats: String)
 }
 
 public class CallbackHandler {
@@ -37,7 +40,8 @@ public class CallbackHandler {
   func onSetInterfaceConfig(
     tunnelAddressIPv4: RustString,
     tunnelAddressIPv6: RustString,
-    dnsAddress: RustString,
+    dnsAddressIPv4: RustString,
+    dnsAddressIPv6: RustString,
     dnsFallbackStrategy: RustString
   ) {
     logger.log(
@@ -45,13 +49,15 @@ public class CallbackHandler {
         CallbackHandler.onSetInterfaceConfig:
           IPv4: \(tunnelAddressIPv4.toString(), privacy: .public)
           IPv6: \(tunnelAddressIPv6.toString(), privacy: .public)
-          DNS: \(dnsAddress.toString(), privacy: .public)
+          DNS IPv4: \(dnsAddressIPv4.toString(), privacy: .public)
+          DNS IPv6: \(dnsAddressIPv6.toString(), privacy: .public)
           dnsFallbackStrategy: \(dnsFallbackStrategy.toString(), privacy: .public)
       """)
     delegate?.onSetInterfaceConfig(
       tunnelAddressIPv4: tunnelAddressIPv4.toString(),
       tunnelAddressIPv6: tunnelAddressIPv6.toString(),
-      dnsAddress: dnsAddress.toString(),
+      dnsAddressIPv4: dnsAddressIPv4.toString(),
+      dnsAddressIPv6: dnsAddressIPv6.toString(),
       dnsFallbackStrategy: dnsFallbackStrategy.toString()
     )
   }
@@ -76,6 +82,16 @@ public class CallbackHandler {
     delegate?.onUpdateResources(resourceList: resourceList.toString())
   }
 
+  func onResourceUnreachable(resourceId: RustString, reason: RustString) {
+    logger.log(
+      """
+        CallbackHandler.onResourceUnreachable:
+          resourceId: \(resourceId.toString(), privacy: .public)
+          reason: \(reason.toString(), privacy: .public)
+      """)
+    delegate?.onResourceUnreachable(resourceId: resourceId.toString(), reason: reason.toString())
+  }
+
   func onDisconnect(error: RustString) {
     logger.log("CallbackHandler.onDisconnect: \(error.toString(), privacy: .public)")
     let error = error.toString()
@@ -90,4 +106,8 @@ public class CallbackHandler {
     logger.log("CallbackHandler.onError: \(error.toString(), privacy: .public)")
     delegate?.onError(error: error.toString())
   }
+
+  func onStats(stats: RustString) {
+    delegate?.onStats(stats: stats.toString())
+  }
 }
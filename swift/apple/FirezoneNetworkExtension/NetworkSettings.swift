@@ -32,7 +32,8 @@ class NetworkSettings {
   // Unchanging values
   let tunnelAddressIPv4: String
   let tunnelAddressIPv6: String
-  let dnsAddress: String
+  let dnsAddressIPv4: String
+  let dnsAddressIPv6: String
 
   // WireGuard has an 80-byte overhead.
   let tunnelOverheadBytes = NSNumber(80)
@@ -46,12 +47,13 @@ class NetworkSettings {
   private(set) var hasUnappliedChanges: Bool
 
   init(
-    tunnelAddressIPv4: String, tunnelAddressIPv6: String, dnsAddress: String,
-    dnsFallbackStrategy: DNSFallbackStrategy
+    tunnelAddressIPv4: String, tunnelAddressIPv6: String, dnsAddressIPv4: String,
+    dnsAddressIPv6: String, dnsFallbackStrategy: DNSFallbackStrategy
   ) {
     self.tunnelAddressIPv4 = tunnelAddressIPv4
     self.tunnelAddressIPv6 = tunnelAddressIPv6
-    self.dnsAddress = dnsAddress
+    self.dnsAddressIPv4 = dnsAddressIPv4
+    self.dnsAddressIPv6 = dnsAddressIPv6
     self.dnsFallbackStrategy = dnsFallbackStrategy
     self.hasUnappliedChanges = true
   }
@@ -169,7 +171,7 @@ class NetworkSettings {
     ipv6Settings.includedRoutes = tunnelIPv6Routes
     tunnelNetworkSettings.ipv6Settings = ipv6Settings
 
-    let dnsSettings = NEDNSSettings(servers: [dnsAddress])
+    let dnsSettings = NEDNSSettings(servers: [dnsAddressIPv4, dnsAddressIPv6])
     switch dnsFallbackStrategy {
     case .systemResolver:
       // Enable split-DNS. Only those domains matching the resources will be sent to the tunnel's DNS.
@@ -75,6 +75,12 @@ class PacketTunnelProvider: NEPacketTunnelProvider {
 
   override func handleAppMessage(_ messageData: Data, completionHandler: ((Data?) -> Void)? = nil) {
     let query = String(data: messageData, encoding: .utf8) ?? ""
+    if query == Adapter.statsAppMessage {
+      adapter?.getStats { stats in
+        completionHandler?(stats?.data(using: .utf8))
+      }
+      return
+    }
     adapter?.getDisplayableResourcesIfVersionDifferentFrom(referenceVersionString: query) {
       displayableResources in
       completionHandler?(displayableResources?.toData())